@@ -0,0 +1,118 @@
+//! A gRPC unary call and a bidirectional streaming call through the proxy
+//!
+//! An echo backend speaking HTTP/2 only, the proxy in front of it (serving h2c) and a client all
+//! run on localhost: `cargo run --example grpc`
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Request, Response, Server};
+use proxylib::connect::{UpstreamConfig, UpstreamProtocol};
+use proxylib::handlers::with_client::WithClient;
+use proxylib::handlers::Redirect;
+use proxylib::{Http2Config, Proxy, ProxyConfig};
+
+// A length-prefixed gRPC message
+fn message(payload: &str) -> Bytes {
+	let mut message = vec![0];
+	message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+	message.extend_from_slice(payload.as_bytes());
+	message.into()
+}
+
+// Echo every message as soon as it arrives, then send the status in the trailers
+async fn echo(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+	let mut messages = request.into_body();
+	let (mut tx, body) = Body::channel();
+	tokio::spawn(async move {
+		while let Some(Ok(message)) = messages.data().await {
+			// The end of the request can come in an empty frame
+			if message.is_empty() {
+				continue;
+			}
+			if tx.send_data(message).await.is_err() {
+				return;
+			}
+		}
+		let mut status = HeaderMap::new();
+		status.insert("grpc-status", "0".parse().unwrap());
+		let _ = tx.send_trailers(status).await;
+	});
+	Ok(Response::builder()
+		.header("content-type", "application/grpc")
+		.body(body)
+		.unwrap())
+}
+
+fn start_backend() -> SocketAddr {
+	let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+	let addr = incoming.local_addr();
+	let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(echo)) });
+	tokio::spawn(Server::builder(incoming).http2_only(true).serve(service));
+	addr
+}
+
+fn start_proxy(backend: SocketAddr) -> SocketAddr {
+	let upstream = UpstreamConfig {
+		protocol: UpstreamProtocol::Http2,
+		..UpstreamConfig::default()
+	};
+	let route = Redirect::change_authority(backend.to_string().parse().unwrap());
+	let config = ProxyConfig::new(
+		"127.0.0.1:0".parse().unwrap(),
+		WithClient::new(route, &upstream),
+	)
+	.with_http2(Http2Config {
+		h2c: true,
+		..Http2Config::default()
+	});
+	let proxy = Proxy::bind(config).unwrap();
+	let addr = proxy.local_addr();
+	tokio::spawn(proxy.run());
+	addr
+}
+
+#[tokio::main]
+async fn main() {
+	let proxy = start_proxy(start_backend());
+	let client = Client::builder().http2_only(true).build_http::<Body>();
+	let call = |body| {
+		let request = Request::post(format!("http://{}/echo.Echo/Echo", proxy))
+			.header("content-type", "application/grpc")
+			.header("te", "trailers")
+			.body(body)
+			.unwrap();
+		client.request(request)
+	};
+
+	// Unary: one message each way, then the status
+	let response = call(Body::from(message("hello"))).await.unwrap();
+	let mut body = response.into_body();
+	let reply = hyper::body::to_bytes(&mut body).await.unwrap();
+	let status = body.trailers().await.unwrap().unwrap();
+	assert_eq!(reply, message("hello"));
+	assert_eq!(status["grpc-status"], "0");
+	println!(
+		"unary: got the reply and grpc-status {:?}",
+		status["grpc-status"]
+	);
+
+	// Bidirectional streaming: every reply arrives before the next message is sent
+	let (mut tx, body) = Body::channel();
+	tx.send_data(message("one")).await.unwrap();
+	let mut replies = call(body).await.unwrap().into_body();
+	assert_eq!(replies.data().await.unwrap().unwrap(), message("one"));
+	tx.send_data(message("two")).await.unwrap();
+	assert_eq!(replies.data().await.unwrap().unwrap(), message("two"));
+	drop(tx);
+	assert!(replies.data().await.is_none());
+	let status = replies.trailers().await.unwrap().unwrap();
+	assert_eq!(status["grpc-status"], "0");
+	println!(
+		"streaming: got both replies in turn and grpc-status {:?}",
+		status["grpc-status"]
+	);
+}
@@ -2,14 +2,13 @@ use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 
 use hyper::http::uri::Authority;
-use once_cell::sync::Lazy;
 use proxylib::handlers::filter::SocketAddrLookupFilter;
-use proxylib::handlers::redirect::ChangeAuthority;
 use proxylib::handlers::{Filter, Redirect};
 use proxylib::ProxyConfig;
 
-static HANDLER: Lazy<Filter<Redirect<ChangeAuthority>, SocketAddrLookupFilter>> = Lazy::new(|| {
-	Filter::<_, SocketAddrLookupFilter>::addr_whitelist(
+#[tokio::main]
+async fn main() {
+	let handler = Filter::<_, SocketAddrLookupFilter>::addr_whitelist(
 		Redirect::change_authority(Authority::from_static("example.com")),
 		{
 			let mut whitelist = HashSet::new();
@@ -19,15 +18,12 @@ static HANDLER: Lazy<Filter<Redirect<ChangeAuthority>, SocketAddrLookupFilter>>
 			whitelist.insert(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 8000));
 			whitelist
 		},
-	)
-});
+	);
 
-#[tokio::main]
-async fn main() {
-	let config = ProxyConfig {
-		listen_on: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
-		request_handler: &*HANDLER,
-	};
+	let config = ProxyConfig::new(
+		SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+		handler,
+	);
 
 	proxylib::run_proxy(config).await.unwrap();
 }
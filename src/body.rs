@@ -0,0 +1,294 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::http::request::Parts;
+use hyper::http::Extensions;
+use hyper::{Body, HeaderMap, Request, Response};
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Instant};
+
+use crate::handlers::deadline::RequestDeadline;
+use crate::handlers::middleware::Middleware;
+use crate::handlers::proxy_auth::ProxyUser;
+use crate::handlers::tenancy::Tenant;
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::transparent::OriginalDestination;
+
+/// Call `f` with the length of every chunk of the body as it is streamed
+///
+/// Bodies that are known to be empty are returned as they are.
+pub fn inspect_len<F: Fn(usize) + Send + 'static>(body: Body, f: F) -> Body {
+	if body.size_hint().exact() == Some(0) {
+		return body;
+	}
+
+	map_data(body, move |data| {
+		data.inspect_ok(move |chunk| f(chunk.len()))
+	})
+}
+
+// A body of the chunks, followed by the trailers once the chunks are done
+//
+// `Body::wrap_stream` has no way to send trailers (which gRPC puts its status in), so the
+// chunks and trailers are sent through a channel instead. The stream is dropped once the
+// trailers have been sent, or the client went away.
+pub(crate) fn with_trailers<S, E, T>(chunks: S, trailers: T) -> Body
+where
+	S: futures::Stream<Item = Result<Bytes, E>> + Send + 'static,
+	T: Future<Output = Option<HeaderMap>> + Send + 'static,
+{
+	let (mut tx, body) = Body::channel();
+	tokio::spawn(async move {
+		futures::pin_mut!(chunks);
+		loop {
+			let chunk = match chunks.next().await {
+				Some(Ok(chunk)) => chunk,
+				Some(Err(_)) => return tx.abort(),
+				None => break,
+			};
+			if tx.send_data(chunk).await.is_err() {
+				return;
+			}
+		}
+		if let Some(trailers) = trailers.await {
+			let _ = tx.send_trailers(trailers).await;
+		}
+	});
+	body
+}
+
+// Change the chunks of the body with `f`, keeping its trailers
+//
+// The trailers are only forwarded if the stream returned by `f` reads the data to the end.
+pub(crate) fn map_data<F, S, E>(body: Body, f: F) -> Body
+where
+	F: FnOnce(BoxStream<'static, hyper::Result<Bytes>>) -> S,
+	S: futures::Stream<Item = Result<Bytes, E>> + Send + 'static,
+{
+	let (done_tx, done_rx) = oneshot::channel();
+	let data = stream::unfold(Some((body, done_tx)), |state| async move {
+		let (mut body, done) = state?;
+		match body.data().await {
+			Some(chunk) => Some((chunk, Some((body, done)))),
+			None => {
+				let _ = done.send(body);
+				None
+			}
+		}
+	});
+	let trailers = async move { done_rx.await.ok()?.trailers().await.ok()? };
+	with_trailers(f(data.boxed()), trailers)
+}
+
+/// A marker in the extensions of a response whose body must be passed on chunk by chunk
+///
+/// Combinators that would otherwise read the whole body first (e.g. to cache or check it)
+/// leave such responses alone, see [`is_streaming`]. It is also a [`Middleware`] that marks all
+/// responses of a route, e.g. for long-polling endpoints, whose responses look like any other.
+///
+/// # Example
+/// ```
+/// use proxylib::body::Streaming;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::RequestHandler;
+///
+/// let updates = Redirect::change_authority("events.internal".parse().unwrap()).layer(Streaming);
+/// ```
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Streaming;
+
+impl Middleware for Streaming {
+	fn after(&self, response: &mut Response<Body>) {
+		response.extensions_mut().insert(Streaming);
+	}
+}
+
+/// Return whether the body of the response must be streamed as it arrives, because it is
+/// marked as [`Streaming`] or has the content type `text/event-stream` (server-sent events) or
+/// `application/grpc` (including subtypes like `application/grpc+proto`)
+pub fn is_streaming<B>(response: &Response<B>) -> bool {
+	let content_type = response
+		.headers()
+		.get(CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.split(';').next())
+		.map(|v| v.trim().to_ascii_lowercase());
+	response.extensions().get::<Streaming>().is_some()
+		|| content_type.is_some_and(|v| {
+			v == "text/event-stream"
+				|| v == "application/grpc"
+				|| v.starts_with("application/grpc+")
+		})
+}
+
+/// Read the whole body, unless it is larger than `limit` bytes
+///
+/// Returns `Ok(None)` as soon as the limit is exceeded.
+pub async fn read_limited(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+	let mut buf = Vec::new();
+	while let Some(chunk) = body.data().await {
+		let chunk = chunk?;
+		if buf.len() + chunk.len() > limit {
+			return Ok(None);
+		}
+		buf.extend_from_slice(&chunk);
+	}
+	Ok(Some(buf.into()))
+}
+
+/// The result of [`buffer`]
+pub enum Buffered {
+	/// The whole body, which was within the limit
+	Complete(Bytes),
+	/// A body equivalent to the original one, which was larger than the limit
+	Partial(Body),
+}
+
+/// Read the whole body if it is at most `limit` bytes large
+///
+/// Unlike [`read_limited`], nothing is lost if the body is too large: the chunks read so far
+/// are put in front of the rest of the body again.
+pub async fn buffer(mut body: Body, limit: usize) -> Result<Buffered, hyper::Error> {
+	let mut chunks = Vec::new();
+	let mut len = 0;
+	while let Some(chunk) = body.data().await {
+		let chunk = chunk?;
+		len += chunk.len();
+		chunks.push(chunk);
+
+		if len > limit {
+			let prefix = stream::iter(chunks.into_iter().map(Ok));
+			return Ok(Buffered::Partial(map_data(body, |rest| prefix.chain(rest))));
+		}
+	}
+
+	let mut buf = Vec::with_capacity(len);
+	for chunk in chunks {
+		buf.extend_from_slice(&chunk);
+	}
+	Ok(Buffered::Complete(buf.into()))
+}
+
+// Copy the extensions that describe the connection and the context of a request (like its
+// addresses, deadline and tenant) to another request
+//
+// Extensions can't be cloned as a whole, so the other ones are lost.
+pub(crate) fn copy_context(from: &Extensions, to: &mut Extensions) {
+	fn copy<T: Clone + Send + Sync + 'static>(from: &Extensions, to: &mut Extensions) {
+		if let Some(value) = from.get::<T>() {
+			to.insert(value.clone());
+		}
+	}
+	copy::<ProxiedAddrs>(from, to);
+	copy::<OriginalDestination>(from, to);
+	copy::<RequestDeadline>(from, to);
+	copy::<Tenant>(from, to);
+	copy::<ProxyUser>(from, to);
+	#[cfg(feature = "graphql")]
+	copy::<crate::handlers::graphql::GraphQlOperation>(from, to);
+}
+
+// Build a copy of a buffered request, e.g. to send it again or to a second upstream, with the
+// context of the original one (see `copy_context`)
+pub(crate) fn copy_request(parts: &Parts, body: &Bytes) -> Request<Body> {
+	let mut request = Request::new(Body::from(body.clone()));
+	*request.method_mut() = parts.method.clone();
+	*request.uri_mut() = parts.uri.clone();
+	*request.version_mut() = parts.version;
+	*request.headers_mut() = parts.headers.clone();
+	copy_context(&parts.extensions, request.extensions_mut());
+	request
+}
+
+/// Read the first `limit` bytes of the body (or all of it, if it is shorter) without consuming it
+///
+/// Returns the bytes read and a body equivalent to the original one.
+pub async fn peek(mut body: Body, limit: usize) -> Result<(Bytes, Body), hyper::Error> {
+	let mut chunks = Vec::new();
+	let mut prefix = Vec::new();
+	while prefix.len() < limit {
+		match body.data().await {
+			Some(chunk) => {
+				let chunk = chunk?;
+				let take = chunk.len().min(limit - prefix.len());
+				prefix.extend_from_slice(&chunk[..take]);
+				chunks.push(chunk);
+			}
+			None => return Ok((prefix.into(), Body::from(concat(chunks)))),
+		}
+	}
+
+	let replayed = stream::iter(chunks.into_iter().map(Ok));
+	Ok((prefix.into(), map_data(body, |rest| replayed.chain(rest))))
+}
+
+fn concat(chunks: Vec<Bytes>) -> Bytes {
+	match chunks.len() {
+		0 => Bytes::new(),
+		1 => chunks.into_iter().next().unwrap(),
+		_ => chunks.concat().into(),
+	}
+}
+
+/// Fail the body with a [`TimedOut`](io::ErrorKind::TimedOut) error if a chunk takes longer
+/// than `read` to arrive or the body isn't complete by `deadline`
+///
+/// Bodies that are known to be empty are returned as they are.
+pub fn with_timeouts(body: Body, read: Option<Duration>, deadline: Option<Instant>) -> Body {
+	if body.size_hint().exact() == Some(0) || (read.is_none() && deadline.is_none()) {
+		return body;
+	}
+
+	map_data(body, move |data| {
+		stream::unfold(Some(data), move |data| async move {
+			let mut data = data?;
+			let now = Instant::now();
+			let until = match (read.map(|read| now + read), deadline) {
+				(Some(a), Some(b)) => a.min(b),
+				(a, b) => a.or(b)?,
+			};
+			match timeout_at(until, data.next()).await {
+				Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(data))),
+				Ok(Some(Err(e))) => Some((Err(io::Error::other(e)), None)),
+				Ok(None) => None,
+				Err(_) => Some((Err(io::ErrorKind::TimedOut.into()), None)),
+			}
+		})
+	})
+}
+
+/// Fail the body with an [`InvalidData`](io::ErrorKind::InvalidData) error as soon as it
+/// exceeds `limit` bytes
+///
+/// Bodies that are known to be within the limit are returned as they are.
+pub fn limit_size(body: Body, limit: u64) -> Body {
+	if body.size_hint().upper().is_some_and(|upper| upper <= limit) {
+		return body;
+	}
+
+	map_data(body, move |data| {
+		stream::unfold(Some((data, 0)), move |state| async move {
+			let (mut data, len) = state?;
+			match data.next().await? {
+				Ok(chunk) => {
+					let len = len + chunk.len() as u64;
+					if len > limit {
+						let e = io::Error::new(
+							io::ErrorKind::InvalidData,
+							format!("body exceeds the limit of {} bytes", limit),
+						);
+						return Some((Err(e), None));
+					}
+					Some((Ok(chunk), Some((data, len))))
+				}
+				Err(e) => Some((Err(io::Error::other(e)), None)),
+			}
+		})
+	})
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{ready, try_join_all, Either, Ready};
+use hyper::header::CONTENT_TYPE;
+use hyper::http::uri::{PathAndQuery, Scheme};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::connect::{Connector, UpstreamConfig, UpstreamProtocol};
+use crate::handlers::balance::Balance;
+use crate::handlers::filter::{
+	request_authority, CidrFilter, Filter, IpNet, PathFilter, PathPattern,
+};
+use crate::handlers::health::{HealthCheck, UpstreamPool};
+use crate::handlers::redirect::{redirect_fn, Redirect};
+use crate::handlers::timeout::{Timeout, TimeoutConfig};
+use crate::handlers::vhost::HostPattern;
+use crate::handlers::with_client::WithClient;
+use crate::{
+	BoxHandlerError, BoxHandlerFuture, BoxRequestHandler, Http2Config, Proxy, ProxyConfig,
+	ProxyError, RequestHandler,
+};
+
+/// A proxy described by a config file, see [`Config::load`]
+///
+/// # Example
+/// ```
+/// use proxylib::config::Config;
+///
+/// let config = Config::from_yaml(
+///     r#"
+/// listeners:
+///   - listen_on: ["0.0.0.0:8080"]
+///     routes:
+///       - hosts: ["api.example.com"]
+///         paths: ["/v1/**"]
+///         timeouts: { connect: 2s, total: 30s }
+///         upstream: api
+///       - allow: ["10.0.0.0/8"]
+///         upstream: www
+/// upstreams:
+///   api:
+///     targets: ["http://10.0.1.1:8080", "http://10.0.1.2:8080"]
+///     health_check: { path: /healthz, interval: 5s }
+///     connections: { idle_timeout: 30s, protocol: http2 }
+///   www:
+///     targets: ["http://10.0.2.1"]
+/// "#,
+/// )
+/// .unwrap();
+/// assert_eq!(config.listeners[0].routes.len(), 2);
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	/// The listeners, each with its own routes
+	pub listeners: Vec<ListenerSpec>,
+	/// The upstreams the routes forward to, by name
+	#[serde(default)]
+	pub upstreams: HashMap<String, UpstreamSpec>,
+}
+
+/// A listener of a [`Config`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSpec {
+	/// The addresses to listen on
+	pub listen_on: Vec<SocketAddr>,
+	/// Whether HTTP/2 with prior knowledge (h2c) is served besides HTTP/1.1
+	#[serde(default)]
+	pub h2c: bool,
+	/// The routes, of which a request takes the first one that matches it
+	///
+	/// Requests that match no route are answered with `404 Not Found`.
+	pub routes: Vec<RouteSpec>,
+}
+
+/// A route of a [`ListenerSpec`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSpec {
+	/// The hosts the route is for, as [`HostPattern`]s like `*.example.com` (any host if empty)
+	#[serde(default)]
+	pub hosts: Vec<String>,
+	/// The paths the route is for, as globs like `/api/**` (see [`PathPattern::glob`]; any path
+	/// if empty)
+	#[serde(default)]
+	pub paths: Vec<String>,
+	/// The networks of the clients that may use the route, like `10.0.0.0/8` (everyone if
+	/// empty)
+	///
+	/// Other clients are answered with `403 Forbidden`.
+	#[serde(default)]
+	pub allow: Vec<String>,
+	/// The networks of the clients that may not use the route
+	#[serde(default)]
+	pub deny: Vec<String>,
+	/// The timeouts of the requests to the upstream
+	#[serde(default)]
+	pub timeouts: Option<TimeoutSpec>,
+	/// The name of the upstream to forward to
+	pub upstream: String,
+}
+
+/// The timeouts of a [`RouteSpec`], see [`TimeoutConfig`]
+///
+/// Durations are written like `500ms`, `5s`, `2m` or `1h`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutSpec {
+	/// How long establishing an upstream connection may take
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub connect: Option<Duration>,
+	/// How long to wait for the response head and for each chunk of the response body
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub read: Option<Duration>,
+	/// How long the whole exchange may take
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub total: Option<Duration>,
+}
+
+/// An upstream of a [`Config`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamSpec {
+	/// The URIs of the servers, like `http://10.0.0.1:8080`, between which the requests are
+	/// balanced round-robin
+	pub targets: Vec<String>,
+	/// The health checks of the servers, if any
+	#[serde(default)]
+	pub health_check: Option<HealthCheckSpec>,
+	/// The options of the connections to the servers, which are also used for the health checks
+	#[serde(default)]
+	pub connections: ConnectionSpec,
+}
+
+/// The options of the connections of an [`UpstreamSpec`], see [`UpstreamConfig`]
+///
+/// Options that aren't given keep the defaults of [`UpstreamConfig`].
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionSpec {
+	/// Whether connections are kept alive and reused for further requests
+	#[serde(default)]
+	pub keep_alive: Option<bool>,
+	/// How long an idle connection is kept around before it is closed
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub idle_timeout: Option<Duration>,
+	/// The maximum number of idle connections kept per server
+	#[serde(default)]
+	pub max_idle_per_host: Option<usize>,
+	/// How long establishing a connection may take (which the `connect` timeout of a route
+	/// overrides)
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub connect_timeout: Option<Duration>,
+	/// The HTTP version spoken with the servers
+	#[serde(default)]
+	pub protocol: Option<ProtocolSpec>,
+	/// How long a connection may go without traffic before it is closed, see
+	/// [`UpstreamConfig::connection_ttl`]
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub ttl: Option<Duration>,
+}
+
+impl ConnectionSpec {
+	/// Get the [`UpstreamConfig`] with these options
+	pub fn upstream_config(&self) -> UpstreamConfig {
+		let default = UpstreamConfig::default();
+		UpstreamConfig {
+			keep_alive: self.keep_alive.unwrap_or(default.keep_alive),
+			idle_timeout: self.idle_timeout.or(default.idle_timeout),
+			max_idle_per_host: self.max_idle_per_host.unwrap_or(default.max_idle_per_host),
+			connect_timeout: self.connect_timeout.or(default.connect_timeout),
+			protocol: match self.protocol {
+				Some(ProtocolSpec::Http1) => UpstreamProtocol::Http1,
+				Some(ProtocolSpec::Auto) => UpstreamProtocol::Auto,
+				Some(ProtocolSpec::Http2) => UpstreamProtocol::Http2,
+				None => default.protocol,
+			},
+			connection_ttl: self.ttl.or(default.connection_ttl),
+			..default
+		}
+	}
+}
+
+/// The HTTP version of a [`ConnectionSpec`], see [`UpstreamProtocol`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolSpec {
+	/// `http1`: HTTP/1.1
+	Http1,
+	/// `auto`: HTTP/2 if the server chooses it with ALPN, HTTP/1.1 otherwise
+	Auto,
+	/// `http2`: HTTP/2 only, e.g. for gRPC servers
+	Http2,
+}
+
+/// The health checks of an [`UpstreamSpec`], see [`HealthCheck`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckSpec {
+	/// The path that is requested, `/` by default
+	#[serde(default)]
+	pub path: Option<String>,
+	/// The time between two checks, 10 seconds by default
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub interval: Option<Duration>,
+	/// How long a server has to respond to a check, 2 seconds by default
+	#[serde(default, deserialize_with = "optional_duration")]
+	pub timeout: Option<Duration>,
+}
+
+/// The error type of loading, building and running a [`Config`]
+#[derive(Debug, Error)]
+pub enum ConfigError {
+	#[error("failed to read the config file: {0}")]
+	/// The config file couldn't be read
+	Read(io::Error),
+	#[error("the config file {0:?} is neither TOML (.toml) nor YAML (.yaml or .yml)")]
+	/// The format of the config file isn't known from its extension
+	UnknownFormat(PathBuf),
+	#[error("invalid TOML config: {0}")]
+	/// The config isn't valid TOML or doesn't describe a proxy
+	Toml(toml::de::Error),
+	#[error("invalid YAML config: {0}")]
+	/// The config isn't valid YAML or doesn't describe a proxy
+	Yaml(serde_yaml::Error),
+	#[error("invalid {what} {value:?}")]
+	/// A value of the config is invalid
+	Invalid {
+		/// What the value is, like `host pattern`
+		what: &'static str,
+		/// The value
+		value: String,
+	},
+	#[error("a listener has no address to listen on")]
+	/// A listener has no address to listen on
+	NoListenAddress,
+	#[error("unknown upstream {0:?}")]
+	/// A route forwards to an upstream that isn't in the config
+	UnknownUpstream(String),
+	#[error("upstream {0:?} has no targets")]
+	/// An upstream has no targets
+	NoTargets(String),
+	#[error("{0}")]
+	/// Running the proxy failed
+	Proxy(#[from] ProxyError),
+}
+
+impl Config {
+	/// Load a config file, which is TOML or YAML depending on its extension
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+		let path = path.as_ref();
+		let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+		let text = || std::fs::read_to_string(path).map_err(ConfigError::Read);
+		match extension.to_ascii_lowercase().as_str() {
+			"toml" => Self::from_toml(&text()?),
+			"yaml" | "yml" => Self::from_yaml(&text()?),
+			_ => Err(ConfigError::UnknownFormat(path.to_path_buf())),
+		}
+	}
+
+	/// Parse a TOML config
+	pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+		toml::from_str(text).map_err(ConfigError::Toml)
+	}
+
+	/// Parse a YAML config
+	pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+		serde_yaml::from_str(text).map_err(ConfigError::Yaml)
+	}
+
+	/// Build the configs of the proxies of the listeners
+	///
+	/// Every upstream is built once, so the routes forwarding to it share its balancing and
+	/// health checks.
+	///
+	/// # Panics
+	/// Panics if an upstream has health checks and this is called outside of a tokio runtime.
+	pub fn build(&self) -> Result<Vec<ProxyConfig<BoxRequestHandler>>, ConfigError> {
+		let upstreams = self
+			.upstreams
+			.iter()
+			.map(|(name, spec)| Ok((name.as_str(), build_upstream(name, spec)?)))
+			.collect::<Result<HashMap<_, _>, ConfigError>>()?;
+
+		self.listeners
+			.iter()
+			.map(|listener| {
+				let routes = listener
+					.routes
+					.iter()
+					.map(|route| build_route(route, &upstreams))
+					.collect::<Result<Vec<_>, _>>()?;
+				let (first, rest) = listener
+					.listen_on
+					.split_first()
+					.ok_or(ConfigError::NoListenAddress)?;
+				let mut config =
+					ProxyConfig::new(*first, Routes(routes).boxed()).with_http2(Http2Config {
+						h2c: listener.h2c,
+						..Http2Config::default()
+					});
+				for addr in rest {
+					config = config.with_additional_listen_on(*addr);
+				}
+				Ok(config)
+			})
+			.collect()
+	}
+
+	/// Build the proxies and run them until one of them fails
+	pub async fn run(&self) -> Result<(), ConfigError> {
+		let proxies = self
+			.build()?
+			.into_iter()
+			.map(Proxy::bind)
+			.collect::<Result<Vec<_>, _>>()?;
+		try_join_all(proxies.into_iter().map(Proxy::run)).await?;
+		Ok(())
+	}
+}
+
+fn invalid(what: &'static str, value: &str) -> ConfigError {
+	ConfigError::Invalid {
+		what,
+		value: value.to_string(),
+	}
+}
+
+// Parse durations like `500ms`, `5s`, `2m` or `1h`
+fn parse_duration(s: &str) -> Option<Duration> {
+	let s = s.trim();
+	let split = s.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+	let (value, unit) = s.split_at(split);
+	let value = value.parse::<f64>().ok()?;
+	let seconds = match unit.trim() {
+		"ms" => value / 1000.0,
+		"s" => value,
+		"m" => value * 60.0,
+		"h" => value * 60.0 * 60.0,
+		_ => return None,
+	};
+	Duration::try_from_secs_f64(seconds).ok()
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+	let s = String::deserialize(deserializer)?;
+	parse_duration(&s)
+		.map(Some)
+		.ok_or_else(|| serde::de::Error::custom(format!("invalid duration {:?}", s)))
+}
+
+// A built upstream, with the config and client of its connections
+struct Upstream {
+	handler: Arc<BoxRequestHandler>,
+	config: UpstreamConfig,
+	client: Client<Connector>,
+}
+
+// Build the balancer of an upstream, with a `Redirect` to each of its targets
+fn build_upstream(name: &str, spec: &UpstreamSpec) -> Result<Upstream, ConfigError> {
+	let targets = spec
+		.targets
+		.iter()
+		.map(|target| {
+			let uri = target
+				.parse::<Uri>()
+				.map_err(|_| invalid("upstream target", target))?;
+			match (uri.scheme(), uri.authority()) {
+				(Some(_), Some(_)) => Ok(uri),
+				_ => Err(invalid("upstream target", target)),
+			}
+		})
+		.collect::<Result<Vec<_>, _>>()?;
+	if targets.is_empty() {
+		return Err(ConfigError::NoTargets(name.to_string()));
+	}
+
+	let config = spec.connections.upstream_config();
+	let client = config.build_client();
+	let redirects = targets.iter().map(redirect_to).collect();
+	let mut balance = Balance::new(redirects);
+	if let Some(check) = &spec.health_check {
+		let default = HealthCheck::default();
+		let path = match &check.path {
+			Some(path) => path
+				.parse::<PathAndQuery>()
+				.map_err(|_| invalid("health check path", path))?,
+			None => default.path.clone(),
+		};
+		let check = HealthCheck {
+			path,
+			interval: check.interval.unwrap_or(default.interval),
+			timeout: check.timeout.unwrap_or(default.timeout),
+			..default
+		};
+		balance = balance.with_health(UpstreamPool::new(targets, check, client.clone()));
+	}
+	Ok(Upstream {
+		handler: Arc::new(balance.boxed()),
+		config,
+		client,
+	})
+}
+
+// Send requests to the scheme and authority of the target, keeping their path and query
+fn redirect_to(target: &Uri) -> BoxRequestHandler {
+	let scheme = target.scheme().cloned().unwrap_or(Scheme::HTTP);
+	let authority = target.authority().cloned();
+	Redirect::new(redirect_fn(move |uri: &mut Uri| {
+		let mut parts = uri.clone().into_parts();
+		parts.scheme = Some(scheme.clone());
+		parts.authority = authority.clone();
+		if parts.path_and_query.is_none() {
+			parts.path_and_query = Some(PathAndQuery::from_static("/"));
+		}
+		// The scheme, authority and path are all set
+		*uri = Uri::from_parts(parts).unwrap();
+	}))
+	.boxed()
+}
+
+// A route with what it matches and its request handler
+struct Route {
+	hosts: Vec<HostPattern>,
+	paths: Option<PathFilter>,
+	handler: BoxRequestHandler,
+}
+
+impl Route {
+	fn matches(&self, request: &Request<Body>) -> bool {
+		let host_matches = self.hosts.is_empty()
+			|| request_authority(request).is_some_and(|authority| {
+				self.hosts
+					.iter()
+					.any(|pattern| pattern.matches(authority.host()))
+			});
+		// The path is normalized like for a `Filter`, so that e.g. `//admin` can't be used to
+		// get around the route for `/admin` and its allowed networks
+		host_matches
+			&& self
+				.paths
+				.as_ref()
+				.is_none_or(|paths| paths.matches(request.uri().path()))
+	}
+}
+
+fn build_route(
+	spec: &RouteSpec,
+	upstreams: &HashMap<&str, Upstream>,
+) -> Result<Route, ConfigError> {
+	let upstream = upstreams
+		.get(spec.upstream.as_str())
+		.ok_or_else(|| ConfigError::UnknownUpstream(spec.upstream.clone()))?;
+	let mut handler = upstream.handler.clone().boxed();
+
+	if let Some(timeouts) = spec.timeouts {
+		let config = TimeoutConfig {
+			connect: timeouts.connect,
+			read: timeouts.read,
+			total: timeouts.total,
+		};
+		handler = Timeout::new(handler, config, &upstream.config).boxed();
+	}
+	// The upstream's client, which the timeouts replace if they have a connect timeout
+	handler = WithClient {
+		inner: handler,
+		client: upstream.client.clone(),
+	}
+	.boxed();
+	let networks = |list: &[String]| {
+		list.iter()
+			.map(|net| net.parse::<IpNet>().map_err(|_| invalid("network", net)))
+			.collect::<Result<Vec<_>, _>>()
+	};
+	if !spec.deny.is_empty() {
+		handler = Filter::<_, CidrFilter>::addr_blacklist(handler, networks(&spec.deny)?)
+			.with_forbidden()
+			.boxed();
+	}
+	if !spec.allow.is_empty() {
+		handler = Filter::<_, CidrFilter>::addr_whitelist(handler, networks(&spec.allow)?)
+			.with_forbidden()
+			.boxed();
+	}
+
+	let hosts = spec
+		.hosts
+		.iter()
+		.map(|host| host.parse().map_err(|_| invalid("host pattern", host)))
+		.collect::<Result<_, _>>()?;
+	let paths = (!spec.paths.is_empty()).then(|| PathFilter {
+		patterns: spec
+			.paths
+			.iter()
+			.map(|path| PathPattern::glob(path))
+			.collect(),
+		is_blacklist: false,
+	});
+	Ok(Route {
+		hosts,
+		paths,
+		handler,
+	})
+}
+
+// The routes of a listener, tried in order
+struct Routes(Vec<Route>);
+
+impl RequestHandler for Routes {
+	type Error = BoxHandlerError;
+	type Output = Either<BoxHandlerFuture, Ready<Result<Response<Body>, BoxHandlerError>>>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		match self.0.iter().find(|route| route.matches(&request)) {
+			Some(route) => Either::Left(route.handler.handle(from_addr, request, client)),
+			None => {
+				let response = Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+					.body(Body::from("No route matches the request.\n"))
+					.unwrap();
+				Either::Right(ready(Ok(response)))
+			}
+		}
+	}
+}
@@ -0,0 +1,226 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The TLS session of a client connection, if the proxy terminates TLS
+///
+/// It is also inserted into the extensions of the requests of the connection.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TlsInfo {
+	/// The server name the client asked for with SNI, if any
+	pub server_name: Option<String>,
+	/// The protocol chosen with ALPN, like `h2`, if any
+	pub alpn_protocol: Option<Vec<u8>>,
+	/// The TLS version, like `TLSv1.3`
+	pub protocol_version: &'static str,
+	/// The DER certificate chain the client authenticated with, starting with its own
+	/// certificate
+	///
+	/// This is empty unless the proxy asks for client certificates, see
+	/// `TlsProxyConfig::from_pem_with_client_auth`.
+	pub client_certificates: Vec<Vec<u8>>,
+}
+
+/// Information about a client connection that was just accepted
+///
+/// If the proxy terminates TLS, this is only reported once the handshake has completed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionInfo {
+	/// The address of the client
+	pub peer_addr: SocketAddr,
+	/// The address the connection was accepted on
+	pub local_addr: SocketAddr,
+	/// The TLS session, if the proxy terminates TLS
+	pub tls: Option<TlsInfo>,
+}
+
+/// Information about a client connection that was just closed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionSummary {
+	/// The address of the client
+	pub peer_addr: SocketAddr,
+	/// The address the connection was accepted on
+	pub local_addr: SocketAddr,
+	/// The TLS session, if the proxy terminates TLS
+	pub tls: Option<TlsInfo>,
+	/// How long the connection was open
+	pub duration: Duration,
+	/// The number of bytes received from the client
+	pub bytes_read: u64,
+	/// The number of bytes sent to the client
+	pub bytes_written: u64,
+}
+
+/// A callback that is called when a client connection is accepted
+pub type OnConnect = Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
+/// A callback that is called when a client connection is closed
+pub type OnDisconnect = Arc<dyn Fn(&ConnectionSummary) + Send + Sync>;
+
+/// The connection hooks of a proxy
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+	pub(crate) on_connect: Option<OnConnect>,
+	pub(crate) on_disconnect: Option<OnDisconnect>,
+	// Whether connections are only reported once their TLS handshake has completed
+	pub(crate) tls: bool,
+}
+
+/// A client connection that reports its lifecycle to the [`Hooks`]
+pub(crate) struct TrackedStream {
+	inner: AddrStream,
+	hooks: Hooks,
+	opened: Instant,
+	// Whether the connection was reported to `on_connect`
+	connected: bool,
+	tls: Option<TlsInfo>,
+	bytes_read: u64,
+	bytes_written: u64,
+}
+
+impl TrackedStream {
+	// Report the connection now that its TLS handshake has completed
+	#[cfg(feature = "tls")]
+	pub(crate) fn handshaken(&mut self, tls: TlsInfo) {
+		self.tls = Some(tls);
+		self.connected = true;
+		if let Some(on_connect) = &self.hooks.on_connect {
+			on_connect(&ConnectionInfo {
+				peer_addr: self.inner.remote_addr(),
+				local_addr: self.inner.local_addr(),
+				tls: self.tls.clone(),
+			});
+		}
+	}
+
+	pub(crate) fn remote_addr(&self) -> SocketAddr {
+		self.inner.remote_addr()
+	}
+
+	pub(crate) fn local_addr(&self) -> SocketAddr {
+		self.inner.local_addr()
+	}
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for TrackedStream {
+	fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+		self.inner.as_raw_fd()
+	}
+}
+
+impl Drop for TrackedStream {
+	fn drop(&mut self) {
+		if !self.connected {
+			return;
+		}
+		if let Some(on_disconnect) = &self.hooks.on_disconnect {
+			on_disconnect(&ConnectionSummary {
+				peer_addr: self.inner.remote_addr(),
+				local_addr: self.inner.local_addr(),
+				tls: self.tls.take(),
+				duration: self.opened.elapsed(),
+				bytes_read: self.bytes_read,
+				bytes_written: self.bytes_written,
+			});
+		}
+	}
+}
+
+impl AsyncRead for TrackedStream {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+		self.bytes_read += (buf.filled().len() - before) as u64;
+		poll
+	}
+}
+
+impl AsyncWrite for TrackedStream {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = poll {
+			self.bytes_written += n as u64;
+		}
+		poll
+	}
+
+	fn poll_write_vectored(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		bufs: &[io::IoSlice<'_>],
+	) -> Poll<io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+		if let Poll::Ready(Ok(n)) = poll {
+			self.bytes_written += n as u64;
+		}
+		poll
+	}
+
+	fn is_write_vectored(&self) -> bool {
+		self.inner.is_write_vectored()
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// The incoming client connections, wrapped in [`TrackedStream`]s
+pub(crate) struct TrackedIncoming {
+	pub(crate) inner: AddrIncoming,
+	pub(crate) hooks: Hooks,
+}
+
+impl Accept for TrackedIncoming {
+	type Conn = TrackedStream;
+	type Error = io::Error;
+
+	fn poll_accept(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+		let stream = match Pin::new(&mut self.inner).poll_accept(cx) {
+			Poll::Ready(Some(Ok(stream))) => stream,
+			Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+			Poll::Ready(None) => return Poll::Ready(None),
+			Poll::Pending => return Poll::Pending,
+		};
+
+		let connected = !self.hooks.tls;
+		if let (true, Some(on_connect)) = (connected, &self.hooks.on_connect) {
+			on_connect(&ConnectionInfo {
+				peer_addr: stream.remote_addr(),
+				local_addr: stream.local_addr(),
+				tls: None,
+			});
+		}
+		Poll::Ready(Some(Ok(TrackedStream {
+			inner: stream,
+			hooks: self.hooks.clone(),
+			opened: Instant::now(),
+			connected,
+			tls: None,
+			bytes_read: 0,
+			bytes_written: 0,
+		})))
+	}
+}
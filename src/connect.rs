@@ -0,0 +1,925 @@
+use std::collections::HashMap;
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::{poll_fn, FutureExt, Map};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Client, Uri};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::chain::{ParentProxies, ProxyChainConnector};
+use crate::dns::{CachingResolver, DnsCacheConfig, Lookup, Resolver, ResolverStats};
+use crate::proxy_protocol::{ProxiedAddrs, ProxyHeaderConnector};
+#[cfg(unix)]
+use crate::unix::UnixConnector;
+
+/// The delay after which a connection attempt to the other address family is started,
+/// as recommended by RFC 8305
+pub const FALLBACK_DELAY: Duration = Duration::from_millis(250);
+
+/// Sort addresses as described in RFC 8305, section 4
+///
+/// The result starts with an IPv6 address (if there is one) and then alternates
+/// between the address families, keeping the relative order within each family.
+pub fn sort_addrs(addrs: &mut Vec<SocketAddr>) {
+	let (v6, v4): (Vec<_>, Vec<_>) = addrs.drain(..).partition(SocketAddr::is_ipv6);
+	let mut v6 = v6.into_iter();
+	let mut v4 = v4.into_iter();
+
+	loop {
+		match (v6.next(), v4.next()) {
+			(None, None) => break,
+			(a, b) => addrs.extend(a.into_iter().chain(b)),
+		}
+	}
+}
+
+/// A [`Resolver`] adapter that sorts the addresses of another resolver with [`sort_addrs`]
+///
+/// Combined with [`happy_eyeballs_connector`], this gives dual-stack connection racing:
+/// ```
+/// use hyper::client::connect::dns::GaiResolver;
+/// use proxylib::connect::{happy_eyeballs_connector, HappyEyeballs};
+/// use proxylib::dns::CachingResolver;
+///
+/// let connector = happy_eyeballs_connector(CachingResolver::new(HappyEyeballs(GaiResolver::new())));
+/// ```
+#[derive(Debug, Clone)]
+pub struct HappyEyeballs<R: Resolver>(pub R);
+
+impl<R: Resolver> Resolver for HappyEyeballs<R> {
+	type Future = Map<R::Future, fn(io::Result<Lookup>) -> io::Result<Lookup>>;
+
+	fn resolve(&self, name: Name) -> Self::Future {
+		self.0.resolve(name).map(|res: io::Result<Lookup>| {
+			res.map(|mut lookup| {
+				sort_addrs(&mut lookup.addrs);
+				lookup
+			})
+		})
+	}
+}
+
+/// Create an [`HttpConnector`] that races IPv6 and IPv4 connection attempts
+///
+/// The connector tries the address family of the first resolved address and starts
+/// trying the other family if no connection was established after [`FALLBACK_DELAY`].
+pub fn happy_eyeballs_connector<R>(resolver: R) -> HttpConnector<R> {
+	let mut connector = HttpConnector::new_with_resolver(resolver);
+	connector.set_happy_eyeballs_timeout(Some(FALLBACK_DELAY));
+	connector
+}
+
+/// The HTTP version spoken with an upstream
+///
+/// # gRPC
+/// gRPC needs HTTP/2 on both sides of the proxy, so its clients usually speak h2c (see
+/// [`Http2Config::h2c`](crate::Http2Config::h2c)) or TLS with ALPN to the proxy, and the route
+/// to the backend speaks [`Http2`](Self::Http2). Request and response trailers (which carry the
+/// `grpc-status`) are forwarded, also through the combinators that wrap bodies, and responses
+/// with the content type `application/grpc` are streamed message by message, see
+/// [`is_streaming`](crate::body::is_streaming). The `grpc` example runs a unary and a streaming
+/// call through a proxy like this one.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use proxylib::connect::{UpstreamConfig, UpstreamProtocol};
+/// use proxylib::handlers::timeout::{Timeout, TimeoutConfig};
+/// use proxylib::handlers::with_client::WithClient;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::{Http2Config, ProxyConfig};
+/// # use hyper::body::HttpBody;
+/// # use hyper::server::conn::AddrIncoming;
+/// # use hyper::service::{make_service_fn, service_fn};
+/// # use hyper::{Body, HeaderMap, Request, Response};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// # // A gRPC backend that echoes every message as it arrives and then sends its status
+/// # let echo = make_service_fn(|_| async {
+/// #     Ok::<_, hyper::Error>(service_fn(|request: Request<Body>| async move {
+/// #         assert_eq!(request.headers()["te"], "trailers");
+/// #         let mut messages = request.into_body();
+/// #         let (mut tx, body) = Body::channel();
+/// #         tokio::spawn(async move {
+/// #             while let Some(message) = messages.data().await {
+/// #                 tx.send_data(message.unwrap()).await.unwrap();
+/// #             }
+/// #             let sent = messages.trailers().await.unwrap().unwrap();
+/// #             let mut status = HeaderMap::new();
+/// #             status.insert("grpc-status", "0".parse().unwrap());
+/// #             status.insert("grpc-message", sent["x-sent"].clone());
+/// #             tx.send_trailers(status).await.unwrap();
+/// #         });
+/// #         Ok::<_, hyper::Error>(
+/// #             Response::builder()
+/// #                 .header("content-type", "application/grpc")
+/// #                 .body(body)
+/// #                 .unwrap(),
+/// #         )
+/// #     }))
+/// # });
+/// # let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+/// # let backend_addr = incoming.local_addr();
+/// # tokio::spawn(hyper::Server::builder(incoming).http2_only(true).serve(echo));
+/// let backend = UpstreamConfig {
+///     protocol: UpstreamProtocol::Http2,
+///     ..UpstreamConfig::default()
+/// };
+/// let route = Timeout::new(
+///     Redirect::change_authority(backend_addr.to_string().parse().unwrap()),
+///     TimeoutConfig {
+///         read: Some(Duration::from_secs(30)),
+///         ..TimeoutConfig::default()
+///     },
+///     &backend,
+/// );
+/// let config = ProxyConfig::new("127.0.0.1:0".parse().unwrap(), WithClient::new(route, &backend))
+///     .with_http2(Http2Config {
+///         h2c: true,
+///         ..Http2Config::default()
+///     });
+/// # let proxy = proxylib::Proxy::bind(config).unwrap();
+/// # let proxy_addr = proxy.local_addr();
+/// # tokio::spawn(proxy.run());
+/// #
+/// # let client = hyper::Client::builder().http2_only(true).build_http::<Body>();
+/// # let call = |body| {
+/// #     let request = Request::post(format!("http://{}/echo.Echo/Echo", proxy_addr))
+/// #         .header("content-type", "application/grpc")
+/// #         .header("te", "trailers")
+/// #         .body(body)
+/// #         .unwrap();
+/// #     tokio::spawn(client.request(request))
+/// # };
+/// # let trailers = |n: &str| {
+/// #     let mut trailers = HeaderMap::new();
+/// #     trailers.insert("x-sent", n.parse().unwrap());
+/// #     trailers
+/// # };
+/// #
+/// # // Unary call
+/// # let (mut tx, body) = Body::channel();
+/// # let response = call(body);
+/// # tx.send_data("\0\0\0\0\x05hello".into()).await.unwrap();
+/// # tx.send_trailers(trailers("1")).await.unwrap();
+/// # drop(tx);
+/// # let response = response.await.unwrap().unwrap();
+/// # assert_eq!(response.headers()["content-type"], "application/grpc");
+/// # let mut body = response.into_body();
+/// # let mut message = Vec::new();
+/// # while let Some(chunk) = body.data().await {
+/// #     message.extend_from_slice(&chunk.unwrap());
+/// # }
+/// # assert_eq!(message, b"\0\0\0\0\x05hello");
+/// # let status = body.trailers().await.unwrap().unwrap();
+/// # assert_eq!(status["grpc-status"], "0");
+/// # assert_eq!(status["grpc-message"], "1");
+/// #
+/// # // Bidirectional streaming call, where each reply arrives before the next message is sent
+/// # let (mut tx, body) = Body::channel();
+/// # let response = call(body);
+/// # tx.send_data("\0\0\0\0\x01a".into()).await.unwrap();
+/// # let mut body = response.await.unwrap().unwrap().into_body();
+/// # assert_eq!(body.data().await.unwrap().unwrap(), "\0\0\0\0\x01a");
+/// # tx.send_data("\0\0\0\0\x01b".into()).await.unwrap();
+/// # assert_eq!(body.data().await.unwrap().unwrap(), "\0\0\0\0\x01b");
+/// # tx.send_trailers(trailers("2")).await.unwrap();
+/// # drop(tx);
+/// # assert!(body.data().await.is_none());
+/// # let status = body.trailers().await.unwrap().unwrap();
+/// # assert_eq!(status["grpc-status"], "0");
+/// # assert_eq!(status["grpc-message"], "2");
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum UpstreamProtocol {
+	#[default]
+	/// HTTP/1.1
+	Http1,
+	/// HTTP/2 if the upstream chooses it with ALPN in the TLS handshake, HTTP/1.1 otherwise
+	///
+	/// Without TLS (or the `tls` feature), this is the same as [`Http1`](Self::Http1).
+	Auto,
+	/// HTTP/2 only, also without TLS (h2c with prior knowledge), e.g. for gRPC backends
+	///
+	/// Protocol upgrades like WebSocket are not possible over HTTP/2 connections.
+	Http2,
+}
+
+/// Options for the connections to an upstream
+///
+/// Different upstreams can be reached with different options by giving their routes their own
+/// client with [`WithClient`](crate::handlers::with_client::WithClient).
+///
+/// The number of requests per connection can't be limited: the connection pool of hyper decides
+/// which connection a request goes to and doesn't tell the connector, so a connection can only
+/// be retired from outside by cutting it, which would fail the request that happens to be on it.
+/// Turn off [`keep_alive`](Self::keep_alive) to use every connection for one request only, or
+/// set [`idle_timeout`](Self::idle_timeout) and [`connection_ttl`](Self::connection_ttl) to
+/// replace connections that aren't busy.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpstreamConfig {
+	/// Whether connections are kept alive and reused for further requests
+	///
+	/// Some legacy upstreams misbehave with connection reuse, so this can be turned off.
+	pub keep_alive: bool,
+	/// How long an idle connection is kept around before it is closed
+	pub idle_timeout: Option<Duration>,
+	/// The maximum number of idle connections kept per host
+	pub max_idle_per_host: usize,
+	/// How long establishing a connection may take
+	pub connect_timeout: Option<Duration>,
+	/// The parent proxies to connect through, see [`ProxyChainConnector`]
+	pub parent_proxies: ParentProxies,
+	/// The HTTP version spoken with the upstream
+	pub protocol: UpstreamProtocol,
+	/// How long a connection may go without traffic before it is closed, even if the pool
+	/// doesn't consider it idle, see [`ConnectionTracker::ttl`]
+	pub connection_ttl: Option<Duration>,
+	/// How the names of upstreams are cached, see [`CachingResolver`]
+	///
+	/// Every client built from this config has its own cache, whose statistics are part of the
+	/// [`ConnectionStats`] of [`build_tracked_client`](Self::build_tracked_client). With `None`,
+	/// every new connection looks the name up again.
+	///
+	/// The names are resolved with `getaddrinfo`, which doesn't report the TTL of the records,
+	/// so lookups are always cached for [`default_ttl`](DnsCacheConfig::default_ttl).
+	pub dns_cache: Option<DnsCacheConfig>,
+	/// Whether `unix://` URIs are connected to Unix sockets, see
+	/// [`UnixSocket`](crate::unix::UnixSocket) (Unix only)
+	///
+	/// This is off by default, as clients of a forward proxy could reach the sockets of the
+	/// host otherwise.
+	#[cfg(unix)]
+	pub unix_sockets: bool,
+}
+
+impl Default for UpstreamConfig {
+	fn default() -> Self {
+		Self {
+			keep_alive: true,
+			idle_timeout: Some(Duration::from_secs(90)),
+			max_idle_per_host: usize::MAX,
+			connect_timeout: None,
+			parent_proxies: ParentProxies::default(),
+			protocol: UpstreamProtocol::default(),
+			connection_ttl: None,
+			dns_cache: Some(DnsCacheConfig::default()),
+			#[cfg(unix)]
+			unix_sockets: false,
+		}
+	}
+}
+
+impl UpstreamConfig {
+	/// Build a client that connects according to this config
+	pub fn build_client(&self) -> Client<Connector> {
+		self.build_tracked_client().0
+	}
+
+	/// Build a client that connects according to this config, along with the live counts of
+	/// its connections per upstream and the statistics of its DNS cache
+	///
+	/// They can be served with
+	/// [`Admin::with_connections`](crate::handlers::admin::Admin::with_connections) or exported
+	/// to Prometheus.
+	/// ```
+	/// use proxylib::connect::UpstreamConfig;
+	/// use proxylib::ProxyConfig;
+	/// # use proxylib::handlers::Redirect;
+	/// # let handler = Redirect::change_authority("app.internal:8080".parse().unwrap());
+	///
+	/// let (client, connections) = UpstreamConfig::default().build_tracked_client();
+	/// let config = ProxyConfig::new("0.0.0.0:8080".parse().unwrap(), handler).with_client(client);
+	/// // later
+	/// for upstream in connections.snapshot() {
+	///     println!("{}: {} open, {} idle", upstream.authority, upstream.open, upstream.idle);
+	/// }
+	/// if let Some(dns) = connections.dns() {
+	///     println!("{} DNS lookups, {} from the cache", dns.lookups, dns.cache_hits);
+	/// }
+	/// ```
+	pub fn build_tracked_client(&self) -> (Client<Connector>, ConnectionStats) {
+		let (connector, resolver) = self.tcp_connector();
+		let (client, mut stats) = self.build_client_over(connector);
+		stats.resolver = resolver;
+		(client, stats)
+	}
+
+	/// Build a client that connects according to this config and starts every connection with
+	/// a PROXY protocol header with the addresses, see [`ProxyHeaderConnector`]
+	pub fn build_client_with_proxy_header(&self, addrs: &ProxiedAddrs) -> Client<Connector> {
+		self.build_client_over(ProxyHeaderConnector::new(self.tcp_connector().0, addrs))
+			.0
+	}
+
+	// The connector for the connections under TLS
+	fn tcp_connector(&self) -> (Connector, Option<CachingResolver>) {
+		match &self.dns_cache {
+			Some(config) => {
+				let resolver = CachingResolver::with_config(GaiResolver::new(), config.clone());
+				let connector = happy_eyeballs_connector(resolver.clone());
+				(self.connector_over(connector), Some(resolver))
+			}
+			None => (
+				self.connector_over(happy_eyeballs_connector(GaiResolver::new())),
+				None,
+			),
+		}
+	}
+
+	fn connector_over<R>(&self, mut connector: HttpConnector<R>) -> Connector
+	where
+		R: Service<Name> + Clone + Send + Sync + 'static,
+		R::Response: Iterator<Item = SocketAddr>,
+		R::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+		R::Future: Send,
+	{
+		connector.set_connect_timeout(self.connect_timeout);
+		#[cfg(feature = "tls")]
+		connector.enforce_http(false);
+		let connector = ProxyChainConnector::new(connector, self.parent_proxies.clone());
+		#[cfg(unix)]
+		if self.unix_sockets {
+			return Connector::new(UnixConnector::new(connector));
+		}
+		Connector::new(connector)
+	}
+
+	fn build_client_over<C>(&self, connector: C) -> (Client<Connector>, ConnectionStats)
+	where
+		C: Service<Uri> + Clone + Send + Sync + 'static,
+		C::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+		C::Error: Into<ConnectError>,
+		C::Future: Send + 'static,
+	{
+		let max_idle_per_host = if self.keep_alive {
+			self.max_idle_per_host
+		} else {
+			0
+		};
+
+		#[cfg(feature = "tls")]
+		let connector = {
+			let mut tls = default_tls_config();
+			tls.alpn_protocols = match self.protocol {
+				UpstreamProtocol::Http1 => Vec::new(),
+				UpstreamProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+				UpstreamProtocol::Http2 => vec![b"h2".to_vec()],
+			};
+			HttpsConnector::with_config(connector, Arc::new(tls))
+		};
+
+		let mut connector = ConnectionTracker::new(connector);
+		connector.ttl = self.connection_ttl;
+		let stats = connector.stats();
+
+		let client = Client::builder()
+			.pool_idle_timeout(self.idle_timeout)
+			.pool_max_idle_per_host(max_idle_per_host)
+			.http2_only(self.protocol == UpstreamProtocol::Http2)
+			.build(Connector::new(connector));
+		(client, stats)
+	}
+}
+
+/// A connector adapter that establishes TLS for `https://` URIs on top of the connections
+/// of another connector
+///
+/// The inner connector has to accept `https://` URIs, so for an [`HttpConnector`],
+/// [`enforce_http`](HttpConnector::enforce_http) has to be turned off.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct HttpsConnector<C> {
+	inner: C,
+	tls: tokio_rustls::TlsConnector,
+}
+
+// Verify upstream certificates with the Mozilla root certificates
+#[cfg(feature = "tls")]
+fn default_tls_config() -> rustls::ClientConfig {
+	let roots = webpki_roots::TLS_SERVER_ROOTS
+		.iter()
+		.cloned()
+		.collect::<rustls::RootCertStore>();
+	rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_root_certificates(roots)
+		.with_no_client_auth()
+}
+
+#[cfg(feature = "tls")]
+impl<C> HttpsConnector<C> {
+	/// Verify upstream certificates with the Mozilla root certificates
+	pub fn new(inner: C) -> Self {
+		Self::with_config(inner, Arc::new(default_tls_config()))
+	}
+
+	/// Use the given TLS config, e.g. for private root certificates or client certificates
+	///
+	/// If the config offers `h2` via ALPN and the upstream chooses it, the connection is used
+	/// for HTTP/2.
+	pub fn with_config(inner: C, config: Arc<rustls::ClientConfig>) -> Self {
+		Self {
+			inner,
+			tls: config.into(),
+		}
+	}
+}
+
+/// A connection made by an [`HttpsConnector`]
+#[cfg(feature = "tls")]
+pub enum MaybeHttps<T> {
+	/// A plain connection to an `http://` URI
+	Http(T),
+	/// A TLS connection to an `https://` URI
+	Https(Box<tokio_rustls::client::TlsStream<T>>),
+}
+
+#[cfg(feature = "tls")]
+impl<T: Connection + AsyncRead + AsyncWrite + Unpin> Connection for MaybeHttps<T> {
+	fn connected(&self) -> Connected {
+		match self {
+			Self::Http(stream) => stream.connected(),
+			Self::Https(stream) => {
+				let (inner, tls) = stream.get_ref();
+				if tls.alpn_protocol() == Some(b"h2") {
+					inner.connected().negotiated_h2()
+				} else {
+					inner.connected()
+				}
+			}
+		}
+	}
+}
+
+#[cfg(feature = "tls")]
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeHttps<T> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Http(stream) => Pin::new(stream).poll_read(cx, buf),
+			Self::Https(stream) => Pin::new(stream).poll_read(cx, buf),
+		}
+	}
+}
+
+#[cfg(feature = "tls")]
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeHttps<T> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Self::Http(stream) => Pin::new(stream).poll_write(cx, buf),
+			Self::Https(stream) => Pin::new(stream).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Http(stream) => Pin::new(stream).poll_flush(cx),
+			Self::Https(stream) => Pin::new(stream).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Http(stream) => Pin::new(stream).poll_shutdown(cx),
+			Self::Https(stream) => Pin::new(stream).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(feature = "tls")]
+impl<C> Service<Uri> for HttpsConnector<C>
+where
+	C: Service<Uri> + Send,
+	C::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+	C::Error: Into<ConnectError>,
+	C::Future: Send + 'static,
+{
+	type Response = MaybeHttps<C::Response>;
+	type Error = ConnectError;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ConnectError>> + Send>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectError>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, uri: Uri) -> Self::Future {
+		let is_https = uri.scheme_str() == Some("https");
+		let host = uri
+			.host()
+			.unwrap_or_default()
+			.trim_start_matches('[')
+			.trim_end_matches(']')
+			.to_string();
+		let connecting = self.inner.call(uri);
+		let tls = self.tls.clone();
+
+		Box::pin(async move {
+			let stream = connecting.await.map_err(Into::into)?;
+			if !is_https {
+				return Ok(MaybeHttps::Http(stream));
+			}
+			let server_name = rustls::pki_types::ServerName::try_from(host)?;
+			let stream = tls.connect(server_name, stream).await?;
+			Ok(MaybeHttps::Https(Box::new(stream)))
+		})
+	}
+}
+
+/// The error type of [`Connector`]
+pub type ConnectError = Box<dyn std::error::Error + Send + Sync>;
+
+trait Io: AsyncRead + AsyncWrite + Connection + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Connection + Unpin + Send> Io for T {}
+
+/// A connection made by a [`Connector`]
+pub struct UpstreamStream(Box<dyn Io>);
+
+impl Connection for UpstreamStream {
+	fn connected(&self) -> Connected {
+		self.0.connected()
+	}
+}
+
+impl AsyncRead for UpstreamStream {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.0).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for UpstreamStream {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.0).poll_write(cx, buf)
+	}
+
+	fn poll_write_vectored(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		bufs: &[io::IoSlice<'_>],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+	}
+
+	fn is_write_vectored(&self) -> bool {
+		self.0.is_write_vectored()
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.0).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.0).poll_shutdown(cx)
+	}
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<UpstreamStream, ConnectError>> + Send>>;
+
+/// The connector of the clients given to [`RequestHandler`](crate::RequestHandler)s
+///
+/// It can wrap any connector, for example one that connects to `https://` upstreams or a
+/// [`ConnectionTracker`], so request handlers don't depend on how connections are made:
+/// ```
+/// use hyper::client::HttpConnector;
+/// use hyper::Client;
+/// use proxylib::connect::{ConnectionTracker, Connector};
+///
+/// let client = Client::builder().build::<_, hyper::Body>(Connector::new(ConnectionTracker::new(HttpConnector::new())));
+/// ```
+#[derive(Clone)]
+pub struct Connector {
+	connect: Arc<dyn Fn(Uri) -> ConnectFuture + Send + Sync>,
+}
+
+impl Connector {
+	/// Wrap a connector
+	pub fn new<C>(connector: C) -> Self
+	where
+		C: Service<Uri> + Clone + Send + Sync + 'static,
+		C::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+		C::Error: Into<ConnectError>,
+		C::Future: Send + 'static,
+	{
+		let connect = move |uri: Uri| -> ConnectFuture {
+			let mut connector = connector.clone();
+			Box::pin(async move {
+				poll_fn(|cx| connector.poll_ready(cx))
+					.await
+					.map_err(Into::into)?;
+				let stream = connector.call(uri).await.map_err(Into::into)?;
+				Ok(UpstreamStream(Box::new(stream)))
+			})
+		};
+		Self {
+			connect: Arc::new(connect),
+		}
+	}
+}
+
+impl Service<Uri> for Connector {
+	type Response = UpstreamStream;
+	type Error = ConnectError;
+	type Future = ConnectFuture;
+
+	fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), ConnectError>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, uri: Uri) -> Self::Future {
+		(self.connect)(uri)
+	}
+}
+
+/// The number of connections to one upstream, as reported by [`ConnectionStats::snapshot`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpstreamConnections {
+	/// The authority (`host:port`) of the upstream
+	pub authority: String,
+	/// The number of open connections
+	pub open: usize,
+	/// The number of open connections without any traffic for at least the idle threshold
+	pub idle: usize,
+}
+
+#[derive(Debug)]
+struct ConnState {
+	opened: Instant,
+	// Milliseconds since `opened`
+	last_activity: AtomicU64,
+}
+
+impl ConnState {
+	fn touch(&self) {
+		self.last_activity
+			.store(self.opened.elapsed().as_millis() as u64, Ordering::Relaxed);
+	}
+
+	fn last_activity(&self) -> Instant {
+		self.opened + Duration::from_millis(self.last_activity.load(Ordering::Relaxed))
+	}
+}
+
+// Forget the connections that have been closed
+fn prune(conns: &mut HashMap<String, Vec<Weak<ConnState>>>) {
+	conns.retain(|_, states| {
+		states.retain(|state| state.strong_count() > 0);
+		!states.is_empty()
+	});
+}
+
+/// The live counts of the connections made by a [`ConnectionTracker`], which can be cloned and
+/// read while the tracker is in use
+///
+/// For the clients built by [`UpstreamConfig`], they also include the statistics of the DNS
+/// cache, see [`dns`](Self::dns).
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+	conns: Arc<Mutex<HashMap<String, Vec<Weak<ConnState>>>>>,
+	idle_threshold: Duration,
+	pub(crate) resolver: Option<CachingResolver>,
+}
+
+impl ConnectionStats {
+	/// Get the statistics of the DNS cache of the client, if it has one, see
+	/// [`UpstreamConfig::dns_cache`]
+	pub fn dns(&self) -> Option<ResolverStats> {
+		self.resolver.as_ref().map(CachingResolver::stats)
+	}
+
+	/// Get the number of open and idle connections per upstream, sorted by authority
+	pub fn snapshot(&self) -> Vec<UpstreamConnections> {
+		let now = Instant::now();
+		let mut conns = self.conns.lock().unwrap();
+		prune(&mut conns);
+
+		let mut snapshot = conns
+			.iter()
+			.map(|(authority, states)| UpstreamConnections {
+				authority: authority.clone(),
+				open: states.len(),
+				idle: states
+					.iter()
+					.filter_map(Weak::upgrade)
+					.filter(|state| {
+						now.duration_since(state.last_activity()) >= self.idle_threshold
+					})
+					.count(),
+			})
+			.collect::<Vec<_>>();
+		snapshot.sort_by(|a, b| a.authority.cmp(&b.authority));
+		snapshot
+	}
+}
+
+/// A connector adapter that keeps track of the connections made by another connector
+/// and closes connections that have been idle for longer than a TTL
+///
+/// Unlike the idle timeout of the connection pool, the TTL also applies to connections
+/// the pool doesn't consider idle, so stale connections to restarted backends are reaped
+/// instead of failing the next request. It has to be longer than the longest expected pause
+/// in a response, since a connection without any traffic for the TTL is closed.
+///
+/// The clients built by [`UpstreamConfig`] track their connections, with the
+/// [`connection_ttl`](UpstreamConfig::connection_ttl) of the config, see
+/// [`UpstreamConfig::build_tracked_client`]. To track the connections of another connector:
+/// ```
+/// use hyper::client::HttpConnector;
+/// use hyper::Client;
+/// use proxylib::connect::ConnectionTracker;
+///
+/// let connector = ConnectionTracker::new(HttpConnector::new());
+/// let tracker = connector.clone();
+/// let client = Client::builder().build::<_, hyper::Body>(connector);
+/// // later
+/// for upstream in tracker.snapshot() {
+///     println!("{}: {} open, {} idle", upstream.authority, upstream.open, upstream.idle);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionTracker<C> {
+	inner: C,
+	/// How long a connection may go without traffic before it is closed
+	pub ttl: Option<Duration>,
+	/// How long a connection has to go without traffic to count as idle in snapshots
+	pub idle_threshold: Duration,
+	conns: Arc<Mutex<HashMap<String, Vec<Weak<ConnState>>>>>,
+}
+
+impl<C> ConnectionTracker<C> {
+	/// Track the connections of a connector, with a TTL of 5 minutes and
+	/// an idle threshold of 1 second
+	pub fn new(inner: C) -> Self {
+		Self {
+			inner,
+			ttl: Some(Duration::from_secs(5 * 60)),
+			idle_threshold: Duration::from_secs(1),
+			conns: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Get the live counts of the connections, with the current
+	/// [`idle_threshold`](Self::idle_threshold)
+	pub fn stats(&self) -> ConnectionStats {
+		ConnectionStats {
+			conns: self.conns.clone(),
+			idle_threshold: self.idle_threshold,
+			resolver: None,
+		}
+	}
+
+	/// Get the number of open and idle connections per upstream
+	pub fn snapshot(&self) -> Vec<UpstreamConnections> {
+		self.stats().snapshot()
+	}
+}
+
+impl<C> Service<Uri> for ConnectionTracker<C>
+where
+	C: Service<Uri> + Send,
+	C::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+	C::Future: Send + 'static,
+{
+	type Response = TrackedConnection<C::Response>;
+	type Error = C::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, C::Error>> + Send>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), C::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, uri: Uri) -> Self::Future {
+		let authority = uri
+			.authority()
+			.map_or_else(String::new, |a| a.as_str().to_ascii_lowercase());
+		let conns = self.conns.clone();
+		let ttl = self.ttl;
+		let fut = self.inner.call(uri);
+
+		Box::pin(async move {
+			let inner = fut.await?;
+			let state = Arc::new(ConnState {
+				opened: Instant::now(),
+				last_activity: AtomicU64::new(0),
+			});
+			let mut conns = conns.lock().unwrap();
+			prune(&mut conns);
+			conns
+				.entry(authority)
+				.or_default()
+				.push(Arc::downgrade(&state));
+
+			Ok(TrackedConnection {
+				inner,
+				state,
+				ttl,
+				reap: None,
+			})
+		})
+	}
+}
+
+/// A connection made by a [`ConnectionTracker`]
+pub struct TrackedConnection<T> {
+	inner: T,
+	state: Arc<ConnState>,
+	ttl: Option<Duration>,
+	reap: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T: Connection> Connection for TrackedConnection<T> {
+	fn connected(&self) -> Connected {
+		self.inner.connected()
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TrackedConnection<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		match Pin::new(&mut self.inner).poll_read(cx, buf) {
+			Poll::Ready(res) => {
+				if buf.filled().len() > before {
+					self.state.touch();
+				}
+				Poll::Ready(res)
+			}
+			Poll::Pending => {
+				let ttl = match self.ttl {
+					Some(ttl) => ttl,
+					None => return Poll::Pending,
+				};
+				let deadline = (self.state.last_activity() + ttl).into();
+				let reap = self
+					.reap
+					.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+				if reap.deadline() != deadline {
+					reap.as_mut().reset(deadline);
+				}
+				// Reaching the end of the stream makes the connection close itself
+				match reap.as_mut().poll(cx) {
+					Poll::Ready(()) => Poll::Ready(Ok(())),
+					Poll::Pending => Poll::Pending,
+				}
+			}
+		}
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TrackedConnection<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = poll {
+			if n > 0 {
+				self.state.touch();
+			}
+		}
+		poll
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::{FutureExt, Map};
+use hyper::client::connect::dns::{GaiAddrs, GaiFuture, GaiResolver, Name};
+use hyper::service::Service;
+
+/// The result of a successful name lookup
+#[derive(Debug, Clone)]
+pub struct Lookup {
+	/// The addresses the name resolved to
+	pub addrs: Vec<SocketAddr>,
+	/// How long the result may be cached, if the resolver knows it
+	pub ttl: Option<Duration>,
+}
+
+/// Something that can resolve the names of upstream hosts
+pub trait Resolver {
+	/// The future returned by [`resolve`](Self::resolve)
+	type Future: Future<Output = io::Result<Lookup>> + Send + 'static;
+
+	/// Resolve the name into a list of addresses
+	fn resolve(&self, name: Name) -> Self::Future;
+}
+
+impl Resolver for GaiResolver {
+	type Future = Map<GaiFuture, fn(io::Result<GaiAddrs>) -> io::Result<Lookup>>;
+
+	fn resolve(&self, name: Name) -> Self::Future {
+		self.clone().call(name).map(|res: io::Result<GaiAddrs>| {
+			res.map(|addrs| Lookup {
+				addrs: addrs.collect(),
+				// getaddrinfo doesn't tell us the record TTL
+				ttl: None,
+			})
+		})
+	}
+}
+
+/// The config of a [`CachingResolver`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsCacheConfig {
+	/// How long to cache a lookup if the resolver didn't report a TTL, which [`GaiResolver`] never
+	/// does
+	pub default_ttl: Duration,
+	/// The upper bound for how long any lookup is cached
+	pub max_ttl: Duration,
+	/// How long an expired entry may still be served if the resolver fails
+	pub max_stale: Duration,
+}
+
+impl Default for DnsCacheConfig {
+	fn default() -> Self {
+		Self {
+			default_ttl: Duration::from_secs(30),
+			max_ttl: Duration::from_secs(3600),
+			max_stale: Duration::from_secs(300),
+		}
+	}
+}
+
+/// A snapshot of the statistics of a [`CachingResolver`]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResolverStats {
+	/// The number of lookups that were answered from the cache
+	pub cache_hits: u64,
+	/// The number of lookups that were passed to the inner resolver
+	pub lookups: u64,
+	/// The number of lookups where the inner resolver failed
+	pub failures: u64,
+	/// The number of failed lookups that were answered with an expired entry
+	pub stale_hits: u64,
+	/// The total time spent waiting for the inner resolver
+	pub total_lookup_time: Duration,
+}
+
+impl ResolverStats {
+	/// The average time the inner resolver took per lookup
+	pub fn average_lookup_time(&self) -> Option<Duration> {
+		if self.lookups == 0 {
+			None
+		} else {
+			Some(self.total_lookup_time.div_f64(self.lookups as f64))
+		}
+	}
+}
+
+#[derive(Default)]
+struct Counters {
+	cache_hits: AtomicU64,
+	lookups: AtomicU64,
+	failures: AtomicU64,
+	stale_hits: AtomicU64,
+	lookup_nanos: AtomicU64,
+}
+
+struct CacheEntry {
+	addrs: Vec<SocketAddr>,
+	expires: Instant,
+}
+
+struct Shared<R> {
+	inner: R,
+	config: DnsCacheConfig,
+	cache: Mutex<HashMap<Name, CacheEntry>>,
+	counters: Counters,
+}
+
+/// A resolver that caches the lookups of another [`Resolver`]
+///
+/// Entries are kept for the TTL reported by the inner resolver (or the configured default),
+/// and if a lookup fails, an expired entry is served for up to
+/// [`max_stale`](DnsCacheConfig::max_stale) instead.
+///
+/// The clients built from an [`UpstreamConfig`](crate::connect::UpstreamConfig) use one by
+/// default, see [`dns_cache`](crate::connect::UpstreamConfig::dns_cache). Other connectors can
+/// use it like this:
+/// ```
+/// use hyper::client::connect::dns::GaiResolver;
+/// use hyper::client::HttpConnector;
+/// use proxylib::dns::CachingResolver;
+///
+/// let connector = HttpConnector::new_with_resolver(CachingResolver::new(GaiResolver::new()));
+/// ```
+pub struct CachingResolver<R: Resolver = GaiResolver> {
+	shared: Arc<Shared<R>>,
+}
+
+impl<R: Resolver> fmt::Debug for CachingResolver<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("CachingResolver")
+			.field("config", &self.shared.config)
+			.field("stats", &self.stats())
+			.finish()
+	}
+}
+
+impl<R: Resolver> Clone for CachingResolver<R> {
+	fn clone(&self) -> Self {
+		Self {
+			shared: self.shared.clone(),
+		}
+	}
+}
+
+impl<R: Resolver> CachingResolver<R> {
+	/// Create a caching resolver with the default config
+	pub fn new(inner: R) -> Self {
+		Self::with_config(inner, DnsCacheConfig::default())
+	}
+
+	/// Create a caching resolver with the given config
+	pub fn with_config(inner: R, config: DnsCacheConfig) -> Self {
+		Self {
+			shared: Arc::new(Shared {
+				inner,
+				config,
+				cache: Mutex::new(HashMap::new()),
+				counters: Counters::default(),
+			}),
+		}
+	}
+
+	/// Get the current statistics
+	pub fn stats(&self) -> ResolverStats {
+		let c = &self.shared.counters;
+		ResolverStats {
+			cache_hits: c.cache_hits.load(Ordering::Relaxed),
+			lookups: c.lookups.load(Ordering::Relaxed),
+			failures: c.failures.load(Ordering::Relaxed),
+			stale_hits: c.stale_hits.load(Ordering::Relaxed),
+			total_lookup_time: Duration::from_nanos(c.lookup_nanos.load(Ordering::Relaxed)),
+		}
+	}
+
+	/// Remove all cached entries
+	pub fn clear(&self) {
+		self.shared.cache.lock().unwrap().clear();
+	}
+}
+
+impl<R: Resolver> Shared<R> {
+	fn cached(&self, name: &Name, now: Instant, max_stale: Duration) -> Option<Vec<SocketAddr>> {
+		let cache = self.cache.lock().unwrap();
+		let entry = cache.get(name)?;
+		if now < entry.expires + max_stale {
+			Some(entry.addrs.clone())
+		} else {
+			None
+		}
+	}
+
+	async fn lookup(&self, name: Name) -> io::Result<Vec<SocketAddr>> {
+		if let Some(addrs) = self.cached(&name, Instant::now(), Duration::from_secs(0)) {
+			self.counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(addrs);
+		}
+
+		let start = Instant::now();
+		let res = self.inner.resolve(name.clone()).await;
+		let now = Instant::now();
+		self.counters.lookups.fetch_add(1, Ordering::Relaxed);
+		self.counters
+			.lookup_nanos
+			.fetch_add((now - start).as_nanos() as u64, Ordering::Relaxed);
+
+		match res {
+			Ok(lookup) => {
+				let ttl = lookup
+					.ttl
+					.unwrap_or(self.config.default_ttl)
+					.min(self.config.max_ttl);
+				let max_stale = self.config.max_stale;
+
+				let mut cache = self.cache.lock().unwrap();
+				cache.retain(|_, entry| now < entry.expires + max_stale);
+				cache.insert(
+					name,
+					CacheEntry {
+						addrs: lookup.addrs.clone(),
+						expires: now + ttl,
+					},
+				);
+				Ok(lookup.addrs)
+			}
+			Err(e) => {
+				self.counters.failures.fetch_add(1, Ordering::Relaxed);
+				match self.cached(&name, now, self.config.max_stale) {
+					Some(addrs) => {
+						self.counters.stale_hits.fetch_add(1, Ordering::Relaxed);
+						Ok(addrs)
+					}
+					None => Err(e),
+				}
+			}
+		}
+	}
+}
+
+impl<R: Resolver + Send + Sync + 'static> Service<Name> for CachingResolver<R> {
+	type Response = std::vec::IntoIter<SocketAddr>;
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+	fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, name: Name) -> Self::Future {
+		let shared = self.shared.clone();
+		Box::pin(async move { shared.lookup(name).await.map(Vec::into_iter) })
+	}
+}
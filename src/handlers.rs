@@ -1,7 +1,113 @@
+/// Functionality relating to [`AccessLog`]
+pub mod access_log;
+/// Functionality relating to [`Accounting`]
+pub mod accounting;
+/// Functionality relating to [`Admin`]
+pub mod admin;
+/// Functionality relating to [`ConnectionAffinity`]
+pub mod affinity;
+/// Functionality relating to [`Aggregate`]
+pub mod aggregate;
+/// Functionality relating to [`AltSvc`]
+pub mod alt_svc;
+#[cfg(feature = "asn")]
+/// Functionality relating to autonomous systems, for [`Filter`] and [`Tenancy`]
+pub mod asn;
+/// Functionality relating to [`Balance`]
+pub mod balance;
+/// Functionality relating to [`ResponseBuffering`]
+pub mod buffering;
+/// Functionality relating to [`Bypass`]
+pub mod bypass;
+/// Functionality relating to [`Cache`]
+pub mod cache;
+/// Functionality relating to [`CircuitBreaker`]
+pub mod circuit_breaker;
+/// Functionality relating to [`ContentRoute`]
+pub mod content_route;
+/// Functionality relating to [`Deadline`]
+pub mod deadline;
+/// Functionality relating to [`Esi`]
+pub mod esi;
+/// Functionality relating to [`Fallback`]
+pub mod fallback;
 /// Functionality relating to [`Filter`]
 pub mod filter;
+/// Functionality relating to [`ForwardProxy`]
+pub mod forward_proxy;
+/// Functionality relating to [`ForwardedHeaders`]
+pub mod forwarded;
+/// Functionality relating to [`handler_fn`]
+pub mod from_fn;
+#[cfg(feature = "graphql")]
+/// Functionality relating to [`GraphQl`]
+pub mod graphql;
+/// Functionality relating to [`GrpcWeb`]
+pub mod grpc_web;
+/// Functionality relating to [`HeaderAllowlist`]
+pub mod header_allowlist;
+/// Functionality relating to [`UpstreamPool`]
+pub mod health;
+/// Functionality relating to [`Idempotency`]
+pub mod idempotency;
+/// Functionality relating to [`Maintenance`]
+pub mod maintenance;
+#[cfg(feature = "prometheus")]
+/// Functionality relating to [`Metered`]
+pub mod metrics;
+/// Functionality relating to [`Layered`]
+pub mod middleware;
+/// Functionality relating to [`Mirror`]
+pub mod mirror;
+/// Functionality relating to [`ModifyRequest`]
+pub mod modify_request;
+/// Functionality relating to [`ModifyResponse`]
+pub mod modify_response;
+/// Functionality relating to [`LoadShed`]
+pub mod overload;
+/// Functionality relating to [`ServePac`]
+pub mod pac;
+/// Functionality relating to [`Prioritize`]
+pub mod prioritize;
+/// Functionality relating to [`ProxyAuth`]
+pub mod proxy_auth;
+/// Functionality relating to [`RateLimit`]
+pub mod rate_limit;
 /// Functionality relating to [`Redirect`]
 pub mod redirect;
+/// Functionality relating to [`Reputation`]
+pub mod reputation;
+/// Functionality relating to [`Retry`]
+pub mod retry;
+/// Functionality relating to [`SendProxyProtocol`]
+pub mod send_proxy;
+/// Functionality relating to [`ResponseSizeLimit`]
+pub mod size_limit;
+/// Functionality relating to [`Sticky`]
+pub mod sticky;
+/// Functionality relating to [`SwappableHandler`]
+pub mod swappable;
+/// Functionality relating to [`Tenancy`]
+pub mod tenancy;
+/// Functionality relating to [`Timeout`]
+pub mod timeout;
+/// Functionality relating to [`Transparent`]
+pub mod transparent;
+/// Functionality relating to [`Tunnel`]
+pub mod tunnel;
+/// Functionality relating to [`UpgradeTunnel`]
+pub mod upgrade;
+#[cfg(feature = "openapi")]
+/// Functionality relating to [`Validate`]
+pub mod validate;
+/// Functionality relating to [`VirtualHosts`]
+pub mod vhost;
+/// Functionality relating to [`Via`]
+pub mod via;
+/// Functionality relating to [`WebSocketLimit`]
+pub mod websocket;
+/// Functionality relating to [`WithClient`]
+pub mod with_client;
 
 /// All functionality from this module, easy to import
 ///
@@ -11,9 +117,117 @@ pub mod redirect;
 /// ```
 /// and you have imported everything
 pub mod prelude {
+	pub use super::access_log::*;
+	pub use super::accounting::*;
+	pub use super::admin::*;
+	pub use super::affinity::*;
+	pub use super::aggregate::*;
+	pub use super::alt_svc::*;
+	#[cfg(feature = "asn")]
+	pub use super::asn::*;
+	pub use super::balance::*;
+	pub use super::buffering::*;
+	pub use super::bypass::*;
+	pub use super::cache::*;
+	pub use super::circuit_breaker::*;
+	pub use super::content_route::*;
+	pub use super::deadline::*;
+	pub use super::esi::*;
+	pub use super::fallback::*;
 	pub use super::filter::*;
+	pub use super::forward_proxy::*;
+	pub use super::forwarded::*;
+	pub use super::from_fn::*;
+	#[cfg(feature = "graphql")]
+	pub use super::graphql::*;
+	pub use super::grpc_web::*;
+	pub use super::header_allowlist::*;
+	pub use super::health::*;
+	pub use super::idempotency::*;
+	pub use super::maintenance::*;
+	#[cfg(feature = "prometheus")]
+	pub use super::metrics::*;
+	pub use super::middleware::*;
+	pub use super::mirror::*;
+	pub use super::modify_request::*;
+	pub use super::modify_response::*;
+	pub use super::overload::*;
+	pub use super::pac::*;
+	pub use super::prioritize::*;
+	pub use super::proxy_auth::*;
+	pub use super::rate_limit::*;
 	pub use super::redirect::*;
+	pub use super::reputation::*;
+	pub use super::retry::*;
+	pub use super::send_proxy::*;
+	pub use super::size_limit::*;
+	pub use super::sticky::*;
+	pub use super::swappable::*;
+	pub use super::tenancy::*;
+	pub use super::timeout::*;
+	pub use super::transparent::*;
+	pub use super::tunnel::*;
+	pub use super::upgrade::*;
+	#[cfg(feature = "openapi")]
+	pub use super::validate::*;
+	pub use super::vhost::*;
+	pub use super::via::*;
+	pub use super::websocket::*;
+	pub use super::with_client::*;
 }
 
+pub use access_log::AccessLog;
+pub use accounting::Accounting;
+pub use admin::Admin;
+pub use affinity::ConnectionAffinity;
+pub use aggregate::Aggregate;
+pub use alt_svc::AltSvc;
+pub use balance::Balance;
+pub use buffering::ResponseBuffering;
+pub use bypass::Bypass;
+pub use cache::Cache;
+pub use circuit_breaker::CircuitBreaker;
+pub use content_route::ContentRoute;
+pub use deadline::Deadline;
+pub use esi::Esi;
+pub use fallback::Fallback;
 pub use filter::Filter;
+pub use forward_proxy::ForwardProxy;
+pub use forwarded::ForwardedHeaders;
+pub use from_fn::handler_fn;
+#[cfg(feature = "graphql")]
+pub use graphql::GraphQl;
+pub use grpc_web::GrpcWeb;
+pub use header_allowlist::HeaderAllowlist;
+pub use health::UpstreamPool;
+pub use idempotency::Idempotency;
+pub use maintenance::Maintenance;
+#[cfg(feature = "prometheus")]
+pub use metrics::Metered;
+pub use middleware::Layered;
+pub use mirror::Mirror;
+pub use modify_request::ModifyRequest;
+pub use modify_response::ModifyResponse;
+pub use overload::LoadShed;
+pub use pac::ServePac;
+pub use prioritize::Prioritize;
+pub use proxy_auth::ProxyAuth;
+pub use rate_limit::RateLimit;
 pub use redirect::Redirect;
+pub use reputation::Reputation;
+pub use retry::Retry;
+pub use send_proxy::SendProxyProtocol;
+pub use size_limit::ResponseSizeLimit;
+pub use sticky::Sticky;
+pub use swappable::SwappableHandler;
+pub use tenancy::Tenancy;
+pub use timeout::Timeout;
+pub use transparent::Transparent;
+pub use tunnel::Tunnel;
+pub use upgrade::UpgradeTunnel;
+#[cfg(feature = "openapi")]
+pub use validate::Validate;
+pub use vhost::VirtualHosts;
+pub use via::Via;
+pub use websocket::WebSocketLimit;
+pub use with_client::WithClient;
@@ -0,0 +1,170 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::{Body, Client, Request, Response};
+
+use crate::body::inspect_len;
+use crate::connect::Connector;
+use crate::handlers::proxy_auth::ProxyUser;
+use crate::handlers::redirect::Upstream;
+use crate::handlers::tenancy::Tenant;
+use crate::log::{LogRecord, LogSink};
+use crate::RequestHandler;
+
+/// A request handler combinator that writes a record of every request to a [`LogSink`]
+///
+/// A record is written once the response body has been sent (or the request was given up on),
+/// with the time the request arrived and these fields:
+/// - `client`: the IP address of the client
+/// - `user`: the [`ProxyUser`], if the request was authenticated by a
+///   [`ProxyAuth`](super::proxy_auth::ProxyAuth) around the [`AccessLog`]
+/// - `tenant`: the [`Tenant`], if the request was resolved to one by a
+///   [`Tenancy`](super::tenancy::Tenancy) around the [`AccessLog`]
+/// - `method`, `path` (with the query) and `version` of the request
+/// - `status`: the status of the response, unless the inner request handler failed
+/// - `upstream`: the scheme and authority the request was forwarded to, see [`Upstream`]
+/// - `latency_ms`: the milliseconds until the response arrived
+/// - `bytes_in` and `bytes_out`: the sizes of the request and response bodies
+/// - `error`: the error of the inner request handler, if it failed
+///
+/// The format is chosen with the sink, e.g. [`LogFormat::Common`](crate::log::LogFormat::Common)
+/// for the Common Log Format of web servers.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::access_log::AccessLog;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::log::{LogFormat, Stdout};
+///
+/// let handler = AccessLog::new(
+///     Redirect::change_authority("app.internal:8080".parse().unwrap()),
+///     Stdout {
+///         format: LogFormat::Common,
+///     },
+/// );
+/// ```
+pub struct AccessLog<H: RequestHandler, S: LogSink> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// Where the records are written to
+	pub sink: Arc<S>,
+}
+
+impl<H: RequestHandler, S: LogSink> AccessLog<H, S> {
+	/// Create an [`AccessLog`] writing to the sink
+	pub fn new(inner: H, sink: S) -> Self {
+		Self {
+			inner,
+			sink: Arc::new(sink),
+		}
+	}
+}
+
+// The record of a request, which is written once nothing refers to it anymore, i.e. both
+// bodies and the response future are gone
+struct Entry<S: LogSink> {
+	sink: Arc<S>,
+	record: Mutex<LogRecord>,
+	bytes_in: AtomicU64,
+	bytes_out: AtomicU64,
+}
+
+impl<S: LogSink> Drop for Entry<S> {
+	fn drop(&mut self) {
+		let record = self.record.get_mut().unwrap();
+		push(record, "bytes_in", self.bytes_in.load(Ordering::Relaxed));
+		push(record, "bytes_out", self.bytes_out.load(Ordering::Relaxed));
+		self.sink.log(record);
+	}
+}
+
+fn push(record: &mut LogRecord, name: &str, value: impl ToString) {
+	record.fields.push((name.to_string(), value.to_string()));
+}
+
+type AccessLogFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H, S> RequestHandler for AccessLog<H, S>
+where
+	H: RequestHandler,
+	S: LogSink + Send + Sync + 'static,
+{
+	type Error = H::Error;
+	type Output = AccessLogFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let start = Instant::now();
+		let mut record = LogRecord::new().with("client", from_addr.ip());
+		if let Some(ProxyUser(user)) = request.extensions().get() {
+			record = record.with("user", user);
+		}
+		if let Some(Tenant(tenant)) = request.extensions().get() {
+			record = record.with("tenant", tenant);
+		}
+		let path = request
+			.uri()
+			.path_and_query()
+			.map_or("/", |path| path.as_str());
+		let record = record
+			.with("method", request.method())
+			.with("path", path)
+			.with("version", format!("{:?}", request.version()));
+		let entry = Arc::new(Entry {
+			sink: self.sink.clone(),
+			record: Mutex::new(record),
+			bytes_in: AtomicU64::new(0),
+			bytes_out: AtomicU64::new(0),
+		});
+
+		let (parts, body) = request.into_parts();
+		let body = {
+			let entry = entry.clone();
+			inspect_len(body, move |len| {
+				entry.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+			})
+		};
+		let fut = self
+			.inner
+			.handle(from_addr, Request::from_parts(parts, body), client);
+
+		Box::pin(async move {
+			let result = fut.await;
+			let mut record = entry.record.lock().unwrap();
+			match &result {
+				Ok(response) => {
+					push(&mut record, "status", response.status().as_u16());
+					if let Some(Upstream(uri)) = response.extensions().get() {
+						if let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) {
+							push(
+								&mut record,
+								"upstream",
+								format!("{}://{}", scheme, authority),
+							);
+						}
+					}
+					push(&mut record, "latency_ms", start.elapsed().as_millis());
+				}
+				Err(error) => {
+					push(&mut record, "latency_ms", start.elapsed().as_millis());
+					push(&mut record, "error", error);
+				}
+			}
+			drop(record);
+
+			let (parts, body) = result?.into_parts();
+			let body = inspect_len(body, move |len| {
+				entry.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderName, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::body::inspect_len;
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The exchangable part of an [`Accounting`] that decides whom a request is billed to
+pub trait ClientKey {
+	/// Return the key of the client that sent the request, or `None` to not account it
+	fn client_key(&self, from_addr: SocketAddr, request: &Request<Body>) -> Option<String>;
+}
+
+/// Obtain a [`ClientKey`] from a function/closure
+pub fn client_key_fn<F: Fn(SocketAddr, &Request<Body>) -> Option<String>>(f: F) -> impl ClientKey {
+	struct ClientKeyFn<F: Fn(SocketAddr, &Request<Body>) -> Option<String>>(F);
+
+	impl<F: Fn(SocketAddr, &Request<Body>) -> Option<String>> ClientKey for ClientKeyFn<F> {
+		fn client_key(&self, from_addr: SocketAddr, request: &Request<Body>) -> Option<String> {
+			(self.0)(from_addr, request)
+		}
+	}
+
+	ClientKeyFn(f)
+}
+
+/// A [`ClientKey`] that identifies clients by their IP address
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpKey;
+
+impl ClientKey for IpKey {
+	fn client_key(&self, from_addr: SocketAddr, _: &Request<Body>) -> Option<String> {
+		Some(from_addr.ip().to_string())
+	}
+}
+
+/// A [`ClientKey`] that identifies clients by the value of a header (e.g. an API key or a tenant id)
+///
+/// Requests without the header are not accounted.
+#[derive(Debug, Clone)]
+pub struct HeaderKey(pub HeaderName);
+
+impl ClientKey for HeaderKey {
+	fn client_key(&self, _: SocketAddr, request: &Request<Body>) -> Option<String> {
+		let value = request.headers().get(&self.0)?;
+		value.to_str().ok().map(str::to_string)
+	}
+}
+
+/// The period over which usage is accumulated
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum QuotaPeriod {
+	/// A calendar day (UTC)
+	Daily,
+	/// A calendar month (UTC)
+	Monthly,
+}
+
+impl QuotaPeriod {
+	/// All periods, in the order usage is recorded for them
+	pub const ALL: [QuotaPeriod; 2] = [QuotaPeriod::Daily, QuotaPeriod::Monthly];
+
+	/// The index of the period containing the given point in time
+	///
+	/// Days are counted from the unix epoch, months as `year * 12 + month`.
+	pub fn index(self, time: SystemTime) -> u64 {
+		let days = days_since_epoch(time);
+
+		match self {
+			QuotaPeriod::Daily => days,
+			QuotaPeriod::Monthly => {
+				let (year, month) = year_month_from_days(days);
+				year * 12 + month
+			}
+		}
+	}
+
+	/// The end of the period containing the given point in time, i.e. when usage starts over
+	pub fn end(self, time: SystemTime) -> SystemTime {
+		let days = days_since_epoch(time);
+		let end = match self {
+			QuotaPeriod::Daily => days + 1,
+			QuotaPeriod::Monthly => {
+				let next = self.index(time) + 1;
+				days_from_year_month(next / 12, next % 12)
+			}
+		};
+		UNIX_EPOCH + Duration::from_secs(end * 86400)
+	}
+}
+
+fn days_since_epoch(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs() / 86400)
+		.unwrap_or(0)
+}
+
+impl fmt::Display for QuotaPeriod {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			QuotaPeriod::Daily => f.write_str("daily"),
+			QuotaPeriod::Monthly => f.write_str("monthly"),
+		}
+	}
+}
+
+// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn year_month_from_days(days: u64) -> (u64, u64) {
+	let z = days + 719468;
+	let era = z / 146097;
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let month = if mp < 10 { mp + 2 } else { mp - 10 };
+	let year = yoe + era * 400 + if month < 2 { 1 } else { 0 };
+	(year, month)
+}
+
+// The first day of a month (counted from 0), see
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_year_month(year: u64, month: u64) -> u64 {
+	let year = if month < 2 { year - 1 } else { year };
+	let era = year / 400;
+	let yoe = year - era * 400;
+	let mp = if month < 2 { month + 10 } else { month - 2 };
+	let doy = (153 * mp + 2) / 5;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	era * 146097 + doe - 719468
+}
+
+/// The usage of a single client in a single period
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Usage {
+	/// The number of requests
+	pub requests: u64,
+	/// The number of request body bytes received from the client
+	pub bytes_in: u64,
+	/// The number of response body bytes sent to the client
+	pub bytes_out: u64,
+}
+
+impl Usage {
+	/// The total number of body bytes transferred
+	pub fn bytes(&self) -> u64 {
+		self.bytes_in + self.bytes_out
+	}
+}
+
+/// The storage backing an [`Accounting`]
+pub trait UsageStore {
+	/// Add `delta` to the usage of `key` in the period with the given index
+	fn record(&self, key: &str, period: QuotaPeriod, index: u64, delta: Usage);
+
+	/// Get the usage of `key` in the period with the given index
+	fn usage(&self, key: &str, period: QuotaPeriod, index: u64) -> Usage;
+}
+
+/// A [`UsageStore`] that keeps the usage in memory
+///
+/// Only the current period is kept for each client; older ones are discarded, and clients
+/// without usage in the current period are removed as soon as a new period begins.
+#[derive(Debug, Default)]
+pub struct MemoryUsageStore {
+	map: Mutex<HashMap<(String, QuotaPeriod), (u64, Usage)>>,
+	current: Mutex<HashMap<QuotaPeriod, u64>>,
+}
+
+impl UsageStore for MemoryUsageStore {
+	fn record(&self, key: &str, period: QuotaPeriod, index: u64, delta: Usage) {
+		let mut map = self.map.lock().unwrap();
+		let mut current = self.current.lock().unwrap();
+		let current = current.entry(period).or_insert(index);
+		if *current < index {
+			map.retain(|(_, p), (i, _)| *p != period || *i >= index);
+			*current = index;
+		}
+
+		let entry = map
+			.entry((key.to_string(), period))
+			.or_insert((index, Usage::default()));
+		if entry.0 < index {
+			*entry = (index, Usage::default());
+		}
+		if entry.0 == index {
+			entry.1.requests += delta.requests;
+			entry.1.bytes_in += delta.bytes_in;
+			entry.1.bytes_out += delta.bytes_out;
+		}
+	}
+
+	fn usage(&self, key: &str, period: QuotaPeriod, index: u64) -> Usage {
+		let map = self.map.lock().unwrap();
+		match map.get(&(key.to_string(), period)) {
+			Some(&(i, usage)) if i == index => usage,
+			_ => Usage::default(),
+		}
+	}
+}
+
+/// A limit on the usage of a single client in a period
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Quota {
+	/// The period the limit applies to
+	pub period: QuotaPeriod,
+	/// The maximum number of requests, if any
+	pub max_requests: Option<u64>,
+	/// The maximum number of body bytes (in both directions), if any
+	pub max_bytes: Option<u64>,
+}
+
+impl Quota {
+	/// Return whether the usage has reached this quota
+	pub fn is_exhausted_by(&self, usage: &Usage) -> bool {
+		self.max_requests.is_some_and(|max| usage.requests >= max)
+			|| self.max_bytes.is_some_and(|max| usage.bytes() >= max)
+	}
+}
+
+/// A request handler combinator that records the traffic of each client and enforces quotas
+/// before giving requests to another request handler
+///
+/// Clients that have exhausted a quota are answered with `429 Too Many Requests` and a
+/// `Retry-After` header pointing to the end of the quota's period.
+pub struct Accounting<H: RequestHandler, K: ClientKey, S: UsageStore = MemoryUsageStore> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The [`ClientKey`] deciding whom a request is billed to
+	pub key: K,
+	/// The storage for the usage, shared so it can be read while the proxy is running
+	pub store: Arc<S>,
+	/// The quotas to enforce
+	pub quotas: Vec<Quota>,
+}
+
+impl<H: RequestHandler, K: ClientKey> Accounting<H, K> {
+	/// Create an [`Accounting`] with in-memory storage and no quotas
+	pub fn new(inner: H, key: K) -> Self {
+		Self {
+			inner,
+			key,
+			store: Arc::new(MemoryUsageStore::default()),
+			quotas: Vec::new(),
+		}
+	}
+}
+
+impl<H: RequestHandler, K: ClientKey, S: UsageStore> Accounting<H, K, S> {
+	/// Add a quota
+	pub fn with_quota(mut self, quota: Quota) -> Self {
+		self.quotas.push(quota);
+		self
+	}
+
+	/// Get the usage of a client in the current period
+	pub fn usage(&self, key: &str, period: QuotaPeriod) -> Usage {
+		self.store
+			.usage(key, period, period.index(SystemTime::now()))
+	}
+}
+
+type AccountingFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+// Tell the client to come back when the period of the exhausted quota is over
+fn quota_exhausted(period: QuotaPeriod, now: SystemTime) -> Response<Body> {
+	let wait = period.end(now).duration_since(now).unwrap_or_default();
+	let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+	Response::builder()
+		.status(StatusCode::TOO_MANY_REQUESTS)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.header(RETRY_AFTER, secs.max(1))
+		.body(Body::from(format!("The {} quota is exhausted.\n", period)))
+		.unwrap()
+}
+
+fn record<S: UsageStore>(store: &S, key: &str, delta: Usage) {
+	let now = SystemTime::now();
+	for &period in &QuotaPeriod::ALL {
+		store.record(key, period, period.index(now), delta);
+	}
+}
+
+impl<H, K, S> RequestHandler for Accounting<H, K, S>
+where
+	H: RequestHandler,
+	K: ClientKey,
+	S: UsageStore + Send + Sync + 'static,
+{
+	type Error = H::Error;
+	type Output = AccountingFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let key = match self.key.client_key(from_addr, &request) {
+			Some(key) => key,
+			None => {
+				return Box::pin(self.inner.handle(from_addr, request, client));
+			}
+		};
+
+		let now = SystemTime::now();
+		for quota in &self.quotas {
+			let usage = self
+				.store
+				.usage(&key, quota.period, quota.period.index(now));
+			if quota.is_exhausted_by(&usage) {
+				let response = quota_exhausted(quota.period, now);
+				return Box::pin(async move { Ok(response) });
+			}
+		}
+
+		record(
+			&*self.store,
+			&key,
+			Usage {
+				requests: 1,
+				..Usage::default()
+			},
+		);
+
+		let (parts, body) = request.into_parts();
+		let body = {
+			let store = self.store.clone();
+			let key = key.clone();
+			inspect_len(body, move |len| {
+				record(
+					&*store,
+					&key,
+					Usage {
+						bytes_in: len as u64,
+						..Usage::default()
+					},
+				)
+			})
+		};
+
+		let fut = self
+			.inner
+			.handle(from_addr, Request::from_parts(parts, body), client);
+		let store = self.store.clone();
+
+		Box::pin(async move {
+			let (parts, body) = fut.await?.into_parts();
+			let body = inspect_len(body, move |len| {
+				record(
+					&*store,
+					&key,
+					Usage {
+						bytes_out: len as u64,
+						..Usage::default()
+					},
+				)
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
@@ -0,0 +1,675 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+
+use crate::body::{buffer, Buffered};
+use crate::chain::percent_decode;
+use crate::connect::{ConnectionStats, Connector};
+use crate::handlers::balance::{Balance, BalanceStrategy, DrainHandle};
+use crate::handlers::cache::{Cache, CacheStore};
+use crate::handlers::health::UpstreamPool;
+use crate::handlers::maintenance::MaintenanceSwitch;
+use crate::log::push_json_string;
+use crate::metrics::{CounterFamily, ProxyStats};
+use crate::prime::{PrimeReport, Primer};
+use crate::{ProxyConfig, RequestHandler};
+
+/// A callback that reloads the config of a proxy, see [`Admin::with_reload`]
+pub type Reload =
+	Arc<dyn Fn() -> Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + Sync>;
+
+// Sends requests through a request handler with a primer
+type PrimeRun = Arc<
+	dyn Fn(
+			Vec<Request<Body>>,
+			Client<Connector>,
+		) -> Pin<Box<dyn Future<Output = PrimeReport> + Send>>
+		+ Send
+		+ Sync,
+>;
+
+// A primer with the request handler it warms, and the report of its last run
+struct Priming {
+	name: String,
+	primer: Arc<Primer>,
+	run: PrimeRun,
+	running: Arc<AtomicBool>,
+	report: Arc<Mutex<Option<PrimeReport>>>,
+}
+
+// The upstreams of a balancer, which can be drained
+struct Upstreams {
+	name: String,
+	len: usize,
+	drain: DrainHandle,
+	health: Option<UpstreamPool>,
+}
+
+/// A request handler serving an admin API for looking into a running proxy and controlling it,
+/// meant for a second listener that only operators can reach
+///
+/// These endpoints are served, with JSON bodies unless noted otherwise:
+/// - `GET /`: the list of endpoints, as plain text
+/// - `GET /config`: the config of the proxy, see [`with_proxy_config`](Self::with_proxy_config)
+/// - `GET /stats`: the live counts of connections and requests (see [`ProxyStats`]), the
+///   values of the added counters, like the hits and misses of caches, and the open and idle
+///   connections of the added clients per upstream, along with the statistics of their DNS
+///   caches
+/// - `GET /upstreams`: the upstreams of the added [`Balance`]s, with their health (if they are
+///   checked), whether they are draining and their requests in flight
+/// - `POST /upstreams/{balancer}/{index}/drain`: take an upstream out of rotation and wait until
+///   its requests have finished, see [`DrainHandle::drain`]
+/// - `POST /upstreams/{balancer}/{index}/undrain`: put an upstream back into rotation
+/// - `GET /maintenance`: whether the added [`MaintenanceSwitch`]es are on
+/// - `POST /maintenance/{switch}/on` and `POST /maintenance/{switch}/off`: turn maintenance mode
+///   on or off
+/// - `POST /prime/{primer}`: warm a cache with the requests in the body, one per line (a URI,
+///   optionally preceded by a method), in the background, see [`with_primer`](Self::with_primer)
+/// - `GET /prime/{primer}`: the progress of the current run and the failures of the last one
+/// - `POST /reload`: call the [`Reload`] callback
+///
+/// Anyone who can reach the API can take upstreams out of rotation or the whole proxy into
+/// maintenance mode, so don't serve it on a public address.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> Result<(), proxylib::ProxyError> {
+/// use std::sync::Arc;
+///
+/// use proxylib::handlers::admin::Admin;
+/// use proxylib::handlers::maintenance::{Maintenance, MaintenanceSwitch};
+/// use proxylib::handlers::{Balance, Redirect};
+/// use proxylib::metrics::ProxyStats;
+/// use proxylib::prime::Primer;
+/// use proxylib::{run_proxy, ProxyConfig};
+///
+/// let balance = Balance::new(vec![
+///     Redirect::change_authority("app-1.internal:8080".parse().unwrap()),
+///     Redirect::change_authority("app-2.internal:8080".parse().unwrap()),
+/// ]);
+/// let switch = MaintenanceSwitch::new();
+/// let admin = Admin::default()
+///     .with_balance("app", &balance)
+///     .with_maintenance("app", &switch);
+/// let handler = Arc::new(Maintenance::new(balance, switch));
+/// let admin = admin.with_primer("app", Primer::new(8), handler.clone());
+/// let config = ProxyConfig::with_shared_handler("0.0.0.0:8080".parse().unwrap(), handler)
+///     .with_stats(Arc::new(ProxyStats::default()));
+/// let admin = admin.with_proxy_config(&config);
+///
+/// // e.g. `curl -X POST http://127.0.0.1:9901/upstreams/app/0/drain` before deploying app-1,
+/// // or `curl -X POST http://127.0.0.1:9901/maintenance/app/on` before migrating its database
+/// let admin_config = ProxyConfig::new("127.0.0.1:9901".parse().unwrap(), admin);
+/// futures::try_join!(run_proxy(config), run_proxy(admin_config))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Admin {
+	/// The config served at `/config`, as a JSON object
+	pub config: String,
+	/// The live counts served at `/stats`
+	pub stats: Option<Arc<ProxyStats>>,
+	counters: Vec<(String, Arc<CounterFamily>)>,
+	connections: Vec<(String, ConnectionStats)>,
+	balancers: Vec<Upstreams>,
+	switches: Vec<(String, MaintenanceSwitch)>,
+	primers: Vec<Priming>,
+	reload: Option<Reload>,
+}
+
+impl Default for Admin {
+	/// An API without a config, stats, upstreams, maintenance switches, primers or reload
+	/// callback
+	fn default() -> Self {
+		Self {
+			config: "{}".to_string(),
+			stats: None,
+			counters: Vec::new(),
+			connections: Vec::new(),
+			balancers: Vec::new(),
+			switches: Vec::new(),
+			primers: Vec::new(),
+			reload: None,
+		}
+	}
+}
+
+impl Admin {
+	/// Serve the config of a proxy, and its stats if it has any (see
+	/// [`ProxyConfig::with_stats`])
+	///
+	/// The config has the listen addresses and the options of the proxy, but not its request
+	/// handler, which can be described with [`with_counters`](Self::with_counters) and
+	/// [`with_balance`](Self::with_balance).
+	pub fn with_proxy_config<T: RequestHandler>(mut self, config: &ProxyConfig<T>) -> Self {
+		self.config = config_json(config);
+		if let Some(stats) = &config.stats {
+			self.stats = Some(stats.clone());
+		}
+		self
+	}
+
+	/// Serve the given live counts
+	pub fn with_stats(mut self, stats: Arc<ProxyStats>) -> Self {
+		self.stats = Some(stats);
+		self
+	}
+
+	/// Serve the values of a counter family under the name
+	pub fn with_counters(mut self, name: impl Into<String>, counters: Arc<CounterFamily>) -> Self {
+		self.counters.push((name.into(), counters));
+		self
+	}
+
+	/// Serve the open and idle connections of a client per upstream (and the statistics of its
+	/// DNS cache) under the name, see
+	/// [`UpstreamConfig::build_tracked_client`](crate::connect::UpstreamConfig::build_tracked_client)
+	pub fn with_connections(
+		mut self,
+		name: impl Into<String>,
+		connections: ConnectionStats,
+	) -> Self {
+		self.connections.push((name.into(), connections));
+		self
+	}
+
+	/// Serve the hits and misses of a [`Cache`] under the name
+	pub fn with_cache<H: RequestHandler, S: CacheStore>(
+		self,
+		name: impl Into<String>,
+		cache: &Cache<H, S>,
+	) -> Self {
+		self.with_counters(name, cache.counters.clone())
+	}
+
+	/// Serve the upstreams of a [`Balance`] under the name, and let them be drained
+	pub fn with_balance<H: RequestHandler, S: BalanceStrategy>(
+		mut self,
+		name: impl Into<String>,
+		balance: &Balance<H, S>,
+	) -> Self {
+		self.balancers.push(Upstreams {
+			name: name.into(),
+			len: balance.upstreams().len(),
+			drain: balance.drain_handle(),
+			health: balance.health.clone(),
+		});
+		self
+	}
+
+	/// Let a [`MaintenanceSwitch`] be toggled under the name, e.g. one shared by the
+	/// [`Maintenance`](super::maintenance::Maintenance)s of a backend's routes and a
+	/// [`Readiness`](super::maintenance::Readiness) endpoint
+	pub fn with_maintenance(mut self, name: impl Into<String>, switch: &MaintenanceSwitch) -> Self {
+		self.switches.push((name.into(), switch.clone()));
+		self
+	}
+
+	/// Let the request handler be warmed with the [`Primer`] under the name, e.g. the whole
+	/// handler chain of a proxy with a cache in it
+	///
+	/// The requests are sent with the client of the admin listener; give the request handler a
+	/// [`WithClient`](super::with_client::WithClient) if it needs another one. Only one run of a
+	/// primer is in progress at a time.
+	pub fn with_primer<H>(
+		mut self,
+		name: impl Into<String>,
+		primer: Primer,
+		handler: Arc<H>,
+	) -> Self
+	where
+		H: RequestHandler + Send + Sync + 'static,
+	{
+		let primer = Arc::new(primer);
+		let run_primer = primer.clone();
+		self.primers.push(Priming {
+			name: name.into(),
+			primer,
+			run: Arc::new(move |requests, client| {
+				let primer = run_primer.clone();
+				let handler = handler.clone();
+				Box::pin(async move { primer.run(&*handler, requests, &client).await })
+			}),
+			running: Arc::default(),
+			report: Arc::default(),
+		});
+		self
+	}
+
+	/// Call the callback on `POST /reload`, e.g. to read a config file again and swap in the
+	/// new request handler with a [`SwapHandle`](super::swappable::SwapHandle)
+	pub fn with_reload<F>(mut self, f: F) -> Self
+	where
+		F: Fn() -> Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + Sync + 'static,
+	{
+		self.reload = Some(Arc::new(f));
+		self
+	}
+
+	fn stats_json(&self) -> String {
+		let mut json = String::from("{");
+		if let Some(stats) = &self.stats {
+			let _ = write!(
+				json,
+				"\"connections_open\":{},\"connections_total\":{},\
+				 \"requests_in_flight\":{},\"requests_total\":{},",
+				stats.connections_open(),
+				stats.connections_total(),
+				stats.requests_in_flight(),
+				stats.requests_total(),
+			);
+		}
+		json.push_str("\"counters\":{");
+		for (i, (name, counters)) in self.counters.iter().enumerate() {
+			if i > 0 {
+				json.push(',');
+			}
+			push_json_string(&mut json, name);
+			json.push_str(":[");
+			for (j, (labels, value)) in counters.snapshot().into_iter().enumerate() {
+				if j > 0 {
+					json.push(',');
+				}
+				json.push_str("{\"labels\":{");
+				for (k, (name, label)) in counters.label_names.iter().zip(&labels).enumerate() {
+					if k > 0 {
+						json.push(',');
+					}
+					push_json_string(&mut json, name);
+					json.push(':');
+					push_json_string(&mut json, label);
+				}
+				let _ = write!(json, "}},\"value\":{}}}", value);
+			}
+			json.push(']');
+		}
+		json.push_str("},\"upstream_connections\":{");
+		for (i, (name, connections)) in self.connections.iter().enumerate() {
+			if i > 0 {
+				json.push(',');
+			}
+			push_json_string(&mut json, name);
+			json.push_str(":[");
+			for (j, upstream) in connections.snapshot().into_iter().enumerate() {
+				if j > 0 {
+					json.push(',');
+				}
+				json.push_str("{\"upstream\":");
+				push_json_string(&mut json, &upstream.authority);
+				let _ = write!(
+					json,
+					",\"open\":{},\"idle\":{}}}",
+					upstream.open, upstream.idle
+				);
+			}
+			json.push(']');
+		}
+		json.push_str("},\"upstream_dns\":{");
+		let dns = self
+			.connections
+			.iter()
+			.filter_map(|(name, connections)| Some((name, connections.dns()?)));
+		for (i, (name, dns)) in dns.enumerate() {
+			if i > 0 {
+				json.push(',');
+			}
+			push_json_string(&mut json, name);
+			let _ = write!(
+				json,
+				":{{\"cache_hits\":{},\"lookups\":{},\"failures\":{},\"stale_hits\":{},\
+				 \"average_lookup_seconds\":{}}}",
+				dns.cache_hits,
+				dns.lookups,
+				dns.failures,
+				dns.stale_hits,
+				dns.average_lookup_time()
+					.map_or(0.0, |time| time.as_secs_f64()),
+			);
+		}
+		json.push_str("}}");
+		json
+	}
+
+	fn upstreams_json(&self) -> String {
+		let mut json = String::from("{");
+		for (i, balancer) in self.balancers.iter().enumerate() {
+			if i > 0 {
+				json.push(',');
+			}
+			push_json_string(&mut json, &balancer.name);
+			json.push_str(":[");
+			for index in 0..balancer.len {
+				if index > 0 {
+					json.push(',');
+				}
+				let _ = write!(json, "{{\"index\":{},\"target\":", index);
+				match balancer
+					.health
+					.as_ref()
+					.and_then(|pool| pool.targets().get(index))
+				{
+					Some(target) => push_json_string(&mut json, &target.to_string()),
+					None => json.push_str("null"),
+				}
+				let healthy = balancer
+					.health
+					.as_ref()
+					.is_none_or(|pool| pool.is_healthy(index));
+				let _ = write!(
+					json,
+					",\"healthy\":{},\"draining\":{},\"in_flight\":{}}}",
+					healthy,
+					balancer.drain.is_draining(index),
+					balancer.drain.in_flight(index),
+				);
+			}
+			json.push(']');
+		}
+		json.push('}');
+		json
+	}
+
+	fn maintenance_json(&self) -> String {
+		let mut json = String::from("{");
+		for (i, (name, switch)) in self.switches.iter().enumerate() {
+			if i > 0 {
+				json.push(',');
+			}
+			push_json_string(&mut json, name);
+			let _ = write!(json, ":{}", switch.is_on());
+		}
+		json.push('}');
+		json
+	}
+
+	fn prime_json(priming: &Priming) -> String {
+		let progress = priming.primer.progress();
+		let mut json = format!(
+			"{{\"running\":{},\"total\":{},\"succeeded\":{},\"failed\":{},\"failures\":[",
+			priming.running.load(Ordering::SeqCst),
+			progress.total,
+			progress.succeeded,
+			progress.failed,
+		);
+		if let Some(report) = &*priming.report.lock().unwrap() {
+			for (i, (method, uri, reason)) in report.failed.iter().enumerate() {
+				if i > 0 {
+					json.push(',');
+				}
+				json.push_str("{\"method\":");
+				push_json_string(&mut json, method.as_str());
+				json.push_str(",\"uri\":");
+				push_json_string(&mut json, &uri.to_string());
+				json.push_str(",\"reason\":");
+				push_json_string(&mut json, reason);
+				json.push('}');
+			}
+		}
+		json.push_str("]}");
+		json
+	}
+
+	// Find a primer by its name
+	fn priming(&self, name: &str) -> Option<&Priming> {
+		let name = percent_decode(name)?;
+		self.primers.iter().find(|p| p.name == name)
+	}
+
+	// Find a maintenance switch by its name
+	fn switch(&self, name: &str) -> Option<&MaintenanceSwitch> {
+		let name = percent_decode(name)?;
+		self.switches
+			.iter()
+			.find(|(n, _)| *n == name)
+			.map(|(_, switch)| switch)
+	}
+
+	// Find an upstream by the name of its balancer and its index
+	fn upstream(&self, name: &str, index: &str) -> Option<(DrainHandle, usize)> {
+		let name = percent_decode(name)?;
+		let balancer = self.balancers.iter().find(|b| b.name == name)?;
+		let index = index.parse().ok().filter(|&index| index < balancer.len)?;
+		Some((balancer.drain.clone(), index))
+	}
+}
+
+fn config_json<T: RequestHandler>(config: &ProxyConfig<T>) -> String {
+	let mut json = String::from("{\"listen_on\":[");
+	let listen_on = std::iter::once(&config.listen_on).chain(&config.additional_listen_on);
+	for (i, addr) in listen_on.enumerate() {
+		if i > 0 {
+			json.push(',');
+		}
+		push_json_string(&mut json, &addr.to_string());
+	}
+	let http2 = &config.http2;
+	let _ = write!(
+		json,
+		"],\"http2\":{{\"enabled\":{},\"h2c\":{},\"max_concurrent_streams\":{},\
+		 \"keep_alive_interval_ms\":{},\"keep_alive_timeout_ms\":{}}}",
+		http2.enabled,
+		http2.h2c,
+		http2.max_concurrent_streams,
+		http2
+			.keep_alive_interval
+			.map_or("null".to_string(), |interval| interval
+				.as_millis()
+				.to_string()),
+		http2.keep_alive_timeout.as_millis(),
+	);
+	let _ = write!(
+		json,
+		",\"listener\":{{\"backlog\":{},\"reuse_address\":{}}},\"proxy_protocol\":{}",
+		config.listener.backlog,
+		config.listener.reuse_address,
+		config.proxy_protocol.is_some(),
+	);
+	#[cfg(feature = "tls")]
+	let _ = write!(json, ",\"tls\":{}", config.tls.is_some());
+	#[cfg(target_os = "linux")]
+	{
+		json.push_str(",\"transparent\":");
+		match config.transparent {
+			Some(mode) => push_json_string(&mut json, &format!("{:?}", mode)),
+			None => json.push_str("null"),
+		}
+		let _ = write!(json, ",\"acceptors\":{}", config.acceptors);
+	}
+	json.push('}');
+	json
+}
+
+fn respond(status: StatusCode, content_type: &str, body: impl Into<Body>) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, content_type)
+		.header(CACHE_CONTROL, "no-store")
+		.body(body.into())
+		.unwrap()
+}
+
+fn json(body: String) -> Response<Body> {
+	respond(StatusCode::OK, "application/json", body)
+}
+
+fn text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+	respond(status, "text/plain; charset=utf-8", body)
+}
+
+const INDEX: &str = "GET /config\n\
+	GET /stats\n\
+	GET /upstreams\n\
+	POST /upstreams/{balancer}/{index}/drain\n\
+	POST /upstreams/{balancer}/{index}/undrain\n\
+	GET /maintenance\n\
+	POST /maintenance/{switch}/on\n\
+	POST /maintenance/{switch}/off\n\
+	GET /prime/{primer}\n\
+	POST /prime/{primer}\n\
+	POST /reload\n";
+
+type AdminFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+impl RequestHandler for Admin {
+	type Error = Infallible;
+	type Output = AdminFuture;
+
+	fn handle(
+		&self,
+		_from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let segments = request
+			.uri()
+			.path()
+			.trim_matches('/')
+			.split('/')
+			.collect::<Vec<_>>();
+		let response = match (request.method(), segments.as_slice()) {
+			(&Method::GET, [""]) => text(StatusCode::OK, INDEX),
+			(&Method::GET, ["config"]) => json(self.config.clone()),
+			(&Method::GET, ["stats"]) => json(self.stats_json()),
+			(&Method::GET, ["upstreams"]) => json(self.upstreams_json()),
+			(&Method::POST, ["upstreams", name, index, "drain"]) => {
+				let (drain, index) = match self.upstream(name, index) {
+					Some(upstream) => upstream,
+					None => return Box::pin(async { Ok(not_found()) }),
+				};
+				return Box::pin(async move {
+					drain.drain(index).await;
+					Ok(text(StatusCode::OK, "drained\n"))
+				});
+			}
+			(&Method::POST, ["upstreams", name, index, "undrain"]) => {
+				match self.upstream(name, index) {
+					Some((drain, index)) => {
+						drain.undrain(index);
+						text(StatusCode::OK, "undrained\n")
+					}
+					None => not_found(),
+				}
+			}
+			(&Method::GET, ["maintenance"]) => json(self.maintenance_json()),
+			(&Method::POST, ["maintenance", name, state @ ("on" | "off")]) => {
+				match self.switch(name) {
+					Some(switch) => {
+						switch.set(*state == "on");
+						text(StatusCode::OK, format!("maintenance {}\n", state))
+					}
+					None => not_found(),
+				}
+			}
+			(&Method::GET, ["prime", name]) => match self.priming(name) {
+				Some(priming) => json(Self::prime_json(priming)),
+				None => not_found(),
+			},
+			(&Method::POST, ["prime", name]) => {
+				let priming = match self.priming(name) {
+					Some(priming) => priming,
+					None => return Box::pin(async { Ok(not_found()) }),
+				};
+				let run = priming.run.clone();
+				let running = priming.running.clone();
+				let report = priming.report.clone();
+				let client = client.clone();
+				return Box::pin(async move {
+					let requests = match buffer(request.into_body(), MAX_PRIME_LIST).await {
+						Ok(Buffered::Complete(body)) => body,
+						Ok(Buffered::Partial(_)) => {
+							return Ok(text(StatusCode::PAYLOAD_TOO_LARGE, "too many requests\n"))
+						}
+						Err(error) => {
+							return Ok(text(
+								StatusCode::BAD_REQUEST,
+								format!("failed to read body: {}\n", error),
+							))
+						}
+					};
+					let requests = match parse_prime_list(&requests) {
+						Ok(requests) => requests,
+						Err(line) => {
+							return Ok(text(
+								StatusCode::BAD_REQUEST,
+								format!("invalid request: {}\n", line),
+							))
+						}
+					};
+					if running.swap(true, Ordering::SeqCst) {
+						return Ok(text(StatusCode::CONFLICT, "already priming\n"));
+					}
+					let count = requests.len();
+					tokio::spawn(async move {
+						let result = run(requests, client).await;
+						*report.lock().unwrap() = Some(result);
+						running.store(false, Ordering::SeqCst);
+					});
+					Ok(text(
+						StatusCode::ACCEPTED,
+						format!("priming {} requests\n", count),
+					))
+				});
+			}
+			(&Method::POST, ["reload"]) => match &self.reload {
+				Some(reload) => match reload() {
+					Ok(()) => text(StatusCode::OK, "reloaded\n"),
+					Err(error) => text(
+						StatusCode::INTERNAL_SERVER_ERROR,
+						format!("reloading failed: {}\n", error),
+					),
+				},
+				None => text(StatusCode::NOT_IMPLEMENTED, "reloading isn't supported\n"),
+			},
+			(_, [""])
+			| (_, ["config"])
+			| (_, ["stats"])
+			| (_, ["upstreams"])
+			| (_, ["maintenance"])
+			| (_, ["prime", _])
+			| (_, ["reload"]) => text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n"),
+			_ => not_found(),
+		};
+		Box::pin(async { Ok(response) })
+	}
+}
+
+// The maximum size of the request list of a primer
+const MAX_PRIME_LIST: usize = 4 << 20;
+
+// Parse a list of requests, one per line, as `[METHOD ]URI`, or give back the invalid line
+fn parse_prime_list(list: &[u8]) -> Result<Vec<Request<Body>>, String> {
+	let list = String::from_utf8_lossy(list);
+	list.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(|line| {
+			let (method, uri) = match line.split_once(char::is_whitespace) {
+				Some((method, uri)) => (method.parse().ok(), uri.trim()),
+				None => (Some(Method::GET), line),
+			};
+			let uri = uri
+				.parse::<hyper::Uri>()
+				.ok()
+				.filter(|uri| uri.scheme().is_some() || uri.to_string().starts_with('/'));
+			match (method, uri) {
+				(Some(method), Some(uri)) => Ok(Request::builder()
+					.method(method)
+					.uri(uri)
+					.body(Body::empty())
+					.unwrap()),
+				_ => Err(line.to_string()),
+			}
+		})
+		.collect()
+}
+
+fn not_found() -> Response<Body> {
+	text(StatusCode::NOT_FOUND, "not found\n")
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request};
+
+use crate::connect::{Connector, UpstreamConfig};
+use crate::RequestHandler;
+
+struct Pinned {
+	client: Client<Connector>,
+	last_used: Instant,
+}
+
+#[derive(Default)]
+struct Clients {
+	map: HashMap<SocketAddr, Pinned>,
+	next_purge: Option<Instant>,
+}
+
+/// A handle to release the upstream connections of closed client connections early
+///
+/// Pass [`release`](Self::release) to
+/// [`ProxyConfig::with_on_disconnect`](crate::ProxyConfig::with_on_disconnect)
+/// so upstream connections are closed together with their client connection.
+#[derive(Clone)]
+pub struct AffinityHandle {
+	clients: Arc<Mutex<Clients>>,
+}
+
+impl AffinityHandle {
+	/// Drop the upstream connections pinned to the client connection from `peer_addr`
+	pub fn release(&self, peer_addr: SocketAddr) {
+		self.clients.lock().unwrap().map.remove(&peer_addr);
+	}
+
+	/// Get the number of client connections with pinned upstream connections
+	pub fn pinned(&self) -> usize {
+		self.clients.lock().unwrap().map.len()
+	}
+}
+
+/// A request handler combinator that pins all requests of a client connection to the same
+/// upstream connection
+///
+/// Every client connection gets its own client, so upstream connections are never shared
+/// between clients. This makes connection-oriented authentication schemes like NTLM and
+/// Negotiate work through the proxy, at the cost of connection reuse across clients.
+/// Clients are dropped after being unused for [`idle_timeout`](Self::idle_timeout),
+/// or earlier via an [`AffinityHandle`].
+pub struct ConnectionAffinity<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The config for the pinned upstream connections
+	pub config: UpstreamConfig,
+	/// How long the upstream connections of a client connection are kept without any requests
+	pub idle_timeout: Duration,
+	clients: Arc<Mutex<Clients>>,
+}
+
+impl<H: RequestHandler> ConnectionAffinity<H> {
+	/// Create a [`ConnectionAffinity`] that keeps upstream connections for 90 seconds
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner,
+			config: UpstreamConfig {
+				keep_alive: true,
+				max_idle_per_host: 1,
+				..UpstreamConfig::default()
+			},
+			idle_timeout: Duration::from_secs(90),
+			clients: Arc::default(),
+		}
+	}
+
+	/// Obtain a handle to release pinned upstream connections
+	pub fn affinity_handle(&self) -> AffinityHandle {
+		AffinityHandle {
+			clients: self.clients.clone(),
+		}
+	}
+
+	fn client_for(&self, from_addr: SocketAddr) -> Client<Connector> {
+		let now = Instant::now();
+		let mut clients = self.clients.lock().unwrap();
+
+		if clients.next_purge.is_none_or(|at| at <= now) {
+			let idle_timeout = self.idle_timeout;
+			clients
+				.map
+				.retain(|_, pinned| now.duration_since(pinned.last_used) < idle_timeout);
+			clients.next_purge = Some(now + Duration::from_secs(60).min(idle_timeout));
+		}
+
+		let config = &self.config;
+		let pinned = clients.map.entry(from_addr).or_insert_with(|| Pinned {
+			client: config.build_client(),
+			last_used: now,
+		});
+		pinned.last_used = now;
+		pinned.client.clone()
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for ConnectionAffinity<H> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		_client: &Client<Connector>,
+	) -> Self::Output {
+		let client = self.client_for(from_addr);
+		self.inner.handle(from_addr, request, &client)
+	}
+}
@@ -0,0 +1,273 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, TE, UPGRADE};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use thiserror::Error;
+use tokio::time::timeout;
+
+use crate::body::read_limited;
+use crate::connect::Connector;
+use crate::handlers::redirect::remove_hop_by_hop_headers;
+use crate::RequestHandler;
+
+/// One of the upstream requests an [`Aggregate`] fans out to
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Target {
+	/// The name of the target, used to label its part of the combined response
+	pub name: String,
+	/// The URI template of the target
+	///
+	/// The placeholders `{path}` and `{query}` are replaced with the path and query
+	/// of the incoming request, e.g. `http://users.internal{path}?{query}`.
+	pub template: String,
+}
+
+impl Target {
+	/// Create a target
+	pub fn new(name: impl Into<String>, template: impl Into<String>) -> Self {
+		Self {
+			name: name.into(),
+			template: template.into(),
+		}
+	}
+
+	/// Expand the template for the given request URI
+	pub fn expand(&self, uri: &Uri) -> Option<Uri> {
+		self.template
+			.replace("{path}", uri.path())
+			.replace("{query}", uri.query().unwrap_or(""))
+			.parse()
+			.ok()
+	}
+}
+
+/// Why a part of an aggregated response is missing
+#[derive(Debug, Error)]
+pub enum PartError {
+	#[error("the target URI is invalid")]
+	/// The template didn't expand to a valid URI
+	InvalidUri,
+	#[error("the deadline passed before the response arrived")]
+	/// The deadline passed
+	Timeout,
+	#[error("the response body is too large")]
+	/// The response body is larger than the maximum body size
+	TooLarge,
+	#[error("{0}")]
+	/// The upstream request failed
+	Upstream(#[from] hyper::Error),
+}
+
+/// A successful upstream response
+#[derive(Debug, Clone)]
+pub struct PartResponse {
+	/// The status
+	pub status: StatusCode,
+	/// The headers
+	pub headers: HeaderMap,
+	/// The whole body
+	pub body: Bytes,
+}
+
+/// The outcome of one upstream request of an [`Aggregate`]
+#[derive(Debug)]
+pub struct Part {
+	/// The name of the [`Target`]
+	pub name: String,
+	/// The response, or why there is none
+	pub result: Result<PartResponse, PartError>,
+}
+
+/// The exchangable part of an [`Aggregate`] that combines the upstream responses
+pub trait Compose {
+	/// Build the response to the client from the parts, which are in the order of the targets
+	fn compose(&self, parts: Vec<Part>) -> Response<Body>;
+}
+
+/// Obtain a [`Compose`] from a function/closure
+pub fn compose_fn<F: Fn(Vec<Part>) -> Response<Body>>(f: F) -> impl Compose {
+	struct ComposeFn<F: Fn(Vec<Part>) -> Response<Body>>(F);
+
+	impl<F: Fn(Vec<Part>) -> Response<Body>> Compose for ComposeFn<F> {
+		fn compose(&self, parts: Vec<Part>) -> Response<Body> {
+			(self.0)(parts)
+		}
+	}
+
+	ComposeFn(f)
+}
+
+/// A [`Compose`] which builds a JSON object with one field per target
+///
+/// Each field holds the parsed JSON response of the target, or `null` if the target failed,
+/// timed out or didn't respond with JSON. If `merge` is `true`, the responses are instead
+/// merged into a single object, with later targets overwriting fields of earlier ones.
+///
+/// This requires the `json` feature.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCompose {
+	/// Whether to merge the responses instead of nesting them
+	pub merge: bool,
+}
+
+#[cfg(feature = "json")]
+impl Compose for JsonCompose {
+	fn compose(&self, parts: Vec<Part>) -> Response<Body> {
+		use serde_json::{Map, Value};
+
+		let mut object = Map::new();
+		for part in parts {
+			let value = part
+				.result
+				.ok()
+				.filter(|res| res.status.is_success())
+				.and_then(|res| serde_json::from_slice::<Value>(&res.body).ok())
+				.unwrap_or(Value::Null);
+
+			match value {
+				Value::Object(fields) if self.merge => object.extend(fields),
+				_ if self.merge => {}
+				value => {
+					object.insert(part.name, value);
+				}
+			}
+		}
+
+		Response::builder()
+			.header(hyper::header::CONTENT_TYPE, "application/json")
+			.body(Body::from(Value::Object(object).to_string()))
+			.unwrap()
+	}
+}
+
+/// A request handler that fans a request out to multiple upstreams and combines their responses
+///
+/// All targets get the method, headers and body of the incoming request, without its
+/// hop-by-hop headers (see [`remove_hop_by_hop_headers`]); as the bodies are read whole, upgrades
+/// and trailers are not passed on either. Requests with a body larger than
+/// [`max_body_size`](Self::max_body_size) are answered with a `413 Payload Too Large`. Targets
+/// that don't respond before the deadline are given to the [`Compose`] as [`PartError::Timeout`].
+pub struct Aggregate<C: Compose> {
+	/// The targets to send requests to
+	pub targets: Arc<Vec<Target>>,
+	/// The [`Compose`] combining the responses
+	pub compose: Arc<C>,
+	/// How long to wait for the upstream responses
+	pub deadline: Duration,
+	/// The maximum size of the request body and each response body in bytes
+	pub max_body_size: usize,
+}
+
+impl<C: Compose> Aggregate<C> {
+	/// Create an [`Aggregate`] with a deadline of 5 seconds and a maximum body size of 1 MiB
+	pub fn new(targets: Vec<Target>, compose: C) -> Self {
+		Self {
+			targets: Arc::new(targets),
+			compose: Arc::new(compose),
+			deadline: Duration::from_secs(5),
+			max_body_size: 1 << 20,
+		}
+	}
+}
+
+/// The error type for `<`[`Aggregate`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum AggregateError {
+	#[error("failed to read request body: {0}")]
+	/// The request body could not be read
+	Body(hyper::Error),
+}
+
+fn payload_too_large() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::PAYLOAD_TOO_LARGE)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("The request body is too large.\n"))
+		.unwrap()
+}
+
+async fn fetch(
+	client: Client<Connector>,
+	request: Request<Body>,
+	max_body_size: usize,
+) -> Result<PartResponse, PartError> {
+	let (parts, body) = client.request(request).await?.into_parts();
+	let body = read_limited(body, max_body_size)
+		.await?
+		.ok_or(PartError::TooLarge)?;
+	Ok(PartResponse {
+		status: parts.status,
+		headers: parts.headers,
+		body,
+	})
+}
+
+impl<C: Compose + Send + Sync + 'static> RequestHandler for Aggregate<C> {
+	type Error = AggregateError;
+	type Output = Pin<Box<dyn Future<Output = Result<Response<Body>, AggregateError>> + Send>>;
+
+	fn handle(
+		&self,
+		_from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let targets = self.targets.clone();
+		let compose = self.compose.clone();
+		let deadline = self.deadline;
+		let max_body_size = self.max_body_size;
+		let client = client.clone();
+
+		Box::pin(async move {
+			let (mut parts, body) = request.into_parts();
+			let body = match read_limited(body, max_body_size)
+				.await
+				.map_err(AggregateError::Body)?
+			{
+				Some(body) => body,
+				None => return Ok(payload_too_large()),
+			};
+			remove_hop_by_hop_headers(&mut parts.headers);
+			for name in [CONNECTION, UPGRADE, TE] {
+				parts.headers.remove(name);
+			}
+			parts.headers.remove(HOST);
+			parts.headers.remove(CONTENT_LENGTH);
+
+			let requests = targets.iter().map(|target| {
+				let uri = target.expand(&parts.uri);
+				let mut request = Request::new(Body::from(body.clone()));
+				*request.method_mut() = parts.method.clone();
+				*request.headers_mut() = parts.headers.clone();
+				let client = client.clone();
+
+				async move {
+					let result = match uri {
+						Some(uri) => {
+							*request.uri_mut() = uri;
+							match timeout(deadline, fetch(client, request, max_body_size)).await {
+								Ok(result) => result,
+								Err(_) => Err(PartError::Timeout),
+							}
+						}
+						None => Err(PartError::InvalidUri),
+					};
+					Part {
+						name: target.name.clone(),
+						result,
+					}
+				}
+			});
+
+			let parts = join_all(requests).await;
+			Ok(compose.compose(parts))
+		})
+	}
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, ALT_SVC};
+use hyper::{Body, Response};
+
+use crate::handlers::middleware::Middleware;
+
+/// A [`Middleware`] that advertises an alternative service, like an HTTP/3 listener, in the
+/// `Alt-Svc` header of responses
+///
+/// The header replaces any `Alt-Svc` header of the upstream, whose alternative services are
+/// usually not reachable through the proxy.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use proxylib::handlers::alt_svc::AltSvc;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::RequestHandler;
+///
+/// let handler = Redirect::change_authority("backend.internal".parse().unwrap())
+///     .layer(AltSvc::h3(443, Duration::from_secs(24 * 60 * 60)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct AltSvc {
+	/// The value of the `Alt-Svc` header
+	pub value: HeaderValue,
+}
+
+impl AltSvc {
+	/// Advertise HTTP/3 on the given UDP port of the same host, for clients to remember for
+	/// `max_age`
+	pub fn h3(port: u16, max_age: Duration) -> Self {
+		let value = format!("h3=\":{}\"; ma={}", port, max_age.as_secs());
+		Self {
+			value: HeaderValue::from_str(&value).unwrap(),
+		}
+	}
+}
+
+impl Middleware for AltSvc {
+	fn after(&self, response: &mut Response<Body>) {
+		response.headers_mut().insert(ALT_SVC, self.value.clone());
+	}
+}
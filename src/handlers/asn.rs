@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use hyper::{Body, Request};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use crate::handlers::accounting::ClientKey;
+use crate::handlers::filter::FilterLogic;
+
+/// The autonomous system an address belongs to
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct AsnInfo {
+	/// The autonomous system number
+	pub number: u32,
+	/// The organization operating the autonomous system
+	pub organization: Option<String>,
+}
+
+/// A MaxMind ASN database (e.g. GeoLite2-ASN)
+pub struct AsnDatabase {
+	reader: Reader<Vec<u8>>,
+}
+
+impl AsnDatabase {
+	/// Read a database file
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, MaxMindDBError> {
+		Ok(Self {
+			reader: Reader::open_readfile(path)?,
+		})
+	}
+
+	/// Read a database from memory
+	pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, MaxMindDBError> {
+		Ok(Self {
+			reader: Reader::from_source(bytes)?,
+		})
+	}
+
+	/// Look up the autonomous system of an address
+	pub fn lookup(&self, ip: IpAddr) -> Option<AsnInfo> {
+		let asn = self.reader.lookup::<geoip2::Asn>(ip).ok()?;
+		Some(AsnInfo {
+			number: asn.autonomous_system_number?,
+			organization: asn.autonomous_system_organization.map(str::to_string),
+		})
+	}
+}
+
+/// A [`FilterLogic`] which looks up the autonomous system of the source address
+/// and blocks based on if it is included in a list or not
+///
+/// Addresses without a known autonomous system count as not included.
+pub struct AsnFilter {
+	/// The database to look addresses up in
+	pub db: Arc<AsnDatabase>,
+	/// The list of autonomous system numbers
+	pub list: HashSet<u32>,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all requests from any autonomous system in the list will be blocked
+	/// and all others will be let through.
+	///
+	/// If it is `false`, all requests from any autonomous system **not** in the list
+	/// will be blocked and all others will be let through.
+	pub is_blacklist: bool,
+}
+
+impl FilterLogic for AsnFilter {
+	fn filter(&self, from_addr: SocketAddr, _: &Request<Body>) -> bool {
+		let listed = self
+			.db
+			.lookup(from_addr.ip())
+			.is_some_and(|asn| self.list.contains(&asn.number));
+		self.is_blacklist != listed
+	}
+}
+
+/// A [`ClientKey`] which uses the autonomous system of the source address
+///
+/// Autonomous systems in [`groups`](Self::groups) are keyed by the name of their group
+/// (e.g. `"aws"` for all of Amazon's ASNs), all others by their number (e.g. `"AS13335"`).
+/// This can be used with [`Tenancy`](super::tenancy::Tenancy) to route by network.
+pub struct AsnKey {
+	/// The database to look addresses up in
+	pub db: Arc<AsnDatabase>,
+	/// Names for groups of autonomous systems
+	pub groups: HashMap<u32, String>,
+}
+
+impl AsnKey {
+	/// Create an [`AsnKey`] without groups
+	pub fn new(db: Arc<AsnDatabase>) -> Self {
+		Self {
+			db,
+			groups: HashMap::new(),
+		}
+	}
+
+	/// Add a group of autonomous systems
+	pub fn with_group(
+		mut self,
+		name: impl Into<String>,
+		numbers: impl IntoIterator<Item = u32>,
+	) -> Self {
+		let name = name.into();
+		for number in numbers {
+			self.groups.insert(number, name.clone());
+		}
+		self
+	}
+}
+
+impl ClientKey for AsnKey {
+	fn client_key(&self, from_addr: SocketAddr, _: &Request<Body>) -> Option<String> {
+		let asn = self.db.lookup(from_addr.ip())?;
+		Some(match self.groups.get(&asn.number) {
+			Some(group) => group.clone(),
+			None => format!("AS{}", asn.number),
+		})
+	}
+}
@@ -0,0 +1,564 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::sync::Notify;
+
+use crate::body::map_data;
+use crate::connect::Connector;
+use crate::handlers::accounting::ClientKey;
+use crate::handlers::health::UpstreamPool;
+use crate::RequestHandler;
+
+/// The exchangable part of a [`Balance`] that decides which upstream gets a request
+pub trait BalanceStrategy {
+	/// Choose the index of the upstream to give the request to, out of `upstreams` upstreams
+	fn choose(&self, from_addr: SocketAddr, request: &Request<Body>, upstreams: usize) -> usize;
+
+	/// Choose the index of the upstream to give the request to, knowing how many requests each
+	/// upstream is handling (including streaming their response bodies)
+	///
+	/// This is what [`Balance`] calls; by default, it ignores the loads and calls
+	/// [`choose`](Self::choose).
+	fn choose_by_load(
+		&self,
+		from_addr: SocketAddr,
+		request: &Request<Body>,
+		in_flight: &[usize],
+	) -> usize {
+		self.choose(from_addr, request, in_flight.len())
+	}
+
+	/// Record that an upstream responded after the given time, successfully or not
+	fn responded(&self, _upstream: usize, _latency: Duration, _success: bool) {}
+
+	/// Return whether the client of the request is pinned to the upstream the strategy chooses
+	/// for it, which a [`Balance`] with [`with_sticky_draining`](Balance::with_sticky_draining)
+	/// keeps giving its requests to while the upstream is draining
+	///
+	/// By default, no client is.
+	fn is_sticky(&self, _from_addr: SocketAddr, _request: &Request<Body>) -> bool {
+		false
+	}
+}
+
+/// A [`BalanceStrategy`] that gives requests to the upstreams in turn
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+	next: AtomicUsize,
+}
+
+impl BalanceStrategy for RoundRobin {
+	fn choose(&self, _: SocketAddr, _: &Request<Body>, upstreams: usize) -> usize {
+		self.next.fetch_add(1, Ordering::Relaxed) % upstreams
+	}
+}
+
+/// A [`BalanceStrategy`] that gives requests to the upstream handling the fewest requests
+///
+/// Ties are broken round-robin. Used outside of [`Balance`] (via
+/// [`choose`](BalanceStrategy::choose)), where the loads are unknown, it is just round-robin.
+#[derive(Debug, Default)]
+pub struct LeastConnections {
+	next: AtomicUsize,
+}
+
+impl BalanceStrategy for LeastConnections {
+	fn choose(&self, _: SocketAddr, _: &Request<Body>, upstreams: usize) -> usize {
+		self.next.fetch_add(1, Ordering::Relaxed) % upstreams
+	}
+
+	fn choose_by_load(&self, _: SocketAddr, _: &Request<Body>, in_flight: &[usize]) -> usize {
+		let least = in_flight.iter().copied().min().unwrap_or(0);
+		let start = self.next.fetch_add(1, Ordering::Relaxed) % in_flight.len();
+		(start..in_flight.len())
+			.chain(0..start)
+			.find(|&i| in_flight[i] == least)
+			.unwrap_or(0)
+	}
+}
+
+/// A [`BalanceStrategy`] that gives all requests of a client to the same upstream, e.g. for
+/// backends keeping sessions in memory
+///
+/// Clients are told apart by a [`ClientKey`] (like [`IpKey`](super::accounting::IpKey),
+/// [`HeaderKey`](super::accounting::HeaderKey) or [`CookieKey`](super::sticky::CookieKey)).
+/// Keys are mapped to upstreams with rendezvous hashing over the [`ids`](Self::ids) of the
+/// upstreams (like their URIs), so when an upstream is added or removed, only the clients of that
+/// upstream move, wherever it is in the list. The mapping only depends on the key and the ids, so
+/// multiple proxy instances agree on it.
+/// Requests without a key are given to the upstreams in turn.
+#[derive(Debug, Default)]
+pub struct ConsistentHash<K: ClientKey> {
+	/// The [`ClientKey`] telling clients apart
+	pub key: K,
+	/// The ids of the upstreams, in order; upstreams without one are identified by their index
+	pub ids: Vec<String>,
+	fallback: RoundRobin,
+}
+
+impl<K: ClientKey> ConsistentHash<K> {
+	/// Create a [`ConsistentHash`] with the given key and the ids of the upstreams, in order
+	///
+	/// # Example
+	/// ```
+	/// use proxylib::handlers::accounting::IpKey;
+	/// use proxylib::handlers::balance::{Balance, ConsistentHash};
+	/// use proxylib::handlers::Redirect;
+	///
+	/// let upstreams = ["app-1.internal:8080", "app-2.internal:8080"];
+	/// let balance = Balance::with_strategy(
+	///     upstreams
+	///         .iter()
+	///         .map(|upstream| Redirect::change_authority(upstream.parse().unwrap()))
+	///         .collect(),
+	///     ConsistentHash::new(IpKey, upstreams.iter().map(|s| s.to_string()).collect()),
+	/// );
+	/// ```
+	pub fn new(key: K, ids: Vec<String>) -> Self {
+		Self {
+			key,
+			ids,
+			fallback: RoundRobin::default(),
+		}
+	}
+}
+
+// FNV-1a, which (unlike `DefaultHasher`) is stable across builds and instances
+fn fnv1a(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+		(hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+	})
+}
+
+// The finalizer of splitmix64, to mix the upstream id into the hash of the key
+fn mix(mut x: u64) -> u64 {
+	x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	x ^ (x >> 31)
+}
+
+impl<K: ClientKey> BalanceStrategy for ConsistentHash<K> {
+	fn choose(&self, from_addr: SocketAddr, request: &Request<Body>, upstreams: usize) -> usize {
+		let key = match self.key.client_key(from_addr, request) {
+			Some(key) => fnv1a(key.as_bytes()),
+			None => return self.fallback.choose(from_addr, request, upstreams),
+		};
+		let id = |i: usize| match self.ids.get(i) {
+			Some(id) => fnv1a(id.as_bytes()),
+			None => mix(i as u64 + 1),
+		};
+		(0..upstreams)
+			.max_by_key(|&i| mix(key ^ id(i)))
+			.unwrap_or(0)
+	}
+
+	fn is_sticky(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool {
+		self.key.client_key(from_addr, request).is_some()
+	}
+}
+
+/// A [`BalanceStrategy`] that gives each upstream a share of the requests proportional to its
+/// weight, e.g. 90% to a big node with weight `9` and 10% to a small one with weight `1`
+///
+/// The requests are spread evenly (smooth weighted round-robin), so an upstream with weight `2`
+/// out of `3` gets two out of every three requests rather than bursts of them.
+/// Upstreams without a weight have a weight of `1`, and upstreams with a weight of `0`
+/// only get requests if all weights are `0`.
+#[derive(Debug)]
+pub struct Weighted {
+	weights: Vec<u32>,
+	current: Mutex<Vec<i64>>,
+}
+
+impl Weighted {
+	/// Create a [`Weighted`] with the weights of the upstreams, in order
+	pub fn new(weights: Vec<u32>) -> Self {
+		Self {
+			current: Mutex::new(vec![0; weights.len()]),
+			weights,
+		}
+	}
+
+	/// Get the weights of the upstreams
+	pub fn weights(&self) -> &[u32] {
+		&self.weights
+	}
+}
+
+impl BalanceStrategy for Weighted {
+	fn choose(&self, _: SocketAddr, _: &Request<Body>, upstreams: usize) -> usize {
+		let weight = |i: usize| i64::from(self.weights.get(i).copied().unwrap_or(1));
+		let mut current = self.current.lock().unwrap();
+		current.resize(upstreams, 0);
+
+		let total = (0..upstreams).map(weight).sum::<i64>();
+		if total == 0 {
+			return 0;
+		}
+		for (i, current) in current.iter_mut().enumerate() {
+			*current += weight(i);
+		}
+		let chosen = (0..upstreams)
+			.filter(|&i| weight(i) > 0)
+			.max_by_key(|&i| (current[i], std::cmp::Reverse(i)))
+			.unwrap_or(0);
+		current[chosen] -= total;
+		chosen
+	}
+}
+
+// A xorshift generator; good enough for spreading requests, and avoids a dependency
+#[derive(Debug)]
+pub(crate) struct Random(AtomicU64);
+
+impl Random {
+	pub(crate) fn new() -> Self {
+		let seed = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_nanos() as u64);
+		Self(AtomicU64::new(seed | 1))
+	}
+
+	// A number in `0.0..1.0`
+	pub(crate) fn next(&self) -> f64 {
+		let mut x = self.0.load(Ordering::Relaxed);
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		self.0.store(x, Ordering::Relaxed);
+		(x >> 11) as f64 / (1u64 << 53) as f64
+	}
+}
+
+/// A [`BalanceStrategy`] that routes proportionally to the inverse of each upstream's latency
+///
+/// The latency is tracked as an exponentially weighted moving average that decays with
+/// [`decay`](Self::decay), so the strategy adapts when a backend slows down or recovers.
+/// Failed requests count as taking [`failure_latency`](Self::failure_latency).
+/// Upstreams without any measurements yet are assumed to be as fast as the average.
+#[derive(Debug)]
+pub struct Ewma {
+	/// The time constant of the moving average; measurements this old weigh `1/e` as much
+	pub decay: Duration,
+	/// The latency recorded for failed requests
+	pub failure_latency: Duration,
+	latencies: Mutex<Vec<Option<(f64, Instant)>>>,
+	random: Random,
+}
+
+impl Default for Ewma {
+	fn default() -> Self {
+		Self {
+			decay: Duration::from_secs(10),
+			failure_latency: Duration::from_secs(5),
+			latencies: Mutex::new(Vec::new()),
+			random: Random::new(),
+		}
+	}
+}
+
+impl Ewma {
+	/// Get the current latency estimates of the upstreams (`None` if not measured yet)
+	pub fn latencies(&self) -> Vec<Option<Duration>> {
+		self.latencies
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|l| l.map(|(secs, _)| Duration::from_secs_f64(secs)))
+			.collect()
+	}
+}
+
+impl BalanceStrategy for Ewma {
+	fn choose(&self, _: SocketAddr, _: &Request<Body>, upstreams: usize) -> usize {
+		let mut latencies = self.latencies.lock().unwrap();
+		latencies.resize(upstreams, None);
+
+		let known = latencies.iter().flatten().map(|&(secs, _)| secs);
+		let (sum, count) = known.fold((0.0, 0), |(sum, count), secs| (sum + secs, count + 1));
+		let average = if count == 0 { 1.0 } else { sum / count as f64 };
+
+		let weights = latencies
+			.iter()
+			.map(|l| 1.0 / l.map_or(average, |(secs, _)| secs).max(1e-6))
+			.collect::<Vec<_>>();
+		let mut pick = self.random.next() * weights.iter().sum::<f64>();
+		for (i, weight) in weights.iter().enumerate() {
+			if pick < *weight {
+				return i;
+			}
+			pick -= weight;
+		}
+		upstreams - 1
+	}
+
+	fn responded(&self, upstream: usize, latency: Duration, success: bool) {
+		let latency = if success {
+			latency
+		} else {
+			latency.max(self.failure_latency)
+		};
+		let now = Instant::now();
+
+		let mut latencies = self.latencies.lock().unwrap();
+		if latencies.len() <= upstream {
+			latencies.resize(upstream + 1, None);
+		}
+		let entry = &mut latencies[upstream];
+		*entry = Some(match *entry {
+			Some((average, updated)) => {
+				let elapsed = now.duration_since(updated).as_secs_f64();
+				let weight = (-elapsed / self.decay.as_secs_f64().max(1e-9)).exp();
+				(
+					average * weight + latency.as_secs_f64() * (1.0 - weight),
+					now,
+				)
+			}
+			None => (latency.as_secs_f64(), now),
+		});
+	}
+}
+
+#[derive(Debug, Default)]
+struct UpstreamState {
+	draining: AtomicBool,
+	in_flight: AtomicUsize,
+	idle: Notify,
+}
+
+// Counts a request as in flight until it is dropped
+pub(crate) struct InFlight {
+	states: Arc<Vec<UpstreamState>>,
+	index: usize,
+}
+
+impl InFlight {
+	fn new(states: &Arc<Vec<UpstreamState>>, index: usize) -> Self {
+		states[index].in_flight.fetch_add(1, Ordering::SeqCst);
+		Self {
+			states: states.clone(),
+			index,
+		}
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		let state = &self.states[self.index];
+		if state.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+			state.idle.notify_waiters();
+		}
+	}
+}
+
+/// A handle to take upstreams of a [`Balance`] or [`Sticky`](super::sticky::Sticky) out of
+/// rotation for deploys
+#[derive(Clone)]
+pub struct DrainHandle {
+	states: Arc<Vec<UpstreamState>>,
+}
+
+impl DrainHandle {
+	pub(crate) fn new(upstreams: usize) -> Self {
+		Self {
+			states: Arc::new((0..upstreams).map(|_| UpstreamState::default()).collect()),
+		}
+	}
+
+	// Count a request as in flight at the upstream until the guard is dropped
+	pub(crate) fn start(&self, upstream: usize) -> InFlight {
+		InFlight::new(&self.states, upstream)
+	}
+
+	/// Stop giving new requests to the upstream and wait until its in-flight requests
+	/// (including streaming their response bodies) have finished
+	///
+	/// The upstream stays out of rotation until [`undrain`](Self::undrain) is called. Sticky
+	/// clients may keep using it if that is configured (see
+	/// [`Balance::with_sticky_draining`] and
+	/// [`Sticky::keep_sessions_while_draining`](super::sticky::Sticky::keep_sessions_while_draining)),
+	/// in which case this only returns once none of their requests are in flight.
+	pub async fn drain(&self, upstream: usize) {
+		let state = &self.states[upstream];
+		state.draining.store(true, Ordering::SeqCst);
+		loop {
+			let idle = state.idle.notified();
+			if state.in_flight.load(Ordering::SeqCst) == 0 {
+				break;
+			}
+			idle.await;
+		}
+	}
+
+	/// Put a drained upstream back into rotation
+	pub fn undrain(&self, upstream: usize) {
+		self.states[upstream]
+			.draining
+			.store(false, Ordering::SeqCst);
+	}
+
+	/// Return whether the upstream is draining or drained
+	pub fn is_draining(&self, upstream: usize) -> bool {
+		self.states[upstream].draining.load(Ordering::SeqCst)
+	}
+
+	/// Get the number of requests the upstream is handling
+	pub fn in_flight(&self, upstream: usize) -> usize {
+		self.states[upstream].in_flight.load(Ordering::SeqCst)
+	}
+}
+
+/// A request handler that distributes requests over multiple upstream request handlers
+///
+/// Which upstream gets a request is decided by a [`BalanceStrategy`], which is told how many
+/// requests each upstream is handling and how long each upstream took to respond.
+/// If the chosen upstream is draining (see [`drain_handle`](Self::drain_handle)) or unhealthy
+/// (see [`with_health`](Self::with_health)), the request goes to the next one that isn't. If all
+/// of them are, the client is answered with `503 Service Unavailable`. Clients the strategy pins
+/// to an upstream (see [`BalanceStrategy::is_sticky`]) can keep using it while it is draining,
+/// see [`with_sticky_draining`](Self::with_sticky_draining).
+pub struct Balance<H: RequestHandler, S: BalanceStrategy = RoundRobin> {
+	/// The upstream request handlers
+	upstreams: Vec<H>,
+	/// The [`BalanceStrategy`] choosing the upstreams
+	pub strategy: Arc<S>,
+	/// The health checks of the upstreams, if any
+	pub health: Option<UpstreamPool>,
+	/// Whether sticky clients keep using a draining upstream, see
+	/// [`with_sticky_draining`](Self::with_sticky_draining)
+	pub sticky_draining: bool,
+	drain: DrainHandle,
+}
+
+impl<H: RequestHandler> Balance<H> {
+	/// Create a round-robin [`Balance`]
+	///
+	/// # Panics
+	/// Panics if `upstreams` is empty.
+	pub fn new(upstreams: Vec<H>) -> Self {
+		Self::with_strategy(upstreams, RoundRobin::default())
+	}
+}
+
+impl<H: RequestHandler, S: BalanceStrategy> Balance<H, S> {
+	/// Create a [`Balance`] with the given strategy
+	///
+	/// # Panics
+	/// Panics if `upstreams` is empty.
+	pub fn with_strategy(upstreams: Vec<H>, strategy: S) -> Self {
+		assert!(!upstreams.is_empty(), "Balance needs at least one upstream");
+		Self {
+			drain: DrainHandle::new(upstreams.len()),
+			upstreams,
+			strategy: Arc::new(strategy),
+			health: None,
+			sticky_draining: false,
+		}
+	}
+
+	/// Keep giving the requests of sticky clients (see [`BalanceStrategy::is_sticky`]) to their
+	/// upstream while it is draining, until it is undrained or removed, e.g. so their sessions
+	/// in the memory of the upstream don't get lost
+	///
+	/// Only new clients are then kept away from a draining upstream.
+	pub fn with_sticky_draining(mut self) -> Self {
+		self.sticky_draining = true;
+		self
+	}
+
+	/// Only give requests to the upstreams the [`UpstreamPool`] considers healthy
+	///
+	/// The upstreams of the pool must be in the same order as those of the [`Balance`].
+	pub fn with_health(mut self, pool: UpstreamPool) -> Self {
+		self.health = Some(pool);
+		self
+	}
+
+	/// Get the upstream request handlers
+	pub fn upstreams(&self) -> &[H] {
+		&self.upstreams
+	}
+
+	/// Obtain a handle to drain upstreams
+	pub fn drain_handle(&self) -> DrainHandle {
+		self.drain.clone()
+	}
+}
+
+type BalanceFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+fn no_upstream() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::SERVICE_UNAVAILABLE)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("No upstream is available.\n"))
+		.unwrap()
+}
+
+impl<H, S> RequestHandler for Balance<H, S>
+where
+	H: RequestHandler,
+	S: BalanceStrategy + Send + Sync + 'static,
+{
+	type Error = H::Error;
+	type Output = BalanceFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let len = self.upstreams.len();
+		let in_flight = (0..len)
+			.map(|i| self.drain.in_flight(i))
+			.collect::<Vec<_>>();
+		let chosen = self
+			.strategy
+			.choose_by_load(from_addr, &request, &in_flight)
+			.min(len - 1);
+		let sticky = self.sticky_draining
+			&& self.drain.is_draining(chosen)
+			&& self.strategy.is_sticky(from_addr, &request);
+		let index = match (chosen..len).chain(0..chosen).find(|&i| {
+			(!self.drain.is_draining(i) || (sticky && i == chosen))
+				&& self.health.as_ref().is_none_or(|pool| pool.is_healthy(i))
+		}) {
+			Some(index) => index,
+			None => return Box::pin(async { Ok(no_upstream()) }),
+		};
+
+		let in_flight = self.drain.start(index);
+		let fut = self.upstreams[index].handle(from_addr, request, client);
+		let strategy = self.strategy.clone();
+		let start = Instant::now();
+
+		Box::pin(async move {
+			let res = fut.await;
+			let success = res
+				.as_ref()
+				.is_ok_and(|response| !response.status().is_server_error());
+			strategy.responded(index, start.elapsed(), success);
+
+			let (parts, body) = res?.into_parts();
+			if body.size_hint().exact() == Some(0) {
+				return Ok(Response::from_parts(parts, body));
+			}
+			// The request is in flight until its response body is dropped
+			let body = map_data(body, move |data| {
+				data.inspect(move |_| {
+					let _ = &in_flight;
+				})
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
@@ -0,0 +1,189 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Client, Request, Response};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+use crate::body::with_trailers;
+use crate::connect::Connector;
+use crate::metrics::CounterFamily;
+use crate::RequestHandler;
+
+/// How a [`ResponseBuffering`] passes response bodies to the client
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BufferMode {
+	#[default]
+	/// Every chunk is read from the upstream only once the client has taken the previous one
+	Streamed,
+	/// The body is read from the upstream ahead of the client, keeping up to this many
+	/// bytes in memory, so slow clients don't hold up the upstream
+	Buffered(usize),
+}
+
+/// A limit on the bytes buffered by all [`ResponseBuffering`]s that share it
+///
+/// When the limit is reached, reading ahead stops until the clients have caught up.
+#[derive(Debug)]
+pub struct BufferPool {
+	/// The maximum number of bytes buffered at once
+	pub max: usize,
+	/// The number of buffered bytes, with the label `route`
+	pub counters: Arc<CounterFamily>,
+	permits: Arc<Semaphore>,
+	peak: AtomicUsize,
+}
+
+impl BufferPool {
+	/// Create a pool of `max` bytes
+	pub fn new(max: usize) -> Self {
+		let max = max.min(Semaphore::MAX_PERMITS);
+		Self {
+			max,
+			counters: Arc::new(CounterFamily::new(
+				"proxylib_response_buffer_bytes_total",
+				"The number of response bytes read ahead of the client",
+				&["route"],
+			)),
+			permits: Arc::new(Semaphore::new(max)),
+			peak: AtomicUsize::new(0),
+		}
+	}
+
+	/// Get the number of bytes buffered right now
+	pub fn in_use(&self) -> usize {
+		self.max - self.permits.available_permits()
+	}
+
+	/// Get the largest number of bytes that were buffered at once
+	pub fn peak(&self) -> usize {
+		self.peak.load(Ordering::Relaxed)
+	}
+}
+
+impl Default for BufferPool {
+	/// Create a pool of 64 MiB
+	fn default() -> Self {
+		Self::new(64 << 20)
+	}
+}
+
+/// A request handler combinator that controls how response bodies are buffered
+///
+/// Multiple routes can share a [`BufferPool`] to limit the memory used for buffering overall.
+pub struct ResponseBuffering<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The name of the route, used as the `route` label
+	pub name: String,
+	/// How response bodies are buffered
+	pub mode: BufferMode,
+	/// The pool the buffered bytes are taken from
+	pub pool: Arc<BufferPool>,
+}
+
+impl<H: RequestHandler> ResponseBuffering<H> {
+	/// Buffer up to `limit` bytes per response, in a pool of its own
+	pub fn buffered(inner: H, name: impl Into<String>, limit: usize) -> Self {
+		Self::with_pool(
+			inner,
+			name,
+			BufferMode::Buffered(limit),
+			Arc::new(BufferPool::default()),
+		)
+	}
+
+	/// Buffer as given by `mode`, in a shared pool
+	pub fn with_pool(
+		inner: H,
+		name: impl Into<String>,
+		mode: BufferMode,
+		pool: Arc<BufferPool>,
+	) -> Self {
+		Self {
+			inner,
+			name: name.into(),
+			mode,
+			pool,
+		}
+	}
+}
+
+type ResponseBufferingFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+fn read_ahead(mut body: Body, limit: usize, pool: Arc<BufferPool>, name: String) -> Body {
+	let own = Arc::new(Semaphore::new(limit.clamp(1, Semaphore::MAX_PERMITS)));
+	let (tx, rx) = mpsc::unbounded_channel();
+	let (trailers_tx, trailers_rx) = oneshot::channel();
+
+	tokio::spawn(async move {
+		while let Some(chunk) = body.data().await {
+			let chunk = match chunk {
+				Ok(chunk) => chunk,
+				Err(e) => {
+					let _ = tx.send(Err(e));
+					return;
+				}
+			};
+			// Chunks larger than the limits take all of it
+			let n = chunk.len().min(limit).min(pool.max).min(u32::MAX as usize) as u32;
+			let permits = match (
+				own.clone().acquire_many_owned(n).await,
+				pool.permits.clone().acquire_many_owned(n).await,
+			) {
+				(Ok(own), Ok(pooled)) => (own, pooled),
+				_ => return,
+			};
+			pool.peak.fetch_max(pool.in_use(), Ordering::Relaxed);
+			pool.counters.add(&[&name], chunk.len() as u64);
+
+			if tx.send(Ok((chunk, permits))).is_err() {
+				// The client went away
+				return;
+			}
+		}
+		let _ = trailers_tx.send(body.trailers().await.ok().flatten());
+	});
+
+	let chunks = stream::unfold(rx, |mut rx| async move {
+		// The permits are released once the chunk is handed to the client
+		let item = rx.recv().await?.map(|(chunk, _permits)| chunk);
+		Some((item, rx))
+	});
+	with_trailers(chunks, async { trailers_rx.await.ok().flatten() })
+}
+
+impl<H: RequestHandler> RequestHandler for ResponseBuffering<H> {
+	type Error = H::Error;
+	type Output = ResponseBufferingFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let fut = self.inner.handle(from_addr, request, client);
+		let limit = match self.mode {
+			BufferMode::Streamed => return Box::pin(fut),
+			BufferMode::Buffered(limit) => limit,
+		};
+		let pool = self.pool.clone();
+		let name = self.name.clone();
+
+		Box::pin(async move {
+			let (parts, body) = fut.await?.into_parts();
+			if body.is_end_stream() {
+				return Ok(Response::from_parts(parts, body));
+			}
+			Ok(Response::from_parts(
+				parts,
+				read_ahead(body, limit, pool, name),
+			))
+		})
+	}
+}
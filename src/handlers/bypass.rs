@@ -0,0 +1,175 @@
+use std::net::{IpAddr, SocketAddr};
+
+use futures::future::{Either, FutureExt, Map};
+use hyper::{Body, Client, Request, Response};
+use thiserror::Error;
+
+use crate::connect::Connector;
+use crate::handlers::filter::{request_authority, IpNet};
+use crate::RequestHandler;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Rule {
+	Any,
+	Domain(String),
+	Net(IpNet),
+}
+
+/// A list of destinations that bypass a forward proxy, with the semantics of `NO_PROXY`
+///
+/// Entries are separated by commas or whitespace and can be
+/// - `*`, matching every destination,
+/// - a domain like `example.com` or `.example.com`, matching it and all its subdomains,
+/// - an IP address or a network in CIDR notation like `10.0.0.0/8`.
+///
+/// Each entry can be followed by a port (e.g. `example.com:8080`), in which case it
+/// only matches destinations with that port.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct NoProxy {
+	rules: Vec<(Rule, Option<u16>)>,
+}
+
+impl NoProxy {
+	/// Parse a list of entries; invalid entries are ignored
+	pub fn parse(list: &str) -> Self {
+		let rules = list
+			.split(|c: char| c == ',' || c.is_whitespace())
+			.filter(|entry| !entry.is_empty())
+			.filter_map(parse_entry)
+			.collect();
+		Self { rules }
+	}
+
+	/// Read the list from the `NO_PROXY` (or `no_proxy`) environment variable
+	pub fn from_env() -> Self {
+		let list = std::env::var("NO_PROXY")
+			.or_else(|_| std::env::var("no_proxy"))
+			.unwrap_or_default();
+		Self::parse(&list)
+	}
+
+	pub(crate) fn rules(&self) -> &[(Rule, Option<u16>)] {
+		&self.rules
+	}
+
+	/// Return whether the destination bypasses the proxy
+	pub fn matches(&self, host: &str, port: Option<u16>) -> bool {
+		let host = host.trim_start_matches('[').trim_end_matches(']');
+		let host = host.trim_end_matches('.').to_ascii_lowercase();
+		let ip = host.parse::<IpAddr>().ok();
+
+		self.rules.iter().any(|(rule, rule_port)| {
+			if rule_port.is_some() && *rule_port != port {
+				return false;
+			}
+			match rule {
+				Rule::Any => true,
+				Rule::Domain(domain) => {
+					host == *domain
+						|| (host.ends_with(domain.as_str())
+							&& host[..host.len() - domain.len()].ends_with('.'))
+				}
+				Rule::Net(net) => ip.is_some_and(|ip| net.contains(ip)),
+			}
+		})
+	}
+
+	/// Return whether the destination of the request bypasses the proxy
+	///
+	/// The destination is taken from the URI authority if present and from the `Host` header otherwise.
+	pub fn matches_request(&self, request: &Request<Body>) -> bool {
+		request_authority(request).is_some_and(|a| self.matches(a.host(), a.port_u16()))
+	}
+}
+
+fn parse_entry(entry: &str) -> Option<(Rule, Option<u16>)> {
+	if entry == "*" {
+		return Some((Rule::Any, None));
+	}
+	if let Ok(net) = entry.parse::<IpNet>() {
+		return Some((Rule::Net(net), None));
+	}
+
+	// Split off a port, taking care of bracketed IPv6 addresses
+	let (host, port) = match entry.rfind(':') {
+		Some(i) if !entry[..i].contains(':') || entry[..i].ends_with(']') => {
+			(&entry[..i], Some(entry[i + 1..].parse::<u16>().ok()?))
+		}
+		_ => (entry, None),
+	};
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+	if let Ok(net) = host.parse::<IpNet>() {
+		return Some((Rule::Net(net), port));
+	}
+
+	let domain = host
+		.trim_start_matches('*')
+		.trim_start_matches('.')
+		.trim_end_matches('.')
+		.to_ascii_lowercase();
+	if domain.is_empty() {
+		return None;
+	}
+	Some((Rule::Domain(domain), port))
+}
+
+/// A request handler that gives requests to destinations on a [`NoProxy`] list to a direct
+/// request handler and all others to the inner one
+///
+/// In forward-proxy mode, the inner request handler usually applies interception or rewriting,
+/// while the direct one (e.g. a [`Redirect`](super::redirect::Redirect) that leaves the URI as it
+/// is) just connects to the destination.
+pub struct Bypass<H: RequestHandler, D: RequestHandler> {
+	/// The request handler for all other destinations
+	pub inner: H,
+	/// The request handler for destinations that bypass the proxy
+	pub direct: D,
+	/// The destinations that bypass the proxy
+	pub no_proxy: NoProxy,
+}
+
+/// The error type for `<`[`Bypass`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum BypassError<E: std::error::Error, D: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("{0}")]
+	/// The direct request handler returned an error
+	Direct(D),
+}
+
+#[allow(type_alias_bounds)]
+type BypassResult<H: RequestHandler, D: RequestHandler> =
+	Result<Response<Body>, BypassError<H::Error, D::Error>>;
+#[allow(type_alias_bounds)]
+type BypassFuture<H: RequestHandler, D: RequestHandler> = Either<
+	Map<H::Output, fn(Result<Response<Body>, H::Error>) -> BypassResult<H, D>>,
+	Map<D::Output, fn(Result<Response<Body>, D::Error>) -> BypassResult<H, D>>,
+>;
+
+impl<H: RequestHandler, D: RequestHandler> RequestHandler for Bypass<H, D> {
+	type Error = BypassError<H::Error, D::Error>;
+	type Output = BypassFuture<H, D>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if self.no_proxy.matches_request(&request) {
+			Either::Right(
+				self.direct
+					.handle(from_addr, request, client)
+					.map(|res: Result<_, _>| res.map_err(BypassError::Direct)),
+			)
+		} else {
+			Either::Left(
+				self.inner
+					.handle(from_addr, request, client)
+					.map(|res: Result<_, _>| res.map_err(BypassError::Inner)),
+			)
+		}
+	}
+}
@@ -0,0 +1,1161 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::body::Bytes;
+use hyper::header::{
+	HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, ETAG,
+	IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, TRANSFER_ENCODING, VARY,
+};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode};
+use thiserror::Error;
+
+use crate::body::{buffer, is_streaming, Buffered};
+use crate::connect::Connector;
+use crate::handlers::filter::request_authority;
+use crate::metrics::CounterFamily;
+use crate::RequestHandler;
+
+/// The directives of `Cache-Control` headers that matter to a shared cache like [`Cache`],
+/// see [RFC 9111, Section 5.2](https://www.rfc-editor.org/rfc/rfc9111#section-5.2)
+/// and [RFC 5861](https://www.rfc-editor.org/rfc/rfc5861)
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CacheControl {
+	/// `no-store`: the message must not be kept
+	pub no_store: bool,
+	/// `no-cache`: a kept response must not be used without revalidating it
+	pub no_cache: bool,
+	/// `private`: the response must not be kept by shared caches
+	pub private: bool,
+	/// `public`: the response may be kept, even if the request was authorized
+	pub public: bool,
+	/// `must-revalidate` or `proxy-revalidate`: the response must not be used once it has expired
+	pub must_revalidate: bool,
+	/// `max-age`: how long the response stays fresh, or the oldest response the client accepts
+	pub max_age: Option<Duration>,
+	/// `s-maxage`: how long the response stays fresh in shared caches
+	pub s_maxage: Option<Duration>,
+	/// `stale-while-revalidate`: how long the response may be used while it is revalidated
+	pub stale_while_revalidate: Option<Duration>,
+	/// `stale-if-error`: how long the response may be used if the upstream fails
+	pub stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+	/// Parse the `Cache-Control` headers, ignoring unknown directives
+	///
+	/// Durations that aren't a number of seconds are taken as zero.
+	pub fn from_headers(headers: &HeaderMap) -> Self {
+		let mut cache_control = Self::default();
+		let directives = headers
+			.get_all(CACHE_CONTROL)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','));
+		for directive in directives {
+			let (name, value) = match directive.split_once('=') {
+				Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+				None => (directive, None),
+			};
+			let seconds = || {
+				let seconds = value.and_then(|v| v.parse::<u64>().ok());
+				Some(seconds.map_or(Duration::ZERO, Duration::from_secs))
+			};
+			match name.trim().to_ascii_lowercase().as_str() {
+				"no-store" => cache_control.no_store = true,
+				"no-cache" => cache_control.no_cache = true,
+				"private" => cache_control.private = true,
+				"public" => cache_control.public = true,
+				"must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+				"max-age" => cache_control.max_age = seconds(),
+				"s-maxage" => cache_control.s_maxage = seconds(),
+				"stale-while-revalidate" => cache_control.stale_while_revalidate = seconds(),
+				"stale-if-error" => cache_control.stale_if_error = seconds(),
+				_ => {}
+			}
+		}
+		cache_control
+	}
+
+	/// Parse the `Cache-Control` headers of a request, also taking `Pragma: no-cache`
+	/// into account if there are none
+	pub fn from_request(request: &Request<Body>) -> Self {
+		let headers = request.headers();
+		let mut cache_control = Self::from_headers(headers);
+		if !headers.contains_key(CACHE_CONTROL) {
+			cache_control.no_cache = headers
+				.get_all(PRAGMA)
+				.iter()
+				.filter_map(|v| v.to_str().ok())
+				.flat_map(|v| v.split(','))
+				.any(|v| v.trim().eq_ignore_ascii_case("no-cache"));
+		}
+		cache_control
+	}
+}
+
+// The names in the `Vary` headers, and whether one of them is `*`
+fn vary(headers: &HeaderMap) -> (Vec<HeaderName>, bool) {
+	let mut names = Vec::new();
+	let mut any = false;
+	let values = headers
+		.get_all(VARY)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','));
+	for value in values {
+		match value.trim() {
+			"*" => any = true,
+			name => names.extend(HeaderName::from_bytes(name.as_bytes()).ok()),
+		}
+	}
+	(names, any)
+}
+
+fn seconds_header(headers: &HeaderMap, name: HeaderName) -> Duration {
+	headers
+		.get(name)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.trim().parse::<u64>().ok())
+		.map_or(Duration::ZERO, Duration::from_secs)
+}
+
+// How long a response with the headers stays fresh, minus the time it spent in other caches
+fn freshness(headers: &HeaderMap, default: Duration) -> Duration {
+	let cache_control = CacheControl::from_headers(headers);
+	let lifetime = if cache_control.no_cache {
+		Duration::ZERO
+	} else {
+		cache_control
+			.s_maxage
+			.or(cache_control.max_age)
+			.unwrap_or(default)
+	};
+	lifetime.saturating_sub(seconds_header(headers, AGE))
+}
+
+// Whether a shared cache may keep the response to a request
+fn is_storable(request: &CacheControl, authorized: bool, response: &Response<Body>) -> bool {
+	let cache_control = CacheControl::from_headers(response.headers());
+	let (_, vary_any) = vary(response.headers());
+	is_cacheable_status(response.status())
+		&& !request.no_store
+		&& !cache_control.no_store
+		&& !cache_control.private
+		&& !vary_any
+		&& (!authorized
+			|| cache_control.public
+			|| cache_control.s_maxage.is_some()
+			|| cache_control.must_revalidate)
+}
+
+/// A response kept by a [`CacheStore`]
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+	/// The status of the response
+	pub status: StatusCode,
+	/// The headers of the response
+	pub headers: HeaderMap,
+	/// The body of the response
+	pub body: Bytes,
+	/// When the response was received
+	pub stored: SystemTime,
+	/// When the response has to be fetched anew
+	pub expires: SystemTime,
+	/// The values of the request headers named in the `Vary` header of the response
+	pub request_headers: HeaderMap,
+}
+
+impl CachedResponse {
+	/// An estimate of the memory used by the response
+	pub fn size(&self) -> usize {
+		let headers = self
+			.headers
+			.iter()
+			.map(|(name, value)| name.as_str().len() + value.len())
+			.sum::<usize>();
+		headers + self.body.len()
+	}
+
+	/// Return whether the response has expired
+	pub fn is_expired(&self, now: SystemTime) -> bool {
+		self.expires <= now
+	}
+
+	/// Get the age of the response, including the time it spent in other caches
+	pub fn age(&self, now: SystemTime) -> Duration {
+		let residence = now.duration_since(self.stored).unwrap_or_default();
+		seconds_header(&self.headers, AGE) + residence
+	}
+
+	/// Return whether the response can be used for the request, i.e. whether the request has the
+	/// same values for the headers named in the `Vary` header of the response
+	pub fn matches(&self, request: &Request<Body>) -> bool {
+		let (names, any) = vary(&self.headers);
+		!any && names.iter().all(|name| {
+			request
+				.headers()
+				.get_all(name)
+				.iter()
+				.eq(self.request_headers.get_all(name).iter())
+		})
+	}
+
+	// How long after expiring the response may still be served, with the given defaults
+	fn stale_windows(
+		&self,
+		stale_while_revalidate: Duration,
+		stale_if_error: Duration,
+	) -> (Duration, Duration) {
+		let cache_control = CacheControl::from_headers(&self.headers);
+		if cache_control.no_cache || cache_control.must_revalidate {
+			return (Duration::ZERO, Duration::ZERO);
+		}
+		(
+			cache_control
+				.stale_while_revalidate
+				.unwrap_or(stale_while_revalidate),
+			cache_control.stale_if_error.unwrap_or(stale_if_error),
+		)
+	}
+
+	// Make the request conditional on the response having changed, if it has validators
+	fn add_validators(&self, request: &mut Request<Body>) -> bool {
+		let headers = request.headers_mut();
+		let mut added = false;
+		if let Some(etag) = self.headers.get(ETAG) {
+			headers.insert(IF_NONE_MATCH, etag.clone());
+			added = true;
+		}
+		if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+			headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+			added = true;
+		}
+		added
+	}
+
+	// Update the response with the headers of a `304 Not Modified` for it
+	fn refresh(&mut self, headers: &HeaderMap, now: SystemTime, ttl: Duration) {
+		for name in headers.keys() {
+			if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+				continue;
+			}
+			self.headers.remove(name);
+			for value in headers.get_all(name) {
+				self.headers.append(name, value.clone());
+			}
+		}
+		self.stored = now;
+		self.expires = now + freshness(&self.headers, ttl);
+	}
+
+	fn to_response(&self, now: SystemTime) -> Response<Body> {
+		let mut response = Response::new(Body::from(self.body.clone()));
+		*response.status_mut() = self.status;
+		*response.headers_mut() = self.headers.clone();
+		let age = self.age(now).as_secs();
+		response.headers_mut().insert(AGE, HeaderValue::from(age));
+		response
+	}
+}
+
+/// The error type of a [`CacheStore`]
+#[derive(Debug, Error)]
+#[error("cache store failed: {0}")]
+pub struct CacheStoreError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// The future type of a [`CacheStore`]
+pub type CacheStoreFuture<T> = Pin<Box<dyn Future<Output = Result<T, CacheStoreError>> + Send>>;
+
+/// The storage of the responses of a [`Cache`]
+///
+/// Stores may keep expired responses; it is up to the [`Cache`] what to do with them.
+pub trait CacheStore {
+	/// Get the response kept under the key
+	fn get(&self, key: &str) -> CacheStoreFuture<Option<CachedResponse>>;
+
+	/// Keep the response under the key, replacing any other response kept under it
+	fn put(&self, key: &str, response: CachedResponse) -> CacheStoreFuture<()>;
+
+	/// Forget the response kept under the key
+	fn remove(&self, key: &str) -> CacheStoreFuture<()>;
+}
+
+// A map that evicts the least recently used entries once it holds too many or too large ones
+#[derive(Debug)]
+struct Lru<V> {
+	map: HashMap<String, (u64, usize, V)>,
+	order: BTreeMap<u64, String>,
+	tick: u64,
+	size: usize,
+}
+
+impl<V> Default for Lru<V> {
+	fn default() -> Self {
+		Self {
+			map: HashMap::new(),
+			order: BTreeMap::new(),
+			tick: 0,
+			size: 0,
+		}
+	}
+}
+
+impl<V> Lru<V> {
+	fn get(&mut self, key: &str) -> Option<&V> {
+		let (used, _, _) = self.map.get_mut(key)?;
+		self.order.remove(used);
+		self.tick += 1;
+		*used = self.tick;
+		self.order.insert(self.tick, key.to_string());
+		self.map.get(key).map(|(_, _, value)| value)
+	}
+
+	fn remove(&mut self, key: &str) -> Option<V> {
+		let (used, size, value) = self.map.remove(key)?;
+		self.order.remove(&used);
+		self.size -= size;
+		Some(value)
+	}
+
+	// Returns the evicted entries, including the new one if it is too large to be kept
+	fn insert(
+		&mut self,
+		key: String,
+		value: V,
+		size: usize,
+		max_entries: usize,
+		max_size: usize,
+	) -> Vec<V> {
+		let mut evicted = self.remove(&key).into_iter().collect::<Vec<_>>();
+		let size = size + key.len();
+		if size > max_size || max_entries == 0 {
+			evicted.push(value);
+			return evicted;
+		}
+		while self.map.len() >= max_entries || self.size + size > max_size {
+			let oldest = match self.order.values().next() {
+				Some(oldest) => oldest.clone(),
+				None => break,
+			};
+			evicted.extend(self.remove(&oldest));
+		}
+		self.tick += 1;
+		self.order.insert(self.tick, key.clone());
+		self.size += size;
+		self.map.insert(key, (self.tick, size, value));
+		evicted
+	}
+
+	fn clear(&mut self) {
+		self.map.clear();
+		self.order.clear();
+		self.size = 0;
+	}
+}
+
+/// A [`CacheStore`] that keeps the responses in memory
+///
+/// Once it holds [`max_entries`](Self::max_entries) responses or
+/// [`max_size`](Self::max_size) bytes, the least recently used ones are evicted.
+#[derive(Debug)]
+pub struct MemoryCacheStore {
+	/// The maximum number of responses kept
+	pub max_entries: usize,
+	/// The maximum total size of the responses kept, in bytes
+	pub max_size: usize,
+	entries: Mutex<Lru<CachedResponse>>,
+}
+
+impl Default for MemoryCacheStore {
+	/// A store for up to 10000 responses, using at most 64 MiB
+	fn default() -> Self {
+		Self::new(10_000, 64 << 20)
+	}
+}
+
+impl MemoryCacheStore {
+	/// Create a store for up to `max_entries` responses, using at most `max_size` bytes
+	pub fn new(max_entries: usize, max_size: usize) -> Self {
+		Self {
+			max_entries,
+			max_size,
+			entries: Mutex::default(),
+		}
+	}
+
+	/// Forget all kept responses
+	pub fn clear(&self) {
+		self.entries.lock().unwrap().clear();
+	}
+
+	/// Get the number of kept responses
+	pub fn len(&self) -> usize {
+		self.entries.lock().unwrap().map.len()
+	}
+
+	/// Return whether no responses are kept
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	fn get_now(&self, key: &str) -> Option<CachedResponse> {
+		self.entries.lock().unwrap().get(key).cloned()
+	}
+
+	fn put_now(&self, key: &str, response: CachedResponse) {
+		let size = response.size();
+		self.entries.lock().unwrap().insert(
+			key.to_string(),
+			response,
+			size,
+			self.max_entries,
+			self.max_size,
+		);
+	}
+}
+
+impl CacheStore for MemoryCacheStore {
+	fn get(&self, key: &str) -> CacheStoreFuture<Option<CachedResponse>> {
+		let response = self.get_now(key);
+		Box::pin(async { Ok(response) })
+	}
+
+	fn put(&self, key: &str, response: CachedResponse) -> CacheStoreFuture<()> {
+		self.put_now(key, response);
+		Box::pin(async { Ok(()) })
+	}
+
+	fn remove(&self, key: &str) -> CacheStoreFuture<()> {
+		self.entries.lock().unwrap().remove(key);
+		Box::pin(async { Ok(()) })
+	}
+}
+
+const ENTRY_MAGIC: &[u8; 4] = b"PXC2";
+const INDEX_MAGIC: &[u8; 4] = b"PXI1";
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+	buf.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+	buf.extend_from_slice(bytes);
+}
+
+fn put_headers(buf: &mut Vec<u8>, headers: &HeaderMap) {
+	buf.extend_from_slice(&(headers.len() as u64).to_le_bytes());
+	for (name, value) in headers {
+		put_bytes(buf, name.as_str().as_bytes());
+		put_bytes(buf, value.as_bytes());
+	}
+}
+
+fn put_time(buf: &mut Vec<u8>, time: SystemTime) {
+	let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	buf.extend_from_slice(&since.as_secs().to_le_bytes());
+	buf.extend_from_slice(&since.subsec_nanos().to_le_bytes());
+}
+
+// Reads the fields written by the `put_*` functions
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+		if self.0.len() < n {
+			return None;
+		}
+		let (taken, rest) = self.0.split_at(n);
+		self.0 = rest;
+		Some(taken)
+	}
+
+	fn u64(&mut self) -> Option<u64> {
+		Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+	}
+
+	fn bytes(&mut self) -> Option<&'a [u8]> {
+		let len = usize::try_from(self.u64()?).ok()?;
+		self.take(len)
+	}
+
+	fn headers(&mut self) -> Option<HeaderMap> {
+		let mut headers = HeaderMap::new();
+		for _ in 0..self.u64()? {
+			let name = HeaderName::from_bytes(self.bytes()?).ok()?;
+			let value = HeaderValue::from_bytes(self.bytes()?).ok()?;
+			headers.append(name, value);
+		}
+		Some(headers)
+	}
+
+	fn time(&mut self) -> Option<SystemTime> {
+		let secs = self.u64()?;
+		let nanos = u32::from_le_bytes(self.take(4)?.try_into().ok()?);
+		UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+	}
+}
+
+fn encode_entry(key: &str, response: &CachedResponse) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(response.size() + key.len() + 64);
+	buf.extend_from_slice(ENTRY_MAGIC);
+	put_bytes(&mut buf, key.as_bytes());
+	buf.extend_from_slice(&response.status.as_u16().to_le_bytes());
+	put_time(&mut buf, response.stored);
+	put_time(&mut buf, response.expires);
+	put_headers(&mut buf, &response.headers);
+	put_headers(&mut buf, &response.request_headers);
+	put_bytes(&mut buf, &response.body);
+	buf
+}
+
+fn decode_entry(bytes: &[u8]) -> Option<(String, CachedResponse)> {
+	let mut reader = Reader(bytes);
+	if reader.take(4)? != ENTRY_MAGIC {
+		return None;
+	}
+	let key = String::from_utf8(reader.bytes()?.to_vec()).ok()?;
+	let status = u16::from_le_bytes(reader.take(2)?.try_into().ok()?);
+	let status = StatusCode::from_u16(status).ok()?;
+	let stored = reader.time()?;
+	let expires = reader.time()?;
+	let headers = reader.headers()?;
+	let request_headers = reader.headers()?;
+	let body = Bytes::copy_from_slice(reader.bytes()?);
+	let response = CachedResponse {
+		status,
+		headers,
+		body,
+		stored,
+		expires,
+		request_headers,
+	};
+	Some((key, response))
+}
+
+// Writes the file under a temporary name first, so it is never seen half-written
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let tmp = path.with_extension("tmp");
+	fs::write(&tmp, contents)?;
+	fs::rename(&tmp, path)
+}
+
+fn store_error(e: io::Error) -> CacheStoreError {
+	CacheStoreError(Box::new(e))
+}
+
+async fn blocking<T: Send + 'static>(
+	f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, CacheStoreError> {
+	match tokio::task::spawn_blocking(f).await {
+		Ok(result) => result.map_err(store_error),
+		Err(e) => Err(CacheStoreError(Box::new(e))),
+	}
+}
+
+// The key, file id and size of a response in the saved index
+type SavedFile = (String, u64, usize);
+
+// The responses on disk, by key, with the id of their file
+#[derive(Debug)]
+struct DiskIndex {
+	files: Lru<u64>,
+	next_id: u64,
+	dirty: bool,
+	saved: Instant,
+}
+
+impl DiskIndex {
+	fn encode(&self) -> Vec<u8> {
+		let mut buf = Vec::new();
+		buf.extend_from_slice(INDEX_MAGIC);
+		buf.extend_from_slice(&self.next_id.to_le_bytes());
+		buf.extend_from_slice(&(self.files.map.len() as u64).to_le_bytes());
+		// From least to most recently used, so the order survives a restart
+		for key in self.files.order.values() {
+			let (_, size, id) = &self.files.map[key];
+			put_bytes(&mut buf, key.as_bytes());
+			buf.extend_from_slice(&id.to_le_bytes());
+			buf.extend_from_slice(&(*size as u64).to_le_bytes());
+		}
+		buf
+	}
+
+	fn decode(bytes: &[u8]) -> Option<(u64, Vec<SavedFile>)> {
+		let mut reader = Reader(bytes);
+		if reader.take(4)? != INDEX_MAGIC {
+			return None;
+		}
+		let next_id = reader.u64()?;
+		let mut files = Vec::new();
+		for _ in 0..reader.u64()? {
+			let key = String::from_utf8(reader.bytes()?.to_vec()).ok()?;
+			let id = reader.u64()?;
+			let size = usize::try_from(reader.u64()?).ok()?;
+			files.push((key, id, size));
+		}
+		Some((next_id, files))
+	}
+}
+
+// The files of a `DiskCacheStore`, shared with its futures
+#[derive(Debug)]
+struct DiskFiles {
+	dir: PathBuf,
+	index: Mutex<DiskIndex>,
+}
+
+impl DiskFiles {
+	fn path(&self, id: u64) -> PathBuf {
+		self.dir.join(format!("{}.entry", id))
+	}
+
+	fn flush(&self) -> io::Result<()> {
+		let mut index = self.index.lock().unwrap();
+		write_atomically(&self.dir.join("index"), &index.encode())?;
+		index.dirty = false;
+		index.saved = Instant::now();
+		Ok(())
+	}
+
+	// Update the index, then delete the files it no longer refers to and save it if it is due
+	fn update(&self, f: impl FnOnce(&mut Lru<u64>) -> Vec<u64>) -> CacheStoreFuture<()> {
+		let mut index = self.index.lock().unwrap();
+		let evicted = f(&mut index.files)
+			.into_iter()
+			.map(|id| self.path(id))
+			.collect::<Vec<_>>();
+		index.dirty = true;
+		let save = if index.saved.elapsed() >= Duration::from_secs(5) {
+			index.dirty = false;
+			index.saved = Instant::now();
+			Some(index.encode())
+		} else {
+			None
+		};
+
+		let index_path = self.dir.join("index");
+		Box::pin(blocking(move || {
+			for path in evicted {
+				match fs::remove_file(path) {
+					Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+					_ => {}
+				}
+			}
+			if let Some(save) = save {
+				write_atomically(&index_path, &save)?;
+			}
+			Ok(())
+		}))
+	}
+}
+
+/// A [`CacheStore`] that keeps the responses on disk, so they survive restarts and aren't
+/// limited by the available memory
+///
+/// Every response is written to its own file in the directory, and the index of the files is
+/// saved alongside them at most every few seconds and on [`flush`](Self::flush)
+/// (which is also called on drop). Files that aren't in the index when the store is
+/// opened (e.g. after a crash) are deleted.
+///
+/// Responses up to [`max_memory_body_size`](Self::max_memory_body_size) are also kept in a
+/// [`MemoryCacheStore`], so frequently used ones don't have to be read from disk.
+/// Once the files take up [`max_size`](Self::max_size) bytes, the least recently used ones
+/// are deleted.
+#[derive(Debug)]
+pub struct DiskCacheStore {
+	/// The maximum total size of the files, in bytes
+	pub max_size: usize,
+	/// The size of the largest response body that is also kept in memory
+	pub max_memory_body_size: usize,
+	/// The store keeping the small responses in memory
+	pub memory: MemoryCacheStore,
+	files: Arc<DiskFiles>,
+}
+
+impl DiskCacheStore {
+	/// Open the store in the directory (creating it if needed), keeping up to `max_size` bytes
+	/// on disk and responses up to 64 KiB in a [`MemoryCacheStore::default`]
+	///
+	/// This reads the index and deletes files that aren't in it, so it should be called before
+	/// the proxy starts serving.
+	pub fn open(dir: impl Into<PathBuf>, max_size: usize) -> io::Result<Self> {
+		let dir = dir.into();
+		fs::create_dir_all(&dir)?;
+
+		let saved = fs::read(dir.join("index"))
+			.ok()
+			.and_then(|bytes| DiskIndex::decode(&bytes));
+		let (next_id, saved) = saved.unwrap_or((0, Vec::new()));
+		let mut files = Lru::default();
+		let mut known = HashSet::new();
+		for (key, id, size) in saved {
+			let name = format!("{}.entry", id);
+			if dir.join(&name).is_file() {
+				files.insert(key, id, size, usize::MAX, usize::MAX);
+				known.insert(name);
+			}
+		}
+		for file in fs::read_dir(&dir)? {
+			let file = file?;
+			let name = file.file_name().to_string_lossy().into_owned();
+			let is_ours = name.ends_with(".entry") || name.ends_with(".tmp");
+			if is_ours && !known.contains(&name) {
+				fs::remove_file(file.path())?;
+			}
+		}
+
+		Ok(Self {
+			max_size,
+			max_memory_body_size: 64 * 1024,
+			memory: MemoryCacheStore::default(),
+			files: Arc::new(DiskFiles {
+				dir,
+				index: Mutex::new(DiskIndex {
+					files,
+					next_id,
+					dirty: false,
+					saved: Instant::now(),
+				}),
+			}),
+		})
+	}
+
+	/// Get the directory the files are kept in
+	pub fn dir(&self) -> &Path {
+		&self.files.dir
+	}
+
+	/// Get the number of responses on disk
+	pub fn len(&self) -> usize {
+		self.files.index.lock().unwrap().files.map.len()
+	}
+
+	/// Return whether no responses are on disk
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Save the index, blocking until it is written
+	pub fn flush(&self) -> io::Result<()> {
+		self.files.flush()
+	}
+}
+
+impl Drop for DiskCacheStore {
+	fn drop(&mut self) {
+		if self.files.index.lock().unwrap().dirty {
+			let _ = self.files.flush();
+		}
+	}
+}
+
+impl CacheStore for DiskCacheStore {
+	fn get(&self, key: &str) -> CacheStoreFuture<Option<CachedResponse>> {
+		if let Some(response) = self.memory.get_now(key) {
+			return Box::pin(async { Ok(Some(response)) });
+		}
+		let id = match self.files.index.lock().unwrap().files.get(key) {
+			Some(&id) => id,
+			None => return Box::pin(async { Ok(None) }),
+		};
+
+		let path = self.files.path(id);
+		let key = key.to_string();
+		let files = self.files.clone();
+		Box::pin(async move {
+			let bytes = blocking(move || match fs::read(path) {
+				Ok(bytes) => Ok(Some(bytes)),
+				// The response was replaced or evicted in the meantime
+				Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+				Err(e) => Err(e),
+			})
+			.await?;
+			let bytes = match bytes {
+				Some(bytes) => bytes,
+				None => return Ok(None),
+			};
+			match decode_entry(&bytes) {
+				Some((stored_key, response)) if stored_key == key => Ok(Some(response)),
+				_ => {
+					// The file is corrupt, so forget it
+					files
+						.update(|index| index.remove(&key).into_iter().collect())
+						.await?;
+					Ok(None)
+				}
+			}
+		})
+	}
+
+	fn put(&self, key: &str, response: CachedResponse) -> CacheStoreFuture<()> {
+		let contents = encode_entry(key, &response);
+		if response.body.len() <= self.max_memory_body_size {
+			self.memory.put_now(key, response);
+		} else {
+			self.memory.entries.lock().unwrap().remove(key);
+		}
+
+		let id = {
+			let mut index = self.files.index.lock().unwrap();
+			index.next_id += 1;
+			index.next_id
+		};
+		let path = self.files.path(id);
+		let key = key.to_string();
+		let max_size = self.max_size;
+		let files = self.files.clone();
+		Box::pin(async move {
+			let size = contents.len();
+			blocking(move || write_atomically(&path, &contents)).await?;
+			// The index only refers to the file once it is completely written
+			files
+				.update(|index| index.insert(key, id, size, usize::MAX, max_size))
+				.await
+		})
+	}
+
+	fn remove(&self, key: &str) -> CacheStoreFuture<()> {
+		self.memory.entries.lock().unwrap().remove(key);
+		self.files
+			.update(|index| index.remove(key).into_iter().collect())
+	}
+}
+
+/// A request handler combinator that keeps responses in a [`CacheStore`] and answers repeated
+/// requests with them, without giving them to the inner request handler
+///
+/// Responses to `GET` and `HEAD` requests with a cacheable status (like `200 OK` or
+/// `404 Not Found`) are kept for [`ttl`](Self::ttl). They are looked up by the method,
+/// authority and path of the request, plus the values of the [`key_headers`](Self::key_headers)
+/// (e.g. `Accept-Encoding`, if the upstream compresses responses). Responses served from the
+/// cache get an `Age` header. Responses larger than [`max_body_size`](Self::max_body_size)
+/// are not kept, and neither are [streaming](crate::body::is_streaming) ones, which are passed
+/// on as they arrive.
+///
+/// The [`CacheControl`] of the responses is honored: `no-store` and `private` responses are
+/// not kept, `s-maxage` and `max-age` replace the `ttl`, and `no-cache` responses are
+/// revalidated every time. Responses to requests with an `Authorization` header are only kept
+/// if they allow it with `public`, `s-maxage` or `must-revalidate`. A kept response is only
+/// used for requests with the same values of the headers named in its `Vary` header; responses
+/// with `Vary: *` are not kept. Clients can skip the cache with `no-store`, and ask for a
+/// revalidation with `no-cache` or `max-age`.
+///
+/// Once a response has expired, the next request for it is made conditional with
+/// `If-None-Match` and `If-Modified-Since` (if the response has an `ETag` or `Last-Modified`
+/// header). If the upstream answers `304 Not Modified`, the kept response is refreshed and
+/// served instead of fetching the whole body again.
+///
+/// For up to [`stale_while_revalidate`](Self::stale_while_revalidate) after a response
+/// expired, it is still served right away, while it is refreshed in the background.
+/// For up to [`stale_if_error`](Self::stale_if_error) after it expired, it is served if the
+/// upstream returns an error or a server error response. Both are off by default.
+///
+/// The responses are kept in a [`MemoryCacheStore`] by default; if the store fails, the request
+/// is given to the inner request handler. Every request that could be cached is counted in
+/// [`counters`](Self::counters), with the label `outcome` being `hit`, `stale`,
+/// `revalidated` or `miss`.
+pub struct Cache<H: RequestHandler, S: CacheStore = MemoryCacheStore> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// How long responses are kept, unless they say otherwise
+	pub ttl: Duration,
+	/// The request headers whose values are part of the cache key
+	pub key_headers: Vec<HeaderName>,
+	/// The size of the largest response body that is kept
+	pub max_body_size: usize,
+	/// How long after expiring a response is still served while it is refreshed in the background
+	pub stale_while_revalidate: Duration,
+	/// How long after expiring a response is still served if the upstream fails
+	pub stale_if_error: Duration,
+	/// The number of hits and misses
+	pub counters: Arc<CounterFamily>,
+	/// The storage for the responses
+	pub store: Arc<S>,
+	refreshing: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<H: RequestHandler> Cache<H> {
+	/// Create a [`Cache`] that keeps responses of up to 1 MiB for a minute in a
+	/// [`MemoryCacheStore::default`]
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			ttl: Duration::from_secs(60),
+			key_headers: Vec::new(),
+			max_body_size: 1 << 20,
+			stale_while_revalidate: Duration::ZERO,
+			stale_if_error: Duration::ZERO,
+			counters: Arc::new(CounterFamily::new(
+				"proxylib_cache_requests_total",
+				"The number of cacheable requests answered from the cache or not",
+				&["outcome"],
+			)),
+			store: Arc::new(MemoryCacheStore::default()),
+			refreshing: Arc::default(),
+		}
+	}
+}
+
+impl<H: RequestHandler, S: CacheStore> Cache<H, S> {
+	/// Keep the responses in the given storage instead
+	pub fn with_store<T: CacheStore>(self, store: T) -> Cache<H, T> {
+		Cache {
+			inner: self.inner,
+			ttl: self.ttl,
+			key_headers: self.key_headers,
+			max_body_size: self.max_body_size,
+			stale_while_revalidate: self.stale_while_revalidate,
+			stale_if_error: self.stale_if_error,
+			counters: self.counters,
+			store: Arc::new(store),
+			refreshing: self.refreshing,
+		}
+	}
+
+	/// Get the key the response to the request is kept under
+	pub fn key(&self, request: &Request<Body>) -> String {
+		let authority = request_authority(request);
+		let mut key = format!(
+			"{} {}{}",
+			request.method(),
+			authority.as_ref().map_or("", |a| a.as_str()),
+			request
+				.uri()
+				.path_and_query()
+				.map_or("/", |path| path.as_str()),
+		);
+		for name in &self.key_headers {
+			key.push('\n');
+			key.push_str(name.as_str());
+			key.push(':');
+			for value in request.headers().get_all(name) {
+				key.push(' ');
+				key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+			}
+		}
+		key
+	}
+}
+
+fn is_cacheable_status(status: StatusCode) -> bool {
+	matches!(
+		status.as_u16(),
+		200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+	)
+}
+
+/// The error type for `<`[`Cache`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum CacheError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("failed to read response body: {0}")]
+	/// The response body couldn't be read
+	Body(hyper::Error),
+}
+
+type CacheFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, CacheError<E>>> + Send>>;
+
+// What a `Cache` needs to fetch a response, shared with background refreshes
+struct Fetch<H, S> {
+	inner: Arc<H>,
+	client: Client<Connector>,
+	store: Arc<S>,
+	key: String,
+	ttl: Duration,
+	max_body_size: usize,
+	stale_if_error: Duration,
+}
+
+impl<H: RequestHandler, S: CacheStore> Fetch<H, S> {
+	// Give the request to the inner handler, revalidating the stale response if there is one,
+	// and keep the response if possible; returns the response and the outcome for the counters
+	async fn run(
+		self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		stale: Option<CachedResponse>,
+	) -> Result<(Response<Body>, &'static str), CacheError<H::Error>> {
+		let request_cache_control = CacheControl::from_request(&request);
+		let authorized = request.headers().contains_key(AUTHORIZATION);
+		let request_headers = request.headers().clone();
+
+		// Let the upstream tell us if the stale response is still good, unless the client
+		// asked a conditional request itself
+		let is_conditional = request.headers().contains_key(IF_NONE_MATCH)
+			|| request.headers().contains_key(IF_MODIFIED_SINCE);
+		let revalidating = match &stale {
+			Some(stale) => !is_conditional && stale.add_validators(&mut request),
+			None => false,
+		};
+
+		let result = self.inner.handle(from_addr, request, &self.client).await;
+		let now = SystemTime::now();
+		let failed = match &result {
+			Ok(response) => response.status().is_server_error(),
+			Err(_) => true,
+		};
+		let had_stale = stale.is_some();
+		if let Some(mut stale) = stale {
+			let (_, stale_if_error) = stale.stale_windows(Duration::ZERO, self.stale_if_error);
+			if failed && now < stale.expires + stale_if_error {
+				return Ok((stale.to_response(now), "stale"));
+			}
+			match &result {
+				Ok(response) if revalidating && response.status() == StatusCode::NOT_MODIFIED => {
+					stale.refresh(response.headers(), now, self.ttl);
+					let refreshed = stale.to_response(now);
+					tokio::spawn(self.store.put(&self.key, stale));
+					return Ok((refreshed, "revalidated"));
+				}
+				_ => {}
+			}
+		}
+
+		let response = result.map_err(CacheError::Inner)?;
+		if !is_storable(&request_cache_control, authorized, &response) || is_streaming(&response) {
+			if had_stale && !request_cache_control.no_store {
+				tokio::spawn(self.store.remove(&self.key));
+			}
+			return Ok((response, "miss"));
+		}
+
+		let (parts, body) = response.into_parts();
+		let body = match buffer(body, self.max_body_size)
+			.await
+			.map_err(CacheError::Body)?
+		{
+			Buffered::Complete(body) => body,
+			Buffered::Partial(body) => return Ok((Response::from_parts(parts, body), "miss")),
+		};
+
+		let (vary_names, _) = vary(&parts.headers);
+		let mut selecting = HeaderMap::new();
+		for name in vary_names {
+			for value in request_headers.get_all(&name) {
+				selecting.append(&name, value.clone());
+			}
+		}
+		let stored = SystemTime::now();
+		let cached = CachedResponse {
+			status: parts.status,
+			headers: parts.headers.clone(),
+			body: body.clone(),
+			stored,
+			expires: stored + freshness(&parts.headers, self.ttl),
+			request_headers: selecting,
+		};
+		// Writing to the store may take a while, so don't hold the response back
+		tokio::spawn(self.store.put(&self.key, cached));
+		Ok((Response::from_parts(parts, Body::from(body)), "miss"))
+	}
+}
+
+// Marks a key as being refreshed in the background until dropped
+struct Refreshing {
+	keys: Arc<Mutex<HashSet<String>>>,
+	key: String,
+}
+
+impl Drop for Refreshing {
+	fn drop(&mut self) {
+		self.keys.lock().unwrap().remove(&self.key);
+	}
+}
+
+impl<H, S> RequestHandler for Cache<H, S>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	S: CacheStore + Send + Sync + 'static,
+{
+	type Error = CacheError<H::Error>;
+	type Output = CacheFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let request_cache_control = CacheControl::from_request(&request);
+		let is_cacheable_method =
+			request.method() == Method::GET || request.method() == Method::HEAD;
+		if !is_cacheable_method || request_cache_control.no_store {
+			let fut = self.inner.handle(from_addr, request, client);
+			return Box::pin(async move { fut.await.map_err(CacheError::Inner) });
+		}
+
+		let key = self.key(&request);
+		let lookup = self.store.get(&key);
+		let fetch = Fetch {
+			inner: self.inner.clone(),
+			client: client.clone(),
+			store: self.store.clone(),
+			key,
+			ttl: self.ttl,
+			max_body_size: self.max_body_size,
+			stale_if_error: self.stale_if_error,
+		};
+		let counters = self.counters.clone();
+		let stale_while_revalidate = self.stale_while_revalidate;
+		let refreshing = self.refreshing.clone();
+
+		Box::pin(async move {
+			let now = SystemTime::now();
+			// Responses for other values of the headers named in `Vary` are replaced
+			let cached = match lookup.await {
+				Ok(Some(cached)) if cached.matches(&request) => Some(cached),
+				_ => None,
+			};
+			// The client can ask for a revalidation, in which case the response isn't served stale
+			let revalidate = |cached: &CachedResponse| {
+				request_cache_control.no_cache
+					|| request_cache_control
+						.max_age
+						.is_some_and(|max_age| cached.age(now) > max_age)
+			};
+			let stale = match cached {
+				Some(cached) if !cached.is_expired(now) && !revalidate(&cached) => {
+					counters.inc(&["hit"]);
+					return Ok(cached.to_response(now));
+				}
+				cached => cached,
+			};
+
+			let serve_stale = stale.as_ref().is_some_and(|stale| {
+				let (stale_while_revalidate, _) =
+					stale.stale_windows(stale_while_revalidate, Duration::ZERO);
+				!revalidate(stale) && now < stale.expires + stale_while_revalidate
+			});
+			match stale {
+				Some(stale) if serve_stale => {
+					let response = stale.to_response(now);
+					// Only refresh each response once at a time
+					if refreshing.lock().unwrap().insert(fetch.key.clone()) {
+						let guard = Refreshing {
+							keys: refreshing,
+							key: fetch.key.clone(),
+						};
+						tokio::spawn(async move {
+							let _ = fetch.run(from_addr, request, Some(stale)).await;
+							drop(guard);
+						});
+					}
+					counters.inc(&["stale"]);
+					Ok(response)
+				}
+				stale => {
+					let (response, outcome) = fetch.run(from_addr, request, stale).await?;
+					counters.inc(&[outcome]);
+					Ok(response)
+				}
+			}
+		})
+	}
+}
@@ -0,0 +1,210 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request, Response};
+
+use crate::connect::Connector;
+use crate::handlers::overload::OverloadResponse;
+use crate::RequestHandler;
+
+/// The state of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CircuitState {
+	/// Requests are given to the upstream, and failures are counted
+	Closed,
+	/// Requests fail fast without reaching the upstream
+	Open,
+	/// A few trial requests are given to the upstream to find out whether it has recovered
+	HalfOpen,
+}
+
+#[derive(Debug)]
+struct Breaker {
+	state: CircuitState,
+	window_start: Instant,
+	requests: u32,
+	failures: u32,
+	opened_at: Instant,
+	probes: u32,
+}
+
+impl Breaker {
+	fn open(&mut self, now: Instant) {
+		self.state = CircuitState::Open;
+		self.opened_at = now;
+		self.probes = 0;
+	}
+
+	fn close(&mut self, now: Instant) {
+		self.state = CircuitState::Closed;
+		self.window_start = now;
+		self.requests = 0;
+		self.failures = 0;
+		self.probes = 0;
+	}
+}
+
+// Gives the slot of a trial request back if it is dropped before it finishes
+struct Probe {
+	breaker: Arc<Mutex<Breaker>>,
+	done: bool,
+}
+
+impl Drop for Probe {
+	fn drop(&mut self) {
+		if !self.done {
+			let mut breaker = self.breaker.lock().unwrap();
+			if breaker.state == CircuitState::HalfOpen {
+				breaker.probes = breaker.probes.saturating_sub(1);
+			}
+		}
+	}
+}
+
+/// A request handler combinator that stops giving requests to an upstream that keeps failing
+///
+/// While the circuit is [closed](CircuitState::Closed), the failures (errors and server error
+/// responses) of the inner request handler are counted in windows of [`window`](Self::window).
+/// Once at least [`min_requests`](Self::min_requests) requests in a window have a failure ratio
+/// of at least [`failure_ratio`](Self::failure_ratio), the circuit [opens](CircuitState::Open)
+/// and requests fail fast with the [`OverloadResponse`] (with the reason `circuit_open`).
+/// After [`open_for`](Self::open_for), the circuit is [half-open](CircuitState::HalfOpen) and lets
+/// [`probes`](Self::probes) trial requests through at a time: the first one to succeed closes
+/// the circuit again, the first one to fail opens it again.
+///
+/// Wrap each upstream of a [`Balance`](super::balance::Balance) in its own [`CircuitBreaker`]
+/// to track them separately.
+pub struct CircuitBreaker<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The ratio of failed requests at which the circuit opens
+	pub failure_ratio: f64,
+	/// The minimum number of requests in a window before the circuit can open
+	pub min_requests: u32,
+	/// The length of the windows in which failures are counted
+	pub window: Duration,
+	/// How long the circuit stays open before trial requests are let through
+	pub open_for: Duration,
+	/// The number of trial requests let through at a time while half-open
+	pub probes: u32,
+	/// The response for requests that fail fast
+	pub overload: Arc<OverloadResponse>,
+	breaker: Arc<Mutex<Breaker>>,
+}
+
+impl<H: RequestHandler> CircuitBreaker<H> {
+	/// Create a [`CircuitBreaker`] that opens for 30 seconds once half of at least 20 requests
+	/// within 10 seconds failed
+	pub fn new(inner: H) -> Self {
+		let now = Instant::now();
+		Self {
+			inner,
+			failure_ratio: 0.5,
+			min_requests: 20,
+			window: Duration::from_secs(10),
+			open_for: Duration::from_secs(30),
+			probes: 1,
+			overload: Arc::default(),
+			breaker: Arc::new(Mutex::new(Breaker {
+				state: CircuitState::Closed,
+				window_start: now,
+				requests: 0,
+				failures: 0,
+				opened_at: now,
+				probes: 0,
+			})),
+		}
+	}
+
+	/// Get the state of the circuit
+	pub fn state(&self) -> CircuitState {
+		let breaker = self.breaker.lock().unwrap();
+		match breaker.state {
+			CircuitState::Open if breaker.opened_at.elapsed() >= self.open_for => {
+				CircuitState::HalfOpen
+			}
+			state => state,
+		}
+	}
+}
+
+type CircuitBreakerFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for CircuitBreaker<H> {
+	type Error = H::Error;
+	type Output = CircuitBreakerFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let now = Instant::now();
+		let mut probe = None;
+		{
+			let mut breaker = self.breaker.lock().unwrap();
+			if breaker.state == CircuitState::Open
+				&& now.duration_since(breaker.opened_at) >= self.open_for
+			{
+				breaker.state = CircuitState::HalfOpen;
+			}
+			match breaker.state {
+				CircuitState::Closed => {
+					if now.duration_since(breaker.window_start) >= self.window {
+						breaker.close(now);
+					}
+				}
+				CircuitState::HalfOpen if breaker.probes < self.probes => {
+					breaker.probes += 1;
+					probe = Some(Probe {
+						breaker: self.breaker.clone(),
+						done: false,
+					});
+				}
+				CircuitState::Open | CircuitState::HalfOpen => {
+					let response = self.overload.respond("circuit_open");
+					return Box::pin(async { Ok(response) });
+				}
+			}
+		}
+
+		let fut = self.inner.handle(from_addr, request, client);
+		let breaker = self.breaker.clone();
+		let (failure_ratio, min_requests) = (self.failure_ratio, self.min_requests);
+
+		Box::pin(async move {
+			let result = fut.await;
+			let failed = match &result {
+				Ok(response) => response.status().is_server_error(),
+				Err(_) => true,
+			};
+
+			let now = Instant::now();
+			let mut breaker = breaker.lock().unwrap();
+			if let Some(mut probe) = probe {
+				probe.done = true;
+				if breaker.state == CircuitState::HalfOpen {
+					if failed {
+						breaker.open(now);
+					} else {
+						breaker.close(now);
+					}
+				}
+			} else if breaker.state == CircuitState::Closed {
+				breaker.requests += 1;
+				breaker.failures += u32::from(failed);
+				if breaker.requests >= min_requests
+					&& f64::from(breaker.failures) >= failure_ratio * f64::from(breaker.requests)
+				{
+					breaker.open(now);
+				}
+			}
+			drop(breaker);
+			result
+		})
+	}
+}
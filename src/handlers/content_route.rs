@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::header::{HeaderName, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use thiserror::Error;
+
+use crate::body::peek;
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The exchangable part of a [`ContentRoute`] that picks the route of a request
+/// from the start of its body
+pub trait ContentKey {
+	/// Return the name of the route for the request, given the first bytes of its body
+	fn content_key(&self, request: &Request<Body>, prefix: &[u8]) -> Option<String>;
+}
+
+/// Obtain a [`ContentKey`] from a function/closure
+pub fn content_key_fn<F: Fn(&Request<Body>, &[u8]) -> Option<String>>(f: F) -> impl ContentKey {
+	struct ContentKeyFn<F: Fn(&Request<Body>, &[u8]) -> Option<String>>(F);
+
+	impl<F: Fn(&Request<Body>, &[u8]) -> Option<String>> ContentKey for ContentKeyFn<F> {
+		fn content_key(&self, request: &Request<Body>, prefix: &[u8]) -> Option<String> {
+			(self.0)(request, prefix)
+		}
+	}
+
+	ContentKeyFn(f)
+}
+
+// Skip JSON whitespace
+fn skip_ws(bytes: &[u8]) -> &[u8] {
+	let start = bytes
+		.iter()
+		.position(|b| !b" \t\r\n".contains(b))
+		.unwrap_or(bytes.len());
+	&bytes[start..]
+}
+
+// Read a JSON string at the start of `bytes`
+fn json_string(bytes: &[u8]) -> Option<String> {
+	let mut rest = bytes.strip_prefix(b"\"")?;
+	let mut s = Vec::new();
+	loop {
+		match *rest.first()? {
+			b'"' => return String::from_utf8(s).ok(),
+			b'\\' => {
+				s.push(match *rest.get(1)? {
+					b'n' => b'\n',
+					b't' => b'\t',
+					b'r' => b'\r',
+					c @ (b'"' | b'\\' | b'/') => c,
+					// Escapes like \u are rare in method names
+					_ => return None,
+				});
+				rest = &rest[2..];
+			}
+			c => {
+				s.push(c);
+				rest = &rest[1..];
+			}
+		}
+	}
+}
+
+/// A [`ContentKey`] which uses the `method` of JSON-RPC requests
+///
+/// The body isn't fully parsed, so the `method` member should come before members
+/// like `params` that could contain another `method` (which is the case for most clients).
+/// For batch requests, the method of the first request is used.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonRpcMethod;
+
+impl ContentKey for JsonRpcMethod {
+	fn content_key(&self, _: &Request<Body>, prefix: &[u8]) -> Option<String> {
+		let mut rest = prefix;
+		while let Some(i) = rest.windows(8).position(|w| w == b"\"method\"") {
+			rest = &rest[i + 8..];
+			if let Some(value) = skip_ws(rest).strip_prefix(b":") {
+				return json_string(skip_ws(value));
+			}
+		}
+		None
+	}
+}
+
+const SOAP_ACTION: HeaderName = HeaderName::from_static("soapaction");
+
+/// A [`ContentKey`] which uses the action of SOAP requests
+///
+/// The action is taken from the `SOAPAction` header (SOAP 1.1) or the `action` parameter
+/// of the content type (SOAP 1.2). If neither is set, the name of the first element in the
+/// SOAP body is used, without its namespace prefix.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoapAction;
+
+impl ContentKey for SoapAction {
+	fn content_key(&self, request: &Request<Body>, prefix: &[u8]) -> Option<String> {
+		let headers = request.headers();
+		let header_action = headers
+			.get(SOAP_ACTION)
+			.and_then(|v| v.to_str().ok())
+			.or_else(|| {
+				let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+				content_type.split(';').find_map(|param| {
+					let (name, value) = param.split_once('=')?;
+					name.trim().eq_ignore_ascii_case("action").then_some(value)
+				})
+			})
+			.map(|action| action.trim().trim_matches('"'))
+			.filter(|action| !action.is_empty());
+		if let Some(action) = header_action {
+			return Some(action.to_string());
+		}
+
+		let text = std::str::from_utf8(prefix)
+			.or_else(|e| std::str::from_utf8(&prefix[..e.valid_up_to()]))
+			.ok()?;
+		let mut tags = text.split('<').skip(1).map(|tag| {
+			tag.split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+				.next()
+				.unwrap_or_default()
+		});
+		let local = |name: &str| name.rsplit(':').next().unwrap_or_default().to_string();
+		tags.find(|name| local(name) == "Body")?;
+		tags.find(|name| !name.starts_with(['/', '?', '!']))
+			.map(local)
+	}
+}
+
+/// A request handler that routes requests based on the start of their body, for example
+/// by [`JsonRpcMethod`] or [`SoapAction`]
+///
+/// Up to [`max_prefix`](Self::max_prefix) bytes of the body are read to pick the route,
+/// and the whole body is given to the route's request handler. Requests without a known route
+/// go to the [`default`](Self::default) handler if there is one, and are answered with
+/// `404 Not Found` otherwise.
+pub struct ContentRoute<H: RequestHandler, K: ContentKey> {
+	/// The [`ContentKey`] picking the route of a request
+	pub key: Arc<K>,
+	/// The request handlers of the routes
+	pub routes: Arc<HashMap<String, H>>,
+	/// The request handler for requests without a known route
+	pub default: Option<Arc<H>>,
+	/// The number of bytes of the body that are looked at
+	pub max_prefix: usize,
+}
+
+impl<H: RequestHandler, K: ContentKey> ContentRoute<H, K> {
+	/// Create a [`ContentRoute`] that looks at the first 4 KiB of bodies
+	pub fn new(key: K, routes: HashMap<String, H>) -> Self {
+		Self {
+			key: Arc::new(key),
+			routes: Arc::new(routes),
+			default: None,
+			max_prefix: 4096,
+		}
+	}
+
+	/// Set the request handler for requests without a known route
+	pub fn with_default(mut self, handler: H) -> Self {
+		self.default = Some(Arc::new(handler));
+		self
+	}
+}
+
+fn unknown_route() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::NOT_FOUND)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("No route matches the request.\n"))
+		.unwrap()
+}
+
+/// The error type for `<`[`ContentRoute`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum ContentRouteError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The route's request handler returned an error
+	Inner(E),
+	#[error("failed to read request body: {0}")]
+	/// The request body couldn't be read
+	Body(hyper::Error),
+}
+
+type ContentRouteFuture<E> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, ContentRouteError<E>>> + Send>>;
+
+impl<H, K> RequestHandler for ContentRoute<H, K>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	K: ContentKey + Send + Sync + 'static,
+{
+	type Error = ContentRouteError<H::Error>;
+	type Output = ContentRouteFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let key = self.key.clone();
+		let routes = self.routes.clone();
+		let default = self.default.clone();
+		let max_prefix = self.max_prefix;
+		let client = client.clone();
+
+		Box::pin(async move {
+			let (parts, body) = request.into_parts();
+			let (prefix, body) = peek(body, max_prefix)
+				.await
+				.map_err(ContentRouteError::Body)?;
+			let request = Request::from_parts(parts, body);
+
+			let handler = key
+				.content_key(&request, &prefix)
+				.and_then(|route| routes.get(&route))
+				.or(default.as_deref());
+			match handler {
+				Some(handler) => handler
+					.handle(from_addr, request, &client)
+					.await
+					.map_err(ContentRouteError::Inner),
+				None => Ok(unknown_route()),
+			}
+		})
+	}
+}
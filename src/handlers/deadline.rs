@@ -0,0 +1,180 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::time::timeout_at;
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The header gRPC uses for deadlines, e.g. `grpc-timeout: 250m`
+pub const GRPC_TIMEOUT: HeaderName = HeaderName::from_static("grpc-timeout");
+/// The header for deadlines of plain HTTP requests, holding the remaining milliseconds
+pub const X_REQUEST_DEADLINE: HeaderName = HeaderName::from_static("x-request-deadline");
+
+/// The point in time by which a request has to be answered, as determined by [`Deadline`]
+///
+/// This is inserted into the request extensions, so inner handlers (e.g. retries) can
+/// check how much time is left.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RequestDeadline(pub Instant);
+
+/// Parse the value of a `grpc-timeout` header
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+	if value.len() < 2 || value.len() > 9 {
+		return None;
+	}
+	let (amount, unit) = value.split_at(value.len() - 1);
+	let amount = amount.parse::<u64>().ok()?;
+	Some(match unit {
+		"H" => Duration::from_secs(amount * 60 * 60),
+		"M" => Duration::from_secs(amount * 60),
+		"S" => Duration::from_secs(amount),
+		"m" => Duration::from_millis(amount),
+		"u" => Duration::from_micros(amount),
+		"n" => Duration::from_nanos(amount),
+		_ => return None,
+	})
+}
+
+/// Format a duration as the value of a `grpc-timeout` header, using the finest unit
+/// that fits into the 8 digits allowed
+pub fn format_grpc_timeout(timeout: Duration) -> String {
+	const MAX: u128 = 99_999_999;
+
+	let units = [
+		(timeout.as_nanos(), "n"),
+		(timeout.as_micros(), "u"),
+		(timeout.as_millis(), "m"),
+		(u128::from(timeout.as_secs()), "S"),
+		(u128::from(timeout.as_secs() / 60), "M"),
+	];
+	match units.iter().find(|(amount, _)| *amount <= MAX) {
+		Some((amount, unit)) => format!("{}{}", amount, unit),
+		None => format!("{}H", (timeout.as_secs() / 60 / 60).min(MAX as u64)),
+	}
+}
+
+fn inbound_timeout(headers: &HeaderMap) -> Option<Duration> {
+	let grpc = headers
+		.get(GRPC_TIMEOUT)
+		.and_then(|v| v.to_str().ok())
+		.and_then(parse_grpc_timeout);
+	let http = headers
+		.get(X_REQUEST_DEADLINE)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.trim().parse::<u64>().ok())
+		.map(Duration::from_millis);
+	match (grpc, http) {
+		(Some(a), Some(b)) => Some(a.min(b)),
+		(a, b) => a.or(b),
+	}
+}
+
+/// A request handler combinator that propagates request deadlines
+///
+/// The inbound deadline is read from `grpc-timeout` or [`X-Request-Deadline`](X_REQUEST_DEADLINE)
+/// (falling back to [`default_timeout`](Self::default_timeout)) and enforced on the inner request
+/// handler. The header the deadline arrived in is rewritten to the remaining time minus
+/// [`own_budget`](Self::own_budget), the time reserved for the proxy itself, so upstreams give
+/// up before the proxy has to and timeouts compose across hops.
+///
+/// If the deadline passes before the inner request handler responds (or the own budget leaves
+/// no time for it), the request is answered with `504 Gateway Timeout`, or for gRPC requests
+/// with a trailers-only response with `grpc-status: 4` (`DEADLINE_EXCEEDED`).
+pub struct Deadline<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The time reserved for the proxy's own processing
+	pub own_budget: Duration,
+	/// The deadline of requests that don't carry one
+	pub default_timeout: Option<Duration>,
+}
+
+impl<H: RequestHandler> Deadline<H> {
+	/// Create a [`Deadline`] with an own budget of 10 milliseconds and no default timeout
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner,
+			own_budget: Duration::from_millis(10),
+			default_timeout: None,
+		}
+	}
+}
+
+type DeadlineFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+	headers
+		.get(CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.split(';').next())
+		.map(|v| v.trim().to_ascii_lowercase())
+		.is_some_and(|v| v == "application/grpc" || v.starts_with("application/grpc+"))
+}
+
+fn deadline_exceeded(grpc: bool) -> Response<Body> {
+	if grpc {
+		// gRPC clients expect the status 200 with the gRPC status in the headers of a
+		// trailers-only response
+		return Response::builder()
+			.header(CONTENT_TYPE, "application/grpc")
+			.header("grpc-status", "4")
+			.header("grpc-message", "deadline exceeded")
+			.body(Body::empty())
+			.unwrap();
+	}
+	Response::builder()
+		.status(StatusCode::GATEWAY_TIMEOUT)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(
+			"The request was not answered before its deadline.\n",
+		))
+		.unwrap()
+}
+
+impl<H: RequestHandler> RequestHandler for Deadline<H> {
+	type Error = H::Error;
+	type Output = DeadlineFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let timeout = match inbound_timeout(request.headers()).or(self.default_timeout) {
+			Some(timeout) => timeout,
+			None => return Box::pin(self.inner.handle(from_addr, request, client)),
+		};
+		let grpc = is_grpc(request.headers());
+		let remaining = match timeout.checked_sub(self.own_budget) {
+			Some(remaining) if remaining > Duration::ZERO => remaining,
+			_ => return Box::pin(async move { Ok(deadline_exceeded(grpc)) }),
+		};
+
+		let deadline = Instant::now() + timeout;
+		let headers = request.headers_mut();
+		let in_grpc_timeout = headers.contains_key(GRPC_TIMEOUT);
+		headers.remove(GRPC_TIMEOUT);
+		headers.remove(X_REQUEST_DEADLINE);
+		let (name, value) = if in_grpc_timeout {
+			(GRPC_TIMEOUT, format_grpc_timeout(remaining))
+		} else {
+			(X_REQUEST_DEADLINE, remaining.as_millis().max(1).to_string())
+		};
+		headers.insert(name, HeaderValue::from_str(&value).unwrap());
+		request.extensions_mut().insert(RequestDeadline(deadline));
+
+		let fut = self.inner.handle(from_addr, request, client);
+		Box::pin(async move {
+			match timeout_at(deadline.into(), fut).await {
+				Ok(res) => res,
+				Err(_) => Ok(deadline_exceeded(grpc)),
+			}
+		})
+	}
+}
@@ -0,0 +1,368 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::future::join_all;
+use hyper::header::{
+	HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING,
+};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Client, Method, Request, Response, Uri};
+use thiserror::Error;
+
+use crate::body::{buffer, is_streaming, Buffered};
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The config of an [`Esi`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EsiConfig {
+	/// Whether only responses with a `Surrogate-Control: content="ESI/1.0"` header are processed
+	///
+	/// If this is `false`, all HTML responses are processed.
+	pub require_surrogate_control: bool,
+	/// How deep includes may be nested in fragments
+	pub max_depth: usize,
+	/// The maximum size of a response or fragment in bytes; larger ones are passed on unprocessed
+	pub max_body_size: usize,
+}
+
+impl Default for EsiConfig {
+	fn default() -> Self {
+		Self {
+			require_surrogate_control: true,
+			max_depth: 3,
+			max_body_size: 1 << 20,
+		}
+	}
+}
+
+/// A request handler combinator that processes Edge Side Includes in the responses of another
+/// request handler
+///
+/// Supported are `<esi:include src="..." alt="..." onerror="continue"/>` (fetched with `GET`
+/// sub-requests through the inner request handler, so fragments can be cached separately),
+/// `<esi:remove>...</esi:remove>` and `<!--esi ...-->`. [Streaming](crate::body::is_streaming)
+/// responses are never processed.
+///
+/// The `Accept-Encoding` header is removed from requests and sub-requests, so the upstream
+/// sends documents and fragments uncompressed; responses that are compressed anyway, or that
+/// aren't valid UTF-8, are passed on unprocessed. Fragments that aren't valid UTF-8 are included
+/// as they are, without processing includes in them.
+pub struct Esi<H: RequestHandler> {
+	/// The inner request handler to give requests and sub-requests to
+	pub inner: Arc<H>,
+	/// The config
+	pub config: EsiConfig,
+}
+
+impl<H: RequestHandler> Esi<H> {
+	/// Create an [`Esi`] with the default config
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			config: EsiConfig::default(),
+		}
+	}
+}
+
+/// The error type for `<`[`Esi`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum EsiError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("failed to read body: {0}")]
+	/// A response or fragment body could not be read
+	Body(hyper::Error),
+	#[error("failed to include {0}")]
+	/// An include failed and had neither an `alt` nor `onerror="continue"`
+	IncludeFailed(String),
+}
+
+type EsiFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, EsiError<E>>> + Send>>;
+
+// Whether the body is compressed (or otherwise encoded)
+fn is_encoded(headers: &HeaderMap) -> bool {
+	headers
+		.get(CONTENT_ENCODING)
+		.is_some_and(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"))
+}
+
+fn should_process(config: &EsiConfig, headers: &HeaderMap) -> bool {
+	let is_html = headers
+		.get(CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|ct| ct.trim_start().starts_with("text/html"));
+	let announced = headers
+		.get("surrogate-control")
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|v| v.contains("ESI/1.0"));
+
+	is_html && !is_encoded(headers) && (announced || !config.require_surrogate_control)
+}
+
+enum Part {
+	Text(String),
+	Include {
+		src: String,
+		alt: Option<String>,
+		continue_on_error: bool,
+	},
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+	let pattern = format!("{}=", name);
+	let start = tag.find(&pattern)? + pattern.len();
+	let rest = &tag[start..];
+	let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+	let end = rest[1..].find(quote)?;
+	Some(rest[1..=end].to_string())
+}
+
+// Remove the `<!--esi` and `-->` around content that is only meant for ESI processors
+fn unwrap_comments(doc: &str) -> String {
+	let mut out = String::with_capacity(doc.len());
+	let mut rest = doc;
+
+	while let Some(start) = rest.find("<!--esi") {
+		let inner = &rest[start + "<!--esi".len()..];
+		let end = match inner.find("-->") {
+			Some(end) => end,
+			None => break,
+		};
+		out.push_str(&rest[..start]);
+		out.push_str(&inner[..end]);
+		rest = &inner[end + "-->".len()..];
+	}
+
+	out.push_str(rest);
+	out
+}
+
+// Split a document into text and includes, dropping `esi:remove` blocks
+fn parse(doc: &str) -> Vec<Part> {
+	let doc = unwrap_comments(doc);
+	let mut parts = Vec::new();
+	let mut text = String::new();
+	let mut rest = doc.as_str();
+
+	while let Some(pos) = rest.find("<esi:") {
+		text.push_str(&rest[..pos]);
+		rest = &rest[pos..];
+
+		if rest.starts_with("<esi:remove") {
+			match rest.find("</esi:remove>") {
+				Some(end) => rest = &rest[end + "</esi:remove>".len()..],
+				None => break,
+			}
+		} else if rest.starts_with("<esi:include") {
+			let end = match rest.find('>') {
+				Some(end) => end,
+				None => break,
+			};
+			let tag = &rest[..end];
+			rest = &rest[end + 1..];
+			if !tag.ends_with('/') {
+				if let Some(close) = rest.find("</esi:include>") {
+					rest = &rest[close + "</esi:include>".len()..];
+				}
+			}
+
+			if let Some(src) = attribute(tag, "src") {
+				parts.push(Part::Text(std::mem::take(&mut text)));
+				parts.push(Part::Include {
+					src,
+					alt: attribute(tag, "alt"),
+					continue_on_error: attribute(tag, "onerror").as_deref() == Some("continue"),
+				});
+			}
+		} else {
+			text.push_str("<esi:");
+			rest = &rest["<esi:".len()..];
+		}
+	}
+
+	text.push_str(rest);
+	parts.push(Part::Text(text));
+	parts
+}
+
+// Resolve the `src` of an include relative to the URI of the including document
+fn resolve_src(base: &Uri, src: &str) -> Option<Uri> {
+	let uri: Uri = src.parse().ok()?;
+	if uri.scheme().is_some() {
+		return Some(uri);
+	}
+
+	let path = if src.starts_with('/') {
+		src.to_string()
+	} else {
+		let base_path = base.path();
+		format!(
+			"{}{}",
+			&base_path[..=base_path.rfind('/').unwrap_or(0)],
+			src
+		)
+	};
+
+	let mut parts = base.clone().into_parts();
+	parts.path_and_query = Some(path.parse::<PathAndQuery>().ok()?);
+	Uri::from_parts(parts).ok()
+}
+
+struct Context<H> {
+	inner: Arc<H>,
+	config: EsiConfig,
+	client: Client<Connector>,
+	from_addr: SocketAddr,
+	headers: HeaderMap,
+}
+
+fn process<H: RequestHandler + Send + Sync + 'static>(
+	cx: Arc<Context<H>>,
+	base: Uri,
+	doc: String,
+	depth: usize,
+) -> EsiFuture<Vec<u8>, H::Error> {
+	Box::pin(async move {
+		let parts = parse(&doc);
+		let fetches = parts.iter().map(|part| {
+			let cx = cx.clone();
+			let base = base.clone();
+			async move {
+				match part {
+					Part::Text(text) => Ok(text.clone().into_bytes()),
+					Part::Include {
+						src,
+						alt,
+						continue_on_error,
+					} => {
+						let mut res = fetch(cx.clone(), &base, src, depth).await;
+						if res.is_err() {
+							if let Some(alt) = alt {
+								res = fetch(cx.clone(), &base, alt, depth).await;
+							}
+						}
+						match res {
+							Err(_) if *continue_on_error => Ok(Vec::new()),
+							res => res,
+						}
+					}
+				}
+			}
+		});
+
+		Ok(join_all(fetches)
+			.await
+			.into_iter()
+			.collect::<Result<Vec<_>, _>>()?
+			.concat())
+	})
+}
+
+async fn fetch<H: RequestHandler + Send + Sync + 'static>(
+	cx: Arc<Context<H>>,
+	base: &Uri,
+	src: &str,
+	depth: usize,
+) -> Result<Vec<u8>, EsiError<H::Error>> {
+	let failed = || EsiError::IncludeFailed(src.to_string());
+
+	if depth >= cx.config.max_depth {
+		return Err(failed());
+	}
+	let uri = resolve_src(base, src).ok_or_else(failed)?;
+
+	let mut request = Request::builder()
+		.method(Method::GET)
+		.uri(uri.clone())
+		.body(Body::empty())
+		.unwrap();
+	*request.headers_mut() = cx.headers.clone();
+
+	let response = cx
+		.inner
+		.handle(cx.from_addr, request, &cx.client)
+		.await
+		.map_err(EsiError::Inner)?;
+	if !response.status().is_success() {
+		return Err(failed());
+	}
+
+	// A compressed fragment can't be put into the document
+	if is_encoded(response.headers()) {
+		return Err(failed());
+	}
+	let process_nested = should_process(&cx.config, response.headers());
+	let body = match buffer(response.into_body(), cx.config.max_body_size)
+		.await
+		.map_err(EsiError::Body)?
+	{
+		Buffered::Complete(body) => body,
+		Buffered::Partial(_) => return Err(failed()),
+	};
+
+	match String::from_utf8(body.to_vec()) {
+		Ok(fragment) if process_nested => process(cx, uri, fragment, depth + 1).await,
+		Ok(fragment) => Ok(fragment.into_bytes()),
+		Err(e) => Ok(e.into_bytes()),
+	}
+}
+
+impl<H: RequestHandler + Send + Sync + 'static> RequestHandler for Esi<H> {
+	type Error = EsiError<H::Error>;
+	type Output = EsiFuture<Response<Body>, H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let base = request.uri().clone();
+		let mut headers = request.headers().clone();
+		headers.remove(CONTENT_LENGTH);
+		headers.remove(CONTENT_TYPE);
+		headers.remove(TRANSFER_ENCODING);
+		headers.remove(ACCEPT_ENCODING);
+
+		let cx = Arc::new(Context {
+			inner: self.inner.clone(),
+			config: self.config.clone(),
+			client: client.clone(),
+			from_addr,
+			headers,
+		});
+		let mut request = request;
+		request.headers_mut().remove(ACCEPT_ENCODING);
+		let fut = self.inner.handle(from_addr, request, client);
+
+		Box::pin(async move {
+			let response = fut.await.map_err(EsiError::Inner)?;
+			if is_streaming(&response) || !should_process(&cx.config, response.headers()) {
+				return Ok(response);
+			}
+
+			let (mut parts, body) = response.into_parts();
+			let body = match buffer(body, cx.config.max_body_size)
+				.await
+				.map_err(EsiError::Body)?
+			{
+				Buffered::Complete(body) => body,
+				Buffered::Partial(body) => return Ok(Response::from_parts(parts, body)),
+			};
+
+			let doc = match String::from_utf8(body.to_vec()) {
+				Ok(doc) => doc,
+				Err(_) => return Ok(Response::from_parts(parts, Body::from(body))),
+			};
+			let processed = process(cx, base, doc, 0).await?;
+
+			parts.headers.remove("surrogate-control");
+			parts.headers.remove(CONTENT_LENGTH);
+			Ok(Response::from_parts(parts, Body::from(processed)))
+		})
+	}
+}
@@ -0,0 +1,133 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::body::Bytes;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use thiserror::Error;
+
+use crate::body::{buffer, copy_context, copy_request, Buffered};
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A request handler combinator that gives requests to a fallback request handler if the
+/// primary one fails
+///
+/// If the primary request handler returns an error or responds with one of the
+/// [`fallback_statuses`](Self::fallback_statuses), the request is given to the fallback request
+/// handler instead, whose response or error is given back.
+///
+/// To be able to send a request again, its body is buffered; requests with bodies larger than
+/// [`max_body_size`](Self::max_body_size) are only given to the primary request handler.
+/// Only the primary request handler gets all extensions of the request; the fallback one gets
+/// the ones describing its connection and context, like its
+/// [`ProxiedAddrs`](crate::proxy_protocol::ProxiedAddrs),
+/// [`OriginalDestination`](crate::transparent::OriginalDestination) and
+/// [`RequestDeadline`](super::deadline::RequestDeadline).
+pub struct Fallback<A: RequestHandler, B: RequestHandler> {
+	/// The request handler that is tried first
+	pub primary: Arc<A>,
+	/// The request handler that is tried if the primary one fails
+	pub fallback: Arc<B>,
+	/// The response statuses of the primary request handler that are given to the fallback one
+	pub fallback_statuses: Vec<StatusCode>,
+	/// The largest request body that is buffered for the fallback request handler
+	pub max_body_size: usize,
+}
+
+impl<A: RequestHandler, B: RequestHandler> Fallback<A, B> {
+	/// Create a [`Fallback`] that falls back on errors as well as `502 Bad Gateway`,
+	/// `503 Service Unavailable` and `504 Gateway Timeout`
+	pub fn new(primary: A, fallback: B) -> Self {
+		Self {
+			primary: Arc::new(primary),
+			fallback: Arc::new(fallback),
+			fallback_statuses: vec![
+				StatusCode::BAD_GATEWAY,
+				StatusCode::SERVICE_UNAVAILABLE,
+				StatusCode::GATEWAY_TIMEOUT,
+			],
+			max_body_size: 64 * 1024,
+		}
+	}
+}
+
+/// The error type for `<`[`Fallback`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum FallbackError<A: std::error::Error, B: std::error::Error> {
+	#[error("{0}")]
+	/// The primary request handler returned an error for a request that couldn't be given to the
+	/// fallback one
+	Primary(A),
+	#[error("{0}")]
+	/// The fallback request handler returned an error
+	Fallback(B),
+}
+
+type FallbackFuture<A, B> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, FallbackError<A, B>>> + Send>>;
+
+impl<A, B> RequestHandler for Fallback<A, B>
+where
+	A: RequestHandler + Send + Sync + 'static,
+	B: RequestHandler + Send + Sync + 'static,
+{
+	type Error = FallbackError<A::Error, B::Error>;
+	type Output = FallbackFuture<A::Error, B::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let primary = self.primary.clone();
+		let fallback = self.fallback.clone();
+		let client = client.clone();
+		let fallback_statuses = self.fallback_statuses.clone();
+		let max_body_size = self.max_body_size;
+
+		Box::pin(async move {
+			let (mut parts, body) = request.into_parts();
+			let body = match buffer(body, max_body_size).await {
+				Ok(Buffered::Complete(body)) => body,
+				Ok(Buffered::Partial(body)) => {
+					let request = Request::from_parts(parts, body);
+					return primary
+						.handle(from_addr, request, &client)
+						.await
+						.map_err(FallbackError::Primary);
+				}
+				// The client went away; let the primary handler deal with the broken body
+				Err(e) => {
+					let body =
+						Body::wrap_stream(futures::stream::once(async { Err::<Bytes, _>(e) }));
+					let request = Request::from_parts(parts, body);
+					return primary
+						.handle(from_addr, request, &client)
+						.await
+						.map_err(FallbackError::Primary);
+				}
+			};
+			// The primary request handler gets all extensions, the fallback one the context of
+			// the request
+			let extensions = std::mem::take(&mut parts.extensions);
+			copy_context(&extensions, &mut parts.extensions);
+			let mut request = copy_request(&parts, &body);
+			*request.extensions_mut() = extensions;
+			match primary.handle(from_addr, request, &client).await {
+				Ok(response) if !fallback_statuses.contains(&response.status()) => {
+					return Ok(response)
+				}
+				_ => {}
+			}
+
+			let request = copy_request(&parts, &body);
+			fallback
+				.handle(from_addr, request, &client)
+				.await
+				.map_err(FallbackError::Fallback)
+		})
+	}
+}
@@ -1,18 +1,49 @@
 use std::collections::HashSet;
-use std::future::{ready, Ready};
+use std::future::{ready, Future, Ready};
 use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use futures::future::{Either, FutureExt, Map};
-use hyper::client::HttpConnector;
-use hyper::{Body, Client, Request, Response};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use hyper::http::uri::Authority;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use regex::Regex;
 use thiserror::Error;
 
+use crate::connect::Connector;
+use crate::metrics::CounterFamily;
 use crate::RequestHandler;
 
 /// The exchangable part of a [`Filter`]
 pub trait FilterLogic {
 	/// Return whether the request should be let through
 	fn filter(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool;
+
+	/// Let requests through only if both `self` and `other` let them through
+	fn and<G: FilterLogic>(self, other: G) -> And<Self, G>
+	where
+		Self: Sized,
+	{
+		And(self, other)
+	}
+
+	/// Let requests through if `self` or `other` lets them through
+	fn or<G: FilterLogic>(self, other: G) -> Or<Self, G>
+	where
+		Self: Sized,
+	{
+		Or(self, other)
+	}
+
+	/// Let requests through only if `self` blocks them
+	fn not(self) -> Not<Self>
+	where
+		Self: Sized,
+	{
+		Not(self)
+	}
 }
 
 /// Obtain a [`FilterLogic`] from a function/closure
@@ -28,6 +59,112 @@ pub fn filter_fn<F: Fn(SocketAddr, &Request<Body>) -> bool>(f: F) -> impl Filter
 	FilterFn(f)
 }
 
+/// A [`FilterLogic`] that lets requests through only if both of its [`FilterLogic`]s do
+///
+/// The second one isn't asked if the first one blocks the request.
+#[derive(Debug, Clone)]
+pub struct And<A: FilterLogic, B: FilterLogic>(pub A, pub B);
+
+impl<A: FilterLogic, B: FilterLogic> FilterLogic for And<A, B> {
+	fn filter(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool {
+		self.0.filter(from_addr, request) && self.1.filter(from_addr, request)
+	}
+}
+
+/// A [`FilterLogic`] that lets requests through if either of its [`FilterLogic`]s does
+///
+/// The second one isn't asked if the first one lets the request through.
+#[derive(Debug, Clone)]
+pub struct Or<A: FilterLogic, B: FilterLogic>(pub A, pub B);
+
+impl<A: FilterLogic, B: FilterLogic> FilterLogic for Or<A, B> {
+	fn filter(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool {
+		self.0.filter(from_addr, request) || self.1.filter(from_addr, request)
+	}
+}
+
+/// A [`FilterLogic`] that lets requests through only if its [`FilterLogic`] blocks them
+#[derive(Debug, Clone)]
+pub struct Not<F: FilterLogic>(pub F);
+
+impl<F: FilterLogic> FilterLogic for Not<F> {
+	fn filter(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool {
+		!self.0.filter(from_addr, request)
+	}
+}
+
+/// The error type of an [`AsyncFilterLogic`]
+#[derive(Debug, Error)]
+#[error("filter failed: {0}")]
+pub struct FilterLogicError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// The future type of an [`AsyncFilterLogic`]
+pub type FilterLogicFuture = Pin<Box<dyn Future<Output = Result<bool, FilterLogicError>> + Send>>;
+
+/// The exchangable part of an [`AsyncFilter`], for filters that need to wait for something,
+/// like an external auth service or a database
+///
+/// Every [`FilterLogic`] is an [`AsyncFilterLogic`] as well.
+pub trait AsyncFilterLogic {
+	/// Return whether the request should be let through
+	fn filter_async(
+		&self,
+		from_addr: SocketAddr,
+		request: &Request<Body>,
+		client: &Client<Connector>,
+	) -> FilterLogicFuture;
+}
+
+impl<F: FilterLogic> AsyncFilterLogic for F {
+	fn filter_async(
+		&self,
+		from_addr: SocketAddr,
+		request: &Request<Body>,
+		_: &Client<Connector>,
+	) -> FilterLogicFuture {
+		Box::pin(ready(Ok(self.filter(from_addr, request))))
+	}
+}
+
+/// Obtain an [`AsyncFilterLogic`] from a function/closure returning a future
+///
+/// ```
+/// use proxylib::handlers::filter::{async_filter_fn, FilterLogicError};
+///
+/// let logic = async_filter_fn(|_addr, request, client| {
+///     let check = format!("http://auth.internal/check{}", request.uri().path());
+///     let lookup = client.get(check.parse().unwrap());
+///     async move {
+///         let response = lookup.await.map_err(|e| FilterLogicError(e.into()))?;
+///         Ok(response.status().is_success())
+///     }
+/// });
+/// ```
+pub fn async_filter_fn<F, Fut>(f: F) -> impl AsyncFilterLogic
+where
+	F: Fn(SocketAddr, &Request<Body>, &Client<Connector>) -> Fut,
+	Fut: Future<Output = Result<bool, FilterLogicError>> + Send + 'static,
+{
+	struct AsyncFilterFn<F>(F);
+
+	impl<F, Fut> AsyncFilterLogic for AsyncFilterFn<F>
+	where
+		F: Fn(SocketAddr, &Request<Body>, &Client<Connector>) -> Fut,
+		Fut: Future<Output = Result<bool, FilterLogicError>> + Send + 'static,
+	{
+		fn filter_async(
+			&self,
+			from_addr: SocketAddr,
+			request: &Request<Body>,
+			client: &Client<Connector>,
+		) -> FilterLogicFuture {
+			Box::pin((self.0)(from_addr, request, client))
+		}
+	}
+
+	AsyncFilterFn(f)
+}
+
 /// A request handler combinator that filters requests before giving those that passed to
 /// another request handler
 pub struct Filter<H: RequestHandler, F: FilterLogic> {
@@ -35,6 +172,44 @@ pub struct Filter<H: RequestHandler, F: FilterLogic> {
 	pub inner: H,
 	/// The [`FilterLogic`] providing the filtering functionality
 	pub logic: F,
+	/// Builds the response sent to clients whose requests are filtered out
+	///
+	/// If it is `None`, filtered out requests fail with [`FilterError::FilteredOut`],
+	/// which closes the client connection.
+	pub blocked_response: Option<BlockedResponse>,
+}
+
+/// A function building the response for a request that was filtered out, see
+/// [`Filter::blocked_response`]
+pub type BlockedResponse = Arc<dyn Fn(SocketAddr, &Request<Body>) -> Response<Body> + Send + Sync>;
+
+impl<H: RequestHandler, F: FilterLogic> Filter<H, F> {
+	/// Create a [`Filter`] that fails filtered out requests with [`FilterError::FilteredOut`]
+	pub fn new(inner: H, logic: F) -> Self {
+		Self {
+			inner,
+			logic,
+			blocked_response: None,
+		}
+	}
+
+	/// Answer filtered out requests with the response built by `f` instead
+	pub fn with_blocked_response<R>(mut self, f: R) -> Self
+	where
+		R: Fn(SocketAddr, &Request<Body>) -> Response<Body> + Send + Sync + 'static,
+	{
+		self.blocked_response = Some(Arc::new(f));
+		self
+	}
+
+	/// Answer filtered out requests with an empty `403 Forbidden`
+	pub fn with_forbidden(self) -> Self {
+		self.with_blocked_response(|_, _| {
+			let mut response = Response::new(Body::empty());
+			*response.status_mut() = StatusCode::FORBIDDEN;
+			response
+		})
+	}
 }
 
 /// The error type for `<`[`Filter`]` as `[`RequestHandler`]`>`
@@ -46,6 +221,9 @@ pub enum FilterError<E: std::error::Error> {
 	#[error("request from {0} was filtered out")]
 	/// The request was filtered out
 	FilteredOut(SocketAddr, Box<Request<Body>>),
+	#[error("{0}")]
+	/// The [`AsyncFilterLogic`] of an [`AsyncFilter`] failed
+	Logic(FilterLogicError),
 }
 
 type FilterResult<E> = Result<Response<Body>, FilterError<E>>;
@@ -65,7 +243,7 @@ impl<H: RequestHandler, F: FilterLogic> RequestHandler for Filter<H, F> {
 		&self,
 		from_addr: SocketAddr,
 		request: Request<Body>,
-		client: &Client<HttpConnector>,
+		client: &Client<Connector>,
 	) -> Self::Output {
 		if self.logic.filter(from_addr, &request) {
 			Either::Left(
@@ -73,7 +251,13 @@ impl<H: RequestHandler, F: FilterLogic> RequestHandler for Filter<H, F> {
 					.handle(from_addr, request, client)
 					.map(|res: Result<_, _>| res.map_err(FilterError::Inner)),
 			)
+		} else if let Some(blocked_response) = &self.blocked_response {
+			#[cfg(feature = "tracing")]
+			tracing::debug!(client = %from_addr, "request blocked by filter");
+			Either::Right(ready(Ok(blocked_response(from_addr, &request))))
 		} else {
+			#[cfg(feature = "tracing")]
+			tracing::debug!(client = %from_addr, "request filtered out");
 			Either::Right(ready(Err(FilterError::FilteredOut(
 				from_addr,
 				Box::new(request),
@@ -82,6 +266,82 @@ impl<H: RequestHandler, F: FilterLogic> RequestHandler for Filter<H, F> {
 	}
 }
 
+/// A request handler combinator like [`Filter`], but with an [`AsyncFilterLogic`]
+///
+/// The inner request handler only gets the request once the filter has finished.
+pub struct AsyncFilter<H: RequestHandler, F: AsyncFilterLogic> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The [`AsyncFilterLogic`] providing the filtering functionality
+	pub logic: F,
+	/// Builds the response sent to clients whose requests are filtered out
+	///
+	/// If it is `None`, filtered out requests fail with [`FilterError::FilteredOut`],
+	/// which closes the client connection.
+	pub blocked_response: Option<BlockedResponse>,
+}
+
+impl<H: RequestHandler, F: AsyncFilterLogic> AsyncFilter<H, F> {
+	/// Create an [`AsyncFilter`] that fails filtered out requests with
+	/// [`FilterError::FilteredOut`]
+	pub fn new(inner: H, logic: F) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			logic,
+			blocked_response: None,
+		}
+	}
+
+	/// Answer filtered out requests with the response built by `f` instead
+	pub fn with_blocked_response<R>(mut self, f: R) -> Self
+	where
+		R: Fn(SocketAddr, &Request<Body>) -> Response<Body> + Send + Sync + 'static,
+	{
+		self.blocked_response = Some(Arc::new(f));
+		self
+	}
+}
+
+type AsyncFilterFuture<E> = Pin<Box<dyn Future<Output = FilterResult<E>> + Send>>;
+
+impl<H, F> RequestHandler for AsyncFilter<H, F>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	F: AsyncFilterLogic,
+{
+	type Error = FilterError<H::Error>;
+	type Output = AsyncFilterFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let decision = self.logic.filter_async(from_addr, &request, client);
+		let inner = self.inner.clone();
+		let blocked_response = self.blocked_response.clone();
+		let client = client.clone();
+
+		Box::pin(async move {
+			if decision.await.map_err(FilterError::Logic)? {
+				inner
+					.handle(from_addr, request, &client)
+					.await
+					.map_err(FilterError::Inner)
+			} else if let Some(blocked_response) = blocked_response {
+				#[cfg(feature = "tracing")]
+				tracing::debug!(client = %from_addr, "request blocked by filter");
+				Ok(blocked_response(from_addr, &request))
+			} else {
+				#[cfg(feature = "tracing")]
+				tracing::debug!(client = %from_addr, "request filtered out");
+				Err(FilterError::FilteredOut(from_addr, Box::new(request)))
+			}
+		})
+	}
+}
+
 /// A [`FilterLogic`] which just looks the source address up in a list of known addresses
 /// and blocks based on if it is included or not
 pub struct SocketAddrLookupFilter {
@@ -112,6 +372,7 @@ impl<H: RequestHandler> Filter<H, SocketAddrLookupFilter> {
 				list: whitelist,
 				is_blacklist: false,
 			},
+			blocked_response: None,
 		}
 	}
 
@@ -123,6 +384,7 @@ impl<H: RequestHandler> Filter<H, SocketAddrLookupFilter> {
 				list: whitelist,
 				is_blacklist: true,
 			},
+			blocked_response: None,
 		}
 	}
 }
@@ -157,6 +419,7 @@ impl<H: RequestHandler> Filter<H, IpAddrLookupFilter> {
 				list: whitelist,
 				is_blacklist: false,
 			},
+			blocked_response: None,
 		}
 	}
 
@@ -168,6 +431,736 @@ impl<H: RequestHandler> Filter<H, IpAddrLookupFilter> {
 				list: whitelist,
 				is_blacklist: true,
 			},
+			blocked_response: None,
+		}
+	}
+}
+
+/// A [`FilterLogic`] which looks the source IP up in a list of networks (e.g. `10.0.0.0/8`
+/// or `fd00::/8`) and blocks based on if it is in any of them or not
+///
+/// Clients connecting over IPv6 with an IPv4-mapped address (`::ffff:10.0.0.1`) are matched
+/// against IPv4 networks as well.
+#[derive(Debug, Clone)]
+pub struct CidrFilter {
+	/// The list of networks
+	pub networks: Vec<IpNet>,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all requests from any address in the networks will be blocked
+	/// and all others will be let through.
+	///
+	/// If it is `false`, all requests from any address **not** in the networks will be blocked
+	/// and all others will be let through.
+	pub is_blacklist: bool,
+}
+
+impl CidrFilter {
+	/// Return whether the address is in any of the networks
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		let mapped = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4),
+			IpAddr::V4(_) => None,
+		};
+		self.networks
+			.iter()
+			.any(|net| net.contains(ip) || mapped.is_some_and(|ip| net.contains(ip)))
+	}
+}
+
+impl FilterLogic for CidrFilter {
+	fn filter(&self, from_addr: SocketAddr, _: &Request<Body>) -> bool {
+		self.is_blacklist != self.contains(from_addr.ip())
+	}
+}
+
+impl<H: RequestHandler> Filter<H, CidrFilter> {
+	/// A shortcut to get a [`Filter`]`<_, `[`CidrFilter`]`>`
+	pub fn addr_whitelist(inner: H, networks: Vec<IpNet>) -> Self {
+		Self {
+			inner,
+			logic: CidrFilter {
+				networks,
+				is_blacklist: false,
+			},
+			blocked_response: None,
+		}
+	}
+
+	/// A shortcut to get a [`Filter`]`<_, `[`CidrFilter`]`>`
+	pub fn addr_blacklist(inner: H, networks: Vec<IpNet>) -> Self {
+		Self {
+			inner,
+			logic: CidrFilter {
+				networks,
+				is_blacklist: true,
+			},
+			blocked_response: None,
 		}
 	}
 }
+
+/// A [`FilterLogic`] which looks the destination host up in a list of domains
+/// and blocks based on if it is included or not
+///
+/// A domain like `example.com` only matches itself, while a wildcard like `*.example.com`
+/// matches all subdomains (but not `example.com` itself). Matching is case-insensitive.
+/// The host is taken from the URI authority if present and from the `Host` header otherwise.
+#[derive(Debug, Clone)]
+pub struct HostFilter {
+	/// The list of domains
+	pub domains: Vec<String>,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all requests to any domain in the list will be blocked
+	/// and all others will be let through.
+	///
+	/// If it is `false`, all requests to any domain **not** in the list will be blocked
+	/// (including requests without a host) and all others will be let through.
+	pub is_blacklist: bool,
+}
+
+impl HostFilter {
+	/// Return whether the host matches any of the domains
+	pub fn contains(&self, host: &str) -> bool {
+		let host = host.trim_end_matches('.');
+		self.domains.iter().any(|domain| {
+			let domain = domain.trim_end_matches('.');
+			match domain.strip_prefix("*.") {
+				Some(parent) => host
+					.len()
+					.checked_sub(parent.len() + 1)
+					.filter(|&dot| dot > 0)
+					.and_then(|dot| host.get(dot..))
+					.and_then(|suffix| suffix.strip_prefix('.'))
+					.is_some_and(|suffix| suffix.eq_ignore_ascii_case(parent)),
+				None => host.eq_ignore_ascii_case(domain),
+			}
+		})
+	}
+}
+
+impl FilterLogic for HostFilter {
+	fn filter(&self, _: SocketAddr, request: &Request<Body>) -> bool {
+		let contained = request_authority(request).is_some_and(|a| self.contains(a.host()));
+		self.is_blacklist != contained
+	}
+}
+
+impl<H: RequestHandler> Filter<H, HostFilter> {
+	/// A shortcut to get a [`Filter`]`<_, `[`HostFilter`]`>`
+	pub fn host_whitelist(inner: H, domains: Vec<String>) -> Self {
+		Self {
+			inner,
+			logic: HostFilter {
+				domains,
+				is_blacklist: false,
+			},
+			blocked_response: None,
+		}
+	}
+
+	/// A shortcut to get a [`Filter`]`<_, `[`HostFilter`]`>`
+	pub fn host_blacklist(inner: H, domains: Vec<String>) -> Self {
+		Self {
+			inner,
+			logic: HostFilter {
+				domains,
+				is_blacklist: true,
+			},
+			blocked_response: None,
+		}
+	}
+}
+
+/// A condition on the headers of a request, for [`HeaderFilter`]
+#[derive(Debug, Clone)]
+pub enum HeaderRule {
+	/// The header is present
+	Present(HeaderName),
+	/// The header is absent
+	Absent(HeaderName),
+	/// A value of the header is equal to the given one
+	Equals(HeaderName, HeaderValue),
+	/// A value of the header matches the regex
+	Matches(HeaderName, Regex),
+}
+
+impl HeaderRule {
+	/// Return whether the headers fulfill the condition
+	pub fn matches(&self, headers: &HeaderMap) -> bool {
+		match self {
+			HeaderRule::Present(name) => headers.contains_key(name),
+			HeaderRule::Absent(name) => !headers.contains_key(name),
+			HeaderRule::Equals(name, value) => headers.get_all(name).iter().any(|v| v == value),
+			HeaderRule::Matches(name, regex) => headers
+				.get_all(name)
+				.iter()
+				.filter_map(|v| v.to_str().ok())
+				.any(|v| regex.is_match(v)),
+		}
+	}
+}
+
+/// Whether all or any of the rules of a [`HeaderFilter`] have to match
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MatchMode {
+	/// All rules have to match
+	All,
+	/// At least one rule has to match
+	Any,
+}
+
+/// A [`FilterLogic`] which checks the request headers against a list of [`HeaderRule`]s
+/// and blocks based on if they match or not
+///
+/// For example, a whitelist with [`HeaderRule::Present`]`(`[`HeaderName`]`::from_static("x-api-key"))`
+/// requires an API key, while a blacklist with a [`HeaderRule::Matches`] on `User-Agent`
+/// blocks specific clients.
+#[derive(Debug, Clone)]
+pub struct HeaderFilter {
+	/// The rules
+	pub rules: Vec<HeaderRule>,
+	/// Whether all or any of the rules have to match for the request to match
+	pub mode: MatchMode,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all matching requests will be blocked and all others will be let through.
+	///
+	/// If it is `false`, all requests **not** matching will be blocked
+	/// and all others will be let through.
+	pub is_blacklist: bool,
+}
+
+impl HeaderFilter {
+	/// Return whether the headers match the rules
+	pub fn matches(&self, headers: &HeaderMap) -> bool {
+		match self.mode {
+			MatchMode::All => self.rules.iter().all(|rule| rule.matches(headers)),
+			MatchMode::Any => self.rules.iter().any(|rule| rule.matches(headers)),
+		}
+	}
+}
+
+impl FilterLogic for HeaderFilter {
+	fn filter(&self, _: SocketAddr, request: &Request<Body>) -> bool {
+		self.is_blacklist != self.matches(request.headers())
+	}
+}
+
+/// A pattern for request paths, for [`PathFilter`]
+#[derive(Debug, Clone)]
+pub struct PathPattern(Regex);
+
+impl PathPattern {
+	/// Create a pattern from a glob
+	///
+	/// `*` matches anything but `/`, `**` matches anything and `?` matches a single character
+	/// other than `/`. A trailing `/**` also matches the path without it, so `/admin/**`
+	/// matches `/admin` as well.
+	pub fn glob(glob: &str) -> Self {
+		let (glob, or_parent) = match glob.strip_suffix("/**") {
+			Some(parent) => (parent, true),
+			None => (glob, false),
+		};
+		let mut regex = String::from("^");
+		let mut chars = glob.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'*' if chars.peek() == Some(&'*') => {
+					chars.next();
+					regex.push_str(".*");
+				}
+				'*' => regex.push_str("[^/]*"),
+				'?' => regex.push_str("[^/]"),
+				c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+			}
+		}
+		if or_parent {
+			regex.push_str("(/.*)?");
+		}
+		regex.push('$');
+		Self(Regex::new(&regex).expect("escaped glob is a valid regex"))
+	}
+
+	/// Create a pattern from a regex, which matches anywhere in the path unless anchored
+	pub fn regex(regex: &str) -> Result<Self, regex::Error> {
+		Regex::new(regex).map(Self)
+	}
+
+	/// Return whether the path matches
+	pub fn matches(&self, path: &str) -> bool {
+		self.0.is_match(path)
+	}
+}
+
+/// A [`FilterLogic`] which checks the request path against a list of [`PathPattern`]s
+/// and blocks based on if any of them matches or not
+///
+/// The path is normalized before matching, so that e.g. `//admin`, `/./admin`, `/%61dmin`,
+/// `\admin` and `/admin%2Fsecret` can't be used to get around patterns for `/admin` or
+/// `/admin/**`: percent-encoded unreserved characters and separators (`%2F` and `%5C`) are
+/// decoded, `\` counts as `/`, and empty, `.` and `..` segments are resolved.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+	/// The patterns
+	pub patterns: Vec<PathPattern>,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all requests to any matching path will be blocked
+	/// and all others will be let through.
+	///
+	/// If it is `false`, all requests to any path **not** matching will be blocked
+	/// and all others will be let through.
+	pub is_blacklist: bool,
+}
+
+impl PathFilter {
+	/// Return whether the path matches any of the patterns
+	pub fn matches(&self, path: &str) -> bool {
+		let path = normalize_path(path);
+		self.patterns.iter().any(|pattern| pattern.matches(&path))
+	}
+}
+
+impl FilterLogic for PathFilter {
+	fn filter(&self, _: SocketAddr, request: &Request<Body>) -> bool {
+		self.is_blacklist != self.matches(request.uri().path())
+	}
+}
+
+impl<H: RequestHandler> Filter<H, PathFilter> {
+	/// A shortcut to get a [`Filter`]`<_, `[`PathFilter`]`>`
+	pub fn path_whitelist(inner: H, patterns: Vec<PathPattern>) -> Self {
+		Self {
+			inner,
+			logic: PathFilter {
+				patterns,
+				is_blacklist: false,
+			},
+			blocked_response: None,
+		}
+	}
+
+	/// A shortcut to get a [`Filter`]`<_, `[`PathFilter`]`>`
+	pub fn path_blacklist(inner: H, patterns: Vec<PathPattern>) -> Self {
+		Self {
+			inner,
+			logic: PathFilter {
+				patterns,
+				is_blacklist: true,
+			},
+			blocked_response: None,
+		}
+	}
+}
+
+// Decode percent-encoded unreserved characters and separators, treat `\` like `/` (as some
+// servers do), drop empty and `.` segments and resolve `..`
+fn normalize_path(path: &str) -> String {
+	let bytes = path.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let escaped = bytes
+			.get(i + 1..i + 3)
+			.filter(|_| bytes[i] == b'%')
+			.and_then(|hex| std::str::from_utf8(hex).ok())
+			.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+			.filter(|b| b.is_ascii_alphanumeric() || b"-._~/\\".contains(b));
+		match escaped {
+			Some(b) => {
+				decoded.push(b);
+				i += 3;
+			}
+			None => {
+				decoded.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+	let decoded = String::from_utf8_lossy(&decoded).replace('\\', "/");
+
+	let mut segments = Vec::new();
+	for segment in decoded.split('/') {
+		match segment {
+			"" | "." => {}
+			".." => {
+				segments.pop();
+			}
+			segment => segments.push(segment),
+		}
+	}
+	let trailing = decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..");
+	let mut normalized = format!("/{}", segments.join("/"));
+	if trailing && !segments.is_empty() {
+		normalized.push('/');
+	}
+	normalized
+}
+
+/// A source of the current time, exchangable to test time-dependent logic deterministically
+pub trait Clock {
+	/// Get the current time
+	fn now(&self) -> SystemTime;
+}
+
+/// The [`Clock`] of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> SystemTime {
+		SystemTime::now()
+	}
+}
+
+impl<F: Fn() -> SystemTime> Clock for F {
+	fn now(&self) -> SystemTime {
+		self()
+	}
+}
+
+/// A day of the week
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Weekday {
+	/// Monday
+	Monday,
+	/// Tuesday
+	Tuesday,
+	/// Wednesday
+	Wednesday,
+	/// Thursday
+	Thursday,
+	/// Friday
+	Friday,
+	/// Saturday
+	Saturday,
+	/// Sunday
+	Sunday,
+}
+
+impl Weekday {
+	/// All days of the week, starting with Monday
+	pub const ALL: [Weekday; 7] = [
+		Weekday::Monday,
+		Weekday::Tuesday,
+		Weekday::Wednesday,
+		Weekday::Thursday,
+		Weekday::Friday,
+		Weekday::Saturday,
+		Weekday::Sunday,
+	];
+
+	fn index(self) -> usize {
+		Self::ALL.iter().position(|&day| day == self).unwrap()
+	}
+
+	fn previous(self) -> Self {
+		Self::ALL[(self.index() + 6) % 7]
+	}
+}
+
+impl std::str::FromStr for Weekday {
+	type Err = ParseTimeWindowError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let names = [
+			"monday",
+			"tuesday",
+			"wednesday",
+			"thursday",
+			"friday",
+			"saturday",
+			"sunday",
+		];
+		let lower = s.to_ascii_lowercase();
+		let index = names
+			.iter()
+			.position(|name| *name == lower || (lower.len() == 3 && name.starts_with(&lower)))
+			.ok_or_else(|| ParseTimeWindowError(s.to_string()))?;
+		Ok(Self::ALL[index])
+	}
+}
+
+/// A weekly window of time, for [`ScheduleFilter`]
+///
+/// If [`end`](Self::end) is before [`start`](Self::start), the window goes on past midnight
+/// into the next day.
+///
+/// Windows can be parsed from strings like `Mon-Fri 09:00-17:00`, `Sat,Sun 10:00-14:00`
+/// or `22:00-06:00` (every day).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeWindow {
+	/// The days on which the window starts
+	pub days: Vec<Weekday>,
+	/// The start of the window, as the time since midnight
+	pub start: Duration,
+	/// The end of the window (exclusive), as the time since midnight
+	pub end: Duration,
+}
+
+impl TimeWindow {
+	/// Return whether the window contains the given day and time since midnight
+	pub fn contains(&self, day: Weekday, time: Duration) -> bool {
+		if self.start <= self.end {
+			self.days.contains(&day) && self.start <= time && time < self.end
+		} else {
+			(self.days.contains(&day) && self.start <= time)
+				|| (self.days.contains(&day.previous()) && time < self.end)
+		}
+	}
+}
+
+/// The error returned when parsing a [`TimeWindow`] or [`Weekday`] fails
+#[derive(Debug, Error)]
+#[error("invalid time window: {0:?}")]
+pub struct ParseTimeWindowError(String);
+
+impl std::str::FromStr for TimeWindow {
+	type Err = ParseTimeWindowError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || ParseTimeWindowError(s.to_string());
+		let (days, times) = match s.trim().rsplit_once(char::is_whitespace) {
+			Some((days, times)) => (Some(days.trim()), times),
+			None => (None, s.trim()),
+		};
+
+		let days = match days {
+			Some(days) => {
+				let mut parsed = Vec::new();
+				for part in days.split(',') {
+					match part.trim().split_once('-') {
+						Some((from, to)) => {
+							let (from, to) = (from.parse::<Weekday>()?, to.parse::<Weekday>()?);
+							let mut day = from;
+							parsed.push(day);
+							while day != to {
+								day = Weekday::ALL[(day.index() + 1) % 7];
+								parsed.push(day);
+							}
+						}
+						None => parsed.push(part.trim().parse()?),
+					}
+				}
+				parsed
+			}
+			None => Weekday::ALL.to_vec(),
+		};
+
+		let time = |t: &str| {
+			let (hours, minutes) = t.split_once(':')?;
+			let (hours, minutes) = (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?);
+			if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+				return None;
+			}
+			Some(Duration::from_secs(hours * 3600 + minutes * 60))
+		};
+		let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+		Ok(Self {
+			days,
+			start: time(start).ok_or_else(invalid)?,
+			end: time(end).ok_or_else(invalid)?,
+		})
+	}
+}
+
+/// A [`FilterLogic`] which lets requests through or blocks them depending on whether the current
+/// time is within any of a list of [`TimeWindow`]s
+///
+/// The time comes from a [`Clock`], which is the [`SystemClock`] unless set otherwise.
+#[derive(Debug, Clone)]
+pub struct ScheduleFilter<C: Clock = SystemClock> {
+	/// The time windows
+	pub windows: Vec<TimeWindow>,
+	/// The offset of the local time the windows are in from UTC, in minutes
+	pub utc_offset_minutes: i32,
+	/// Whether the filter acts as a blacklist (`true`) or a whitelist (`false`)
+	///
+	/// If it is `true`, all requests within any window will be blocked
+	/// and all others will be let through.
+	///
+	/// If it is `false`, all requests **not** within any window will be blocked
+	/// and all others will be let through.
+	pub is_blacklist: bool,
+	/// The clock
+	pub clock: C,
+}
+
+impl ScheduleFilter {
+	/// Create a [`ScheduleFilter`] that only lets requests through within the windows, in UTC
+	pub fn new(windows: Vec<TimeWindow>) -> Self {
+		Self {
+			windows,
+			utc_offset_minutes: 0,
+			is_blacklist: false,
+			clock: SystemClock,
+		}
+	}
+}
+
+impl<C: Clock> ScheduleFilter<C> {
+	/// Use the given clock instead
+	pub fn with_clock<D: Clock>(self, clock: D) -> ScheduleFilter<D> {
+		ScheduleFilter {
+			windows: self.windows,
+			utc_offset_minutes: self.utc_offset_minutes,
+			is_blacklist: self.is_blacklist,
+			clock,
+		}
+	}
+
+	/// Return whether the given point in time is within any of the windows
+	pub fn contains(&self, time: SystemTime) -> bool {
+		let secs = match time.duration_since(UNIX_EPOCH) {
+			Ok(d) => d.as_secs() as i64,
+			Err(e) => -(e.duration().as_secs() as i64),
+		};
+		let local = secs + i64::from(self.utc_offset_minutes) * 60;
+		// The unix epoch was a Thursday
+		let day = Weekday::ALL[(local.div_euclid(86400) + 3).rem_euclid(7) as usize];
+		let time = Duration::from_secs(local.rem_euclid(86400) as u64);
+		self.windows.iter().any(|window| window.contains(day, time))
+	}
+}
+
+impl<C: Clock> FilterLogic for ScheduleFilter<C> {
+	fn filter(&self, _: SocketAddr, _: &Request<Body>) -> bool {
+		self.is_blacklist != self.contains(self.clock.now())
+	}
+}
+
+// The destination of the request, from the URI authority if present and the `Host` header otherwise
+pub(crate) fn request_authority(request: &Request<Body>) -> Option<Authority> {
+	match request.uri().authority() {
+		Some(authority) => Some(authority.clone()),
+		None => request
+			.headers()
+			.get(HOST)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.parse::<Authority>().ok()),
+	}
+}
+
+/// An IP network in CIDR notation (e.g. `10.0.0.0/8` or `2001:db8::/32`)
+///
+/// A plain address parses as a network containing only that address.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct IpNet {
+	/// The address of the network
+	pub addr: IpAddr,
+	/// The length of the network prefix in bits
+	pub prefix_len: u8,
+}
+
+impl IpNet {
+	/// Return whether the network contains the address
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		fn masked(bits: u128, len: u8, prefix_len: u8) -> u128 {
+			match u32::from(prefix_len) {
+				0 => 0,
+				n if n >= u32::from(len) => bits,
+				n => bits >> (u32::from(len) - n),
+			}
+		}
+
+		match (self.addr, ip) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				masked(u32::from(net).into(), 32, self.prefix_len)
+					== masked(u32::from(ip).into(), 32, self.prefix_len)
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				masked(net.into(), 128, self.prefix_len) == masked(ip.into(), 128, self.prefix_len)
+			}
+			(IpAddr::V6(_), IpAddr::V4(ip)) => self.contains(IpAddr::V6(ip.to_ipv6_mapped())),
+			_ => false,
+		}
+	}
+}
+
+impl From<IpAddr> for IpNet {
+	fn from(addr: IpAddr) -> Self {
+		let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+		Self { addr, prefix_len }
+	}
+}
+
+impl std::str::FromStr for IpNet {
+	type Err = std::net::AddrParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, len) = match s.split_once('/') {
+			Some((addr, len)) => (addr, Some(len)),
+			None => (s, None),
+		};
+		let mut net = IpNet::from(addr.parse::<IpAddr>()?);
+		if let Some(len) = len {
+			// Reuse the error of `IpAddr` for invalid prefix lengths
+			let invalid = || "".parse::<IpAddr>().unwrap_err();
+			let len = len.parse::<u8>().map_err(|_| invalid())?;
+			if len > net.prefix_len {
+				return Err(invalid());
+			}
+			net.prefix_len = len;
+		}
+		Ok(net)
+	}
+}
+
+/// Create the counter family used by [`Counted`], with the labels `filter` and `outcome`
+/// (`allowed` or `blocked`)
+pub fn filter_counters() -> Arc<CounterFamily> {
+	Arc::new(CounterFamily::new(
+		"proxylib_filter_requests_total",
+		"The number of requests allowed or blocked by filters",
+		&["filter", "outcome"],
+	))
+}
+
+/// A [`FilterLogic`] that counts the requests another [`FilterLogic`] allows and blocks
+///
+/// Multiple [`Counted`]s can share one counter family, telling them apart by their names.
+pub struct Counted<F: FilterLogic> {
+	/// The [`FilterLogic`] whose decisions are counted
+	pub logic: F,
+	/// The name of the filter (or rule), used as the `filter` label
+	pub name: String,
+	/// The counters
+	pub counters: Arc<CounterFamily>,
+}
+
+impl<F: FilterLogic> Counted<F> {
+	/// Count the decisions of a [`FilterLogic`] in a new counter family
+	pub fn new(logic: F, name: impl Into<String>) -> Self {
+		Self::with_counters(logic, name, filter_counters())
+	}
+
+	/// Count the decisions of a [`FilterLogic`] in the given counter family
+	pub fn with_counters(logic: F, name: impl Into<String>, counters: Arc<CounterFamily>) -> Self {
+		Self {
+			logic,
+			name: name.into(),
+			counters,
+		}
+	}
+
+	/// Get the number of allowed requests
+	pub fn allowed(&self) -> u64 {
+		self.counters.get(&[&self.name, "allowed"])
+	}
+
+	/// Get the number of blocked requests
+	pub fn blocked(&self) -> u64 {
+		self.counters.get(&[&self.name, "blocked"])
+	}
+}
+
+impl<F: FilterLogic> FilterLogic for Counted<F> {
+	fn filter(&self, from_addr: SocketAddr, request: &Request<Body>) -> bool {
+		let allowed = self.logic.filter(from_addr, request);
+		let outcome = if allowed { "allowed" } else { "blocked" };
+		self.counters.inc(&[&self.name, outcome]);
+		allowed
+	}
+}
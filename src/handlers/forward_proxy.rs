@@ -0,0 +1,93 @@
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+
+use futures::future::Either;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST};
+use hyper::http::uri::Scheme;
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::connect::Connector;
+use crate::handlers::redirect::{downgrade_version, forward, remove_hop_by_hop_headers, Forward};
+use crate::RequestHandler;
+
+/// A request handler for clients that use the proxy as their HTTP proxy, forwarding requests
+/// to the origin named in their absolute-form target (like `GET http://example.com/ HTTP/1.1`)
+///
+/// The `Host` header is replaced with the authority of the target and hop-by-hop headers
+/// (including `Proxy-Authorization` and `Proxy-Connection`) are removed with
+/// [`remove_hop_by_hop_headers`]. Requests with an origin-form target (like `GET /`) are
+/// meant for an origin server rather than a proxy and are answered with `400 Bad Request`,
+/// as are targets with a scheme other than the [`schemes`](Self::schemes).
+///
+/// `https://` sites are reached through `CONNECT` requests, so to serve those as well, wrap
+/// the [`ForwardProxy`] in a [`Tunnel`](super::tunnel::Tunnel).
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::forward_proxy::ForwardProxy;
+/// use proxylib::handlers::tunnel::Tunnel;
+///
+/// let handler = Tunnel::new(ForwardProxy::default());
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ForwardProxy {
+	/// The schemes of the targets that are forwarded to
+	pub schemes: Vec<Scheme>,
+}
+
+impl Default for ForwardProxy {
+	/// Forward to `http://` targets, and to `https://` targets if the `tls` feature is enabled
+	fn default() -> Self {
+		Self {
+			schemes: vec![
+				Scheme::HTTP,
+				#[cfg(feature = "tls")]
+				Scheme::HTTPS,
+			],
+		}
+	}
+}
+
+fn bad_request(text: &'static str) -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::BAD_REQUEST)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(text))
+		.unwrap()
+}
+
+impl RequestHandler for ForwardProxy {
+	type Error = hyper::Error;
+	type Output = Either<Forward, Ready<hyper::Result<Response<Body>>>>;
+
+	fn handle(
+		&self,
+		_from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let (mut parts, body) = request.into_parts();
+
+		let authority = match (parts.uri.scheme(), parts.uri.authority()) {
+			(Some(scheme), Some(authority)) if self.schemes.contains(scheme) => authority.clone(),
+			(Some(_), Some(_)) => {
+				return Either::Right(ready(Ok(bad_request(
+					"Requests with this scheme are not forwarded.\n",
+				))))
+			}
+			_ => {
+				return Either::Right(ready(Ok(bad_request(
+					"Requests to a proxy need an absolute URI.\n",
+				))))
+			}
+		};
+
+		remove_hop_by_hop_headers(&mut parts.headers);
+		downgrade_version(&mut parts);
+		if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+			parts.headers.insert(HOST, host);
+		}
+
+		Either::Left(forward(client, Request::from_parts(parts, body), true))
+	}
+}
@@ -0,0 +1,166 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::header::{HeaderName, HeaderValue, FORWARDED};
+use hyper::http::uri::Scheme;
+use hyper::{Body, HeaderMap, Request, Response};
+
+use crate::handlers::filter::{request_authority, IpNet};
+use crate::handlers::middleware::Middleware;
+
+/// The `X-Forwarded-For` header
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+/// The `X-Forwarded-Proto` header
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+/// The `X-Forwarded-Host` header
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// A [`Middleware`] that tells the upstream about the client with the `X-Forwarded-*` headers
+/// and optionally the standard `Forwarded` header ([RFC 7239](https://www.rfc-editor.org/rfc/rfc7239))
+///
+/// The address of the client is appended to `X-Forwarded-For`, and `X-Forwarded-Proto` and
+/// `X-Forwarded-Host` are set to the scheme and host the client used.
+///
+/// Clients can send these headers themselves to pretend to be someone else, so they are only
+/// kept if the client is one of the [`trusted`](Self::trusted) proxies in front of this one;
+/// otherwise they are replaced.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::forwarded::ForwardedHeaders;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::RequestHandler;
+///
+/// let handler = Redirect::change_authority("backend.internal".parse().unwrap()).layer(
+///     ForwardedHeaders {
+///         trusted: vec!["10.0.0.0/8".parse().unwrap()],
+///         forwarded: true,
+///         ..ForwardedHeaders::default()
+///     },
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct ForwardedHeaders {
+	/// The proxies whose forwarding headers are kept and appended to
+	pub trusted: Vec<IpNet>,
+	/// The scheme clients use to reach the proxy
+	pub proto: Scheme,
+	/// Whether the `X-Forwarded-*` headers are set
+	pub x_forwarded: bool,
+	/// Whether the `Forwarded` header is set
+	pub forwarded: bool,
+	/// The identifier of the proxy used as `by` in the `Forwarded` header, if any
+	pub by: Option<String>,
+}
+
+impl Default for ForwardedHeaders {
+	/// Set the `X-Forwarded-*` headers for `http`, trusting no proxies
+	fn default() -> Self {
+		Self {
+			trusted: Vec::new(),
+			proto: Scheme::HTTP,
+			x_forwarded: true,
+			forwarded: false,
+			by: None,
+		}
+	}
+}
+
+// All values of the header joined into a list, if there are any and all of them are valid
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+	let values = headers
+		.get_all(name)
+		.iter()
+		.map(|v| v.to_str().ok())
+		.collect::<Option<Vec<_>>>()?;
+	if values.is_empty() {
+		None
+	} else {
+		Some(values.join(", "))
+	}
+}
+
+fn append_list(headers: &mut HeaderMap, name: HeaderName, keep: bool, element: &str) {
+	let value = match joined(headers, &name).filter(|_| keep) {
+		Some(existing) => format!("{}, {}", existing, element),
+		None => element.to_string(),
+	};
+	headers.remove(&name);
+	if let Ok(value) = HeaderValue::from_str(&value) {
+		headers.insert(name, value);
+	}
+}
+
+fn set_unless_kept(headers: &mut HeaderMap, name: HeaderName, keep: bool, value: Option<&str>) {
+	if keep && headers.contains_key(&name) {
+		return;
+	}
+	headers.remove(&name);
+	if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+		headers.insert(name, value);
+	}
+}
+
+// A value of a `Forwarded` parameter, quoted unless it is a token
+fn forwarded_value(value: &str) -> String {
+	let is_token = !value.is_empty()
+		&& value
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+	if is_token {
+		value.to_string()
+	} else {
+		format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+	}
+}
+
+impl ForwardedHeaders {
+	/// Return whether the address is one of a trusted proxy
+	pub fn is_trusted(&self, ip: IpAddr) -> bool {
+		self.trusted.iter().any(|net| net.contains(ip))
+	}
+}
+
+impl Middleware for ForwardedHeaders {
+	fn before(&self, from_addr: SocketAddr, request: &mut Request<Body>) -> Option<Response<Body>> {
+		let ip = from_addr.ip().to_canonical();
+		let trusted = self.is_trusted(ip);
+		let host = request_authority(request);
+		let host = host.as_ref().map(|host| host.as_str());
+		let headers = request.headers_mut();
+
+		if self.x_forwarded {
+			append_list(headers, X_FORWARDED_FOR, trusted, &ip.to_string());
+			set_unless_kept(
+				headers,
+				X_FORWARDED_PROTO,
+				trusted,
+				Some(self.proto.as_str()),
+			);
+			set_unless_kept(headers, X_FORWARDED_HOST, trusted, host);
+		} else if !trusted {
+			headers.remove(X_FORWARDED_FOR);
+			headers.remove(X_FORWARDED_PROTO);
+			headers.remove(X_FORWARDED_HOST);
+		}
+
+		if self.forwarded {
+			let node = match ip {
+				IpAddr::V4(ip) => ip.to_string(),
+				IpAddr::V6(ip) => format!("[{}]", ip),
+			};
+			let mut element = format!("for={}", forwarded_value(&node));
+			if let Some(by) = &self.by {
+				element += &format!(";by={}", forwarded_value(by));
+			}
+			if let Some(host) = host {
+				element += &format!(";host={}", forwarded_value(host));
+			}
+			element += &format!(";proto={}", forwarded_value(self.proto.as_str()));
+			append_list(headers, FORWARDED, trusted, &element);
+		} else if !trusted {
+			headers.remove(FORWARDED);
+		}
+
+		None
+	}
+}
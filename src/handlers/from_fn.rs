@@ -0,0 +1,54 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use hyper::{Body, Client, Request, Response};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The future type of a request handler made with [`handler_fn`]
+pub type HandlerFnFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+/// Obtain a [`RequestHandler`] from a function/closure returning a future
+///
+/// The closure gets its own clone of the client, so the future can hold on to it.
+///
+/// ```
+/// use proxylib::handlers::handler_fn;
+///
+/// let handler = handler_fn(|_addr, request, client| async move {
+///     let (mut parts, body) = request.into_parts();
+///     parts.uri = format!("http://localhost:8080{}", parts.uri.path()).parse().unwrap();
+///     client.request(hyper::Request::from_parts(parts, body)).await
+/// });
+/// ```
+pub fn handler_fn<F, Fut, E>(f: F) -> impl RequestHandler<Error = E, Output = HandlerFnFuture<E>>
+where
+	F: Fn(SocketAddr, Request<Body>, Client<Connector>) -> Fut,
+	Fut: Future<Output = Result<Response<Body>, E>> + Send + 'static,
+	E: std::error::Error + Send + Sync + 'static,
+{
+	struct HandlerFn<F>(F);
+
+	impl<F, Fut, E> RequestHandler for HandlerFn<F>
+	where
+		F: Fn(SocketAddr, Request<Body>, Client<Connector>) -> Fut,
+		Fut: Future<Output = Result<Response<Body>, E>> + Send + 'static,
+		E: std::error::Error + Send + Sync + 'static,
+	{
+		type Error = E;
+		type Output = HandlerFnFuture<E>;
+
+		fn handle(
+			&self,
+			from_addr: SocketAddr,
+			request: Request<Body>,
+			client: &Client<Connector>,
+		) -> Self::Output {
+			Box::pin((self.0)(from_addr, request, client.clone()))
+		}
+	}
+
+	HandlerFn(f)
+}
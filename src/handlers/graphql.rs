@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use graphql_parser::query::{
+	parse_query, Definition, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::body::read_limited;
+use crate::chain::percent_decode;
+use crate::connect::Connector;
+use crate::handlers::accounting::{ClientKey, IpKey};
+use crate::RequestHandler;
+
+/// The kind of a GraphQL operation
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum OperationKind {
+	/// A query
+	Query,
+	/// A mutation
+	Mutation,
+	/// A subscription
+	Subscription,
+}
+
+/// The GraphQL operation a request executes, as determined by [`GraphQl`]
+///
+/// This is inserted into the request extensions, so inner handlers (e.g. caches or rate limiters)
+/// can key on the operation name, like [`GraphQlOperationKey`] does. Batches get a
+/// [`GraphQlBatch`] instead.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GraphQlOperation {
+	/// The name of the operation, if it has one
+	pub name: Option<String>,
+	/// The kind of the operation
+	pub kind: OperationKind,
+	/// The depth of the selection, with fragments expanded
+	pub depth: usize,
+	/// The number of selected fields, with fragments expanded
+	pub complexity: usize,
+	/// Whether the operation queries the schema (`__schema` or `__type`)
+	pub introspection: bool,
+}
+
+/// The GraphQL operations a batch request executes, in order, as determined by [`GraphQl`]
+///
+/// This is inserted into the request extensions instead of a [`GraphQlOperation`] for requests
+/// whose JSON body is an array of operations.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GraphQlBatch(pub Vec<GraphQlOperation>);
+
+/// A [`ClientKey`] that tells requests apart by their [`GraphQlOperation`], e.g. to give each
+/// operation its own [`RateLimit`](crate::handlers::RateLimit)
+///
+/// The key is the kind and name of the operation (like `query` or `mutation CreateUser`), and
+/// for a [`GraphQlBatch`] those of all its operations, separated by commas. With a
+/// [`client`](Self::client) key, it is prefixed by the key of the client, so every client has
+/// its own bucket per operation. Requests that were not annotated by a [`GraphQl`] (so the
+/// handler using this key must be inside it) and requests of clients without a key have no key.
+#[derive(Debug, Clone, Default)]
+pub struct GraphQlOperationKey<K: ClientKey = IpKey> {
+	/// The [`ClientKey`] telling clients apart, if operations are keyed per client
+	pub client: Option<K>,
+}
+
+impl GraphQlOperationKey {
+	/// Create a [`GraphQlOperationKey`] that keys on the operation only
+	pub fn new() -> Self {
+		Self { client: None }
+	}
+}
+
+impl<K: ClientKey> GraphQlOperationKey<K> {
+	/// Create a [`GraphQlOperationKey`] that keys on the client and the operation
+	pub fn per_client(client: K) -> Self {
+		Self {
+			client: Some(client),
+		}
+	}
+}
+
+fn operation_key(op: &GraphQlOperation) -> String {
+	let kind = match op.kind {
+		OperationKind::Query => "query",
+		OperationKind::Mutation => "mutation",
+		OperationKind::Subscription => "subscription",
+	};
+	match &op.name {
+		Some(name) => format!("{} {}", kind, name),
+		None => kind.to_string(),
+	}
+}
+
+impl<K: ClientKey> ClientKey for GraphQlOperationKey<K> {
+	fn client_key(&self, from_addr: SocketAddr, request: &Request<Body>) -> Option<String> {
+		let extensions = request.extensions();
+		let operation = match (
+			extensions.get::<GraphQlOperation>(),
+			extensions.get::<GraphQlBatch>(),
+		) {
+			(Some(op), _) => operation_key(op),
+			(None, Some(batch)) => batch
+				.0
+				.iter()
+				.map(operation_key)
+				.collect::<Vec<_>>()
+				.join(","),
+			(None, None) => return None,
+		};
+		match &self.client {
+			Some(client) => Some(format!(
+				"{}/{}",
+				client.client_key(from_addr, request)?,
+				operation
+			)),
+			None => Some(operation),
+		}
+	}
+}
+
+/// The limits enforced by [`GraphQl`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GraphQlLimits {
+	/// The maximum depth of a selection
+	pub max_depth: Option<usize>,
+	/// The maximum number of selected fields
+	pub max_complexity: Option<usize>,
+	/// Whether introspection queries are allowed (this should usually be off in production)
+	pub allow_introspection: bool,
+	/// The maximum size of a request body in bytes
+	pub max_body_size: usize,
+	/// The maximum nesting of braces, brackets and parentheses in a document, and of fragments
+	/// spreading other fragments
+	///
+	/// This is checked before a document is parsed, so deeply nested documents can't overflow
+	/// the stack.
+	pub max_nesting: usize,
+	/// The maximum number of operations in a batch (a JSON array of requests)
+	pub max_batch_size: usize,
+}
+
+impl Default for GraphQlLimits {
+	fn default() -> Self {
+		Self {
+			max_depth: Some(15),
+			max_complexity: Some(1000),
+			allow_introspection: false,
+			max_body_size: 1 << 20,
+			max_nesting: 32,
+			max_batch_size: 10,
+		}
+	}
+}
+
+// The depth and complexity of a selection set, with fragments expanded
+#[derive(Debug, Clone, Copy, Default)]
+struct Cost {
+	depth: usize,
+	complexity: usize,
+	introspection: bool,
+}
+
+struct Walker<'a, 'd> {
+	fragments: HashMap<&'a str, &'d FragmentDefinition<'a, &'a str>>,
+	// The costs of the fragments walked so far, so fragments spread many times are walked once
+	costs: HashMap<&'a str, Cost>,
+	visiting: Vec<&'a str>,
+	max_depth: Option<usize>,
+	max_complexity: Option<usize>,
+	max_nesting: usize,
+}
+
+impl<'a, 'd> Walker<'a, 'd> {
+	// Walk a selection set that starts at the given depth, stopping once a limit is exceeded
+	fn walk(&mut self, set: &'d SelectionSet<'a, &'a str>, level: usize) -> Result<Cost, String> {
+		let mut cost = Cost::default();
+		for item in &set.items {
+			let item_cost = match item {
+				Selection::Field(field) => {
+					let child = if field.selection_set.items.is_empty() {
+						Cost::default()
+					} else {
+						self.walk(&field.selection_set, level + 1)?
+					};
+					Cost {
+						depth: 1 + child.depth,
+						complexity: child.complexity.saturating_add(1),
+						introspection: child.introspection
+							|| field.name == "__schema"
+							|| field.name == "__type",
+					}
+				}
+				Selection::InlineFragment(fragment) => self.walk(&fragment.selection_set, level)?,
+				Selection::FragmentSpread(spread) => self.fragment(spread.fragment_name)?,
+			};
+			cost.depth = cost.depth.max(item_cost.depth);
+			cost.complexity = cost.complexity.saturating_add(item_cost.complexity);
+			cost.introspection |= item_cost.introspection;
+			self.check(&cost, level)?;
+		}
+		Ok(cost)
+	}
+
+	// Get the cost of a fragment, walking it if it wasn't yet
+	fn fragment(&mut self, name: &'a str) -> Result<Cost, String> {
+		if let Some(cost) = self.costs.get(name) {
+			return Ok(*cost);
+		}
+		if self.visiting.contains(&name) {
+			return Err(format!("fragment `{}` spreads itself", name));
+		}
+		if self.visiting.len() >= self.max_nesting {
+			return Err("fragments are nested too deeply".to_string());
+		}
+		let fragment = match self.fragments.get(name) {
+			Some(fragment) => *fragment,
+			None => return Err(format!("unknown fragment `{}`", name)),
+		};
+		self.visiting.push(name);
+		let cost = self.walk(&fragment.selection_set, 0);
+		self.visiting.pop();
+		let cost = cost?;
+		self.costs.insert(name, cost);
+		Ok(cost)
+	}
+
+	fn check(&self, cost: &Cost, level: usize) -> Result<(), String> {
+		if let Some(max) = self.max_depth {
+			if level + cost.depth > max {
+				return Err(format!("query depth exceeds the limit of {}", max));
+			}
+		}
+		if let Some(max) = self.max_complexity {
+			if cost.complexity > max {
+				return Err(format!("query complexity exceeds the limit of {}", max));
+			}
+		}
+		Ok(())
+	}
+}
+
+// Whether braces, brackets and parentheses outside of strings and comments nest deeper than
+// the limit
+fn nests_deeper(query: &str, limit: usize) -> bool {
+	let bytes = query.as_bytes();
+	let mut nesting = 0usize;
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'{' | b'[' | b'(' => {
+				nesting += 1;
+				if nesting > limit {
+					return true;
+				}
+			}
+			b'}' | b']' | b')' => nesting = nesting.saturating_sub(1),
+			b'#' => {
+				while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+					i += 1;
+				}
+			}
+			b'"' if bytes[i..].starts_with(b"\"\"\"") => {
+				i += 3;
+				while i < bytes.len() && !bytes[i..].starts_with(b"\"\"\"") {
+					i += if bytes[i..].starts_with(b"\\\"\"\"") {
+						4
+					} else {
+						1
+					};
+				}
+				i += 2;
+			}
+			b'"' => {
+				i += 1;
+				while i < bytes.len() && bytes[i] != b'"' {
+					i += if bytes[i] == b'\\' { 2 } else { 1 };
+				}
+			}
+			_ => {}
+		}
+		i += 1;
+	}
+	false
+}
+
+/// Analyze a GraphQL document, choosing the operation with the given name
+/// (or the only operation if there is no name)
+///
+/// Only the [`max_nesting`](GraphQlLimits::max_nesting) of the default limits is enforced; see
+/// [`GraphQlLimits::analyze`] for enforcing limits while analyzing.
+pub fn analyze(query: &str, operation_name: Option<&str>) -> Result<GraphQlOperation, String> {
+	let limits = GraphQlLimits {
+		max_depth: None,
+		max_complexity: None,
+		allow_introspection: true,
+		..GraphQlLimits::default()
+	};
+	limits.analyze(query, operation_name)
+}
+
+fn analyze_with(
+	query: &str,
+	operation_name: Option<&str>,
+	limits: &GraphQlLimits,
+) -> Result<GraphQlOperation, String> {
+	if nests_deeper(query, limits.max_nesting) {
+		return Err(format!(
+			"the query is nested deeper than the limit of {}",
+			limits.max_nesting
+		));
+	}
+	let doc = parse_query::<&str>(query).map_err(|e| e.to_string())?;
+
+	let mut fragments = HashMap::new();
+	let mut operations = Vec::new();
+	for def in &doc.definitions {
+		match def {
+			Definition::Fragment(fragment) => {
+				fragments.insert(fragment.name, fragment);
+			}
+			Definition::Operation(op) => {
+				let (name, kind, set) = match op {
+					OperationDefinition::SelectionSet(set) => (None, OperationKind::Query, set),
+					OperationDefinition::Query(q) => {
+						(q.name, OperationKind::Query, &q.selection_set)
+					}
+					OperationDefinition::Mutation(m) => {
+						(m.name, OperationKind::Mutation, &m.selection_set)
+					}
+					OperationDefinition::Subscription(s) => {
+						(s.name, OperationKind::Subscription, &s.selection_set)
+					}
+				};
+				operations.push((name, kind, set));
+			}
+		}
+	}
+
+	let (name, kind, set) = match operation_name {
+		Some(wanted) => operations
+			.into_iter()
+			.find(|(name, _, _)| *name == Some(wanted))
+			.ok_or_else(|| format!("unknown operation `{}`", wanted))?,
+		None if operations.len() == 1 => operations.remove(0),
+		None => return Err("an operation name is required".to_string()),
+	};
+
+	let mut walker = Walker {
+		fragments,
+		costs: HashMap::new(),
+		visiting: Vec::new(),
+		max_depth: limits.max_depth,
+		max_complexity: limits.max_complexity,
+		max_nesting: limits.max_nesting,
+	};
+	let cost = walker.walk(set, 0)?;
+
+	Ok(GraphQlOperation {
+		name: name.map(str::to_string),
+		kind,
+		depth: cost.depth,
+		complexity: cost.complexity,
+		introspection: cost.introspection,
+	})
+}
+
+impl GraphQlLimits {
+	/// Analyze a GraphQL document like [`analyze`] and check the operation against the limits
+	///
+	/// Fragments are only walked once however often they are spread, and the walk stops as soon
+	/// as the depth or complexity exceeds the limit, so expensive documents are cheap to reject.
+	pub fn analyze(
+		&self,
+		query: &str,
+		operation_name: Option<&str>,
+	) -> Result<GraphQlOperation, String> {
+		let op = analyze_with(query, operation_name, self)?;
+		self.check(&op)?;
+		Ok(op)
+	}
+
+	/// Return why the operation is not allowed, if it isn't
+	pub fn check(&self, op: &GraphQlOperation) -> Result<(), String> {
+		if op.introspection && !self.allow_introspection {
+			return Err("introspection is disabled".to_string());
+		}
+		if let Some(max) = self.max_depth {
+			if op.depth > max {
+				return Err(format!(
+					"query depth {} exceeds the limit of {}",
+					op.depth, max
+				));
+			}
+		}
+		if let Some(max) = self.max_complexity {
+			if op.complexity > max {
+				return Err(format!(
+					"query complexity {} exceeds the limit of {}",
+					op.complexity, max
+				));
+			}
+		}
+		Ok(())
+	}
+
+	// Analyze a JSON request (`{"query": ..., "operationName": ...}`)
+	fn analyze_json(&self, json: &Value) -> Result<GraphQlOperation, String> {
+		let query = json
+			.get("query")
+			.and_then(Value::as_str)
+			.ok_or("missing `query`")?;
+		let name = json.get("operationName").and_then(Value::as_str);
+		self.analyze(query, name)
+	}
+
+	// Analyze every request of a JSON batch
+	fn analyze_batch(&self, batch: &[Value]) -> Result<GraphQlBatch, String> {
+		if batch.is_empty() {
+			return Err("empty batch".to_string());
+		}
+		if batch.len() > self.max_batch_size {
+			return Err(format!(
+				"batch of {} operations exceeds the limit of {}",
+				batch.len(),
+				self.max_batch_size
+			));
+		}
+		batch
+			.iter()
+			.enumerate()
+			.map(|(i, json)| {
+				self.analyze_json(json)
+					.map_err(|message| format!("operation {}: {}", i, message))
+			})
+			.collect::<Result<_, _>>()
+			.map(GraphQlBatch)
+	}
+}
+
+// The operations of a request body
+enum Analyzed {
+	Single(GraphQlOperation),
+	Batch(GraphQlBatch),
+}
+
+fn error_response(message: &str) -> Response<Body> {
+	error_response_with(StatusCode::BAD_REQUEST, message)
+}
+
+fn error_response_with(status: StatusCode, message: &str) -> Response<Body> {
+	let body = json!({ "errors": [{ "message": message }] });
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body.to_string()))
+		.unwrap()
+}
+
+// Get the `query` and `operationName` parameters of a query string, if it has a `query`
+fn url_params(query_string: &str) -> Result<Option<(String, Option<String>)>, String> {
+	let mut query = None;
+	let mut name = None;
+	for pair in query_string.split('&') {
+		let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+		let target = match key {
+			"query" => &mut query,
+			"operationName" => &mut name,
+			_ => continue,
+		};
+		let value = percent_decode(&value.replace('+', " "))
+			.ok_or_else(|| format!("invalid `{}` parameter", key))?;
+		*target = Some(value);
+	}
+	Ok(query.map(|query| (query, name)))
+}
+
+/// A request handler combinator that inspects GraphQL requests before giving those that are
+/// allowed to another request handler
+///
+/// `POST` requests with a JSON body (`{"query": ..., "operationName": ...}`) or an
+/// `application/graphql` body and requests with a `query` (and `operationName`) in the query
+/// string are parsed, checked against the [`GraphQlLimits`] and annotated with a
+/// [`GraphQlOperation`]. A JSON body can also be an array of such requests, a batch of up to
+/// [`max_batch_size`](GraphQlLimits::max_batch_size) operations that are each checked and which
+/// is annotated with a [`GraphQlBatch`]; if any of them is rejected, the whole batch is. Rejected requests are answered with a `400 Bad Request` in the GraphQL
+/// error format, as are mutations in the query string of `GET` requests. `POST` requests with
+/// other bodies are answered with a `415 Unsupported Media Type`, and all other requests (like
+/// `GET` requests for a GraphiQL page) are passed through as they are.
+pub struct GraphQl<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The limits to enforce
+	pub limits: GraphQlLimits,
+}
+
+impl<H: RequestHandler> GraphQl<H> {
+	/// Create a [`GraphQl`] with the default limits
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			limits: GraphQlLimits::default(),
+		}
+	}
+}
+
+/// The error type for `<`[`GraphQl`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum GraphQlError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("failed to read request body: {0}")]
+	/// The request body could not be read
+	Body(hyper::Error),
+}
+
+type GraphQlFuture<E> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, GraphQlError<E>>> + Send>>;
+
+impl<H: RequestHandler + Send + Sync + 'static> RequestHandler for GraphQl<H> {
+	type Error = GraphQlError<H::Error>;
+	type Output = GraphQlFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		// An operation in the query string is checked whatever the method is
+		let mut url_op = None;
+		match request.uri().query().map_or(Ok(None), url_params) {
+			Ok(Some((query, name))) => match self.limits.analyze(&query, name.as_deref()) {
+				Ok(op) if op.kind == OperationKind::Mutation && request.method() == Method::GET => {
+					return Box::pin(async {
+						Ok(error_response("mutations are not allowed in GET requests"))
+					});
+				}
+				Ok(op) => url_op = Some(op),
+				Err(message) => return Box::pin(async move { Ok(error_response(&message)) }),
+			},
+			Ok(None) => {}
+			Err(message) => return Box::pin(async move { Ok(error_response(&message)) }),
+		}
+
+		let content_type = request
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|ct| ct.split(';').next())
+			.map(|ct| ct.trim().to_ascii_lowercase());
+		let is_json = match content_type.as_deref() {
+			_ if request.method() != Method::POST => None,
+			Some("application/json") => Some(true),
+			Some("application/graphql") => Some(false),
+			_ if url_op.is_some() => None,
+			_ => {
+				return Box::pin(async {
+					Ok(error_response_with(
+						StatusCode::UNSUPPORTED_MEDIA_TYPE,
+						"GraphQL requests need a JSON or application/graphql body",
+					))
+				})
+			}
+		};
+		let is_json = match is_json {
+			Some(is_json) => is_json,
+			None => {
+				let mut request = request;
+				if let Some(op) = url_op {
+					request.extensions_mut().insert(op);
+				}
+				let fut = self.inner.handle(from_addr, request, client);
+				return Box::pin(async move { fut.await.map_err(GraphQlError::Inner) });
+			}
+		};
+
+		let inner = self.inner.clone();
+		let client = client.clone();
+		let limits = self.limits.clone();
+
+		Box::pin(async move {
+			let (mut parts, body) = request.into_parts();
+			let body = match read_limited(body, limits.max_body_size)
+				.await
+				.map_err(GraphQlError::Body)?
+			{
+				Some(body) => body,
+				None => return Ok(error_response("request body is too large")),
+			};
+
+			let analyzed = if is_json {
+				serde_json::from_slice::<Value>(&body)
+					.map_err(|e| format!("invalid JSON: {}", e))
+					.and_then(|json| match json {
+						Value::Array(batch) => limits.analyze_batch(&batch).map(Analyzed::Batch),
+						json => limits.analyze_json(&json).map(Analyzed::Single),
+					})
+			} else {
+				std::str::from_utf8(&body)
+					.map_err(|e| e.to_string())
+					.and_then(|query| limits.analyze(query, None))
+					.map(Analyzed::Single)
+			};
+
+			match analyzed {
+				Ok(Analyzed::Single(op)) => {
+					parts.extensions.insert(op);
+				}
+				Ok(Analyzed::Batch(batch)) => {
+					parts.extensions.insert(batch);
+				}
+				Err(message) => return Ok(error_response(&message)),
+			}
+
+			inner
+				.handle(
+					from_addr,
+					Request::from_parts(parts, Body::from(body)),
+					&client,
+				)
+				.await
+				.map_err(GraphQlError::Inner)
+		})
+	}
+}
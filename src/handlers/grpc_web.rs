@@ -0,0 +1,254 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use futures::future::Either;
+use futures::stream::BoxStream;
+use futures::{stream, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TE};
+use hyper::{Body, Client, HeaderMap, Request, Response};
+
+use crate::chain::encode_base64;
+use crate::connect::Connector;
+use crate::handlers::proxy_auth::decode_base64;
+use crate::RequestHandler;
+
+/// How the messages of a gRPC-Web request or response are encoded
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GrpcWebEncoding {
+	/// `application/grpc-web`, where the messages are sent as they are
+	Binary,
+	/// `application/grpc-web-text`, where the messages are base64-encoded
+	Text,
+}
+
+/// Return the encoding of a gRPC-Web request and the rest of its content type (like `+proto`),
+/// or `None` if it isn't one
+pub fn grpc_web_request(request: &Request<Body>) -> Option<(GrpcWebEncoding, String)> {
+	let headers = request.headers();
+	match content_type_suffix(headers, "application/grpc-web-text") {
+		Some(suffix) => Some((GrpcWebEncoding::Text, suffix)),
+		None => content_type_suffix(headers, "application/grpc-web")
+			.map(|suffix| (GrpcWebEncoding::Binary, suffix)),
+	}
+}
+
+// Return the rest of the content type (like `+proto`) if it is `mime` or a subtype of it
+fn content_type_suffix(headers: &HeaderMap, mime: &str) -> Option<String> {
+	let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+	let content_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+	let rest = content_type.strip_prefix(mime)?;
+	if !rest.is_empty() && !rest.starts_with('+') {
+		return None;
+	}
+	Some(rest.to_string())
+}
+
+/// A request handler combinator that translates gRPC-Web requests (as sent by browsers) into
+/// gRPC requests for the inner request handler, and their responses back
+///
+/// The content type of requests is changed to `application/grpc` (keeping a suffix like
+/// `+proto`), base64-encoded messages are decoded and `TE: trailers` is added. gRPC responses
+/// get the gRPC-Web content type with the encoding of the request, and their trailers (with
+/// the `grpc-status`) are sent as a final frame of the body, which browsers can read. Other
+/// requests and responses, like errors of the proxy itself, are passed through as they are.
+///
+/// gRPC needs HTTP/2, so the route to the upstream should speak
+/// [`Http2`](crate::connect::UpstreamProtocol::Http2), while browsers can send gRPC-Web over
+/// any version. Browsers only let scripts read the `grpc-status` and `grpc-message` headers of
+/// cross-origin responses if they are listed in `Access-Control-Expose-Headers`.
+///
+/// # Example
+/// ```
+/// use proxylib::connect::{UpstreamConfig, UpstreamProtocol};
+/// use proxylib::handlers::grpc_web::GrpcWeb;
+/// use proxylib::handlers::with_client::WithClient;
+/// use proxylib::handlers::Redirect;
+///
+/// let backend = UpstreamConfig {
+///     protocol: UpstreamProtocol::Http2,
+///     ..UpstreamConfig::default()
+/// };
+/// let handler = GrpcWeb::new(WithClient::new(
+///     Redirect::change_authority("grpc.internal:50051".parse().unwrap()),
+///     &backend,
+/// ));
+/// ```
+pub struct GrpcWeb<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+}
+
+impl<H: RequestHandler> GrpcWeb<H> {
+	/// Create a [`GrpcWeb`] around the inner request handler
+	pub fn new(inner: H) -> Self {
+		Self { inner }
+	}
+}
+
+type Chunks = BoxStream<'static, Result<Bytes, Box<dyn std::error::Error + Send + Sync>>>;
+
+// Decode base64 text that may be split anywhere between chunks and may contain padding
+// between the messages
+fn decode_text(chunks: Chunks) -> Chunks {
+	stream::unfold(Some((chunks, Vec::new())), |state| async move {
+		let (mut chunks, mut pending) = state?;
+		loop {
+			match chunks.next().await {
+				Some(Ok(chunk)) => {
+					pending.extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+					let whole = pending.len() / 4 * 4;
+					if whole == 0 {
+						continue;
+					}
+					let mut decoded = Vec::with_capacity(whole / 4 * 3);
+					for group in pending[..whole].chunks(4) {
+						match std::str::from_utf8(group).ok().and_then(decode_base64) {
+							Some(bytes) => decoded.extend(bytes),
+							None => return Some((Err(invalid_text().into()), None)),
+						}
+					}
+					pending.drain(..whole);
+					return Some((Ok(decoded.into()), Some((chunks, pending))));
+				}
+				Some(Err(e)) => return Some((Err(e), None)),
+				None if pending.is_empty() => return None,
+				None => return Some((Err(invalid_text().into()), None)),
+			}
+		}
+	})
+	.boxed()
+}
+
+fn invalid_text() -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		"invalid base64 in gRPC-Web text",
+	)
+}
+
+// Encode the chunks as one base64 text, which is only padded at its end
+fn encode_text(chunks: Chunks) -> Chunks {
+	stream::unfold(Some((chunks, Vec::new())), |state| async move {
+		let (mut chunks, mut pending) = state?;
+		loop {
+			match chunks.next().await {
+				Some(Ok(chunk)) => {
+					pending.extend_from_slice(&chunk);
+					let whole = pending.len() / 3 * 3;
+					if whole == 0 {
+						continue;
+					}
+					let text = encode_base64(&pending[..whole]);
+					pending.drain(..whole);
+					return Some((Ok(text.into()), Some((chunks, pending))));
+				}
+				Some(Err(e)) => return Some((Err(e), None)),
+				None if pending.is_empty() => return None,
+				None => return Some((Ok(encode_base64(&pending).into()), None)),
+			}
+		}
+	})
+	.boxed()
+}
+
+// The trailers as a frame like the ones of messages, with the most significant bit of its
+// flags set and an HTTP/1 header block as its payload
+fn trailer_frame(trailers: &HeaderMap) -> Bytes {
+	let mut block = Vec::new();
+	for (name, value) in trailers {
+		block.extend_from_slice(name.as_str().as_bytes());
+		block.extend_from_slice(b": ");
+		block.extend_from_slice(value.as_bytes());
+		block.extend_from_slice(b"\r\n");
+	}
+	let mut frame = Vec::with_capacity(5 + block.len());
+	frame.push(0x80);
+	frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+	frame.extend_from_slice(&block);
+	frame.into()
+}
+
+// The messages of the body followed by the frame of its trailers, if it has any
+fn with_trailer_frame(body: Body) -> Chunks {
+	stream::unfold(Some(body), |body| async move {
+		let mut body = body?;
+		match body.data().await {
+			Some(chunk) => Some((chunk.map_err(Into::into), Some(body))),
+			None => match body.trailers().await {
+				Ok(Some(trailers)) => Some((Ok(trailer_frame(&trailers)), None)),
+				Ok(None) => None,
+				Err(e) => Some((Err(e.into()), None)),
+			},
+		}
+	})
+	.boxed()
+}
+
+// The suffix comes from a header value, so the content type is a valid one
+fn content_type(prefix: &str, suffix: &str) -> HeaderValue {
+	HeaderValue::from_str(&format!("{}{}", prefix, suffix)).unwrap()
+}
+
+type GrpcWebFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for GrpcWeb<H> {
+	type Error = H::Error;
+	type Output = Either<H::Output, GrpcWebFuture<H::Error>>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let (encoding, suffix) = match grpc_web_request(&request) {
+			Some(web) => web,
+			None => return Either::Left(self.inner.handle(from_addr, request, client)),
+		};
+
+		let (mut parts, body) = request.into_parts();
+		parts
+			.headers
+			.insert(CONTENT_TYPE, content_type("application/grpc", &suffix));
+		parts
+			.headers
+			.insert(TE, HeaderValue::from_static("trailers"));
+		let body = match encoding {
+			GrpcWebEncoding::Binary => body,
+			GrpcWebEncoding::Text => {
+				parts.headers.remove(CONTENT_LENGTH);
+				let chunks = body.map(|chunk| chunk.map_err(Into::into)).boxed();
+				Body::wrap_stream(decode_text(chunks))
+			}
+		};
+		let fut = self
+			.inner
+			.handle(from_addr, Request::from_parts(parts, body), client);
+
+		Either::Right(Box::pin(async move {
+			let response = fut.await?;
+			let suffix = match content_type_suffix(response.headers(), "application/grpc") {
+				Some(suffix) => suffix,
+				None => return Ok(response),
+			};
+
+			let (mut parts, body) = response.into_parts();
+			parts.headers.remove(CONTENT_LENGTH);
+			let frames = with_trailer_frame(body);
+			let (prefix, body) = match encoding {
+				GrpcWebEncoding::Binary => ("application/grpc-web", Body::wrap_stream(frames)),
+				GrpcWebEncoding::Text => (
+					"application/grpc-web-text",
+					Body::wrap_stream(encode_text(frames)),
+				),
+			};
+			parts
+				.headers
+				.insert(CONTENT_TYPE, content_type(prefix, &suffix));
+			Ok(Response::from_parts(parts, body))
+		}))
+	}
+}
@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::header::HeaderName;
+use hyper::{Body, Client, HeaderMap, Request, Response};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// Remove all headers whose names aren't in `allowed`
+pub fn retain_allowed(headers: &mut HeaderMap, allowed: &HashSet<HeaderName>) {
+	let denied = headers
+		.keys()
+		.filter(|name| !allowed.contains(*name))
+		.cloned()
+		.collect::<Vec<_>>();
+	for name in denied {
+		headers.remove(name);
+	}
+}
+
+/// A request handler combinator that only forwards explicitly allowed headers
+///
+/// This is a deny-by-default mode for reverse proxies in front of sensitive services:
+/// request headers not in [`request`](Self::request) are stripped before the request is given
+/// to the inner request handler, and response headers not in [`response`](Self::response)
+/// are stripped from its response. A direction without an allowlist is left untouched.
+///
+/// Body framing is unaffected, since the headers describing it are set anew when
+/// the message is sent.
+pub struct HeaderAllowlist<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The request headers that are forwarded
+	pub request: Option<HashSet<HeaderName>>,
+	/// The response headers that are forwarded
+	pub response: Option<Arc<HashSet<HeaderName>>>,
+}
+
+impl<H: RequestHandler> HeaderAllowlist<H> {
+	/// Create a [`HeaderAllowlist`] with the given allowed request and response headers
+	pub fn new(
+		inner: H,
+		request: impl IntoIterator<Item = HeaderName>,
+		response: impl IntoIterator<Item = HeaderName>,
+	) -> Self {
+		Self {
+			inner,
+			request: Some(request.into_iter().collect()),
+			response: Some(Arc::new(response.into_iter().collect())),
+		}
+	}
+}
+
+type AllowlistFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for HeaderAllowlist<H> {
+	type Error = H::Error;
+	type Output = AllowlistFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if let Some(allowed) = &self.request {
+			retain_allowed(request.headers_mut(), allowed);
+		}
+		let fut = self.inner.handle(from_addr, request, client);
+		let allowed = self.response.clone();
+
+		Box::pin(async move {
+			let mut response = fut.await?;
+			if let Some(allowed) = allowed {
+				retain_allowed(response.headers_mut(), &allowed);
+			}
+			Ok(response)
+		})
+	}
+}
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Client, Request, Uri};
+
+use crate::body::read_limited;
+use crate::connect::Connector;
+
+/// The configuration of the active health checks of an [`UpstreamPool`]
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+	/// The path (and query) that is requested with `GET` on each upstream
+	pub path: PathAndQuery,
+	/// The time between two checks of an upstream
+	pub interval: Duration,
+	/// How long an upstream has to respond to a check
+	pub timeout: Duration,
+	/// The number of consecutive successful checks after which an unhealthy upstream
+	/// is reinstated
+	pub healthy_threshold: u32,
+	/// The number of consecutive failed checks after which a healthy upstream is taken
+	/// out of rotation
+	pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+	fn default() -> Self {
+		Self {
+			path: PathAndQuery::from_static("/"),
+			interval: Duration::from_secs(10),
+			timeout: Duration::from_secs(2),
+			healthy_threshold: 2,
+			unhealthy_threshold: 3,
+		}
+	}
+}
+
+impl HealthCheck {
+	/// Create a [`HealthCheck`] requesting the given path, with the default interval,
+	/// timeout and thresholds
+	pub fn new(path: PathAndQuery) -> Self {
+		Self {
+			path,
+			..Self::default()
+		}
+	}
+}
+
+/// A set of upstreams whose health is checked in the background
+///
+/// Every upstream gets a background task that requests [`HealthCheck::path`] every
+/// [`HealthCheck::interval`]; a check fails if the upstream doesn't answer with a success or
+/// redirect status within [`HealthCheck::timeout`]. Upstreams start out healthy.
+/// The tasks stop once the pool and all of its clones are dropped.
+///
+/// Give the pool to a [`Balance`](super::balance::Balance) with
+/// [`with_health`](super::balance::Balance::with_health) to only give requests to the
+/// healthy upstreams; the upstreams of the pool and of the balancer must be in the same order.
+#[derive(Clone)]
+pub struct UpstreamPool {
+	targets: Arc<Vec<Uri>>,
+	healthy: Arc<Vec<AtomicBool>>,
+}
+
+impl UpstreamPool {
+	/// Create a pool of the upstreams at the given URIs (of which only the scheme and authority
+	/// are used) and start checking them
+	///
+	/// # Panics
+	/// Panics if called outside of a tokio runtime.
+	pub fn new(targets: Vec<Uri>, check: HealthCheck, client: Client<Connector>) -> Self {
+		let pool = Self {
+			healthy: Arc::new(targets.iter().map(|_| AtomicBool::new(true)).collect()),
+			targets: Arc::new(targets),
+		};
+		for index in 0..pool.targets.len() {
+			tokio::spawn(run_checks(
+				pool.targets[index].clone(),
+				Arc::downgrade(&pool.healthy),
+				index,
+				check.clone(),
+				client.clone(),
+			));
+		}
+		pool
+	}
+
+	/// Get the URIs of the upstreams
+	pub fn targets(&self) -> &[Uri] {
+		&self.targets
+	}
+
+	/// Return whether the upstream is healthy
+	pub fn is_healthy(&self, upstream: usize) -> bool {
+		self.healthy
+			.get(upstream)
+			.is_none_or(|healthy| healthy.load(Ordering::SeqCst))
+	}
+
+	/// Get the indices of the healthy upstreams
+	pub fn healthy(&self) -> Vec<usize> {
+		(0..self.targets.len())
+			.filter(|&i| self.is_healthy(i))
+			.collect()
+	}
+}
+
+async fn run_checks(
+	target: Uri,
+	healthy: Weak<Vec<AtomicBool>>,
+	index: usize,
+	check: HealthCheck,
+	client: Client<Connector>,
+) {
+	let mut parts = target.into_parts();
+	parts.path_and_query = Some(check.path.clone());
+	let uri = Uri::from_parts(parts);
+
+	let mut interval = tokio::time::interval(check.interval);
+	let (mut successes, mut failures) = (0, 0);
+	loop {
+		interval.tick().await;
+		let success = match &uri {
+			Ok(uri) => probe(&client, uri.clone(), check.timeout).await,
+			Err(_) => false,
+		};
+
+		let healthy = match healthy.upgrade() {
+			Some(healthy) => healthy,
+			None => return,
+		};
+		let state = &healthy[index];
+		if success {
+			successes += 1;
+			failures = 0;
+			if successes >= check.healthy_threshold {
+				state.store(true, Ordering::SeqCst);
+			}
+		} else {
+			failures += 1;
+			successes = 0;
+			if failures >= check.unhealthy_threshold {
+				state.store(false, Ordering::SeqCst);
+			}
+		}
+	}
+}
+
+async fn probe(client: &Client<Connector>, uri: Uri, timeout: Duration) -> bool {
+	let request = match Request::get(uri).body(Body::empty()) {
+		Ok(request) => request,
+		Err(_) => return false,
+	};
+	let check = async {
+		let response = client.request(request).await.ok()?;
+		let status = response.status();
+		// Read the body so the connection can be reused
+		read_limited(response.into_body(), 64 * 1024).await.ok()?;
+		Some(status.is_success() || status.is_redirection())
+	};
+	tokio::time::timeout(timeout, check)
+		.await
+		.ok()
+		.flatten()
+		.unwrap_or(false)
+}
@@ -0,0 +1,370 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use thiserror::Error;
+
+use crate::body::{buffer, is_streaming, Buffered};
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The header clients use to mark retries of the same request
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// The header that marks responses replayed by [`Idempotency`]
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// A function returning the scope of a request, see [`Idempotency::scope`]
+pub type IdempotencyScope = Arc<dyn Fn(SocketAddr, &Request<Body>) -> String + Send + Sync>;
+
+/// The default [`IdempotencyScope`], which is a hash of the credentials of the request (its
+/// `Authorization`, `Proxy-Authorization` and `Cookie` headers), or the IP address of the
+/// client if the request has none
+pub fn credential_scope(from_addr: SocketAddr, request: &Request<Body>) -> String {
+	let mut hasher = DefaultHasher::new();
+	let mut has_credentials = false;
+	for name in &[AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE] {
+		for value in request.headers().get_all(name) {
+			has_credentials = true;
+			name.as_str().hash(&mut hasher);
+			value.as_bytes().hash(&mut hasher);
+		}
+	}
+	if has_credentials {
+		format!("credentials:{:016x}", hasher.finish())
+	} else {
+		format!("ip:{}", from_addr.ip())
+	}
+}
+
+// The scope, the idempotency key, the method and the URI
+type Key = (String, String, Method, Uri);
+
+enum Entry {
+	InProgress,
+	Done {
+		id: u64,
+		size: usize,
+		status: StatusCode,
+		headers: HeaderMap,
+		body: Bytes,
+		expires: Instant,
+	},
+}
+
+#[derive(Default)]
+struct Entries {
+	map: HashMap<Key, Entry>,
+	// The kept responses by age, which may contain ones that were removed since
+	order: VecDeque<(u64, Key)>,
+	next_id: u64,
+	kept: usize,
+	size: usize,
+	next_purge: Option<Instant>,
+}
+
+impl Entries {
+	fn remove(&mut self, key: &Key) {
+		if let Some(Entry::Done { size, .. }) = self.map.remove(key) {
+			self.kept -= 1;
+			self.size -= size;
+		}
+	}
+
+	// Keeps a response, evicting the oldest ones to stay within the limits
+	fn keep(&mut self, key: Key, entry: Entry, max_entries: usize, max_size: usize) {
+		self.remove(&key);
+		let (id, size) = match &entry {
+			Entry::Done { id, size, .. } => (*id, *size),
+			Entry::InProgress => unreachable!(),
+		};
+		if size > max_size || max_entries == 0 {
+			return;
+		}
+		while self.kept >= max_entries || self.size + size > max_size {
+			let (oldest_id, oldest) = match self.order.pop_front() {
+				Some(oldest) => oldest,
+				None => break,
+			};
+			if matches!(self.map.get(&oldest), Some(Entry::Done { id, .. }) if *id == oldest_id) {
+				self.remove(&oldest);
+			}
+		}
+		self.kept += 1;
+		self.size += size;
+		self.order.push_back((id, key.clone()));
+		self.map.insert(key, entry);
+	}
+
+	fn purge(&mut self, now: Instant) {
+		let expired = self
+			.map
+			.iter()
+			.filter(|(_, entry)| matches!(entry, Entry::Done { expires, .. } if *expires <= now))
+			.map(|(key, _)| key.clone())
+			.collect::<Vec<_>>();
+		for key in expired {
+			self.remove(&key);
+		}
+		let map = &self.map;
+		self.order.retain(
+			|(id, key)| matches!(map.get(key), Some(Entry::Done { id: kept, .. }) if kept == id),
+		);
+	}
+}
+
+// Forgets the key if the request doesn't complete, so it can be retried
+struct InProgress {
+	entries: Arc<Mutex<Entries>>,
+	key: Option<Key>,
+}
+
+impl InProgress {
+	fn complete(mut self, kept: Option<(Kept, usize, usize)>) {
+		let key = self.key.take().unwrap();
+		let mut entries = self.entries.lock().unwrap();
+		entries.map.remove(&key);
+		if let Some((kept, max_entries, max_size)) = kept {
+			let id = entries.next_id;
+			entries.next_id += 1;
+			let size = kept.size(&key);
+			let entry = Entry::Done {
+				id,
+				size,
+				status: kept.status,
+				headers: kept.headers,
+				body: kept.body,
+				expires: kept.expires,
+			};
+			entries.keep(key, entry, max_entries, max_size);
+		}
+	}
+}
+
+// A response that is to be kept
+struct Kept {
+	status: StatusCode,
+	headers: HeaderMap,
+	body: Bytes,
+	expires: Instant,
+}
+
+impl Kept {
+	fn size(&self, key: &Key) -> usize {
+		let headers = self
+			.headers
+			.iter()
+			.map(|(name, value)| name.as_str().len() + value.len())
+			.sum::<usize>();
+		let key = key.0.len() + key.1.len() + key.3.to_string().len();
+		self.body.len() + headers + key
+	}
+}
+
+impl Drop for InProgress {
+	fn drop(&mut self) {
+		if let Some(key) = self.key.take() {
+			self.entries.lock().unwrap().map.remove(&key);
+		}
+	}
+}
+
+/// A request handler combinator that suppresses duplicates of requests with an
+/// [`Idempotency-Key`](IDEMPOTENCY_KEY) header
+///
+/// The response of the first completed request with a key is kept for [`ttl`](Self::ttl)
+/// and returned to later requests with the same key, method, URI and [`scope`](Self::scope)
+/// instead of giving them to the inner request handler. Duplicates that arrive while the
+/// first request is still being handled get a `409 Conflict`.
+///
+/// The scope keeps clients from getting each other's responses by reusing their keys; by
+/// default it is made up of the credentials of the request (see [`credential_scope`]).
+///
+/// Server errors, [streaming](crate::body::is_streaming) responses and responses larger than
+/// [`max_body_size`](Self::max_body_size) are not kept, so the request can be retried.
+/// Once [`max_entries`](Self::max_entries) responses or [`max_size`](Self::max_size) bytes
+/// are kept, the oldest ones are evicted.
+pub struct Idempotency<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// How long responses are kept
+	pub ttl: Duration,
+	/// The size of the largest response body that is kept
+	pub max_body_size: usize,
+	/// The maximum number of responses kept
+	pub max_entries: usize,
+	/// The maximum total size of the responses kept, in bytes
+	pub max_size: usize,
+	/// The scope of a request, which has to match for a response to be returned again
+	pub scope: IdempotencyScope,
+	entries: Arc<Mutex<Entries>>,
+}
+
+impl<H: RequestHandler> Idempotency<H> {
+	/// Create an [`Idempotency`] that keeps up to 10000 responses of up to 1 MiB, using at most
+	/// 64 MiB, for 24 hours, scoped by [`credential_scope`]
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			ttl: Duration::from_secs(24 * 60 * 60),
+			max_body_size: 1 << 20,
+			max_entries: 10_000,
+			max_size: 64 << 20,
+			scope: Arc::new(credential_scope),
+			entries: Arc::default(),
+		}
+	}
+
+	/// Use the given function for the scope of requests, e.g. the id of an authenticated user
+	pub fn with_scope<F>(mut self, scope: F) -> Self
+	where
+		F: Fn(SocketAddr, &Request<Body>) -> String + Send + Sync + 'static,
+	{
+		self.scope = Arc::new(scope);
+		self
+	}
+
+	/// Forget all kept responses
+	pub fn clear(&self) {
+		let mut entries = self.entries.lock().unwrap();
+		entries
+			.map
+			.retain(|_, entry| matches!(entry, Entry::InProgress));
+		entries.order.clear();
+		entries.kept = 0;
+		entries.size = 0;
+	}
+}
+
+/// The error type for `<`[`Idempotency`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum IdempotencyError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("failed to read response body: {0}")]
+	/// The response body couldn't be read
+	Body(hyper::Error),
+}
+
+type IdempotencyFuture<E> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, IdempotencyError<E>>> + Send>>;
+
+fn conflict() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::CONFLICT)
+		.body(Body::from(
+			"a request with this idempotency key is in progress",
+		))
+		.unwrap()
+}
+
+impl<H> RequestHandler for Idempotency<H>
+where
+	H: RequestHandler + Send + Sync + 'static,
+{
+	type Error = IdempotencyError<H::Error>;
+	type Output = IdempotencyFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let idempotency_key = request
+			.headers()
+			.get(IDEMPOTENCY_KEY)
+			.and_then(|v| v.to_str().ok())
+			.map(str::to_string);
+		let idempotency_key = match idempotency_key {
+			Some(key) => key,
+			None => {
+				let fut = self.inner.handle(from_addr, request, client);
+				return Box::pin(async move { fut.await.map_err(IdempotencyError::Inner) });
+			}
+		};
+		let key = (
+			(self.scope)(from_addr, &request),
+			idempotency_key,
+			request.method().clone(),
+			request.uri().clone(),
+		);
+
+		let now = Instant::now();
+		{
+			let mut entries = self.entries.lock().unwrap();
+			if entries.next_purge.is_none_or(|at| at <= now) {
+				entries.purge(now);
+				entries.next_purge = Some(now + Duration::from_secs(60));
+			}
+
+			match entries.map.get(&key) {
+				Some(Entry::InProgress) => return Box::pin(async { Ok(conflict()) }),
+				Some(Entry::Done {
+					status,
+					headers,
+					body,
+					expires,
+					..
+				}) if *expires > now => {
+					let mut response = Response::new(Body::from(body.clone()));
+					*response.status_mut() = *status;
+					*response.headers_mut() = headers.clone();
+					response
+						.headers_mut()
+						.insert(IDEMPOTENT_REPLAYED, "true".parse().unwrap());
+					return Box::pin(async { Ok(response) });
+				}
+				_ => {}
+			}
+			entries.remove(&key);
+			entries.map.insert(key.clone(), Entry::InProgress);
+		}
+
+		let in_progress = InProgress {
+			entries: self.entries.clone(),
+			key: Some(key),
+		};
+		let fut = self.inner.handle(from_addr, request, client);
+		let ttl = self.ttl;
+		let max_body_size = self.max_body_size;
+		let (max_entries, max_size) = (self.max_entries, self.max_size);
+
+		Box::pin(async move {
+			let response = fut.await.map_err(IdempotencyError::Inner)?;
+			if response.status().is_server_error() || is_streaming(&response) {
+				in_progress.complete(None);
+				return Ok(response);
+			}
+
+			let (parts, body) = response.into_parts();
+			let body = match buffer(body, max_body_size)
+				.await
+				.map_err(IdempotencyError::Body)?
+			{
+				Buffered::Complete(body) => body,
+				Buffered::Partial(body) => {
+					in_progress.complete(None);
+					return Ok(Response::from_parts(parts, body));
+				}
+			};
+
+			let kept = Kept {
+				status: parts.status,
+				headers: parts.headers.clone(),
+				body: body.clone(),
+				expires: Instant::now() + ttl,
+			};
+			in_progress.complete(Some((kept, max_entries, max_size)));
+			Ok(Response::from_parts(parts, Body::from(body)))
+		})
+	}
+}
@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{ready, Either, Ready};
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A flag that puts routes into maintenance mode at runtime
+///
+/// Share one switch between several [`Maintenance`]s to toggle them together
+/// (e.g. all routes of a backend, or the whole proxy). Operators can toggle it through the
+/// admin API, see [`Admin::with_maintenance`](super::admin::Admin::with_maintenance).
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceSwitch(Arc<AtomicBool>);
+
+impl MaintenanceSwitch {
+	/// Create a switch that is off
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Turn maintenance mode on or off
+	pub fn set(&self, on: bool) {
+		self.0.store(on, Ordering::SeqCst);
+	}
+
+	/// Return whether maintenance mode is on
+	pub fn is_on(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+}
+
+/// A request handler combinator that answers all requests with a maintenance page
+/// while its [`MaintenanceSwitch`] is on
+pub struct Maintenance<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The switch toggling maintenance mode
+	pub switch: MaintenanceSwitch,
+	/// The value of the `Retry-After` header, if any
+	pub retry_after: Option<Duration>,
+	/// The content type of [`page`](Self::page)
+	pub content_type: String,
+	/// The body of the maintenance response
+	pub page: String,
+}
+
+impl<H: RequestHandler> Maintenance<H> {
+	/// Create a [`Maintenance`] with a plain-text page
+	pub fn new(inner: H, switch: MaintenanceSwitch) -> Self {
+		Self {
+			inner,
+			switch,
+			retry_after: None,
+			content_type: "text/plain; charset=utf-8".to_string(),
+			page: "The service is down for maintenance.\n".to_string(),
+		}
+	}
+
+	fn respond(&self) -> Response<Body> {
+		let mut response = Response::builder()
+			.status(StatusCode::SERVICE_UNAVAILABLE)
+			.header(CONTENT_TYPE, &self.content_type);
+		if let Some(retry_after) = self.retry_after {
+			response = response.header(RETRY_AFTER, retry_after.as_secs().max(1));
+		}
+		response.body(Body::from(self.page.clone())).unwrap()
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for Maintenance<H> {
+	type Error = H::Error;
+	type Output = Either<Ready<Result<Response<Body>, H::Error>>, H::Output>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if self.switch.is_on() {
+			Either::Left(ready(Ok(self.respond())))
+		} else {
+			Either::Right(self.inner.handle(from_addr, request, client))
+		}
+	}
+}
+
+/// A request handler that serves a readiness endpoint at a path and gives all other requests
+/// to the inner request handler
+///
+/// The endpoint answers `200 OK` unless any of its [`MaintenanceSwitch`]es is on, in which
+/// case it answers `503 Service Unavailable`, so load balancers and orchestrators stop
+/// sending traffic while the proxy is in maintenance mode.
+pub struct Readiness<H: RequestHandler> {
+	/// The request handler for all other requests
+	pub inner: H,
+	/// The path of the endpoint, e.g. `/ready`
+	pub path: String,
+	/// The switches that make the proxy not ready
+	pub switches: Vec<MaintenanceSwitch>,
+}
+
+impl<H: RequestHandler> Readiness<H> {
+	/// Return whether none of the switches is on
+	pub fn is_ready(&self) -> bool {
+		!self.switches.iter().any(MaintenanceSwitch::is_on)
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for Readiness<H> {
+	type Error = H::Error;
+	type Output = Either<Ready<Result<Response<Body>, H::Error>>, H::Output>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let is_probe = request.uri().path() == self.path
+			&& (request.method() == Method::GET || request.method() == Method::HEAD);
+		if !is_probe {
+			return Either::Right(self.inner.handle(from_addr, request, client));
+		}
+
+		let (status, body) = if self.is_ready() {
+			(StatusCode::OK, "ready\n")
+		} else {
+			(StatusCode::SERVICE_UNAVAILABLE, "not ready\n")
+		};
+		let body = if request.method() == Method::HEAD {
+			Body::empty()
+		} else {
+			Body::from(body)
+		};
+		let response = Response::builder().status(status).body(body).unwrap();
+		Either::Left(ready(Ok(response)))
+	}
+}
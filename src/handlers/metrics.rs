@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::future::{ready, Either, Ready};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response};
+
+use crate::body::inspect_len;
+use crate::connect::Connector;
+use crate::handlers::tenancy::Tenant;
+use crate::metrics::CounterFamily;
+use crate::prometheus::{Gauge, HistogramFamily, Registry, DEFAULT_BUCKETS};
+use crate::RequestHandler;
+
+/// The metrics collected by [`Metered`]
+///
+/// Clones share the metrics, so one set can be collected by several [`Metered`]s.
+///
+/// The counters and the histogram have a `tenant` label with the [`Tenant`] of the requests,
+/// which is set for the [`Metered`]s in the request handlers of a
+/// [`Tenancy`](super::tenancy::Tenancy) and empty otherwise.
+#[derive(Debug, Clone)]
+pub struct RequestMetrics {
+	/// `proxylib_requests_total`, the number of responses with the labels `tenant` and
+	/// `status_class` (like `2xx`)
+	pub requests: Arc<CounterFamily>,
+	/// `proxylib_request_duration_seconds`, the seconds until the responses arrived, with the
+	/// label `tenant`
+	pub duration: Arc<HistogramFamily>,
+	/// `proxylib_requests_in_flight`, the number of requests whose response hasn't been sent
+	/// completely yet
+	pub in_flight: Arc<Gauge>,
+	/// `proxylib_upstream_errors_total`, the number of requests the inner request handler
+	/// failed, which for forwarding handlers means the upstream couldn't be reached or
+	/// broke off, with the label `tenant`
+	pub upstream_errors: Arc<CounterFamily>,
+}
+
+impl Default for RequestMetrics {
+	fn default() -> Self {
+		Self {
+			requests: Arc::new(CounterFamily::new(
+				"proxylib_requests_total",
+				"The number of responses, by tenant and status class",
+				&["tenant", "status_class"],
+			)),
+			duration: Arc::new(HistogramFamily::new(
+				"proxylib_request_duration_seconds",
+				"The seconds until the response arrived",
+				&["tenant"],
+				&DEFAULT_BUCKETS,
+			)),
+			in_flight: Arc::new(Gauge::new(
+				"proxylib_requests_in_flight",
+				"The number of requests whose response hasn't been sent completely",
+			)),
+			upstream_errors: Arc::new(CounterFamily::new(
+				"proxylib_upstream_errors_total",
+				"The number of requests the request handler failed",
+				&["tenant"],
+			)),
+		}
+	}
+}
+
+impl RequestMetrics {
+	/// Add the metrics to a registry
+	pub fn register(&self, registry: &Registry) {
+		registry.register(self.requests.clone());
+		registry.register(self.duration.clone());
+		registry.register(self.in_flight.clone());
+		registry.register(self.upstream_errors.clone());
+	}
+}
+
+/// A request handler combinator that collects [`RequestMetrics`] about the requests it gives to
+/// another request handler
+pub struct Metered<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The collected metrics
+	pub metrics: RequestMetrics,
+}
+
+impl<H: RequestHandler> Metered<H> {
+	/// Create a [`Metered`] collecting the given metrics
+	pub fn new(inner: H, metrics: RequestMetrics) -> Self {
+		Self { inner, metrics }
+	}
+}
+
+// Counts a request as in flight until it is dropped, i.e. the response body is gone
+struct InFlight(Arc<Gauge>);
+
+impl InFlight {
+	fn new(gauge: &Arc<Gauge>) -> Self {
+		gauge.inc();
+		Self(gauge.clone())
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.0.dec();
+	}
+}
+
+fn status_class(response: &Response<Body>) -> &'static str {
+	match response.status().as_u16() / 100 {
+		1 => "1xx",
+		2 => "2xx",
+		3 => "3xx",
+		4 => "4xx",
+		_ => "5xx",
+	}
+}
+
+type MeteredFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for Metered<H> {
+	type Error = H::Error;
+	type Output = MeteredFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let start = Instant::now();
+		let tenant = request
+			.extensions()
+			.get::<Tenant>()
+			.map_or_else(String::new, |Tenant(tenant)| tenant.clone());
+		let in_flight = InFlight::new(&self.metrics.in_flight);
+		let metrics = self.metrics.clone();
+		let fut = self.inner.handle(from_addr, request, client);
+
+		Box::pin(async move {
+			let result = fut.await;
+			metrics
+				.duration
+				.observe(&[&tenant], start.elapsed().as_secs_f64());
+			let response = match result {
+				Ok(response) => response,
+				Err(error) => {
+					metrics.upstream_errors.inc(&[&tenant]);
+					return Err(error);
+				}
+			};
+			metrics.requests.inc(&[&tenant, status_class(&response)]);
+
+			let (parts, body) = response.into_parts();
+			// The request stays in flight until the body is gone
+			let body = inspect_len(body, move |_| {
+				let _ = &in_flight;
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
+
+/// A request handler that serves the metrics of a [`Registry`] in the Prometheus text format
+/// at a path and gives all other requests to the inner request handler
+///
+/// The metrics are public to everyone who can reach the path, so serve them on an internal
+/// listener or put a [`Filter`](super::filter::Filter) in front.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::metrics::{Metered, RequestMetrics, ServeMetrics};
+/// use proxylib::handlers::Redirect;
+/// use proxylib::prometheus::Registry;
+///
+/// let registry = Registry::default();
+/// let metrics = RequestMetrics::default();
+/// metrics.register(&registry);
+/// let handler = ServeMetrics::new(
+///     Metered::new(
+///         Redirect::change_authority("app.internal:8080".parse().unwrap()),
+///         metrics,
+///     ),
+///     "/metrics",
+///     registry,
+/// );
+/// ```
+pub struct ServeMetrics<H: RequestHandler> {
+	/// The request handler for all other requests
+	pub inner: H,
+	/// The path the metrics are served at, e.g. `/metrics`
+	pub path: String,
+	/// The metrics to serve
+	pub registry: Registry,
+}
+
+impl<H: RequestHandler> ServeMetrics<H> {
+	/// Serve the metrics of the registry at the path
+	pub fn new(inner: H, path: impl Into<String>, registry: Registry) -> Self {
+		Self {
+			inner,
+			path: path.into(),
+			registry,
+		}
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for ServeMetrics<H> {
+	type Error = H::Error;
+	type Output = Either<Ready<Result<Response<Body>, H::Error>>, H::Output>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let is_metrics = request.uri().path() == self.path
+			&& (request.method() == Method::GET || request.method() == Method::HEAD);
+		if !is_metrics {
+			return Either::Right(self.inner.handle(from_addr, request, client));
+		}
+		let body = if request.method() == Method::HEAD {
+			Body::empty()
+		} else {
+			Body::from(self.registry.encode())
+		};
+		let response = Response::builder()
+			.header(CONTENT_TYPE, crate::prometheus::CONTENT_TYPE)
+			.header(CACHE_CONTROL, "no-store")
+			.body(body)
+			.unwrap();
+		Either::Left(ready(Ok(response)))
+	}
+}
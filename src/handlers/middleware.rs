@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::{Body, Client, Request, Response};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// Something that looks at or changes requests before and responses after a request handler,
+/// to be used with [`Layered`]
+///
+/// Both hooks do nothing by default, so only the ones that are needed have to be implemented.
+pub trait Middleware {
+	/// Look at or change the request before it is given to the inner request handler
+	///
+	/// Return a response to answer the request with it instead of giving it to the inner request
+	/// handler; [`after`](Self::after) is not called for that response.
+	fn before(&self, from_addr: SocketAddr, request: &mut Request<Body>) -> Option<Response<Body>> {
+		let _ = (from_addr, request);
+		None
+	}
+
+	/// Look at or change the response of the inner request handler
+	fn after(&self, response: &mut Response<Body>) {
+		let _ = response;
+	}
+}
+
+/// A request handler combinator that runs a [`Middleware`] around the inner request handler
+///
+/// Middlewares are stacked by layering a [`Layered`] over another, e.g. with
+/// [`RequestHandler::layer`]; the outermost one sees the request first and the response last.
+///
+/// # Example
+/// ```
+/// use std::net::SocketAddr;
+/// use hyper::header::{HeaderValue, SERVER};
+/// use hyper::{Body, Request, Response};
+/// use proxylib::handlers::middleware::Middleware;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::RequestHandler;
+///
+/// struct HideServer;
+///
+/// impl Middleware for HideServer {
+///     fn after(&self, response: &mut Response<Body>) {
+///         response.headers_mut().insert(SERVER, HeaderValue::from_static("proxy"));
+///     }
+/// }
+///
+/// let handler = Redirect::change_authority("example.com".parse().unwrap()).layer(HideServer);
+/// ```
+pub struct Layered<M: Middleware, H: RequestHandler> {
+	/// The middleware to run
+	pub middleware: Arc<M>,
+	/// The inner request handler to give requests to
+	pub inner: H,
+}
+
+impl<M: Middleware, H: RequestHandler> Layered<M, H> {
+	/// Create a [`Layered`] running the given middleware around the inner request handler
+	pub fn new(middleware: M, inner: H) -> Self {
+		Self {
+			middleware: Arc::new(middleware),
+			inner,
+		}
+	}
+}
+
+type LayeredFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<M, H> RequestHandler for Layered<M, H>
+where
+	M: Middleware + Send + Sync + 'static,
+	H: RequestHandler,
+{
+	type Error = H::Error;
+	type Output = LayeredFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if let Some(response) = self.middleware.before(from_addr, &mut request) {
+			return Box::pin(async { Ok(response) });
+		}
+		let fut = self.inner.handle(from_addr, request, client);
+		let middleware = self.middleware.clone();
+
+		Box::pin(async move {
+			let mut response = fut.await?;
+			middleware.after(&mut response);
+			Ok(response)
+		})
+	}
+}
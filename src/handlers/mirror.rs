@@ -0,0 +1,295 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use thiserror::Error;
+
+use crate::body::{buffer, copy_request, is_streaming, Buffered};
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A header whose value differs between the primary and the shadow response
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HeaderMismatch {
+	/// The name of the header
+	pub name: HeaderName,
+	/// The value in the primary response
+	pub primary: Option<HeaderValue>,
+	/// The value in the shadow response
+	pub shadow: Option<HeaderValue>,
+}
+
+/// A report of how the shadow response differs from the primary one
+#[derive(Debug, Clone)]
+pub struct MismatchReport {
+	/// The method of the request
+	pub method: Method,
+	/// The URI of the request
+	pub uri: Uri,
+	/// The statuses of the primary and shadow responses, if they differ
+	pub status: Option<(StatusCode, StatusCode)>,
+	/// The headers that differ
+	pub headers: Vec<HeaderMismatch>,
+	/// Whether the (normalized) bodies differ
+	pub body_differs: bool,
+	/// The error of the shadow handler, if it failed
+	pub shadow_error: Option<String>,
+}
+
+impl MismatchReport {
+	/// Return whether anything differs
+	pub fn is_mismatch(&self) -> bool {
+		self.status.is_some()
+			|| !self.headers.is_empty()
+			|| self.body_differs
+			|| self.shadow_error.is_some()
+	}
+}
+
+/// A function that normalizes a body before it is compared, e.g. `normalize_json`
+pub type Normalize = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// How the primary and shadow responses are compared by a [`Mirror`]
+#[derive(Clone)]
+pub struct Compare {
+	/// Headers that are expected to differ (e.g. `date`) and are not compared
+	pub ignore_headers: Vec<HeaderName>,
+	/// A function that normalizes bodies before they are compared
+	pub normalize: Option<Normalize>,
+	/// The callback that receives the reports of mismatches
+	pub report: Arc<dyn Fn(MismatchReport) + Send + Sync>,
+}
+
+impl Compare {
+	/// Compare with the given callback, ignoring the `date` header and not normalizing bodies
+	pub fn new<F: Fn(MismatchReport) + Send + Sync + 'static>(report: F) -> Self {
+		Self {
+			ignore_headers: vec![hyper::header::DATE],
+			normalize: None,
+			report: Arc::new(report),
+		}
+	}
+
+	fn diff(
+		&self,
+		(method, uri): (Method, Uri),
+		primary: &(StatusCode, HeaderMap, Bytes),
+		shadow: &(StatusCode, HeaderMap, Bytes),
+	) -> MismatchReport {
+		let status = if primary.0 != shadow.0 {
+			Some((primary.0, shadow.0))
+		} else {
+			None
+		};
+
+		let mut headers = Vec::new();
+		for name in primary.1.keys().chain(shadow.1.keys()) {
+			if self.ignore_headers.contains(name)
+				|| headers.iter().any(|h: &HeaderMismatch| h.name == name)
+			{
+				continue;
+			}
+			let a = primary.1.get_all(name).iter().collect::<Vec<_>>();
+			let b = shadow.1.get_all(name).iter().collect::<Vec<_>>();
+			if a != b {
+				headers.push(HeaderMismatch {
+					name: name.clone(),
+					primary: a.first().map(|v| (*v).clone()),
+					shadow: b.first().map(|v| (*v).clone()),
+				});
+			}
+		}
+
+		let body_differs = match &self.normalize {
+			Some(normalize) => normalize(&primary.2) != normalize(&shadow.2),
+			None => primary.2 != shadow.2,
+		};
+
+		MismatchReport {
+			method,
+			uri,
+			status,
+			headers,
+			body_differs,
+			shadow_error: None,
+		}
+	}
+}
+
+/// Normalize a JSON body by parsing and reserializing it, which sorts object keys and
+/// removes insignificant whitespace
+///
+/// Bodies that aren't valid JSON are returned unchanged.
+///
+/// This requires the `json` feature.
+#[cfg(feature = "json")]
+pub fn normalize_json(body: &[u8]) -> Vec<u8> {
+	serde_json::from_slice::<serde_json::Value>(body)
+		.ok()
+		.and_then(|value| serde_json::to_vec(&value).ok())
+		.unwrap_or_else(|| body.to_vec())
+}
+
+/// A request handler combinator that gives requests to a primary request handler and
+/// a copy of them to a shadow request handler
+///
+/// The client only ever sees the primary response; the shadow request runs in the background.
+/// With [`compare`](Self::compare) set, the shadow response is compared with the primary one
+/// and mismatches are reported, which helps validating rewrites and migrations.
+///
+/// Requests (and, when comparing, primary responses) with bodies larger than
+/// [`max_body_size`](Self::max_body_size) are not mirrored. [Streaming](crate::body::is_streaming)
+/// primary responses are passed on as they arrive and not compared.
+pub struct Mirror<P: RequestHandler, S: RequestHandler> {
+	/// The request handler whose responses are sent to the client
+	pub primary: Arc<P>,
+	/// The request handler receiving the copies
+	pub shadow: Arc<S>,
+	/// Whether and how responses are compared
+	pub compare: Option<Compare>,
+	/// The maximum size of a body that is buffered for mirroring, in bytes
+	pub max_body_size: usize,
+}
+
+impl<P: RequestHandler, S: RequestHandler> Mirror<P, S> {
+	/// Create a [`Mirror`] without comparison and a maximum body size of 1 MiB
+	pub fn new(primary: P, shadow: S) -> Self {
+		Self {
+			primary: Arc::new(primary),
+			shadow: Arc::new(shadow),
+			compare: None,
+			max_body_size: 1 << 20,
+		}
+	}
+
+	/// Compare the responses
+	pub fn with_compare(mut self, compare: Compare) -> Self {
+		self.compare = Some(compare);
+		self
+	}
+}
+
+/// The error type for `<`[`Mirror`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum MirrorError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The primary request handler returned an error
+	Inner(E),
+	#[error("failed to read body: {0}")]
+	/// The request or primary response body could not be read
+	Body(hyper::Error),
+}
+
+type MirrorFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, MirrorError<E>>> + Send>>;
+
+impl<P, S> RequestHandler for Mirror<P, S>
+where
+	P: RequestHandler + Send + Sync + 'static,
+	S: RequestHandler + Send + Sync + 'static,
+{
+	type Error = MirrorError<P::Error>;
+	type Output = MirrorFuture<P::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let primary = self.primary.clone();
+		let shadow = self.shadow.clone();
+		let compare = self.compare.clone();
+		let max_body_size = self.max_body_size;
+		let client = client.clone();
+
+		Box::pin(async move {
+			let (parts, body) = request.into_parts();
+			let body = match buffer(body, max_body_size)
+				.await
+				.map_err(MirrorError::Body)?
+			{
+				Buffered::Complete(body) => body,
+				Buffered::Partial(body) => {
+					return primary
+						.handle(from_addr, Request::from_parts(parts, body), &client)
+						.await
+						.map_err(MirrorError::Inner);
+				}
+			};
+
+			let shadow_fut = shadow.handle(from_addr, copy_request(&parts, &body), &client);
+			let request_line = (parts.method.clone(), parts.uri.clone());
+			let response = primary
+				.handle(
+					from_addr,
+					Request::from_parts(parts, Body::from(body)),
+					&client,
+				)
+				.await
+				.map_err(MirrorError::Inner)?;
+
+			let compare = match compare {
+				Some(compare) if !is_streaming(&response) => compare,
+				_ => {
+					tokio::spawn(async move {
+						let _ = shadow_fut.await;
+					});
+					return Ok(response);
+				}
+			};
+
+			let (parts, body) = response.into_parts();
+			let body = match buffer(body, max_body_size)
+				.await
+				.map_err(MirrorError::Body)?
+			{
+				Buffered::Complete(body) => body,
+				Buffered::Partial(body) => {
+					tokio::spawn(async move {
+						let _ = shadow_fut.await;
+					});
+					return Ok(Response::from_parts(parts, body));
+				}
+			};
+			let primary = (parts.status, parts.headers.clone(), body.clone());
+
+			tokio::spawn(async move {
+				let report = match shadow_fut.await {
+					Ok(response) => {
+						let (parts, body) = response.into_parts();
+						match buffer(body, max_body_size).await {
+							Ok(Buffered::Complete(body)) => compare.diff(
+								request_line,
+								&primary,
+								&(parts.status, parts.headers, body),
+							),
+							Ok(Buffered::Partial(_)) => return,
+							Err(e) => shadow_error(request_line, e.to_string()),
+						}
+					}
+					Err(e) => shadow_error(request_line, e.to_string()),
+				};
+				if report.is_mismatch() {
+					(compare.report)(report);
+				}
+			});
+
+			Ok(Response::from_parts(parts, Body::from(body)))
+		})
+	}
+}
+
+fn shadow_error((method, uri): (Method, Uri), error: String) -> MismatchReport {
+	MismatchReport {
+		method,
+		uri,
+		status: None,
+		headers: Vec::new(),
+		body_differs: false,
+		shadow_error: Some(error),
+	}
+}
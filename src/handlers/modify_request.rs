@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+
+use hyper::header::{HeaderName, HeaderValue, InvalidHeaderValue, AUTHORIZATION, HOST};
+use hyper::http::uri::Authority;
+use hyper::{Body, Client, HeaderMap, Request};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A change to the headers of a message
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HeaderOp {
+	/// Replace all values of the header with the value
+	Set(HeaderName, HeaderValue),
+	/// Add the value to the values of the header
+	Append(HeaderName, HeaderValue),
+	/// Remove all values of the header
+	Remove(HeaderName),
+}
+
+impl HeaderOp {
+	/// Override the `Host` header with the authority
+	pub fn host(authority: &Authority) -> Self {
+		// An authority is always a valid header value
+		Self::Set(HOST, HeaderValue::from_str(authority.as_str()).unwrap())
+	}
+
+	/// Set the `Authorization` header to a bearer token, marked as sensitive
+	pub fn bearer(token: &str) -> Result<Self, InvalidHeaderValue> {
+		let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+		value.set_sensitive(true);
+		Ok(Self::Set(AUTHORIZATION, value))
+	}
+
+	/// Apply the change to the headers
+	pub fn apply(&self, headers: &mut HeaderMap) {
+		match self {
+			Self::Set(name, value) => {
+				headers.insert(name, value.clone());
+			}
+			Self::Append(name, value) => {
+				headers.append(name, value.clone());
+			}
+			Self::Remove(name) => {
+				headers.remove(name);
+			}
+		}
+	}
+}
+
+/// The exchangable part of a [`ModifyRequest`]
+pub trait ModifyRequestLogic {
+	/// Change the request before it is given to the inner request handler
+	fn modify_request(&self, from_addr: SocketAddr, request: &mut Request<Body>);
+}
+
+/// Get a [`ModifyRequestLogic`] from a function/closure
+pub fn modify_request_fn<F: Fn(SocketAddr, &mut Request<Body>)>(f: F) -> impl ModifyRequestLogic {
+	struct ModifyRequestFn<F: Fn(SocketAddr, &mut Request<Body>)>(F);
+
+	impl<F: Fn(SocketAddr, &mut Request<Body>)> ModifyRequestLogic for ModifyRequestFn<F> {
+		fn modify_request(&self, from_addr: SocketAddr, request: &mut Request<Body>) {
+			(self.0)(from_addr, request)
+		}
+	}
+
+	ModifyRequestFn(f)
+}
+
+/// Applies the operations in order
+impl ModifyRequestLogic for Vec<HeaderOp> {
+	fn modify_request(&self, _from_addr: SocketAddr, request: &mut Request<Body>) {
+		for op in self {
+			op.apply(request.headers_mut());
+		}
+	}
+}
+
+/// A request handler combinator that changes requests before giving them to the inner
+/// request handler
+///
+/// The changes are either a list of [`HeaderOp`]s or any other [`ModifyRequestLogic`],
+/// e.g. a closure given to [`modify_request_fn`].
+///
+/// # Example
+/// ```
+/// use hyper::header::{HeaderName, HeaderValue};
+/// use proxylib::handlers::modify_request::{HeaderOp, ModifyRequest};
+/// use proxylib::handlers::Redirect;
+///
+/// let authority = "backend.internal".parse().unwrap();
+/// let handler = ModifyRequest::new(
+///     Redirect::change_authority(authority),
+///     vec![
+///         HeaderOp::Remove(HeaderName::from_static("cookie")),
+///         HeaderOp::Set(
+///             HeaderName::from_static("x-tenant"),
+///             HeaderValue::from_static("acme"),
+///         ),
+///         HeaderOp::bearer("secret").unwrap(),
+///     ],
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct ModifyRequest<H: RequestHandler, L: ModifyRequestLogic = Vec<HeaderOp>> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The [`ModifyRequestLogic`] changing the requests
+	pub logic: L,
+}
+
+impl<H: RequestHandler, L: ModifyRequestLogic> ModifyRequest<H, L> {
+	/// Create a [`ModifyRequest`] changing requests with the given logic
+	pub fn new(inner: H, logic: L) -> Self {
+		Self { inner, logic }
+	}
+}
+
+impl<H: RequestHandler, L: ModifyRequestLogic> RequestHandler for ModifyRequest<H, L> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		self.logic.modify_request(from_addr, &mut request);
+		self.inner.handle(from_addr, request, client)
+	}
+}
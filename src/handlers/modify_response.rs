@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::http::uri::{Authority, Scheme};
+use hyper::{Body, Client, Request, Response, Uri};
+
+use crate::connect::Connector;
+use crate::handlers::modify_request::HeaderOp;
+use crate::RequestHandler;
+
+/// The exchangable part of a [`ModifyResponse`]
+pub trait ModifyResponseLogic {
+	/// Change the response of the inner request handler
+	fn modify_response(&self, response: &mut Response<Body>);
+}
+
+/// Get a [`ModifyResponseLogic`] from a function/closure
+pub fn modify_response_fn<F: Fn(&mut Response<Body>)>(f: F) -> impl ModifyResponseLogic {
+	struct ModifyResponseFn<F: Fn(&mut Response<Body>)>(F);
+
+	impl<F: Fn(&mut Response<Body>)> ModifyResponseLogic for ModifyResponseFn<F> {
+		fn modify_response(&self, response: &mut Response<Body>) {
+			(self.0)(response)
+		}
+	}
+
+	ModifyResponseFn(f)
+}
+
+/// Applies the operations in order
+impl ModifyResponseLogic for Vec<HeaderOp> {
+	fn modify_response(&self, response: &mut Response<Body>) {
+		for op in self {
+			op.apply(response.headers_mut());
+		}
+	}
+}
+
+/// Applies the first logic, then the second one
+impl<A: ModifyResponseLogic, B: ModifyResponseLogic> ModifyResponseLogic for (A, B) {
+	fn modify_response(&self, response: &mut Response<Body>) {
+		self.0.modify_response(response);
+		self.1.modify_response(response);
+	}
+}
+
+/// A [`ModifyResponseLogic`] that rewrites absolute `Location` headers pointing at the upstream
+/// to point at the proxy instead
+///
+/// Relative locations and locations pointing elsewhere are left as they are.
+#[derive(Debug, Clone)]
+pub struct RewriteLocation {
+	/// The authority of the upstream
+	pub from: Authority,
+	/// The authority the clients use to reach the proxy
+	pub to: Authority,
+	/// The scheme the clients use to reach the proxy, if it differs from the upstream's
+	pub scheme: Option<Scheme>,
+}
+
+impl ModifyResponseLogic for RewriteLocation {
+	fn modify_response(&self, response: &mut Response<Body>) {
+		let location = match response.headers().get(LOCATION) {
+			Some(location) => location,
+			None => return,
+		};
+		let uri = match location.to_str().ok().and_then(|s| s.parse::<Uri>().ok()) {
+			Some(uri) => uri,
+			None => return,
+		};
+		if uri.authority() != Some(&self.from) {
+			return;
+		}
+
+		let mut parts = uri.into_parts();
+		parts.authority = Some(self.to.clone());
+		if let Some(scheme) = &self.scheme {
+			parts.scheme = Some(scheme.clone());
+		}
+		let value = Uri::from_parts(parts)
+			.ok()
+			.and_then(|uri| HeaderValue::from_str(&uri.to_string()).ok());
+		if let Some(value) = value {
+			response.headers_mut().insert(LOCATION, value);
+		}
+	}
+}
+
+/// A request handler combinator that changes the responses of the inner request handler
+///
+/// The changes are either a list of [`HeaderOp`]s or any other [`ModifyResponseLogic`],
+/// e.g. a closure given to [`modify_response_fn`]; logics are combined with tuples.
+///
+/// # Example
+/// ```
+/// use hyper::header::{HeaderName, SERVER};
+/// use proxylib::handlers::modify_request::HeaderOp;
+/// use proxylib::handlers::modify_response::{ModifyResponse, RewriteLocation};
+/// use proxylib::handlers::Redirect;
+///
+/// let backend = "backend.internal:8080".parse().unwrap();
+/// let handler = ModifyResponse::new(
+///     Redirect::change_authority(backend),
+///     (
+///         vec![
+///             HeaderOp::Remove(SERVER),
+///             HeaderOp::Remove(HeaderName::from_static("x-powered-by")),
+///         ],
+///         RewriteLocation {
+///             from: "backend.internal:8080".parse().unwrap(),
+///             to: "example.com".parse().unwrap(),
+///             scheme: Some("https".parse().unwrap()),
+///         },
+///     ),
+/// );
+/// ```
+pub struct ModifyResponse<H: RequestHandler, L: ModifyResponseLogic = Vec<HeaderOp>> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The [`ModifyResponseLogic`] changing the responses
+	pub logic: Arc<L>,
+}
+
+impl<H: RequestHandler, L: ModifyResponseLogic> ModifyResponse<H, L> {
+	/// Create a [`ModifyResponse`] changing responses with the given logic
+	pub fn new(inner: H, logic: L) -> Self {
+		Self {
+			inner,
+			logic: Arc::new(logic),
+		}
+	}
+}
+
+type ModifyResponseFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H, L> RequestHandler for ModifyResponse<H, L>
+where
+	H: RequestHandler,
+	L: ModifyResponseLogic + Send + Sync + 'static,
+{
+	type Error = H::Error;
+	type Output = ModifyResponseFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let fut = self.inner.handle(from_addr, request, client);
+		let logic = self.logic.clone();
+
+		Box::pin(async move {
+			let mut response = fut.await?;
+			logic.modify_response(&mut response);
+			Ok(response)
+		})
+	}
+}
@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::body::map_data;
+use crate::connect::Connector;
+use crate::metrics::CounterFamily;
+use crate::RequestHandler;
+
+/// The response sent to clients that are turned away because the proxy is overloaded
+///
+/// Answering with a `503 Service Unavailable` and a `Retry-After` is friendlier to clients
+/// than dropping their connection, since they can tell the proxy is busy and back off.
+/// Every response is counted, with the reason as the `reason` label.
+#[derive(Debug)]
+pub struct OverloadResponse {
+	/// The value of the `Retry-After` header, if any
+	pub retry_after: Option<Duration>,
+	/// The content type of [`page`](Self::page)
+	pub content_type: String,
+	/// The body of the response, where `{reason}` and `{retry_after}` are replaced
+	/// with the reason and the number of seconds to wait
+	pub page: String,
+	/// The number of responses sent, with the label `reason`
+	pub counters: Arc<CounterFamily>,
+}
+
+impl Default for OverloadResponse {
+	fn default() -> Self {
+		Self {
+			retry_after: Some(Duration::from_secs(1)),
+			content_type: "text/plain; charset=utf-8".to_string(),
+			page: "The service is busy ({reason}), please try again later.\n".to_string(),
+			counters: Arc::new(CounterFamily::new(
+				"proxylib_overload_responses_total",
+				"The number of requests turned away because the proxy was overloaded",
+				&["reason"],
+			)),
+		}
+	}
+}
+
+impl OverloadResponse {
+	/// Use a static page (e.g. a "we're busy" HTML page) as the body
+	pub fn with_page(mut self, content_type: impl Into<String>, page: impl Into<String>) -> Self {
+		self.content_type = content_type.into();
+		self.page = page.into();
+		self
+	}
+
+	/// Build the response for the given reason and count it
+	pub fn respond(&self, reason: &str) -> Response<Body> {
+		self.counters.inc(&[reason]);
+
+		let retry_after = self.retry_after.map(|d| d.as_secs().max(1));
+		let page = self.page.replace("{reason}", reason).replace(
+			"{retry_after}",
+			&retry_after.map_or_else(String::new, |s| s.to_string()),
+		);
+		let mut response = Response::builder()
+			.status(StatusCode::SERVICE_UNAVAILABLE)
+			.header(CONTENT_TYPE, &self.content_type);
+		if let Some(retry_after) = retry_after {
+			response = response.header(RETRY_AFTER, retry_after);
+		}
+		response.body(Body::from(page)).unwrap()
+	}
+}
+
+// Counts a request as in flight until it is dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// A request handler combinator that sheds load by turning requests away early with an
+/// [`OverloadResponse`] while too many are in flight
+///
+/// A request is in flight until its response body has been sent.
+pub struct LoadShed<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The maximum number of requests in flight
+	pub max_in_flight: usize,
+	/// The response for turned away requests
+	pub overload: Arc<OverloadResponse>,
+	in_flight: Arc<AtomicUsize>,
+}
+
+impl<H: RequestHandler> LoadShed<H> {
+	/// Create a [`LoadShed`] with the default [`OverloadResponse`]
+	pub fn new(inner: H, max_in_flight: usize) -> Self {
+		Self {
+			inner,
+			max_in_flight,
+			overload: Arc::default(),
+			in_flight: Arc::default(),
+		}
+	}
+
+	/// Get the number of requests in flight
+	pub fn in_flight(&self) -> usize {
+		self.in_flight.load(Ordering::SeqCst)
+	}
+}
+
+type LoadShedFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for LoadShed<H> {
+	type Error = H::Error;
+	type Output = LoadShedFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.max_in_flight {
+			self.in_flight.fetch_sub(1, Ordering::SeqCst);
+			let response = self.overload.respond("load_shedding");
+			return Box::pin(async { Ok(response) });
+		}
+
+		let in_flight = InFlight(self.in_flight.clone());
+		let fut = self.inner.handle(from_addr, request, client);
+
+		Box::pin(async move {
+			let (parts, body) = fut.await?.into_parts();
+			if body.is_end_stream() {
+				return Ok(Response::from_parts(parts, body));
+			}
+			// The request is in flight until its response body is dropped
+			let body = map_data(body, move |data| {
+				data.inspect(move |_| {
+					let _ = &in_flight;
+				})
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
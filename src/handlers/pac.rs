@@ -0,0 +1,122 @@
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+
+use futures::future::{ready, Either, Ready};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response};
+
+use crate::connect::Connector;
+use crate::handlers::bypass::{NoProxy, Rule};
+use crate::RequestHandler;
+
+/// The media type of proxy auto-config scripts
+pub const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Generate a proxy auto-config (PAC) script that sends everything through the proxy at `proxy`
+/// (e.g. `"proxy.internal:8080"`) except for the destinations on the [`NoProxy`] list,
+/// which are connected to directly
+///
+/// Note that the listen address of the proxy is often not reachable by clients as it is
+/// (e.g. `0.0.0.0:8080`), so pass the host name clients should use instead.
+pub fn pac_script(proxy: &str, no_proxy: &NoProxy) -> String {
+	let mut script = String::from(
+		"function FindProxyForURL(url, host) {\n\
+		 \tvar m = url.match(/^[a-z][a-z0-9+.-]*:\\/\\/(?:[^@\\/]*@)?(?:\\[[^\\]]*\\]|[^:\\/]*)(?::(\\d+))?/i);\n\
+		 \tvar port = m && m[1] ? m[1] : (url.substring(0, 6).toLowerCase() == \"https:\" ? \"443\" : \"80\");\n\
+		 \tvar ipv4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n\
+		 \thost = host.toLowerCase();\n",
+	);
+	for (rule, port) in no_proxy.rules() {
+		let condition = match rule {
+			Rule::Any => "true".to_string(),
+			Rule::Domain(domain) => format!(
+				"host == {:?} || dnsDomainIs(host, {:?})",
+				domain,
+				format!(".{}", domain)
+			),
+			Rule::Net(net) => match net.addr {
+				IpAddr::V4(addr) => {
+					let mask = u32::MAX
+						.checked_shl(32 - u32::from(net.prefix_len))
+						.unwrap_or(0);
+					format!(
+						"ipv4 && isInNet(host, \"{}\", \"{}\")",
+						addr,
+						std::net::Ipv4Addr::from(mask)
+					)
+				}
+				IpAddr::V6(addr) => format!(
+					"typeof isInNetEx == \"function\" && isInNetEx(host, \"{}/{}\")",
+					addr, net.prefix_len
+				),
+			},
+		};
+		let condition = match port {
+			Some(port) => format!("port == \"{}\" && ({})", port, condition),
+			None => condition,
+		};
+		let _ = writeln!(script, "\tif ({}) return \"DIRECT\";", condition);
+	}
+	let _ = writeln!(script, "\treturn \"PROXY {}\";\n}}", proxy);
+	script
+}
+
+/// A request handler that serves a PAC script at a path and gives all other requests
+/// to the inner request handler
+pub struct ServePac<H: RequestHandler> {
+	/// The request handler for all other requests
+	pub inner: H,
+	/// The path the script is served at, e.g. `/proxy.pac`
+	pub path: String,
+	/// The PAC script
+	pub script: String,
+}
+
+impl<H: RequestHandler> ServePac<H> {
+	/// Serve a script generated by [`pac_script`] for the proxy listening on `listen_on`
+	///
+	/// If `listen_on` is an unspecified address (like `0.0.0.0`), set
+	/// [`script`](Self::script) with a reachable host name instead.
+	pub fn new(
+		inner: H,
+		path: impl Into<String>,
+		listen_on: SocketAddr,
+		no_proxy: &NoProxy,
+	) -> Self {
+		Self {
+			inner,
+			path: path.into(),
+			script: pac_script(&listen_on.to_string(), no_proxy),
+		}
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for ServePac<H> {
+	type Error = H::Error;
+	type Output = Either<Ready<Result<Response<Body>, H::Error>>, H::Output>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let is_pac = request.uri().path() == self.path
+			&& (request.method() == Method::GET || request.method() == Method::HEAD);
+		if !is_pac {
+			return Either::Right(self.inner.handle(from_addr, request, client));
+		}
+
+		let body = if request.method() == Method::HEAD {
+			Body::empty()
+		} else {
+			Body::from(self.script.clone())
+		};
+		let response = Response::builder()
+			.header(CONTENT_TYPE, PAC_CONTENT_TYPE)
+			.header(CACHE_CONTROL, "max-age=300")
+			.body(body)
+			.unwrap();
+		Either::Left(ready(Ok(response)))
+	}
+}
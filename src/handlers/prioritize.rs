@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Client, Request, Response};
+
+use crate::body::inspect_len;
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The exchangable part of a [`Prioritize`] that assigns requests to priority classes
+pub trait Classifier {
+	/// Return the index of the class the request belongs to
+	fn classify(&self, from_addr: SocketAddr, request: &Request<Body>) -> usize;
+}
+
+/// Obtain a [`Classifier`] from a function/closure
+pub fn classifier_fn<F: Fn(SocketAddr, &Request<Body>) -> usize>(f: F) -> impl Classifier {
+	struct ClassifierFn<F: Fn(SocketAddr, &Request<Body>) -> usize>(F);
+
+	impl<F: Fn(SocketAddr, &Request<Body>) -> usize> Classifier for ClassifierFn<F> {
+		fn classify(&self, from_addr: SocketAddr, request: &Request<Body>) -> usize {
+			(self.0)(from_addr, request)
+		}
+	}
+
+	ClassifierFn(f)
+}
+
+/// A [`Classifier`] which assigns classes based on the value of a header
+pub struct HeaderClassifier {
+	/// The header to look at
+	pub header: HeaderName,
+	/// The classes for known header values
+	pub classes: Vec<(HeaderValue, usize)>,
+	/// The class of requests without the header or with an unknown value
+	pub default: usize,
+}
+
+impl Classifier for HeaderClassifier {
+	fn classify(&self, _: SocketAddr, request: &Request<Body>) -> usize {
+		request
+			.headers()
+			.get(&self.header)
+			.and_then(|value| self.classes.iter().find(|(v, _)| v == value))
+			.map_or(self.default, |&(_, class)| class)
+	}
+}
+
+// The virtual time one request of a class with weight 1 takes
+const VIRTUAL_COST: u64 = 1 << 20;
+
+struct ClassQueue {
+	weight: u64,
+	virtual_time: u64,
+	waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+struct State {
+	in_flight: usize,
+	virtual_time: u64,
+	classes: Vec<ClassQueue>,
+}
+
+struct Scheduler {
+	max_in_flight: usize,
+	state: Mutex<State>,
+}
+
+struct Permit(Option<Arc<Scheduler>>);
+
+impl Drop for Permit {
+	fn drop(&mut self) {
+		if let Some(scheduler) = self.0.take() {
+			scheduler.release();
+		}
+	}
+}
+
+impl Scheduler {
+	fn acquire(self: &Arc<Self>, class: usize) -> oneshot::Receiver<Permit> {
+		let (tx, rx) = oneshot::channel();
+		let mut state = self.state.lock().unwrap();
+
+		let class = class.min(state.classes.len() - 1);
+		if state.in_flight < self.max_in_flight {
+			state.in_flight += 1;
+			drop(state);
+			let _ = tx.send(Permit(Some(self.clone())));
+		} else {
+			let now = state.virtual_time;
+			let queue = &mut state.classes[class];
+			if queue.waiters.is_empty() {
+				// don't let a class that was idle catch up on the time it didn't use
+				queue.virtual_time = queue.virtual_time.max(now);
+			}
+			queue.waiters.push_back(tx);
+		}
+
+		rx
+	}
+
+	fn release(self: &Arc<Self>) {
+		let mut state = self.state.lock().unwrap();
+
+		loop {
+			let next = state
+				.classes
+				.iter()
+				.enumerate()
+				.filter(|(_, queue)| !queue.waiters.is_empty())
+				.min_by_key(|(_, queue)| queue.virtual_time)
+				.map(|(i, _)| i);
+
+			let i = match next {
+				Some(i) => i,
+				None => {
+					state.in_flight -= 1;
+					return;
+				}
+			};
+
+			let queue = &mut state.classes[i];
+			let tx = queue.waiters.pop_front().unwrap();
+			queue.virtual_time += VIRTUAL_COST / queue.weight;
+			let virtual_time = queue.virtual_time;
+			state.virtual_time = virtual_time;
+
+			match tx.send(Permit(Some(self.clone()))) {
+				Ok(()) => return,
+				// the waiting request is gone, so the permit is still ours to hand out
+				Err(mut permit) => drop(permit.0.take()),
+			}
+		}
+	}
+}
+
+/// A request handler combinator that limits the number of requests in flight and,
+/// under contention, admits waiting requests by priority class
+///
+/// Waiting requests are scheduled with weighted fair queuing: each class gets a share
+/// of the admissions proportional to its weight, so high-priority classes are preferred
+/// without starving low-priority ones.
+///
+/// A request stays admitted until its response body has been sent (or the client went away),
+/// so long streaming responses count against the limit for as long as they last.
+///
+/// Note that the inner handler's [`handle`](RequestHandler::handle) is called right away,
+/// only the returned future waits for admission.
+pub struct Prioritize<H: RequestHandler, C: Classifier> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The [`Classifier`] assigning requests to classes
+	pub classifier: C,
+	scheduler: Arc<Scheduler>,
+}
+
+impl<H: RequestHandler, C: Classifier> Prioritize<H, C> {
+	/// Create a [`Prioritize`] with the given limit and class weights
+	///
+	/// Class indices returned by the classifier that are out of range are treated as the last class.
+	///
+	/// # Panics
+	/// Panics if `weights` is empty, any weight is zero, or `max_in_flight` is zero.
+	pub fn new(inner: H, classifier: C, max_in_flight: usize, weights: &[u32]) -> Self {
+		assert!(!weights.is_empty(), "at least one class is required");
+		assert!(weights.iter().all(|&w| w > 0), "weights must be positive");
+		assert!(max_in_flight > 0, "max_in_flight must be positive");
+
+		let classes = weights
+			.iter()
+			.map(|&weight| ClassQueue {
+				weight: weight.into(),
+				virtual_time: 0,
+				waiters: VecDeque::new(),
+			})
+			.collect();
+
+		Self {
+			inner,
+			classifier,
+			scheduler: Arc::new(Scheduler {
+				max_in_flight,
+				state: Mutex::new(State {
+					in_flight: 0,
+					virtual_time: 0,
+					classes,
+				}),
+			}),
+		}
+	}
+
+	/// The number of requests currently admitted
+	pub fn in_flight(&self) -> usize {
+		self.scheduler.state.lock().unwrap().in_flight
+	}
+}
+
+type PrioritizeFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler, C: Classifier> RequestHandler for Prioritize<H, C> {
+	type Error = H::Error;
+	type Output = PrioritizeFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let class = self.classifier.classify(from_addr, &request);
+		let admission = self.scheduler.acquire(class);
+		let fut = self.inner.handle(from_addr, request, client);
+
+		Box::pin(async move {
+			let permit = admission.await;
+			let (parts, body) = fut.await?.into_parts();
+			// The request stays admitted until the body is gone
+			let body = inspect_len(body, move |_| {
+				let _ = &permit;
+			});
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
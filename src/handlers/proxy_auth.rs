@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::Either;
+use hyper::header::{HeaderValue, CONTENT_TYPE, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// The storage of the users a [`ProxyAuth`] lets through
+pub trait CredentialStore {
+	/// Return whether the user exists and has the given password
+	fn verify(&self, user: &str, password: &str) -> bool;
+}
+
+// Compare in constant time, so the time taken doesn't tell how much of a password was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Maps users to their passwords
+impl CredentialStore for HashMap<String, String> {
+	fn verify(&self, user: &str, password: &str) -> bool {
+		self.get(user)
+			.is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+	}
+}
+
+/// The user a request was authenticated as by [`ProxyAuth`]
+///
+/// This is inserted into the request extensions before the request is given to the inner
+/// handler, so inner handlers can use it to label their logs and metrics.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ProxyUser(pub String);
+
+// Decode standard base64 with optional padding
+pub(crate) fn decode_base64(s: &str) -> Option<Vec<u8>> {
+	let s = s.trim_end_matches('=').as_bytes();
+	let mut out = Vec::with_capacity(s.len() * 3 / 4);
+	let mut acc = 0u32;
+	let mut bits = 0;
+	for &c in s {
+		let value = match c {
+			b'A'..=b'Z' => c - b'A',
+			b'a'..=b'z' => c - b'a' + 26,
+			b'0'..=b'9' => c - b'0' + 52,
+			b'+' => 62,
+			b'/' => 63,
+			_ => return None,
+		};
+		acc = (acc << 6) | u32::from(value);
+		bits += 6;
+		if bits >= 8 {
+			bits -= 8;
+			out.push((acc >> bits) as u8);
+		}
+	}
+	Some(out)
+}
+
+/// Get the user and password from the `Proxy-Authorization` header of a request using the
+/// `Basic` scheme, if there is one
+pub fn basic_credentials(request: &Request<Body>) -> Option<(String, String)> {
+	let value = request.headers().get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+	let (scheme, credentials) = value.trim().split_once(' ')?;
+	if !scheme.eq_ignore_ascii_case("basic") {
+		return None;
+	}
+	let credentials = String::from_utf8(decode_base64(credentials.trim())?).ok()?;
+	let (user, password) = credentials.split_once(':')?;
+	Some((user.to_string(), password.to_string()))
+}
+
+/// A request handler combinator that only lets clients through that authenticate to the proxy
+/// with the `Basic` scheme
+///
+/// Requests without valid credentials in the `Proxy-Authorization` header are answered with
+/// `407 Proxy Authentication Required`, asking the client for credentials for the
+/// [`realm`](Self::realm). Otherwise, the `Proxy-Authorization` header is removed, the
+/// [`ProxyUser`] is put into the request extensions and the request is given to the inner
+/// request handler.
+///
+/// Basic credentials are sent in plain text, so unless the clients reach the proxy through
+/// a trusted network, the proxy should be served with TLS.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+///
+/// use proxylib::handlers::forward_proxy::ForwardProxy;
+/// use proxylib::handlers::proxy_auth::ProxyAuth;
+/// use proxylib::handlers::tunnel::Tunnel;
+///
+/// let mut users = HashMap::new();
+/// users.insert("alice".to_string(), "correct horse battery staple".to_string());
+/// let handler = ProxyAuth::new(Tunnel::new(ForwardProxy::default()), users);
+/// ```
+pub struct ProxyAuth<H: RequestHandler, S: CredentialStore = HashMap<String, String>> {
+	/// The inner request handler to give authenticated requests to
+	pub inner: H,
+	/// The storage of the users
+	pub store: Arc<S>,
+	/// The realm shown to users when they are asked for credentials
+	pub realm: String,
+}
+
+impl<H: RequestHandler, S: CredentialStore> ProxyAuth<H, S> {
+	/// Create a [`ProxyAuth`] for the realm `proxy`
+	pub fn new(inner: H, store: S) -> Self {
+		Self {
+			inner,
+			store: Arc::new(store),
+			realm: "proxy".to_string(),
+		}
+	}
+
+	fn challenge(&self) -> Response<Body> {
+		let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+		let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+		let mut response = Response::builder()
+			.status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+			.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+			.body(Body::from("The proxy requires authentication.\n"))
+			.unwrap();
+		if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+			response.headers_mut().insert(PROXY_AUTHENTICATE, challenge);
+		}
+		response
+	}
+}
+
+impl<H: RequestHandler, S: CredentialStore> RequestHandler for ProxyAuth<H, S> {
+	type Error = H::Error;
+	type Output = Either<H::Output, Ready<Result<Response<Body>, H::Error>>>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		match basic_credentials(&request) {
+			Some((user, password)) if self.store.verify(&user, &password) => {
+				request.headers_mut().remove(PROXY_AUTHORIZATION);
+				request.extensions_mut().insert(ProxyUser(user));
+				Either::Left(self.inner.handle(from_addr, request, client))
+			}
+			_ => Either::Right(ready(Ok(self.challenge()))),
+		}
+	}
+}
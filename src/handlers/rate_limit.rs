@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use thiserror::Error;
+
+use crate::connect::Connector;
+use crate::handlers::accounting::{ClientKey, IpKey};
+use crate::RequestHandler;
+
+/// The error type of a [`RateLimitStore`]
+#[derive(Debug, Error)]
+#[error("rate limit store failed: {0}")]
+pub struct RateLimitStoreError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// The future type of a [`RateLimitStore`]
+pub type RateLimitStoreFuture =
+	Pin<Box<dyn Future<Output = Result<Option<Duration>, RateLimitStoreError>> + Send>>;
+
+/// The storage of the token buckets of a [`RateLimit`]
+pub trait RateLimitStore {
+	/// Take a token from the bucket of `key`, which holds up to `burst` tokens and refills at
+	/// `rate` tokens per second
+	///
+	/// Returns `None` if a token was taken, or how long until one is available otherwise
+	/// ([`Duration::MAX`] if never).
+	fn acquire(&self, key: &str, rate: f64, burst: u32) -> RateLimitStoreFuture;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+/// A [`RateLimitStore`] that keeps the buckets in memory
+///
+/// Full buckets are removed at most once a minute.
+#[derive(Debug)]
+pub struct MemoryRateLimitStore {
+	map: Mutex<HashMap<String, Bucket>>,
+	next_purge: Mutex<Instant>,
+}
+
+impl Default for MemoryRateLimitStore {
+	fn default() -> Self {
+		Self {
+			map: Mutex::new(HashMap::new()),
+			next_purge: Mutex::new(Instant::now()),
+		}
+	}
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+	fn acquire(&self, key: &str, rate: f64, burst: u32) -> RateLimitStoreFuture {
+		let now = Instant::now();
+		let burst = f64::from(burst);
+		let refill = |bucket: &Bucket| {
+			let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+			(bucket.tokens + elapsed * rate).min(burst)
+		};
+
+		let mut map = self.map.lock().unwrap();
+		let mut next_purge = self.next_purge.lock().unwrap();
+		// Full buckets are the same as missing ones
+		if *next_purge <= now {
+			map.retain(|_, bucket| refill(bucket) < burst);
+			*next_purge = now + Duration::from_secs(60);
+		}
+
+		let bucket = map.entry(key.to_string()).or_insert(Bucket {
+			tokens: burst,
+			updated: now,
+		});
+		let tokens = refill(bucket);
+		let wait = if tokens >= 1.0 {
+			*bucket = Bucket {
+				tokens: tokens - 1.0,
+				updated: now,
+			};
+			None
+		} else {
+			Some(Duration::try_from_secs_f64((1.0 - tokens) / rate).unwrap_or(Duration::MAX))
+		};
+		Box::pin(async move { Ok(wait) })
+	}
+}
+
+// Refills and takes from the bucket atomically, using the clock of the Redis server so that
+// proxy instances with skewed clocks agree; returns the wait in milliseconds (-1 for never)
+#[cfg(feature = "redis")]
+const ACQUIRE_SCRIPT: &str = r"
+if redis.replicate_commands then redis.replicate_commands() end
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local wait = 0
+if tokens >= 1 then
+	tokens = tokens - 1
+elseif rate > 0 then
+	wait = math.ceil((1 - tokens) / rate * 1000)
+else
+	wait = -1
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+local ttl = 86400000
+if rate > 0 then ttl = math.min(ttl, math.ceil((burst - tokens) / rate * 1000) + 1000) end
+redis.call('PEXPIRE', KEYS[1], ttl)
+return wait
+";
+
+/// A [`RateLimitStore`] that keeps the buckets in Redis, so that all proxy instances using
+/// the same Redis enforce the limits together
+///
+/// Buckets expire once they would be full again. This requires the `redis` feature.
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisRateLimitStore {
+	conn: redis::aio::ConnectionManager,
+	prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisRateLimitStore {
+	/// Connect to Redis, storing the buckets under keys starting with `prefix`
+	pub async fn connect(url: &str, prefix: impl Into<String>) -> redis::RedisResult<Self> {
+		let client = redis::Client::open(url)?;
+		Ok(Self {
+			conn: redis::aio::ConnectionManager::new(client).await?,
+			prefix: prefix.into(),
+		})
+	}
+}
+
+#[cfg(feature = "redis")]
+impl RateLimitStore for RedisRateLimitStore {
+	fn acquire(&self, key: &str, rate: f64, burst: u32) -> RateLimitStoreFuture {
+		let mut conn = self.conn.clone();
+		let key = format!("{}{}", self.prefix, key);
+		Box::pin(async move {
+			let wait: i64 = redis::cmd("EVAL")
+				.arg(ACQUIRE_SCRIPT)
+				.arg(1)
+				.arg(key)
+				.arg(rate)
+				.arg(burst)
+				.query_async(&mut conn)
+				.await
+				.map_err(|e| RateLimitStoreError(Box::new(e)))?;
+			Ok(match wait {
+				0 => None,
+				wait if wait < 0 => Some(Duration::MAX),
+				wait => Some(Duration::from_millis(wait as u64)),
+			})
+		})
+	}
+}
+
+/// A request handler combinator that limits the rate of requests of each client with a token
+/// bucket, answering requests over the limit with `429 Too Many Requests`
+///
+/// Each client has a bucket of [`burst`](Self::burst) tokens, which refills at
+/// [`rate`](Self::rate) tokens per second. Every request takes a token; if there is none left,
+/// the request is turned away with a `Retry-After` header saying when the next one is available.
+/// Clients are told apart by a [`ClientKey`], by default their IP address;
+/// requests without a key are not limited.
+///
+/// The buckets are kept in a [`RateLimitStore`], by default in memory. If the store fails,
+/// the request is let through.
+pub struct RateLimit<
+	H: RequestHandler,
+	K: ClientKey = IpKey,
+	S: RateLimitStore = MemoryRateLimitStore,
+> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The [`ClientKey`] telling clients apart
+	pub key: K,
+	/// The number of requests per second a client can make in the long run
+	pub rate: f64,
+	/// The number of requests a client can make at once
+	pub burst: u32,
+	/// The storage for the buckets
+	pub store: Arc<S>,
+}
+
+impl<H: RequestHandler> RateLimit<H> {
+	/// Create a [`RateLimit`] that limits each IP address, with in-memory storage
+	pub fn new(inner: H, rate: f64, burst: u32) -> Self {
+		Self::with_key(inner, IpKey, rate, burst)
+	}
+}
+
+impl<H: RequestHandler, K: ClientKey> RateLimit<H, K> {
+	/// Create a [`RateLimit`] that tells clients apart by the given key, with in-memory storage
+	pub fn with_key(inner: H, key: K, rate: f64, burst: u32) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			key,
+			rate,
+			burst,
+			store: Arc::new(MemoryRateLimitStore::default()),
+		}
+	}
+}
+
+impl<H: RequestHandler, K: ClientKey, S: RateLimitStore> RateLimit<H, K, S> {
+	/// Keep the buckets in the given storage instead
+	pub fn with_store<T: RateLimitStore>(self, store: T) -> RateLimit<H, K, T> {
+		RateLimit {
+			inner: self.inner,
+			key: self.key,
+			rate: self.rate,
+			burst: self.burst,
+			store: Arc::new(store),
+		}
+	}
+}
+
+fn too_many_requests(wait: Duration) -> Response<Body> {
+	let mut response = Response::builder()
+		.status(StatusCode::TOO_MANY_REQUESTS)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8");
+	if wait != Duration::MAX {
+		let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+		response = response.header(RETRY_AFTER, secs.max(1));
+	}
+	response
+		.body(Body::from("Too many requests, please slow down.\n"))
+		.unwrap()
+}
+
+type RateLimitFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H, K, S> RequestHandler for RateLimit<H, K, S>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	K: ClientKey,
+	S: RateLimitStore,
+{
+	type Error = H::Error;
+	type Output = RateLimitFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let key = match self.key.client_key(from_addr, &request) {
+			Some(key) => key,
+			None => return Box::pin(self.inner.handle(from_addr, request, client)),
+		};
+		let acquire = self.store.acquire(&key, self.rate, self.burst);
+		let inner = self.inner.clone();
+		let client = client.clone();
+
+		Box::pin(async move {
+			if let Ok(Some(wait)) = acquire.await {
+				return Ok(too_many_requests(wait));
+			}
+			inner.handle(from_addr, request, &client).await
+		})
+	}
+}
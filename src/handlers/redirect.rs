@@ -1,9 +1,19 @@
+use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use hyper::client::{HttpConnector, ResponseFuture};
+use futures::ready;
+use hyper::client::ResponseFuture;
+use hyper::header::{
+	HeaderName, HeaderValue, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+	TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::http::request::Parts;
 use hyper::http::uri::Authority;
-use hyper::{Body, Client, Request, Uri};
+use hyper::{Body, Client, HeaderMap, Request, Response, Uri, Version};
 
+use crate::connect::Connector;
 use crate::RequestHandler;
 
 /// The exchangable part of a [`Redirect`]
@@ -25,28 +35,171 @@ pub fn redirect_fn<F: Fn(&mut Uri)>(f: F) -> impl RedirectLogic {
 	RedirectFn(f)
 }
 
+/// The headers that only apply to a single connection and must not be forwarded by proxies,
+/// see [RFC 7230, Section 6.1](https://www.rfc-editor.org/rfc/rfc7230#section-6.1)
+pub const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+	CONNECTION,
+	HeaderName::from_static("keep-alive"),
+	HeaderName::from_static("proxy-connection"),
+	PROXY_AUTHENTICATE,
+	PROXY_AUTHORIZATION,
+	TE,
+	TRAILER,
+	TRANSFER_ENCODING,
+	UPGRADE,
+];
+
+/// Remove the [`HOP_BY_HOP_HEADERS`] and the headers named in `Connection` from a message
+///
+/// Upgrades (e.g. to WebSocket) are kept, with `Connection: upgrade` and the `Upgrade` header,
+/// as is `TE: trailers`, which gRPC relies on.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+	let tokens = |headers: &HeaderMap, name| {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.map(|v| v.trim().to_ascii_lowercase())
+			.filter(|v| !v.is_empty())
+			.collect::<Vec<_>>()
+	};
+	let connection = tokens(headers, CONNECTION);
+	let upgrade = if connection.iter().any(|t| t == "upgrade") {
+		headers.get_all(UPGRADE).iter().cloned().collect()
+	} else {
+		Vec::new()
+	};
+	let trailers = tokens(headers, TE).iter().any(|t| t == "trailers");
+
+	for name in connection {
+		if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+			headers.remove(name);
+		}
+	}
+	for name in &HOP_BY_HOP_HEADERS {
+		headers.remove(name);
+	}
+
+	if !upgrade.is_empty() {
+		headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+		for value in upgrade {
+			headers.append(UPGRADE, value);
+		}
+	}
+	if trailers {
+		headers.insert(TE, HeaderValue::from_static("trailers"));
+	}
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 /// A request handler that works by changing the request URI and forwarding that request to the client
+///
+/// Hop-by-hop headers are removed from the request and the response with
+/// [`remove_hop_by_hop_headers`], unless [`strip_hop_by_hop`](Self::strip_hop_by_hop) is unset.
+/// The URI the request was sent to is put into the extensions of the response as [`Upstream`].
 pub struct Redirect<L: RedirectLogic> {
 	/// The [`RedirectLogic`] providing the redirect functionality
 	pub logic: L,
+	/// Whether hop-by-hop headers are removed
+	pub strip_hop_by_hop: bool,
+}
+
+impl<L: RedirectLogic> Redirect<L> {
+	/// Create a [`Redirect`] with the given logic that removes hop-by-hop headers
+	pub fn new(logic: L) -> Self {
+		Self {
+			logic,
+			strip_hop_by_hop: true,
+		}
+	}
+}
+
+// Requests that arrived over HTTP/2 or HTTP/3 are sent as HTTP/1.1 ones, which the client
+// upgrades by itself if the upstream connection uses HTTP/2; as they are, they would fail on
+// HTTP/1.1 connections
+pub(crate) fn downgrade_version(parts: &mut Parts) {
+	if matches!(parts.version, Version::HTTP_2 | Version::HTTP_3) {
+		parts.version = Version::HTTP_11;
+	}
+}
+
+/// The URI a request was forwarded to, which the request handlers that forward requests (like
+/// [`Redirect`]) put into the extensions of its response
+///
+/// Combinators around them can read it, e.g. to log the upstream of a request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Upstream(pub Uri);
+
+/// The future of a request forwarded by [`Redirect`] and the other request handlers that
+/// forward requests
+pub struct Forward {
+	response: ResponseFuture,
+	upstream: Option<Uri>,
+	strip_hop_by_hop: bool,
+}
+
+// Send the request to the upstream of its URI
+pub(crate) fn forward(
+	client: &Client<Connector>,
+	request: Request<Body>,
+	strip_hop_by_hop: bool,
+) -> Forward {
+	#[cfg(feature = "tracing")]
+	tracing::debug!(upstream = %request.uri(), "forwarding request");
+	Forward {
+		upstream: Some(request.uri().clone()),
+		response: client.request(request),
+		strip_hop_by_hop,
+	}
+}
+
+impl Future for Forward {
+	type Output = hyper::Result<Response<Body>>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let result = ready!(Pin::new(&mut self.response).poll(cx));
+		#[cfg(feature = "tracing")]
+		match &result {
+			Ok(response) => {
+				tracing::debug!(status = response.status().as_u16(), "upstream responded")
+			}
+			Err(error) => tracing::debug!(%error, "upstream request failed"),
+		}
+		let mut response = result?;
+		if self.strip_hop_by_hop {
+			remove_hop_by_hop_headers(response.headers_mut());
+		}
+		if let Some(upstream) = self.upstream.take() {
+			response.extensions_mut().insert(Upstream(upstream));
+		}
+		Poll::Ready(Ok(response))
+	}
 }
 
 impl<L: RedirectLogic> RequestHandler for Redirect<L> {
 	type Error = hyper::Error;
-	type Output = ResponseFuture;
+	type Output = Forward;
 
 	fn handle(
 		&self,
 		_from_addr: SocketAddr,
 		request: Request<Body>,
-		client: &Client<HttpConnector>,
+		client: &Client<Connector>,
 	) -> Self::Output {
 		let (mut parts, body) = request.into_parts();
 
 		self.logic.change_uri(&mut parts.uri);
+		downgrade_version(&mut parts);
 
-		client.request(Request::from_parts(parts, body))
+		if self.strip_hop_by_hop {
+			remove_hop_by_hop_headers(&mut parts.headers);
+		}
+		forward(
+			client,
+			Request::from_parts(parts, body),
+			self.strip_hop_by_hop,
+		)
 	}
 }
 
@@ -69,8 +222,6 @@ impl RedirectLogic for ChangeAuthority {
 impl Redirect<ChangeAuthority> {
 	/// A convenience method to get a [`Redirect`]`<`[`ChangeAuthority`]`>`
 	pub fn change_authority(to: Authority) -> Self {
-		Self {
-			logic: ChangeAuthority { to },
-		}
+		Self::new(ChangeAuthority { to })
 	}
 }
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request, Response, Uri};
+use thiserror::Error;
+
+use crate::body::read_limited;
+use crate::connect::Connector;
+use crate::handlers::filter::IpNet;
+use crate::RequestHandler;
+
+/// The error type of a [`ReputationProvider`]
+#[derive(Debug, Error)]
+#[error("reputation lookup failed: {0}")]
+pub struct ProviderError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// The future type of a [`ReputationProvider`]
+pub type ProviderFuture = Pin<Box<dyn Future<Output = Result<Option<u8>, ProviderError>> + Send>>;
+
+/// A source of IP reputation scores
+///
+/// Scores range from `0` (harmless) to `100` (known malicious);
+/// `None` means the provider knows nothing about the address.
+pub trait ReputationProvider {
+	/// Look up the score of an address
+	fn score(&self, ip: IpAddr, client: &Client<Connector>) -> ProviderFuture;
+}
+
+/// Multiple providers, of which the highest score is used
+///
+/// Providers that fail are ignored, unless all of them fail.
+impl ReputationProvider for Vec<Box<dyn ReputationProvider + Send + Sync>> {
+	fn score(&self, ip: IpAddr, client: &Client<Connector>) -> ProviderFuture {
+		let lookups = self
+			.iter()
+			.map(|provider| provider.score(ip, client))
+			.collect::<Vec<_>>();
+
+		Box::pin(async move {
+			let results = futures::future::join_all(lookups).await;
+			let mut score = None;
+			let mut error = None;
+			for result in results {
+				match result {
+					Ok(s) => score = score.max(s),
+					Err(e) => error = Some(e),
+				}
+			}
+			match error {
+				Some(e) if score.is_none() => Err(e),
+				_ => Ok(score),
+			}
+		})
+	}
+}
+
+/// A [`ReputationProvider`] backed by a local feed file
+///
+/// Each line of the feed holds an address or network, optionally followed by whitespace
+/// and a score (which is `100` if omitted). Empty lines and lines starting with `#` are ignored.
+/// If an address is in multiple networks, the highest score is used.
+#[derive(Debug)]
+pub struct FeedProvider {
+	path: Option<PathBuf>,
+	entries: RwLock<Vec<(IpNet, u8)>>,
+}
+
+impl FeedProvider {
+	/// Parse a feed
+	pub fn parse(feed: &str) -> Self {
+		Self {
+			path: None,
+			entries: RwLock::new(parse_feed(feed)),
+		}
+	}
+
+	/// Read a feed file
+	pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+		let path = path.as_ref().to_path_buf();
+		let entries = parse_feed(&std::fs::read_to_string(&path)?);
+		Ok(Self {
+			path: Some(path),
+			entries: RwLock::new(entries),
+		})
+	}
+
+	/// Re-read the feed file, e.g. after it was updated
+	///
+	/// This does nothing for feeds created with [`parse`](Self::parse).
+	pub fn reload(&self) -> io::Result<()> {
+		if let Some(path) = &self.path {
+			let entries = parse_feed(&std::fs::read_to_string(path)?);
+			*self.entries.write().unwrap() = entries;
+		}
+		Ok(())
+	}
+}
+
+fn parse_feed(feed: &str) -> Vec<(IpNet, u8)> {
+	feed.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.filter_map(|line| {
+			let mut fields = line.split_whitespace();
+			let net = fields.next()?.parse().ok()?;
+			let score = match fields.next() {
+				Some(score) => score.parse::<u8>().ok()?.min(100),
+				None => 100,
+			};
+			Some((net, score))
+		})
+		.collect()
+}
+
+impl ReputationProvider for FeedProvider {
+	fn score(&self, ip: IpAddr, _: &Client<Connector>) -> ProviderFuture {
+		let score = self
+			.entries
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|(net, _)| net.contains(ip))
+			.map(|&(_, score)| score)
+			.max();
+		Box::pin(async move { Ok(score) })
+	}
+}
+
+/// A function that extracts a score from a response body of an [`HttpProvider`]
+pub type ExtractScore = Arc<dyn Fn(&[u8]) -> Option<u8> + Send + Sync>;
+
+/// A [`ReputationProvider`] that queries an HTTP threat-intelligence API
+///
+/// The placeholder `{ip}` in the URI template is replaced with the address. Responses other than
+/// `2xx` are treated as "unknown" for `404` and as errors otherwise; the score is taken from
+/// the body by [`extract`](Self::extract), which by default parses the whole body as a number.
+pub struct HttpProvider {
+	/// The URI template, e.g. `https://intel.example.com/v1/ip/{ip}/score`
+	pub template: String,
+	/// Headers to add to the requests, e.g. an API key
+	pub headers: hyper::HeaderMap,
+	/// The function that extracts the score from the response body
+	pub extract: ExtractScore,
+}
+
+impl HttpProvider {
+	/// Create an [`HttpProvider`] expecting plain number responses
+	pub fn new(template: impl Into<String>) -> Self {
+		Self {
+			template: template.into(),
+			headers: hyper::HeaderMap::new(),
+			extract: Arc::new(|body| std::str::from_utf8(body).ok()?.trim().parse().ok()),
+		}
+	}
+}
+
+impl ReputationProvider for HttpProvider {
+	fn score(&self, ip: IpAddr, client: &Client<Connector>) -> ProviderFuture {
+		let uri = self
+			.template
+			.replace("{ip}", &ip.to_string())
+			.parse::<Uri>();
+		let headers = self.headers.clone();
+		let extract = self.extract.clone();
+		let client = client.clone();
+
+		Box::pin(async move {
+			let mut request = Request::new(Body::empty());
+			*request.uri_mut() = uri.map_err(|e| ProviderError(Box::new(e)))?;
+			*request.headers_mut() = headers;
+
+			let response = client
+				.request(request)
+				.await
+				.map_err(|e| ProviderError(Box::new(e)))?;
+			if response.status() == hyper::StatusCode::NOT_FOUND {
+				return Ok(None);
+			}
+			if !response.status().is_success() {
+				return Err(ProviderError(
+					format!("unexpected status {}", response.status()).into(),
+				));
+			}
+			let body = read_limited(response.into_body(), 64 * 1024)
+				.await
+				.map_err(|e| ProviderError(Box::new(e)))?
+				.ok_or_else(|| ProviderError("response body is too large".into()))?;
+			Ok(extract(&body).map(|score| score.min(100)))
+		})
+	}
+}
+
+// The cached scores; expired entries are removed at most once a minute
+#[derive(Default)]
+struct ScoreCache {
+	scores: HashMap<IpAddr, (Option<u8>, Instant)>,
+	next_purge: Option<Instant>,
+}
+
+/// A request handler combinator that drops requests from sources with a bad reputation
+/// before giving the others to another request handler
+///
+/// Scores are cached per address for [`cache_ttl`](Self::cache_ttl). If the provider fails,
+/// the request is let through (and the failure is not cached).
+pub struct Reputation<H: RequestHandler, P: ReputationProvider> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The provider of the scores
+	pub provider: P,
+	/// The score from which on requests are dropped
+	pub threshold: u8,
+	/// How long scores are cached
+	pub cache_ttl: Duration,
+	cache: Arc<Mutex<ScoreCache>>,
+}
+
+impl<H: RequestHandler, P: ReputationProvider> Reputation<H, P> {
+	/// Create a [`Reputation`] with a threshold of `50` and a cache TTL of 10 minutes
+	pub fn new(inner: H, provider: P) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			provider,
+			threshold: 50,
+			cache_ttl: Duration::from_secs(10 * 60),
+			cache: Arc::new(Mutex::new(ScoreCache::default())),
+		}
+	}
+
+	/// Clear the cached scores, e.g. after a feed was reloaded
+	pub fn clear_cache(&self) {
+		self.cache.lock().unwrap().scores.clear();
+	}
+}
+
+/// The error type for `<`[`Reputation`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum ReputationError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("request from {0} was dropped because of its reputation score {1}")]
+	/// The source of the request has a bad reputation
+	Blocked(SocketAddr, u8, Box<Request<Body>>),
+}
+
+type ReputationFuture<E> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, ReputationError<E>>> + Send>>;
+
+impl<H, P> RequestHandler for Reputation<H, P>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	P: ReputationProvider,
+{
+	type Error = ReputationError<H::Error>;
+	type Output = ReputationFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let ip = from_addr.ip();
+		let now = Instant::now();
+		let cached = {
+			let mut cache = self.cache.lock().unwrap();
+			match cache.scores.get(&ip) {
+				Some(&(score, expires)) if expires > now => Some(score),
+				Some(_) => {
+					cache.scores.remove(&ip);
+					None
+				}
+				None => None,
+			}
+		};
+		let lookup = match cached {
+			Some(_) => None,
+			None => Some(self.provider.score(ip, client)),
+		};
+
+		let inner = self.inner.clone();
+		let cache = self.cache.clone();
+		let cache_ttl = self.cache_ttl;
+		let threshold = self.threshold;
+		let client = client.clone();
+
+		Box::pin(async move {
+			let score = match (cached, lookup) {
+				(Some(score), _) => score,
+				(None, Some(lookup)) => match lookup.await {
+					Ok(score) => {
+						let mut cache = cache.lock().unwrap();
+						if cache.next_purge.is_none_or(|next| next <= now) {
+							cache.scores.retain(|_, (_, expires)| *expires > now);
+							cache.next_purge = Some(now + Duration::from_secs(60));
+						}
+						cache.scores.insert(ip, (score, now + cache_ttl));
+						score
+					}
+					Err(_) => None,
+				},
+				(None, None) => None,
+			};
+
+			match score {
+				Some(score) if score >= threshold => Err(ReputationError::Blocked(
+					from_addr,
+					score,
+					Box::new(request),
+				)),
+				_ => inner
+					.handle(from_addr, request, &client)
+					.await
+					.map_err(ReputationError::Inner),
+			}
+		})
+	}
+}
@@ -0,0 +1,292 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+
+use crate::body::{buffer, copy_context, copy_request, Buffered};
+use crate::connect::Connector;
+use crate::handlers::balance::Random;
+use crate::handlers::deadline::RequestDeadline;
+use crate::RequestHandler;
+
+/// A limit on retries relative to the recent request volume, shared by retrying handlers
+///
+/// Every original request deposits into the budget and every retry withdraws from it;
+/// retries are only allowed while they stay below [`ratio`](Self::ratio) of the requests in
+/// the last [`window`](Self::window), plus [`min_per_sec`](Self::min_per_sec) retries per second
+/// so low-traffic services can still retry. This keeps a degraded backend from being hammered
+/// by amplified retries, since the retries of all handlers sharing the budget are capped together.
+#[derive(Debug)]
+pub struct RetryBudget {
+	/// The allowed ratio of retries to requests
+	pub ratio: f64,
+	/// The number of retries per second that are always allowed
+	pub min_per_sec: u32,
+	/// How far back requests and retries are counted
+	pub window: Duration,
+	buckets: Mutex<Buckets>,
+}
+
+// Requests and retries counted in one-second buckets, the last one being the current second
+#[derive(Debug)]
+struct Buckets {
+	start: Instant,
+	second: u64,
+	counts: Vec<(u64, u64)>,
+}
+
+impl Buckets {
+	// Advance to the current second, discarding buckets that left the window
+	fn advance(&mut self) -> &mut (u64, u64) {
+		let second = self.start.elapsed().as_secs();
+		let len = self.counts.len();
+		let passed = ((second - self.second) as usize).min(len);
+		self.counts.rotate_left(passed);
+		for bucket in &mut self.counts[len - passed..] {
+			*bucket = (0, 0);
+		}
+		self.second = second;
+		&mut self.counts[len - 1]
+	}
+}
+
+impl Default for RetryBudget {
+	/// A budget of 20% of the requests of the last 10 seconds, plus 10 retries per second
+	fn default() -> Self {
+		Self::new(0.2, 10, Duration::from_secs(10))
+	}
+}
+
+impl RetryBudget {
+	/// Create a budget
+	pub fn new(ratio: f64, min_per_sec: u32, window: Duration) -> Self {
+		let seconds = window.as_secs().max(1) as usize;
+		Self {
+			ratio,
+			min_per_sec,
+			window,
+			buckets: Mutex::new(Buckets {
+				start: Instant::now(),
+				second: 0,
+				counts: vec![(0, 0); seconds],
+			}),
+		}
+	}
+
+	/// Record an original (not retried) request
+	pub fn deposit(&self) {
+		self.buckets.lock().unwrap().advance().0 += 1;
+	}
+
+	/// Try to spend budget on a retry, returning whether the retry is allowed
+	pub fn try_withdraw(&self) -> bool {
+		let mut buckets = self.buckets.lock().unwrap();
+		buckets.advance();
+		let (requests, retries) = buckets
+			.counts
+			.iter()
+			.fold((0, 0), |(a, b), &(c, d)| (a + c, b + d));
+
+		let allowed = requests as f64 * self.ratio
+			+ f64::from(self.min_per_sec) * buckets.counts.len() as f64;
+		if (retries as f64) < allowed {
+			buckets.advance().1 += 1;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// How long a [`Retry`] waits before each retry
+///
+/// The delay starts at [`initial`](Self::initial) and is multiplied by
+/// [`multiplier`](Self::multiplier) after every retry, up to [`max`](Self::max).
+/// Up to [`jitter`](Self::jitter) of each delay is randomly taken off, so that clients
+/// that failed at the same time don't retry at the same time.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+	/// The delay before the first retry
+	pub initial: Duration,
+	/// The longest delay
+	pub max: Duration,
+	/// The factor the delay grows by with every retry
+	pub multiplier: f64,
+	/// The fraction of the delay (from `0.0` to `1.0`) that is randomized
+	pub jitter: f64,
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self {
+			initial: Duration::from_millis(100),
+			max: Duration::from_secs(2),
+			multiplier: 2.0,
+			jitter: 0.5,
+		}
+	}
+}
+
+impl Backoff {
+	// The delay before the given retry (starting at 0)
+	fn delay(&self, retry: u32, random: &Random) -> Duration {
+		let delay = self.initial.as_secs_f64() * self.multiplier.powi(retry as i32);
+		let delay = delay.min(self.max.as_secs_f64());
+		let delay = delay * (1.0 - self.jitter.clamp(0.0, 1.0) * random.next());
+		Duration::try_from_secs_f64(delay).unwrap_or(self.max)
+	}
+}
+
+/// A request handler combinator that retries requests the inner request handler failed,
+/// with exponential [`Backoff`]
+///
+/// A request is retried if the inner request handler returns an error (e.g. because the
+/// upstream couldn't be connected to) or responds with one of the
+/// [`retry_statuses`](Self::retry_statuses). The last response or error is given back.
+///
+/// To be able to send a request again, its body is buffered; requests with bodies larger than
+/// [`max_body_size`](Self::max_body_size) are not retried. Neither are requests with methods that
+/// aren't idempotent (like `POST`), unless [`retry_all_methods`](Self::retry_all_methods) is set.
+/// Retries stop early if the [`RequestDeadline`] of the request would pass during the backoff,
+/// and are limited by the [`RetryBudget`], if any.
+///
+/// Only the first attempt gets all extensions of the request; the retries carry over the ones
+/// describing its connection and context, like its
+/// [`ProxiedAddrs`](crate::proxy_protocol::ProxiedAddrs),
+/// [`OriginalDestination`](crate::transparent::OriginalDestination) and [`RequestDeadline`].
+pub struct Retry<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The maximum number of attempts, including the first one
+	pub max_attempts: u32,
+	/// The response statuses that are retried
+	pub retry_statuses: Vec<StatusCode>,
+	/// How long to wait before retrying
+	pub backoff: Backoff,
+	/// The largest request body that is buffered for retries
+	pub max_body_size: usize,
+	/// Whether requests with methods that aren't idempotent are retried as well
+	pub retry_all_methods: bool,
+	/// The budget limiting the retries, possibly shared with other handlers
+	pub budget: Option<Arc<RetryBudget>>,
+	random: Arc<Random>,
+}
+
+impl<H: RequestHandler> Retry<H> {
+	/// Create a [`Retry`] making up to 3 attempts, retrying errors as well as
+	/// `502 Bad Gateway`, `503 Service Unavailable` and `504 Gateway Timeout`
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			max_attempts: 3,
+			retry_statuses: vec![
+				StatusCode::BAD_GATEWAY,
+				StatusCode::SERVICE_UNAVAILABLE,
+				StatusCode::GATEWAY_TIMEOUT,
+			],
+			backoff: Backoff::default(),
+			max_body_size: 64 * 1024,
+			retry_all_methods: false,
+			budget: None,
+			random: Arc::new(Random::new()),
+		}
+	}
+
+	/// Limit the retries with the given budget
+	pub fn with_budget(mut self, budget: Arc<RetryBudget>) -> Self {
+		self.budget = Some(budget);
+		self
+	}
+}
+
+fn is_idempotent(method: &Method) -> bool {
+	matches!(
+		*method,
+		Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+	)
+}
+
+type RetryFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler + Send + Sync + 'static> RequestHandler for Retry<H> {
+	type Error = H::Error;
+	type Output = RetryFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if let Some(budget) = &self.budget {
+			budget.deposit();
+		}
+		if self.max_attempts <= 1 || !(self.retry_all_methods || is_idempotent(request.method())) {
+			return Box::pin(self.inner.handle(from_addr, request, client));
+		}
+
+		let inner = self.inner.clone();
+		let client = client.clone();
+		let max_attempts = self.max_attempts;
+		let retry_statuses = self.retry_statuses.clone();
+		let backoff = self.backoff.clone();
+		let max_body_size = self.max_body_size;
+		let budget = self.budget.clone();
+		let random = self.random.clone();
+
+		Box::pin(async move {
+			let (mut parts, body) = request.into_parts();
+			let body = match buffer(body, max_body_size).await {
+				Ok(Buffered::Complete(body)) => body,
+				Ok(Buffered::Partial(body)) => {
+					let request = Request::from_parts(parts, body);
+					return inner.handle(from_addr, request, &client).await;
+				}
+				// The client went away; let the inner handler deal with the broken body
+				Err(e) => {
+					let body =
+						Body::wrap_stream(futures::stream::once(async { Err::<Bytes, _>(e) }));
+					let request = Request::from_parts(parts, body);
+					return inner.handle(from_addr, request, &client).await;
+				}
+			};
+			let deadline = parts.extensions.get::<RequestDeadline>().copied();
+			// The first attempt gets all extensions, the retries the context of the request
+			let extensions = std::mem::take(&mut parts.extensions);
+			copy_context(&extensions, &mut parts.extensions);
+			let mut extensions = Some(extensions);
+
+			let mut attempt = 0;
+			loop {
+				let mut request = copy_request(&parts, &body);
+				if let Some(extensions) = extensions.take() {
+					*request.extensions_mut() = extensions;
+				}
+				let result = inner.handle(from_addr, request, &client).await;
+				attempt += 1;
+
+				let failed = match &result {
+					Ok(response) => retry_statuses.contains(&response.status()),
+					Err(_) => true,
+				};
+				if !failed || attempt >= max_attempts {
+					return result;
+				}
+
+				let delay = backoff.delay(attempt - 1, &random);
+				let past_deadline = deadline
+					.is_some_and(|RequestDeadline(deadline)| Instant::now() + delay >= deadline);
+				let allowed = budget.as_ref().is_none_or(|budget| budget.try_withdraw());
+				if past_deadline || !allowed {
+					return result;
+				}
+				drop(result);
+				tokio::time::sleep(delay).await;
+			}
+		})
+	}
+}
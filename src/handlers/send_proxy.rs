@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request};
+
+use crate::connect::{Connector, UpstreamConfig};
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::RequestHandler;
+
+/// A request handler combinator that gives requests to another request handler together with
+/// a client whose connections start with a PROXY protocol header (version 2) with the address
+/// of the client
+///
+/// This is for upstreams that want the address of the client on the TCP level, like another
+/// proxy or a plain TCP service behind a [`Tunnel`](super::tunnel::Tunnel). As the header is
+/// about one client, every client gets its own client built from the config, like with
+/// [`WithClient`](super::with_client::WithClient), whose connections are only reused for its
+/// requests. Clients that haven't made requests for the
+/// [`idle_timeout`](UpstreamConfig::idle_timeout) of the config (or 90 seconds) are dropped.
+///
+/// The destination address in the header is the one the client connected to, taken from the
+/// [`ProxiedAddrs`] of the request, or the unspecified address if there are none.
+///
+/// # Example
+/// ```
+/// use proxylib::connect::UpstreamConfig;
+/// use proxylib::handlers::send_proxy::SendProxyProtocol;
+/// use proxylib::handlers::Redirect;
+///
+/// let handler = SendProxyProtocol::new(
+///     Redirect::change_authority("edge.internal:8080".parse().unwrap()),
+///     UpstreamConfig::default(),
+/// );
+/// ```
+pub struct SendProxyProtocol<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	config: UpstreamConfig,
+	clients: Mutex<HashMap<ProxiedAddrs, (Client<Connector>, Instant)>>,
+}
+
+impl<H: RequestHandler> SendProxyProtocol<H> {
+	/// Create a [`SendProxyProtocol`] whose clients are built from the given config
+	pub fn new(inner: H, config: UpstreamConfig) -> Self {
+		Self {
+			inner,
+			config,
+			clients: Mutex::default(),
+		}
+	}
+
+	/// Get the config the clients are built from
+	pub fn config(&self) -> &UpstreamConfig {
+		&self.config
+	}
+
+	// Get the client for the addresses, building it if there is none
+	fn client_for(&self, addrs: ProxiedAddrs) -> Client<Connector> {
+		let now = Instant::now();
+		let mut clients = self.clients.lock().unwrap();
+		if let Some((client, used)) = clients.get_mut(&addrs) {
+			*used = now;
+			return client.clone();
+		}
+
+		// The idle connections of clients that haven't been used for this long are gone anyway
+		let expiry = self.config.idle_timeout.unwrap_or(Duration::from_secs(90));
+		clients.retain(|_, (_, used)| now.duration_since(*used) < expiry);
+		let client = self.config.build_client_with_proxy_header(&addrs);
+		clients.insert(addrs, (client.clone(), now));
+		client
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for SendProxyProtocol<H> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		_client: &Client<Connector>,
+	) -> Self::Output {
+		let destination = match request.extensions().get::<ProxiedAddrs>() {
+			Some(addrs) => addrs.destination,
+			None => {
+				let unspecified = match from_addr {
+					SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+					SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+				};
+				SocketAddr::new(unspecified, 0)
+			}
+		};
+		let client = self.client_for(ProxiedAddrs {
+			source: from_addr,
+			destination,
+		});
+		self.inner.handle(from_addr, request, &client)
+	}
+}
@@ -0,0 +1,63 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use hyper::body::HttpBody;
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::body::limit_size;
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A request handler combinator that stops forwarding upstream responses larger than a limit
+///
+/// Responses that declare a larger `Content-Length` are replaced with a `502 Bad Gateway`.
+/// Responses that turn out to be larger while streaming are cut off with an error, since
+/// their status has already been sent by then.
+///
+/// This protects the memory of caching or transforming handlers further out, which buffer
+/// the responses they get from here.
+pub struct ResponseSizeLimit<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The size of the largest response body that is forwarded
+	pub max: u64,
+}
+
+impl<H: RequestHandler> ResponseSizeLimit<H> {
+	/// Create a [`ResponseSizeLimit`] allowing bodies up to `max` bytes
+	pub fn new(inner: H, max: u64) -> Self {
+		Self { inner, max }
+	}
+}
+
+type ResponseSizeLimitFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for ResponseSizeLimit<H> {
+	type Error = H::Error;
+	type Output = ResponseSizeLimitFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let fut = self.inner.handle(from_addr, request, client);
+		let max = self.max;
+
+		Box::pin(async move {
+			let (parts, body) = fut.await?.into_parts();
+			if body.size_hint().lower() > max {
+				return Ok(Response::builder()
+					.status(StatusCode::BAD_GATEWAY)
+					.body(Body::from(format!(
+						"the upstream response exceeds the limit of {} bytes",
+						max
+					)))
+					.unwrap());
+			}
+			Ok(Response::from_parts(parts, limit_size(body, max)))
+		})
+	}
+}
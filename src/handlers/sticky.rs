@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::COOKIE;
+use hyper::{Body, Client, Request, Response};
+use thiserror::Error;
+
+use crate::body::map_data;
+use crate::connect::Connector;
+use crate::handlers::accounting::ClientKey;
+use crate::handlers::balance::{DrainHandle, InFlight};
+use crate::RequestHandler;
+
+/// A [`ClientKey`] which uses the value of a cookie (e.g. a session id)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CookieKey(pub String);
+
+impl ClientKey for CookieKey {
+	fn client_key(&self, _: SocketAddr, request: &Request<Body>) -> Option<String> {
+		request
+			.headers()
+			.get_all(COOKIE)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(';'))
+			.filter_map(|pair| pair.trim().split_once('='))
+			.find(|(name, _)| *name == self.0)
+			.map(|(_, value)| value.to_string())
+	}
+}
+
+/// The error type of a [`SessionStore`]
+#[derive(Debug, Error)]
+#[error("session store failed: {0}")]
+pub struct SessionStoreError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// The future type of a [`SessionStore`]
+pub type SessionFuture<T> = Pin<Box<dyn Future<Output = Result<T, SessionStoreError>> + Send>>;
+
+/// The storage of the session-to-upstream mappings of a [`Sticky`]
+pub trait SessionStore {
+	/// Get the upstream the session is pinned to
+	fn get(&self, session: &str) -> SessionFuture<Option<String>>;
+
+	/// Pin the session to an upstream for the given time
+	fn set(&self, session: &str, upstream: &str, ttl: Duration) -> SessionFuture<()>;
+}
+
+/// A [`SessionStore`] that keeps the mappings in memory
+///
+/// Expired mappings are removed at most once a minute.
+#[derive(Debug)]
+pub struct MemorySessionStore {
+	map: Mutex<HashMap<String, (String, Instant)>>,
+	next_purge: Mutex<Instant>,
+}
+
+impl Default for MemorySessionStore {
+	fn default() -> Self {
+		Self {
+			map: Mutex::new(HashMap::new()),
+			next_purge: Mutex::new(Instant::now()),
+		}
+	}
+}
+
+impl SessionStore for MemorySessionStore {
+	fn get(&self, session: &str) -> SessionFuture<Option<String>> {
+		let map = self.map.lock().unwrap();
+		let upstream = map
+			.get(session)
+			.filter(|(_, expires)| *expires > Instant::now())
+			.map(|(upstream, _)| upstream.clone());
+		Box::pin(async move { Ok(upstream) })
+	}
+
+	fn set(&self, session: &str, upstream: &str, ttl: Duration) -> SessionFuture<()> {
+		let now = Instant::now();
+		let mut map = self.map.lock().unwrap();
+		let mut next_purge = self.next_purge.lock().unwrap();
+		if *next_purge <= now {
+			map.retain(|_, (_, expires)| *expires > now);
+			*next_purge = now + Duration::from_secs(60);
+		}
+		map.insert(session.to_string(), (upstream.to_string(), now + ttl));
+		Box::pin(async { Ok(()) })
+	}
+}
+
+/// A [`SessionStore`] that keeps the mappings in Redis, so they survive restarts
+/// and are shared by all proxy instances using the same Redis
+///
+/// This requires the `redis` feature.
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisSessionStore {
+	conn: redis::aio::ConnectionManager,
+	prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionStore {
+	/// Connect to Redis, storing the mappings under keys starting with `prefix`
+	pub async fn connect(url: &str, prefix: impl Into<String>) -> redis::RedisResult<Self> {
+		let client = redis::Client::open(url)?;
+		Ok(Self {
+			conn: redis::aio::ConnectionManager::new(client).await?,
+			prefix: prefix.into(),
+		})
+	}
+}
+
+#[cfg(feature = "redis")]
+impl SessionStore for RedisSessionStore {
+	fn get(&self, session: &str) -> SessionFuture<Option<String>> {
+		let mut conn = self.conn.clone();
+		let key = format!("{}{}", self.prefix, session);
+		Box::pin(async move {
+			redis::cmd("GET")
+				.arg(key)
+				.query_async(&mut conn)
+				.await
+				.map_err(|e| SessionStoreError(Box::new(e)))
+		})
+	}
+
+	fn set(&self, session: &str, upstream: &str, ttl: Duration) -> SessionFuture<()> {
+		let mut conn = self.conn.clone();
+		let key = format!("{}{}", self.prefix, session);
+		let upstream = upstream.to_string();
+		Box::pin(async move {
+			redis::cmd("SET")
+				.arg(key)
+				.arg(upstream)
+				.arg("PX")
+				.arg(ttl.as_millis().max(1) as u64)
+				.query_async(&mut conn)
+				.await
+				.map_err(|e| SessionStoreError(Box::new(e)))
+		})
+	}
+}
+
+/// A request handler that pins sessions to one of multiple upstream request handlers
+///
+/// The session of a request is determined by a [`ClientKey`], e.g. [`CookieKey`].
+/// New sessions (and sessions whose upstream no longer exists) are assigned round-robin.
+/// Every request refreshes the mapping, so it expires `ttl` after the last request.
+/// If the [`SessionStore`] fails, the request is still handled, just without stickiness.
+///
+/// Upstreams can be taken out of rotation with a [`DrainHandle`] (see
+/// [`drain_handle`](Self::drain_handle)), after which no new sessions are assigned to them.
+/// Existing sessions keep using them unless
+/// [`keep_sessions_while_draining`](Self::keep_sessions_while_draining) is turned off.
+pub struct Sticky<H: RequestHandler, K: ClientKey, S: SessionStore = MemorySessionStore> {
+	/// The named upstream request handlers
+	pub upstreams: Arc<Vec<(String, H)>>,
+	/// The [`ClientKey`] determining the session of a request
+	pub key: K,
+	/// The storage for the mappings
+	pub store: Arc<S>,
+	/// How long a mapping is kept after the last request of the session
+	pub ttl: Duration,
+	/// Whether the sessions pinned to a draining upstream keep using it (`true` by default),
+	/// rather than being moved to another upstream
+	pub keep_sessions_while_draining: bool,
+	next: AtomicUsize,
+	drain: DrainHandle,
+}
+
+impl<H: RequestHandler, K: ClientKey> Sticky<H, K> {
+	/// Create a [`Sticky`] with in-memory storage and a TTL of 30 minutes
+	///
+	/// # Panics
+	/// Panics if `upstreams` is empty.
+	pub fn new(upstreams: Vec<(String, H)>, key: K) -> Self {
+		Self::with_store(upstreams, key, MemorySessionStore::default())
+	}
+}
+
+impl<H: RequestHandler, K: ClientKey, S: SessionStore> Sticky<H, K, S> {
+	/// Create a [`Sticky`] with the given storage and a TTL of 30 minutes
+	///
+	/// # Panics
+	/// Panics if `upstreams` is empty.
+	pub fn with_store(upstreams: Vec<(String, H)>, key: K, store: S) -> Self {
+		assert!(!upstreams.is_empty(), "Sticky needs at least one upstream");
+		Self {
+			drain: DrainHandle::new(upstreams.len()),
+			upstreams: Arc::new(upstreams),
+			key,
+			store: Arc::new(store),
+			ttl: Duration::from_secs(30 * 60),
+			keep_sessions_while_draining: true,
+			next: AtomicUsize::new(0),
+		}
+	}
+
+	/// Obtain a handle to drain upstreams, by their index in [`upstreams`](Self::upstreams)
+	pub fn drain_handle(&self) -> DrainHandle {
+		self.drain.clone()
+	}
+
+	// The next upstream in turn that isn't draining, or the next one if all are
+	fn next_upstream(&self) -> usize {
+		let len = self.upstreams.len();
+		let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+		(start..len)
+			.chain(0..start)
+			.find(|&i| !self.drain.is_draining(i))
+			.unwrap_or(start)
+	}
+}
+
+// Keep the request in flight at its upstream until the response body is dropped
+fn track<E>(result: Result<Response<Body>, E>, in_flight: InFlight) -> Result<Response<Body>, E> {
+	let (parts, body) = result?.into_parts();
+	if body.size_hint().exact() == Some(0) {
+		return Ok(Response::from_parts(parts, body));
+	}
+	let body = map_data(body, move |data| {
+		data.inspect(move |_| {
+			let _ = &in_flight;
+		})
+	});
+	Ok(Response::from_parts(parts, body))
+}
+
+/// The error type for `<`[`Sticky`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum StickyError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The upstream request handler returned an error
+	Inner(E),
+}
+
+type StickyFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, StickyError<E>>> + Send>>;
+
+impl<H, K, S> RequestHandler for Sticky<H, K, S>
+where
+	H: RequestHandler + Send + Sync + 'static,
+	K: ClientKey,
+	S: SessionStore + Send + Sync + 'static,
+{
+	type Error = StickyError<H::Error>;
+	type Output = StickyFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let round_robin = self.next_upstream();
+		let session = match self.key.client_key(from_addr, &request) {
+			Some(session) => session,
+			None => {
+				let fut = self.upstreams[round_robin]
+					.1
+					.handle(from_addr, request, client);
+				let in_flight = self.drain.start(round_robin);
+				return Box::pin(
+					async move { track(fut.await, in_flight).map_err(StickyError::Inner) },
+				);
+			}
+		};
+
+		let upstreams = self.upstreams.clone();
+		let store = self.store.clone();
+		let ttl = self.ttl;
+		let keep_sessions = self.keep_sessions_while_draining;
+		let drain = self.drain.clone();
+		let client = client.clone();
+
+		Box::pin(async move {
+			let pinned = store.get(&session).await.ok().flatten();
+			let index = pinned
+				.and_then(|name| upstreams.iter().position(|(n, _)| *n == name))
+				.filter(|&i| keep_sessions || !drain.is_draining(i))
+				.unwrap_or(round_robin);
+			let (name, upstream) = &upstreams[index];
+
+			let _ = store.set(&session, name, ttl).await;
+			let in_flight = drain.start(index);
+			let result = upstream.handle(from_addr, request, &client).await;
+			track(result, in_flight).map_err(StickyError::Inner)
+		})
+	}
+}
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use hyper::{Body, Client, Request};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// A request handler whose inner request handler can be replaced while the proxy is running
+///
+/// Requests are given to whichever request handler is current when they arrive;
+/// requests already in flight finish on the one they started on.
+/// Use [`swap_handle`](Self::swap_handle) to obtain a handle for replacing it,
+/// e.g. when reloading the config or switching between blue/green deployments.
+///
+/// The listener keeps running while the request handler is replaced. To replace it with one of
+/// a different type (like a whole new pipeline built from a reloaded config), swap
+/// [`BoxRequestHandler`](crate::BoxRequestHandler)s.
+///
+/// # Example
+/// ```
+/// use hyper::http::uri::Authority;
+/// use proxylib::handlers::prelude::*;
+/// use proxylib::{BoxRequestHandler, RequestHandler};
+///
+/// let upstream = |to| Redirect::change_authority(Authority::from_static(to));
+/// let handler = SwappableHandler::new(upstream("blue.internal:8080").boxed());
+/// let swap: SwapHandle<BoxRequestHandler> = handler.swap_handle();
+///
+/// // After a reload, which gave a pipeline of another type
+/// let green = Maintenance::new(upstream("green.internal:8080"), MaintenanceSwitch::new());
+/// swap.swap(green.boxed());
+/// ```
+pub struct SwappableHandler<H: RequestHandler> {
+	current: Arc<ArcSwap<H>>,
+}
+
+/// A handle to replace the request handler of a [`SwappableHandler`]
+pub struct SwapHandle<H: RequestHandler> {
+	current: Arc<ArcSwap<H>>,
+}
+
+impl<H: RequestHandler> SwappableHandler<H> {
+	/// Create a [`SwappableHandler`] starting with the given request handler
+	pub fn new(inner: H) -> Self {
+		Self {
+			current: Arc::new(ArcSwap::from_pointee(inner)),
+		}
+	}
+
+	/// Obtain a handle to replace the request handler
+	pub fn swap_handle(&self) -> SwapHandle<H> {
+		SwapHandle {
+			current: self.current.clone(),
+		}
+	}
+}
+
+impl<H: RequestHandler> SwapHandle<H> {
+	/// Atomically replace the request handler, returning the previous one
+	pub fn swap(&self, inner: H) -> Arc<H> {
+		self.current.swap(Arc::new(inner))
+	}
+
+	/// Get the current request handler
+	pub fn current(&self) -> Arc<H> {
+		self.current.load_full()
+	}
+}
+
+impl<H: RequestHandler> Clone for SwapHandle<H> {
+	fn clone(&self) -> Self {
+		Self {
+			current: self.current.clone(),
+		}
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for SwappableHandler<H> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		self.current.load().handle(from_addr, request, client)
+	}
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+
+use futures::future::Either;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+#[cfg(feature = "tls")]
+use crate::conn::TlsInfo;
+use crate::connect::Connector;
+use crate::handlers::accounting::ClientKey;
+use crate::handlers::filter::request_authority;
+use crate::RequestHandler;
+
+/// The tenant a request was resolved to by [`Tenancy`]
+///
+/// This is inserted into the request extensions before the request is given to the tenant's
+/// handler, so inner handlers can use it to label their logs and metrics: an
+/// [`AccessLog`](super::access_log::AccessLog) writes it in the `tenant` field, a
+/// [`Metered`](super::metrics::Metered) in the `tenant` label, and an
+/// [`Accounting`](super::accounting::Accounting) with a [`TenantKey`] bills usage to it.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Tenant(pub String);
+
+/// A [`ClientKey`] which uses the host the request was sent to (without the port)
+///
+/// The host is taken from the URI authority if present and from the `Host` header otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostKey;
+
+impl ClientKey for HostKey {
+	fn client_key(&self, _: SocketAddr, request: &Request<Body>) -> Option<String> {
+		Some(request_authority(request)?.host().to_ascii_lowercase())
+	}
+}
+
+/// A [`ClientKey`] which uses the SHA-256 fingerprint of the client certificate, in lowercase
+/// hex like `openssl x509 -noout -fingerprint -sha256` prints it without the colons
+///
+/// Clients only send certificates if the proxy asks for them, see
+/// [`TlsProxyConfig::from_pem_with_client_auth`](crate::tls::TlsProxyConfig::from_pem_with_client_auth).
+/// Requests without a client certificate get no key.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::tenancy::{CertificateKey, Tenancy};
+/// use proxylib::handlers::Redirect;
+///
+/// let handler = Tenancy::new(CertificateKey).with_tenant(
+///     "5d0b3e1c9a7f2e84b6c1d0a9e3f7b2c4d8e6a1f0b9c3d7e2a4f6b8c0d1e3f5a7",
+///     Redirect::change_authority("a.internal:8080".parse().unwrap()),
+/// );
+/// ```
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CertificateKey;
+
+#[cfg(feature = "tls")]
+impl ClientKey for CertificateKey {
+	fn client_key(&self, _: SocketAddr, request: &Request<Body>) -> Option<String> {
+		let tls = request.extensions().get::<TlsInfo>()?;
+		let certificate = tls.client_certificates.first()?;
+		let digest = ring::digest::digest(&ring::digest::SHA256, certificate);
+		Some(
+			digest
+				.as_ref()
+				.iter()
+				.map(|b| format!("{:02x}", b))
+				.collect(),
+		)
+	}
+}
+
+/// A [`ClientKey`] which uses the [`Tenant`] of the request, e.g. to account usage or limit rates
+/// per tenant inside the request handlers of a [`Tenancy`]
+///
+/// Requests without a tenant get no key.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::accounting::Accounting;
+/// use proxylib::handlers::tenancy::{HostKey, Tenancy, TenantKey};
+/// use proxylib::handlers::Redirect;
+///
+/// // Bill the usage of every tenant to the tenant
+/// let upstream = |to: &str| Accounting::new(Redirect::change_authority(to.parse().unwrap()), TenantKey);
+/// let handler = Tenancy::new(HostKey)
+///     .with_tenant("a.example.com", upstream("a.internal:8080"))
+///     .with_tenant("b.example.com", upstream("b.internal:8080"));
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TenantKey;
+
+impl ClientKey for TenantKey {
+	fn client_key(&self, _: SocketAddr, request: &Request<Body>) -> Option<String> {
+		let Tenant(tenant) = request.extensions().get()?;
+		Some(tenant.clone())
+	}
+}
+
+/// A request handler that serves many tenants from one proxy
+///
+/// Every tenant has its own request handler (and thus its own routes, limits and auth),
+/// and the tenant of a request is resolved by a [`ClientKey`], for example [`HostKey`],
+/// [`HeaderKey`](super::accounting::HeaderKey) for API keys or [`CertificateKey`] for client
+/// certificates. Requests without a known tenant go to the [`default`](Self::default) handler
+/// if there is one, and are answered with `404 Not Found` otherwise.
+pub struct Tenancy<H: RequestHandler, K: ClientKey> {
+	/// The [`ClientKey`] resolving the tenant of a request
+	pub key: K,
+	/// The request handlers of the tenants
+	pub tenants: HashMap<String, H>,
+	/// The request handler for requests without a known tenant
+	pub default: Option<H>,
+}
+
+impl<H: RequestHandler, K: ClientKey> Tenancy<H, K> {
+	/// Create a [`Tenancy`] without any tenants
+	pub fn new(key: K) -> Self {
+		Self {
+			key,
+			tenants: HashMap::new(),
+			default: None,
+		}
+	}
+
+	/// Add a tenant
+	pub fn with_tenant(mut self, tenant: impl Into<String>, handler: H) -> Self {
+		self.tenants.insert(tenant.into(), handler);
+		self
+	}
+
+	/// Set the request handler for requests without a known tenant, which get no [`Tenant`]
+	pub fn with_default(mut self, handler: H) -> Self {
+		self.default = Some(handler);
+		self
+	}
+}
+
+fn unknown_tenant() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::NOT_FOUND)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("There is no tenant for this request.\n"))
+		.unwrap()
+}
+
+#[allow(type_alias_bounds)]
+type TenancyFuture<H: RequestHandler> = Either<H::Output, Ready<Result<Response<Body>, H::Error>>>;
+
+impl<H: RequestHandler, K: ClientKey> RequestHandler for Tenancy<H, K> {
+	type Error = H::Error;
+	type Output = TenancyFuture<H>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let tenant = self
+			.key
+			.client_key(from_addr, &request)
+			.and_then(|key| self.tenants.get_key_value(&key));
+
+		match (tenant, &self.default) {
+			(Some((tenant, handler)), _) => {
+				request.extensions_mut().insert(Tenant(tenant.clone()));
+				Either::Left(handler.handle(from_addr, request, client))
+			}
+			(None, Some(default)) => Either::Left(default.handle(from_addr, request, client)),
+			(None, None) => Either::Right(ready(Ok(unknown_tenant()))),
+		}
+	}
+}
@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::time::{timeout_at, Instant};
+
+use crate::body::with_timeouts;
+use crate::connect::{Connector, UpstreamConfig};
+use crate::RequestHandler;
+
+/// The timeouts of a [`Timeout`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TimeoutConfig {
+	/// How long establishing an upstream connection may take
+	pub connect: Option<Duration>,
+	/// How long to wait for the response head and for each chunk of the response body
+	pub read: Option<Duration>,
+	/// How long the whole exchange may take, including streaming the response body
+	pub total: Option<Duration>,
+}
+
+impl TimeoutConfig {
+	/// Timeouts for API calls: 2 seconds to connect, 10 seconds in total
+	pub fn api() -> Self {
+		Self {
+			connect: Some(Duration::from_secs(2)),
+			read: None,
+			total: Some(Duration::from_secs(10)),
+		}
+	}
+
+	/// Timeouts for long-lived streams like uploads or server-sent events:
+	/// 5 seconds to connect, 5 minutes between chunks, no total timeout
+	pub fn streaming() -> Self {
+		Self {
+			connect: Some(Duration::from_secs(5)),
+			read: Some(Duration::from_secs(5 * 60)),
+			total: None,
+		}
+	}
+}
+
+/// A request handler combinator that applies its own timeouts to another request handler
+///
+/// Wrapping the handlers of individual routes in [`Timeout`]s gives them different timeouts.
+/// A connect timeout needs a separate client, so the inner request handler gets one built from
+/// the config of the upstream connections with the connect timeout set, instead of the proxy's
+/// one (as with [`WithClient`](super::with_client::WithClient)).
+///
+/// If the response head doesn't arrive in time, the client is answered with
+/// `504 Gateway Timeout`. Timeouts that pass while the response body is streamed end the body
+/// with an error.
+pub struct Timeout<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The timeouts
+	pub config: TimeoutConfig,
+	/// The client the inner request handler gets, if not the proxy's
+	pub client: Option<Client<Connector>>,
+}
+
+impl<H: RequestHandler> Timeout<H> {
+	/// Create a [`Timeout`], building a client from the config of the upstream connections
+	/// (which should be the one the proxy's client was built from) if a connect timeout is set
+	pub fn new(inner: H, config: TimeoutConfig, upstream: &UpstreamConfig) -> Self {
+		let client = config.connect.map(|connect| {
+			UpstreamConfig {
+				connect_timeout: Some(connect),
+				..upstream.clone()
+			}
+			.build_client()
+		});
+		Self {
+			inner,
+			config,
+			client,
+		}
+	}
+}
+
+type TimeoutFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+fn gateway_timeout() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::GATEWAY_TIMEOUT)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("The upstream did not respond in time.\n"))
+		.unwrap()
+}
+
+impl<H: RequestHandler> RequestHandler for Timeout<H> {
+	type Error = H::Error;
+	type Output = TimeoutFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let client = self.client.as_ref().unwrap_or(client);
+		let fut = self.inner.handle(from_addr, request, client);
+		let TimeoutConfig { read, total, .. } = self.config;
+
+		Box::pin(async move {
+			let now = Instant::now();
+			let deadline = total.map(|total| now + total);
+			let head_deadline = match (read.map(|read| now + read), deadline) {
+				(Some(a), Some(b)) => Some(a.min(b)),
+				(a, b) => a.or(b),
+			};
+
+			let response = match head_deadline {
+				Some(until) => match timeout_at(until, fut).await {
+					Ok(result) => result?,
+					Err(_) => return Ok(gateway_timeout()),
+				},
+				None => fut.await?,
+			};
+
+			let (parts, body) = response.into_parts();
+			Ok(Response::from_parts(
+				parts,
+				with_timeouts(body, read, deadline),
+			))
+		})
+	}
+}
@@ -0,0 +1,84 @@
+use std::convert::TryFrom;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+
+use futures::future::Either;
+use hyper::header::CONTENT_TYPE;
+use hyper::http::uri::{Authority, PathAndQuery, Scheme};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+
+use crate::connect::Connector;
+use crate::handlers::redirect::{downgrade_version, forward, remove_hop_by_hop_headers, Forward};
+use crate::transparent::OriginalDestination;
+use crate::RequestHandler;
+
+/// A request handler for transparent proxies, forwarding requests to the
+/// [`OriginalDestination`] of their connection
+///
+/// The clients don't know about the proxy, so they send their requests with an origin-form
+/// target (like `GET /`), which is sent to the original destination with the `Host` header of
+/// the client. Hop-by-hop headers are removed with [`remove_hop_by_hop_headers`]. Requests
+/// without an original destination, i.e. ones made to the proxy itself, are answered with
+/// `421 Misdirected Request`.
+///
+/// Only plain HTTP can be proxied this way, so only connections to HTTP ports (like 80)
+/// should be redirected to the proxy.
+///
+/// # Example
+/// ```no_run
+/// # #[cfg(target_os = "linux")]
+/// # async fn run() {
+/// use proxylib::handlers::transparent::Transparent;
+/// use proxylib::transparent::TransparentMode;
+/// use proxylib::ProxyConfig;
+///
+/// // With e.g. `iptables -t nat -A PREROUTING -p tcp --dport 80 -j REDIRECT --to-ports 3129`
+/// let config = ProxyConfig::new("0.0.0.0:3129".parse().unwrap(), Transparent)
+///     .with_transparent(TransparentMode::Redirect);
+/// proxylib::run_proxy(config).await.unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Transparent;
+
+fn misdirected() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::MISDIRECTED_REQUEST)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(
+			"The connection wasn't redirected to the proxy, so there is no destination.\n",
+		))
+		.unwrap()
+}
+
+impl RequestHandler for Transparent {
+	type Error = hyper::Error;
+	type Output = Either<Forward, Ready<hyper::Result<Response<Body>>>>;
+
+	fn handle(
+		&self,
+		_from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let (mut parts, body) = request.into_parts();
+		let destination = match parts.extensions.get::<OriginalDestination>() {
+			Some(OriginalDestination(destination)) => *destination,
+			None => return Either::Right(ready(Ok(misdirected()))),
+		};
+
+		let mut uri_parts = parts.uri.clone().into_parts();
+		uri_parts.scheme = Some(Scheme::HTTP);
+		// A socket address is always a valid authority
+		uri_parts.authority = Some(Authority::try_from(destination.to_string()).unwrap());
+		if uri_parts.path_and_query.is_none() {
+			uri_parts.path_and_query = Some(PathAndQuery::from_static("/"));
+		}
+		// The scheme, authority and path are all set
+		parts.uri = Uri::from_parts(uri_parts).unwrap();
+		downgrade_version(&mut parts);
+		remove_hop_by_hop_headers(&mut parts.headers);
+
+		Either::Left(forward(client, Request::from_parts(parts, body), true))
+	}
+}
@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::future::Either;
+use hyper::client::connect::dns::GaiResolver;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+
+use crate::connect::{happy_eyeballs_connector, Connector};
+use crate::RequestHandler;
+
+fn text_response(status: StatusCode, text: &'static str) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(text))
+		.unwrap()
+}
+
+/// A request handler combinator that answers `CONNECT` requests by tunneling the client
+/// connection to the requested host, so the proxy can be used as a forward proxy for `https://`
+/// sites; all other requests are given to the inner request handler
+///
+/// Once the connection to the target is established, the client gets `200 OK` and from then on
+/// the bytes are copied in both directions until either side closes the connection. The proxy
+/// doesn't see into the tunnel, which usually carries TLS.
+///
+/// Only the [`allowed_ports`](Self::allowed_ports) can be tunneled to (just 443 by default), so
+/// the proxy can't be used to reach arbitrary services; other ports are answered with
+/// `403 Forbidden`. If the target can't be reached, the client gets `502 Bad Gateway`,
+/// or `504 Gateway Timeout` after [`connect_timeout`](Self::connect_timeout).
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::tunnel::Tunnel;
+/// use proxylib::handlers::Redirect;
+///
+/// let mut handler = Tunnel::new(Redirect::change_authority("backend.internal".parse().unwrap()));
+/// handler.allowed_ports.push(8443);
+/// ```
+pub struct Tunnel<H: RequestHandler> {
+	/// The inner request handler to give requests other than `CONNECT` to
+	pub inner: H,
+	/// The ports that can be tunneled to; if empty, all ports can
+	pub allowed_ports: Vec<u16>,
+	/// How long connecting to the target may take
+	pub connect_timeout: Duration,
+	/// The connector for the connections to the targets
+	pub connector: Connector,
+}
+
+impl<H: RequestHandler> Tunnel<H> {
+	/// Create a [`Tunnel`] to port 443 that connects like
+	/// [`UpstreamConfig`](crate::connect::UpstreamConfig) and gives up after 10 seconds
+	pub fn new(inner: H) -> Self {
+		Self {
+			inner,
+			allowed_ports: vec![443],
+			connect_timeout: Duration::from_secs(10),
+			connector: Connector::new(happy_eyeballs_connector(GaiResolver::new())),
+		}
+	}
+}
+
+type TunnelFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for Tunnel<H> {
+	type Error = H::Error;
+	type Output = Either<H::Output, TunnelFuture<H::Error>>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if request.method() != Method::CONNECT {
+			return Either::Left(self.inner.handle(from_addr, request, client));
+		}
+
+		let authority = match request.uri().authority() {
+			Some(authority) if authority.port_u16().is_some() => authority.clone(),
+			_ => {
+				let response = text_response(
+					StatusCode::BAD_REQUEST,
+					"CONNECT requests need a host and port.\n",
+				);
+				return Either::Right(Box::pin(async move { Ok(response) }));
+			}
+		};
+		let port = authority.port_u16().unwrap_or_default();
+		if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&port) {
+			let response = text_response(
+				StatusCode::FORBIDDEN,
+				"Tunnels to this port are not allowed.\n",
+			);
+			return Either::Right(Box::pin(async move { Ok(response) }));
+		}
+
+		let uri = Uri::builder()
+			.scheme("http")
+			.authority(authority)
+			.path_and_query("/")
+			.build()
+			.unwrap();
+		let connect = self.connector.clone().call(uri);
+		let connect_timeout = self.connect_timeout;
+
+		Either::Right(Box::pin(async move {
+			let mut target = match tokio::time::timeout(connect_timeout, connect).await {
+				Ok(Ok(target)) => target,
+				Ok(Err(_)) => {
+					return Ok(text_response(
+						StatusCode::BAD_GATEWAY,
+						"The target could not be reached.\n",
+					))
+				}
+				Err(_) => {
+					return Ok(text_response(
+						StatusCode::GATEWAY_TIMEOUT,
+						"The target could not be reached in time.\n",
+					))
+				}
+			};
+
+			// The connection is only upgraded once the response has been sent
+			tokio::spawn(async move {
+				if let Ok(mut upgraded) = hyper::upgrade::on(request).await {
+					let _ = tokio::io::copy_bidirectional(&mut upgraded, &mut target).await;
+				}
+			});
+			Ok(Response::new(Body::empty()))
+		}))
+	}
+}
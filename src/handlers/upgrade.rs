@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use futures::future::{try_join, Either};
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+
+use crate::connect::Connector;
+use crate::handlers::websocket::{relay_websocket, WebSocketPermit};
+use crate::RequestHandler;
+
+/// Return whether the request asks to be upgraded to another protocol, like WebSocket
+pub fn is_upgrade(request: &Request<Body>) -> bool {
+	let headers = request.headers();
+	let connection_upgrade = headers
+		.get_all(CONNECTION)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.any(|v| v.trim().eq_ignore_ascii_case("upgrade"));
+	connection_upgrade && headers.contains_key(UPGRADE)
+}
+
+/// A request handler combinator that passes protocol upgrades (like WebSocket) through to the
+/// upstream
+///
+/// Upgrade requests are given to the inner request handler with their `Connection` and
+/// `Upgrade` headers, e.g. to a [`Redirect`](super::Redirect), which keeps them. If the
+/// upstream switches protocols with `101 Switching Protocols`, the response is sent to the
+/// client and from then on the bytes are copied between the client and the upstream until
+/// either side closes the connection.
+///
+/// If the response has a [`WebSocketPermit`] (from a [`WebSocketLimit`](super::WebSocketLimit)
+/// inside of this), the WebSocket frames are forwarded one by one, enforcing the
+/// [`max_message_size`](super::websocket::WebSocketLimits::max_message_size) and
+/// [`idle_timeout`](super::websocket::WebSocketLimits::idle_timeout) of the limits.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::upgrade::UpgradeTunnel;
+/// use proxylib::handlers::websocket::{WebSocketLimit, WebSocketLimits};
+/// use proxylib::handlers::Redirect;
+///
+/// let handler = UpgradeTunnel::new(WebSocketLimit::new(
+///     Redirect::change_authority("chat.internal:8080".parse().unwrap()),
+///     WebSocketLimits::default(),
+/// ));
+/// ```
+pub struct UpgradeTunnel<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+}
+
+impl<H: RequestHandler> UpgradeTunnel<H> {
+	/// Create an [`UpgradeTunnel`] around the inner request handler
+	pub fn new(inner: H) -> Self {
+		Self { inner }
+	}
+}
+
+type UpgradeTunnelFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for UpgradeTunnel<H> {
+	type Error = H::Error;
+	type Output = Either<H::Output, UpgradeTunnelFuture<H::Error>>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		mut request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if !is_upgrade(&request) {
+			return Either::Left(self.inner.handle(from_addr, request, client));
+		}
+
+		// The client connection is only handed over once the response has been sent
+		let client_upgrade = hyper::upgrade::on(&mut request);
+		let fut = self.inner.handle(from_addr, request, client);
+
+		Either::Right(Box::pin(async move {
+			let mut response = fut.await?;
+			if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+				return Ok(response);
+			}
+
+			let upstream_upgrade = hyper::upgrade::on(&mut response);
+			let permit = response.extensions_mut().remove::<WebSocketPermit>();
+			tokio::spawn(async move {
+				let (mut client, mut upstream) =
+					match try_join(client_upgrade, upstream_upgrade).await {
+						Ok(upgraded) => upgraded,
+						Err(_) => return,
+					};
+				match permit {
+					Some(permit) => relay_websocket(client, upstream, permit).await,
+					None => {
+						let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+					}
+				}
+			});
+			Ok(response)
+		}))
+	}
+}
@@ -0,0 +1,878 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::body::{buffer, is_streaming, read_limited, Buffered};
+use crate::chain::percent_decode;
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// An error while loading an [`OpenApiSpec`]
+#[derive(Debug, Error)]
+pub enum SpecError {
+	#[error("failed to read spec: {0}")]
+	/// The spec file could not be read
+	Io(#[from] std::io::Error),
+	#[error("failed to parse spec as JSON: {0}")]
+	/// The spec is not valid JSON
+	Json(#[from] serde_json::Error),
+	#[error("failed to parse spec as YAML: {0}")]
+	/// The spec is not valid YAML
+	Yaml(#[from] serde_yaml::Error),
+	#[error("invalid spec: {0}")]
+	/// The spec is not a valid OpenAPI document
+	Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Location {
+	Path,
+	Query,
+	Header,
+}
+
+impl Location {
+	fn name(self) -> &'static str {
+		match self {
+			Location::Path => "path",
+			Location::Query => "query",
+			Location::Header => "header",
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+struct Parameter {
+	name: String,
+	location: Location,
+	required: bool,
+	schema: Value,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+	Literal(String),
+	Param(String),
+}
+
+#[derive(Debug, Clone)]
+struct Operation {
+	method: Method,
+	segments: Vec<Segment>,
+	parameters: Vec<Parameter>,
+	body_required: bool,
+	// the schemas of the accepted content types, `None` if there is no request body
+	body: Option<Vec<(String, Value)>>,
+	// the documented responses by status code pattern (`200`, `2XX` or `default`)
+	responses: Vec<(String, Vec<(String, Value)>)>,
+}
+
+/// An OpenAPI 3 document, reduced to what is needed to validate requests
+#[derive(Debug, Clone)]
+pub struct OpenApiSpec {
+	root: Value,
+	operations: Vec<Operation>,
+}
+
+const METHODS: [&str; 8] = [
+	"get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+impl OpenApiSpec {
+	/// Parse a spec from a JSON string
+	pub fn from_json(s: &str) -> Result<Self, SpecError> {
+		Self::from_value(serde_json::from_str(s)?)
+	}
+
+	/// Parse a spec from a YAML string
+	pub fn from_yaml(s: &str) -> Result<Self, SpecError> {
+		Self::from_value(serde_yaml::from_str(s)?)
+	}
+
+	/// Load a spec from a file, which is parsed as JSON if its extension is `.json`
+	/// and as YAML otherwise
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SpecError> {
+		let path = path.as_ref();
+		let s = std::fs::read_to_string(path)?;
+		if path.extension().is_some_and(|ext| ext == "json") {
+			Self::from_json(&s)
+		} else {
+			Self::from_yaml(&s)
+		}
+	}
+
+	/// Build a spec from an already parsed document
+	pub fn from_value(root: Value) -> Result<Self, SpecError> {
+		let paths = root
+			.get("paths")
+			.and_then(Value::as_object)
+			.ok_or_else(|| SpecError::Invalid("missing `paths` object".to_string()))?;
+
+		let mut operations = Vec::new();
+		for (template, item) in paths {
+			let item = resolve(&root, item);
+			let shared_params = parse_parameters(&root, item.get("parameters"))?;
+
+			for &method in &METHODS {
+				let op = match item.get(method) {
+					Some(op) => op,
+					None => continue,
+				};
+
+				let mut parameters = shared_params.clone();
+				for param in parse_parameters(&root, op.get("parameters"))? {
+					parameters.retain(|p| p.name != param.name || p.location != param.location);
+					parameters.push(param);
+				}
+
+				let (body_required, body) = match op.get("requestBody").map(|b| resolve(&root, b)) {
+					Some(body) => {
+						let required = body
+							.get("required")
+							.and_then(Value::as_bool)
+							.unwrap_or(false);
+						(required, Some(parse_content(body)))
+					}
+					None => (false, None),
+				};
+
+				let responses = op
+					.get("responses")
+					.and_then(Value::as_object)
+					.map(|responses| {
+						responses
+							.iter()
+							.map(|(status, response)| {
+								(
+									status.to_ascii_uppercase(),
+									parse_content(resolve(&root, response)),
+								)
+							})
+							.collect()
+					})
+					.unwrap_or_default();
+
+				operations.push(Operation {
+					method: method.to_ascii_uppercase().parse().unwrap(),
+					segments: parse_template(template),
+					parameters,
+					body_required,
+					body,
+					responses,
+				});
+			}
+		}
+
+		// prefer literal segments over parameters when templates overlap
+		operations.sort_by_key(|op| {
+			op.segments
+				.iter()
+				.map(|s| matches!(s, Segment::Param(_)))
+				.collect::<Vec<_>>()
+		});
+
+		Ok(Self { root, operations })
+	}
+}
+
+fn parse_template(template: &str) -> Vec<Segment> {
+	template
+		.trim_start_matches('/')
+		.split('/')
+		.map(|s| {
+			if s.starts_with('{') && s.ends_with('}') {
+				Segment::Param(s[1..s.len() - 1].to_string())
+			} else {
+				Segment::Literal(s.to_string())
+			}
+		})
+		.collect()
+}
+
+// Parse the `content` of a request body or response into (media type, schema) pairs
+fn parse_content(object: &Value) -> Vec<(String, Value)> {
+	object
+		.get("content")
+		.and_then(Value::as_object)
+		.map(|content| {
+			content
+				.iter()
+				.map(|(ty, media)| {
+					let schema = media.get("schema").cloned().unwrap_or(Value::Bool(true));
+					(ty.to_ascii_lowercase(), schema)
+				})
+				.collect()
+		})
+		.unwrap_or_default()
+}
+
+fn parse_parameters(root: &Value, params: Option<&Value>) -> Result<Vec<Parameter>, SpecError> {
+	let params = match params.and_then(Value::as_array) {
+		Some(params) => params,
+		None => return Ok(Vec::new()),
+	};
+
+	let mut res = Vec::new();
+	for param in params {
+		let param = resolve(root, param);
+		let name = param
+			.get("name")
+			.and_then(Value::as_str)
+			.ok_or_else(|| SpecError::Invalid("parameter without a name".to_string()))?;
+		let location = match param.get("in").and_then(Value::as_str) {
+			Some("path") => Location::Path,
+			Some("query") => Location::Query,
+			Some("header") => Location::Header,
+			// cookie parameters are not validated
+			_ => continue,
+		};
+		res.push(Parameter {
+			name: name.to_string(),
+			location,
+			required: location == Location::Path
+				|| param
+					.get("required")
+					.and_then(Value::as_bool)
+					.unwrap_or(false),
+			schema: param.get("schema").cloned().unwrap_or(Value::Bool(true)),
+		});
+	}
+	Ok(res)
+}
+
+// Follow local `$ref`s like `#/components/schemas/Pet`
+fn resolve<'a>(root: &'a Value, mut value: &'a Value) -> &'a Value {
+	// limit the depth to not loop forever on cyclic references
+	for _ in 0..32 {
+		let target = match value.get("$ref").and_then(Value::as_str) {
+			Some(r) => r,
+			None => break,
+		};
+		match target.strip_prefix('#').and_then(|ptr| root.pointer(ptr)) {
+			Some(v) => value = v,
+			None => break,
+		}
+	}
+	value
+}
+
+fn type_matches(ty: &str, value: &Value) -> bool {
+	match ty {
+		"string" => value.is_string(),
+		"integer" => value.is_i64() || value.is_u64(),
+		"number" => value.is_number(),
+		"boolean" => value.is_boolean(),
+		"array" => value.is_array(),
+		"object" => value.is_object(),
+		"null" => value.is_null(),
+		_ => true,
+	}
+}
+
+// Validate a value against a (subset of a) JSON schema, pushing violations into `errors`
+fn validate_schema(
+	root: &Value,
+	schema: &Value,
+	value: &Value,
+	at: &str,
+	errors: &mut Vec<String>,
+) {
+	let schema = resolve(root, schema);
+	let schema = match schema {
+		Value::Bool(true) => return,
+		Value::Bool(false) => return errors.push(format!("{}: no value is allowed here", at)),
+		Value::Object(schema) => schema,
+		_ => return,
+	};
+
+	if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+		return;
+	}
+
+	if let Some(ty) = schema.get("type") {
+		let ok = match ty {
+			Value::String(ty) => type_matches(ty, value),
+			Value::Array(tys) => tys
+				.iter()
+				.filter_map(Value::as_str)
+				.any(|ty| type_matches(ty, value)),
+			_ => true,
+		};
+		if !ok {
+			let ty = ty.as_str().map_or_else(|| ty.to_string(), str::to_string);
+			return errors.push(format!("{}: expected type {}", at, ty));
+		}
+	}
+
+	if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+		if !options.contains(value) {
+			errors.push(format!("{}: value is not one of the allowed values", at));
+		}
+	}
+
+	if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+		for sub in all {
+			validate_schema(root, sub, value, at, errors);
+		}
+	}
+	for key in &["anyOf", "oneOf"] {
+		if let Some(any) = schema.get(*key).and_then(Value::as_array) {
+			let matching = any
+				.iter()
+				.filter(|sub| {
+					let mut sub_errors = Vec::new();
+					validate_schema(root, sub, value, at, &mut sub_errors);
+					sub_errors.is_empty()
+				})
+				.count();
+			if matching == 0 || (*key == "oneOf" && matching > 1) {
+				errors.push(format!("{}: value doesn't match {}", at, key));
+			}
+		}
+	}
+
+	match value {
+		Value::String(s) => {
+			let len = s.chars().count() as u64;
+			if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+				if len < min {
+					errors.push(format!("{}: string is shorter than {}", at, min));
+				}
+			}
+			if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+				if len > max {
+					errors.push(format!("{}: string is longer than {}", at, max));
+				}
+			}
+		}
+		Value::Number(n) => {
+			let n = n.as_f64().unwrap_or(0.0);
+			if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+				if n < min {
+					errors.push(format!("{}: number is less than {}", at, min));
+				}
+			}
+			if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+				if n > max {
+					errors.push(format!("{}: number is greater than {}", at, max));
+				}
+			}
+		}
+		Value::Array(items) => {
+			if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+				if (items.len() as u64) < min {
+					errors.push(format!("{}: array has fewer than {} items", at, min));
+				}
+			}
+			if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+				if items.len() as u64 > max {
+					errors.push(format!("{}: array has more than {} items", at, max));
+				}
+			}
+			if let Some(item_schema) = schema.get("items") {
+				for (i, item) in items.iter().enumerate() {
+					validate_schema(root, item_schema, item, &format!("{}[{}]", at, i), errors);
+				}
+			}
+		}
+		Value::Object(fields) => validate_object(root, schema, fields, at, errors),
+		_ => {}
+	}
+}
+
+fn validate_object(
+	root: &Value,
+	schema: &Map<String, Value>,
+	fields: &Map<String, Value>,
+	at: &str,
+	errors: &mut Vec<String>,
+) {
+	if let Some(required) = schema.get("required").and_then(Value::as_array) {
+		for name in required.iter().filter_map(Value::as_str) {
+			if !fields.contains_key(name) {
+				errors.push(format!("{}: missing required property `{}`", at, name));
+			}
+		}
+	}
+
+	let properties = schema.get("properties").and_then(Value::as_object);
+	let additional = schema.get("additionalProperties");
+	for (name, field) in fields {
+		let field_at = format!("{}.{}", at, name);
+		match properties.and_then(|p| p.get(name)) {
+			Some(prop_schema) => validate_schema(root, prop_schema, field, &field_at, errors),
+			None => {
+				if let Some(additional) = additional {
+					validate_schema(root, additional, field, &field_at, errors);
+				}
+			}
+		}
+	}
+}
+
+// Turn a parameter string into a JSON value of the type the schema expects
+fn coerce(root: &Value, schema: &Value, raw: &str) -> Value {
+	let ty = resolve(root, schema).get("type").and_then(Value::as_str);
+	match ty {
+		Some("integer") => raw.parse::<i64>().map(Value::from).ok(),
+		Some("number") => raw.parse::<f64>().ok().map(Value::from),
+		Some("boolean") => raw.parse::<bool>().map(Value::from).ok(),
+		Some("array") => Some(Value::Array(
+			raw.split(',')
+				.map(|item| {
+					let items = resolve(root, schema).get("items").unwrap_or(&Value::Null);
+					coerce(root, items, item)
+				})
+				.collect(),
+		)),
+		_ => None,
+	}
+	.unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+// Decode a path segment or query component, keeping it as it is if it isn't valid
+fn decode(s: &str) -> String {
+	percent_decode(s).unwrap_or_else(|| s.to_string())
+}
+
+/// The reasons a request can be rejected by [`Validate`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Violation {
+	/// No path in the spec matches the request path
+	UnknownPath,
+	/// The path exists, but not with the request method, only with these methods
+	MethodNotAllowed(Vec<Method>),
+	/// Parameters or the body don't match the spec
+	Invalid(Vec<String>),
+	/// The body is too large to be validated
+	BodyTooLarge,
+}
+
+impl OpenApiSpec {
+	fn find(
+		&self,
+		method: &Method,
+		path: &str,
+	) -> Result<(usize, Vec<(String, String)>), Violation> {
+		let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+		let mut allowed = Vec::new();
+
+		for (i, op) in self.operations.iter().enumerate() {
+			if op.segments.len() != segments.len() {
+				continue;
+			}
+
+			let mut params = Vec::new();
+			let matches =
+				op.segments
+					.iter()
+					.zip(&segments)
+					.all(|(template, actual)| match template {
+						Segment::Literal(lit) => lit == actual,
+						Segment::Param(name) => {
+							params.push((name.clone(), decode(actual)));
+							!actual.is_empty()
+						}
+					});
+			if !matches {
+				continue;
+			}
+
+			if op.method == method {
+				return Ok((i, params));
+			}
+			allowed.push(op.method.clone());
+		}
+
+		if allowed.is_empty() {
+			Err(Violation::UnknownPath)
+		} else {
+			Err(Violation::MethodNotAllowed(allowed))
+		}
+	}
+
+	// Check the parts of a request that don't need the body, returning the matched operation
+	fn check_head(&self, request: &Request<Body>) -> Result<usize, Violation> {
+		let (index, path_params) = self.find(request.method(), request.uri().path())?;
+		let op = &self.operations[index];
+		let query: Vec<(String, String)> = request
+			.uri()
+			.query()
+			.unwrap_or("")
+			.split('&')
+			.filter(|pair| !pair.is_empty())
+			.map(|pair| {
+				let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+				// '+' only stands for a space in query strings, not in paths
+				let decode_query = |s: &str| decode(&s.replace('+', " "));
+				(decode_query(k), decode_query(v))
+			})
+			.collect();
+
+		let mut errors = Vec::new();
+		for param in &op.parameters {
+			let raw = match param.location {
+				Location::Path => path_params
+					.iter()
+					.find(|(name, _)| *name == param.name)
+					.map(|(_, v)| v.clone()),
+				Location::Query => query
+					.iter()
+					.find(|(k, _)| *k == param.name)
+					.map(|(_, v)| v.clone()),
+				Location::Header => request
+					.headers()
+					.get(param.name.as_str())
+					.and_then(|v| v.to_str().ok())
+					.map(str::to_string),
+			};
+
+			let at = format!("{} parameter `{}`", param.location.name(), param.name);
+			match raw {
+				Some(raw) => {
+					let value = coerce(&self.root, &param.schema, &raw);
+					validate_schema(&self.root, &param.schema, &value, &at, &mut errors);
+				}
+				None if param.required => errors.push(format!("{}: missing", at)),
+				None => {}
+			}
+		}
+
+		if op.body.is_none() && has_body(request) {
+			errors.push("body: the operation doesn't accept a request body".to_string());
+		}
+
+		if errors.is_empty() {
+			Ok(index)
+		} else {
+			Err(Violation::Invalid(errors))
+		}
+	}
+
+	// Check a buffered request body against the matched operation
+	fn check_body(
+		&self,
+		op: &Operation,
+		content_type: Option<&str>,
+		body: &[u8],
+	) -> Result<(), Violation> {
+		let content = match &op.body {
+			Some(content) => content,
+			None => return Ok(()),
+		};
+
+		if body.is_empty() {
+			return if op.body_required {
+				Err(Violation::Invalid(vec!["body: missing".to_string()]))
+			} else {
+				Ok(())
+			};
+		}
+
+		let errors = self.check_content(content, content_type, body, "body");
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(Violation::Invalid(errors))
+		}
+	}
+
+	// Check a buffered response against the responses documented for the matched operation
+	fn check_response(
+		&self,
+		op: &Operation,
+		status: StatusCode,
+		content_type: Option<&str>,
+		body: Option<&[u8]>,
+	) -> Vec<String> {
+		let code = status.as_str().to_string();
+		let class = format!("{}XX", &code[..1]);
+		let documented = ["DEFAULT", &class, &code]
+			.iter()
+			.rev()
+			.find_map(|key| op.responses.iter().find(|(status, _)| status == key));
+
+		let content = match documented {
+			Some((_, content)) => content,
+			None => return vec![format!("status: {} is not documented", code)],
+		};
+
+		match body {
+			Some(body) if !body.is_empty() && !content.is_empty() => {
+				self.check_content(content, content_type, body, "body")
+			}
+			_ => Vec::new(),
+		}
+	}
+
+	// Check a non-empty body against the (media type, schema) pairs it may have
+	fn check_content(
+		&self,
+		content: &[(String, Value)],
+		content_type: Option<&str>,
+		body: &[u8],
+		at: &str,
+	) -> Vec<String> {
+		let media_type = content_type
+			.and_then(|ct| ct.split(';').next())
+			.map(|ct| ct.trim().to_ascii_lowercase())
+			.unwrap_or_default();
+
+		let schema = content.iter().find(|(ty, _)| {
+			*ty == media_type
+				|| ty == "*/*"
+				|| (ty.ends_with("/*") && media_type.starts_with(&ty[..ty.len() - 1]))
+		});
+		let schema = match schema {
+			Some((_, schema)) => schema,
+			None => return vec![format!("{}: unsupported content type `{}`", at, media_type)],
+		};
+
+		if !(media_type == "application/json" || media_type.ends_with("+json")) {
+			// only JSON bodies are validated against their schema
+			return Vec::new();
+		}
+
+		let value: Value = match serde_json::from_slice(body) {
+			Ok(value) => value,
+			Err(e) => return vec![format!("{}: invalid JSON: {}", at, e)],
+		};
+		let mut errors = Vec::new();
+		validate_schema(&self.root, schema, &value, at, &mut errors);
+		errors
+	}
+}
+
+fn has_body(request: &Request<Body>) -> bool {
+	request.body().size_hint().exact() != Some(0)
+		&& request
+			.headers()
+			.get(CONTENT_LENGTH)
+			.is_none_or(|len| len != "0")
+}
+
+/// Create the response sent to the client for a violation
+pub fn violation_response(violation: &Violation, method: &Method, path: &str) -> Response<Body> {
+	let (message, details) = match violation {
+		Violation::UnknownPath => ("unknown path", Vec::new()),
+		Violation::MethodNotAllowed(_) => ("method not allowed for this path", Vec::new()),
+		Violation::Invalid(details) => (
+			"request does not match the API specification",
+			details.clone(),
+		),
+		Violation::BodyTooLarge => ("request body is too large to be validated", Vec::new()),
+	};
+	let body = json!({
+		"error": message,
+		"method": method.as_str(),
+		"path": path,
+		"details": details,
+	});
+
+	let mut response = Response::builder().header(CONTENT_TYPE, "application/json");
+	response = match violation {
+		Violation::MethodNotAllowed(allowed) => {
+			let allowed = allowed.iter().map(Method::as_str).collect::<Vec<_>>();
+			response
+				.status(StatusCode::METHOD_NOT_ALLOWED)
+				.header(ALLOW, allowed.join(", "))
+		}
+		_ => response.status(StatusCode::BAD_REQUEST),
+	};
+	response.body(Body::from(body.to_string())).unwrap()
+}
+
+/// A response that doesn't match the spec
+#[derive(Debug, Clone)]
+pub struct ContractViolation {
+	/// The method of the request
+	pub method: Method,
+	/// The path of the request
+	pub path: String,
+	/// The status of the response
+	pub status: StatusCode,
+	/// What doesn't match
+	pub details: Vec<String>,
+}
+
+/// What [`Validate`] does with the responses of the inner request handler
+#[derive(Clone)]
+pub enum ResponseValidation {
+	/// Responses are not validated
+	Off,
+	/// Violations are passed to the callback, but the response is sent unchanged
+	Report(Arc<dyn Fn(ContractViolation) + Send + Sync>),
+	/// Violations are passed to the callback and the response is replaced by a
+	/// `502 Bad Gateway`
+	Enforce(Arc<dyn Fn(ContractViolation) + Send + Sync>),
+}
+
+/// A request handler combinator that validates requests against an OpenAPI spec before giving
+/// those that match to another request handler
+///
+/// Requests that don't match are answered with a `400 Bad Request` (or a `405 Method Not
+/// Allowed` with an `Allow` header if only the method is wrong) and a JSON body describing
+/// the problem. Since the body has to be buffered for validation, bodies larger than
+/// [`max_body_size`](Self::max_body_size) are rejected.
+///
+/// Optionally, the responses can be checked as well (see [`ResponseValidation`]), which is
+/// useful for catching contract drift in staging. Response bodies larger than
+/// [`max_body_size`](Self::max_body_size) and [streaming](crate::body::is_streaming) ones are
+/// passed on without checking their content.
+pub struct Validate<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: Arc<H>,
+	/// The spec requests are validated against
+	pub spec: Arc<OpenApiSpec>,
+	/// The maximum size of a request or response body in bytes
+	pub max_body_size: usize,
+	/// Whether and how responses are validated
+	pub responses: ResponseValidation,
+}
+
+impl<H: RequestHandler> Validate<H> {
+	/// Create a [`Validate`] with a maximum body size of 1 MiB that doesn't validate responses
+	pub fn new(inner: H, spec: OpenApiSpec) -> Self {
+		Self {
+			inner: Arc::new(inner),
+			spec: Arc::new(spec),
+			max_body_size: 1 << 20,
+			responses: ResponseValidation::Off,
+		}
+	}
+
+	/// Set how responses are validated
+	pub fn with_response_validation(mut self, responses: ResponseValidation) -> Self {
+		self.responses = responses;
+		self
+	}
+}
+
+/// The error type for `<`[`Validate`]` as `[`RequestHandler`]`>`
+#[derive(Debug, Error)]
+pub enum ValidateError<E: std::error::Error> {
+	#[error("{0}")]
+	/// The inner request handler returned an error
+	Inner(E),
+	#[error("failed to read body: {0}")]
+	/// The request or response body could not be read
+	Body(hyper::Error),
+}
+
+type ValidateFuture<E> =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, ValidateError<E>>> + Send>>;
+
+impl<H: RequestHandler + Send + Sync + 'static> RequestHandler for Validate<H> {
+	type Error = ValidateError<H::Error>;
+	type Output = ValidateFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let method = request.method().clone();
+		let path = request.uri().path().to_string();
+
+		let op_index = match self.spec.check_head(&request) {
+			Ok(i) => i,
+			Err(v) => {
+				return Box::pin(async move { Ok(violation_response(&v, &method, &path)) });
+			}
+		};
+
+		let inner = self.inner.clone();
+		let spec = self.spec.clone();
+		let client = client.clone();
+		let max_body_size = self.max_body_size;
+		let responses = self.responses.clone();
+
+		Box::pin(async move {
+			let op = &spec.operations[op_index];
+
+			let request = if op.body.is_some() {
+				let (parts, body) = request.into_parts();
+				let body = match read_limited(body, max_body_size)
+					.await
+					.map_err(ValidateError::Body)?
+				{
+					Some(body) => body,
+					None => {
+						return Ok(violation_response(&Violation::BodyTooLarge, &method, &path))
+					}
+				};
+
+				let content_type = parts
+					.headers
+					.get(CONTENT_TYPE)
+					.and_then(|v| v.to_str().ok());
+				if let Err(v) = spec.check_body(op, content_type, &body) {
+					return Ok(violation_response(&v, &method, &path));
+				}
+
+				Request::from_parts(parts, Body::from(body))
+			} else {
+				request
+			};
+
+			let response = inner
+				.handle(from_addr, request, &client)
+				.await
+				.map_err(ValidateError::Inner)?;
+
+			let (report, enforce) = match responses {
+				ResponseValidation::Off => return Ok(response),
+				ResponseValidation::Report(report) => (report, false),
+				ResponseValidation::Enforce(report) => (report, true),
+			};
+
+			let streaming = is_streaming(&response);
+			let (parts, body) = response.into_parts();
+			let (buffered, body) = if streaming {
+				(None, body)
+			} else {
+				match buffer(body, max_body_size)
+					.await
+					.map_err(ValidateError::Body)?
+				{
+					Buffered::Complete(bytes) => (Some(bytes.clone()), Body::from(bytes)),
+					Buffered::Partial(body) => (None, body),
+				}
+			};
+
+			let content_type = parts
+				.headers
+				.get(CONTENT_TYPE)
+				.and_then(|v| v.to_str().ok());
+			let details = spec.check_response(op, parts.status, content_type, buffered.as_deref());
+			if !details.is_empty() {
+				report(ContractViolation {
+					method,
+					path,
+					status: parts.status,
+					details,
+				});
+
+				if enforce {
+					return Ok(Response::builder()
+						.status(StatusCode::BAD_GATEWAY)
+						.body(Body::from(
+							"upstream response does not match the API specification",
+						))
+						.unwrap());
+				}
+			}
+
+			Ok(Response::from_parts(parts, body))
+		})
+	}
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+
+use futures::future::Either;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use thiserror::Error;
+
+use crate::connect::Connector;
+use crate::handlers::filter::request_authority;
+use crate::RequestHandler;
+
+/// A pattern for the hosts of a virtual host in [`VirtualHosts`]
+///
+/// Patterns are parsed from strings: `app.example.com` only matches that host, while
+/// `*.example.com` matches all of its subdomains (like `app.example.com` or
+/// `a.b.example.com`), but not `example.com` itself. Hosts are compared case-insensitively
+/// and without the port.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum HostPattern {
+	/// Exactly this host
+	Exact(String),
+	/// All hosts ending with `.` and this domain
+	Wildcard(String),
+}
+
+/// The error returned when parsing a [`HostPattern`] fails
+#[derive(Debug, Error)]
+#[error("invalid host pattern: {0:?}")]
+pub struct ParseHostPatternError(String);
+
+// Lowercase the host and remove the dot at the end of fully qualified names
+fn normalize(host: &str) -> String {
+	host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+impl HostPattern {
+	/// Return whether the host (without the port) matches the pattern
+	pub fn matches(&self, host: &str) -> bool {
+		let host = normalize(host);
+		match self {
+			Self::Exact(exact) => host == *exact,
+			Self::Wildcard(domain) => host
+				.strip_suffix(domain.as_str())
+				.and_then(|sub| sub.strip_suffix('.'))
+				.is_some_and(|sub| !sub.is_empty()),
+		}
+	}
+
+	// Exact hosts beat wildcards, and longer wildcards beat shorter ones
+	fn specificity(&self) -> usize {
+		match self {
+			Self::Exact(_) => usize::MAX,
+			Self::Wildcard(domain) => domain.len(),
+		}
+	}
+}
+
+impl std::str::FromStr for HostPattern {
+	type Err = ParseHostPatternError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || ParseHostPatternError(s.to_string());
+		let (wildcard, host) = match s.trim().strip_prefix("*.") {
+			Some(domain) => (true, domain),
+			None => (false, s.trim()),
+		};
+		let host = normalize(host);
+		let valid = !host.is_empty()
+			&& host.bytes().all(|b| {
+				b.is_ascii_alphanumeric()
+					|| b"-._".contains(&b)
+					|| (!wildcard && b"[]:".contains(&b))
+			});
+		if !valid {
+			return Err(invalid());
+		}
+		Ok(if wildcard {
+			Self::Wildcard(host)
+		} else {
+			Self::Exact(host)
+		})
+	}
+}
+
+impl fmt::Display for HostPattern {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Exact(host) => f.write_str(host),
+			Self::Wildcard(domain) => write!(f, "*.{}", domain),
+		}
+	}
+}
+
+/// A request handler that serves several virtual hosts from one listener, picking the request
+/// handler by the host the request was sent to
+///
+/// The host is taken from the URI authority if present and from the `Host` header otherwise.
+/// If several [`HostPattern`]s match, an exact host is preferred over wildcards and a longer
+/// wildcard over a shorter one. Requests for hosts that match no pattern go to the
+/// [`default`](Self::default) handler if there is one, and are answered with
+/// `421 Misdirected Request` otherwise.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::vhost::VirtualHosts;
+/// use proxylib::handlers::Redirect;
+///
+/// let handler = VirtualHosts::new()
+///     .with_host(
+///         "app1.example.com".parse().unwrap(),
+///         Redirect::change_authority("app1.internal:8080".parse().unwrap()),
+///     )
+///     .with_host(
+///         "*.app2.example.com".parse().unwrap(),
+///         Redirect::change_authority("app2.internal:8080".parse().unwrap()),
+///     )
+///     .with_default(Redirect::change_authority("www.internal:8080".parse().unwrap()));
+/// ```
+pub struct VirtualHosts<H: RequestHandler> {
+	/// The virtual hosts and their request handlers
+	pub hosts: Vec<(HostPattern, H)>,
+	/// The request handler for hosts that match no pattern
+	pub default: Option<H>,
+}
+
+impl<H: RequestHandler> VirtualHosts<H> {
+	/// Create a [`VirtualHosts`] without any virtual hosts
+	pub fn new() -> Self {
+		Self {
+			hosts: Vec::new(),
+			default: None,
+		}
+	}
+
+	/// Add a virtual host
+	pub fn with_host(mut self, pattern: HostPattern, handler: H) -> Self {
+		self.hosts.push((pattern, handler));
+		self
+	}
+
+	/// Set the request handler for hosts that match no pattern
+	pub fn with_default(mut self, handler: H) -> Self {
+		self.default = Some(handler);
+		self
+	}
+
+	/// Get the request handler for the host (without the port)
+	pub fn route(&self, host: &str) -> Option<&H> {
+		self.hosts
+			.iter()
+			.filter(|(pattern, _)| pattern.matches(host))
+			.max_by_key(|(pattern, _)| pattern.specificity())
+			.map(|(_, handler)| handler)
+			.or(self.default.as_ref())
+	}
+}
+
+impl<H: RequestHandler> Default for VirtualHosts<H> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+fn misdirected() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::MISDIRECTED_REQUEST)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from("This host is not served here.\n"))
+		.unwrap()
+}
+
+#[allow(type_alias_bounds)]
+type VirtualHostsFuture<H: RequestHandler> =
+	Either<H::Output, Ready<Result<Response<Body>, H::Error>>>;
+
+impl<H: RequestHandler> RequestHandler for VirtualHosts<H> {
+	type Error = H::Error;
+	type Output = VirtualHostsFuture<H>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		let handler = match request_authority(&request) {
+			Some(authority) => self.route(authority.host()),
+			None => self.default.as_ref(),
+		};
+
+		match handler {
+			Some(handler) => Either::Left(handler.handle(from_addr, request, client)),
+			None => Either::Right(ready(Ok(misdirected()))),
+		}
+	}
+}
@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, VIA};
+use hyper::{Body, Request, Response, StatusCode, Version};
+
+use crate::handlers::middleware::Middleware;
+
+/// A [`Middleware`] that adds the proxy to the `Via` header of requests and responses and
+/// detects forwarding loops
+///
+/// If the `Via` header of a request already names the [`pseudonym`](Self::pseudonym), the request
+/// has been through this proxy before and is answered with `508 Loop Detected` instead of
+/// being forwarded again. Proxies that forward to each other therefore need different
+/// pseudonyms.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::via::Via;
+/// use proxylib::handlers::Redirect;
+/// use proxylib::RequestHandler;
+///
+/// let handler = Redirect::change_authority("backend.internal".parse().unwrap())
+///     .layer(Via::new("edge-1").unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Via {
+	/// The name of the proxy in the `Via` header
+	pub pseudonym: String,
+	/// Whether the `Via` header is added to responses as well
+	pub responses: bool,
+}
+
+impl Via {
+	/// Create a [`Via`] with the given pseudonym, which also adds it to responses
+	///
+	/// Returns `None` if the pseudonym contains characters that can't be used in the header
+	/// (anything but letters, digits and ``!#$%&'*+-.^_`|~``).
+	pub fn new(pseudonym: impl Into<String>) -> Option<Self> {
+		let pseudonym = pseudonym.into();
+		let valid = !pseudonym.is_empty()
+			&& pseudonym
+				.bytes()
+				.all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+		valid.then_some(Self {
+			pseudonym,
+			responses: true,
+		})
+	}
+
+	/// Return whether the `Via` headers name the pseudonym
+	pub fn is_listed(&self, headers: &HeaderMap) -> bool {
+		headers
+			.get_all(VIA)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.filter_map(|entry| entry.split_whitespace().nth(1))
+			.any(|received_by| received_by.eq_ignore_ascii_case(&self.pseudonym))
+	}
+
+	fn append(&self, headers: &mut HeaderMap, version: Version) {
+		let protocol = match version {
+			Version::HTTP_09 => "0.9",
+			Version::HTTP_10 => "1.0",
+			Version::HTTP_2 => "2",
+			Version::HTTP_3 => "3",
+			_ => "1.1",
+		};
+		if let Ok(value) = HeaderValue::from_str(&format!("{} {}", protocol, self.pseudonym)) {
+			headers.append(VIA, value);
+		}
+	}
+}
+
+impl Middleware for Via {
+	fn before(&self, _: SocketAddr, request: &mut Request<Body>) -> Option<Response<Body>> {
+		if self.is_listed(request.headers()) {
+			let response = Response::builder()
+				.status(StatusCode::LOOP_DETECTED)
+				.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+				.body(Body::from("The request has been forwarded in a loop.\n"))
+				.unwrap();
+			return Some(response);
+		}
+		let version = request.version();
+		self.append(request.headers_mut(), version);
+		None
+	}
+
+	fn after(&self, response: &mut Response<Body>) {
+		if self.responses {
+			let version = response.version();
+			self.append(response.headers_mut(), version);
+		}
+	}
+}
@@ -0,0 +1,311 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{select, Either};
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::connect::Connector;
+use crate::handlers::overload::OverloadResponse;
+use crate::RequestHandler;
+
+/// Return whether the request asks to be upgraded to a WebSocket connection
+pub fn is_websocket_upgrade(request: &Request<Body>) -> bool {
+	let headers = request.headers();
+	let has_token = |name, token: &str| {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.any(|v| v.trim().eq_ignore_ascii_case(token))
+	};
+	has_token(CONNECTION, "upgrade") && has_token(UPGRADE, "websocket")
+}
+
+/// Limits for the WebSocket connections of a route
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WebSocketLimits {
+	/// The maximum number of concurrent connections
+	pub max_connections: usize,
+	/// The size of the largest message that is forwarded; larger ones close the connection
+	/// with status 1009 (message too big)
+	pub max_message_size: usize,
+	/// How long a connection may go without any data before it is closed with status 1001
+	/// (going away)
+	///
+	/// A large frame that is still arriving counts as activity.
+	pub idle_timeout: Option<Duration>,
+}
+
+impl Default for WebSocketLimits {
+	fn default() -> Self {
+		Self {
+			max_connections: 1024,
+			max_message_size: 1 << 20,
+			idle_timeout: Some(Duration::from_secs(5 * 60)),
+		}
+	}
+}
+
+/// A slot for one WebSocket connection, put into the extensions of successful upgrade responses
+/// by [`WebSocketLimit`]
+///
+/// The connection counts towards [`max_connections`](WebSocketLimits::max_connections) until
+/// the permit is dropped, so whatever tunnels the upgraded connection should keep it for as long
+/// as the connection is open, and apply the other limits to it, like
+/// [`UpgradeTunnel`](super::upgrade::UpgradeTunnel) does.
+#[derive(Debug)]
+pub struct WebSocketPermit {
+	/// The limits of the route
+	pub limits: WebSocketLimits,
+	_slot: OwnedSemaphorePermit,
+}
+
+/// A request handler combinator that limits the WebSocket connections of a route
+///
+/// Upgrade requests beyond [`max_connections`](WebSocketLimits::max_connections) are rejected
+/// with an [`OverloadResponse`]. Other requests are passed through as they are.
+pub struct WebSocketLimit<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The response for rejected upgrade requests
+	pub overload: Arc<OverloadResponse>,
+	limits: WebSocketLimits,
+	slots: Arc<Semaphore>,
+}
+
+impl<H: RequestHandler> WebSocketLimit<H> {
+	/// Create a [`WebSocketLimit`] with the given limits
+	pub fn new(inner: H, limits: WebSocketLimits) -> Self {
+		Self {
+			inner,
+			overload: Arc::default(),
+			limits,
+			slots: Arc::new(Semaphore::new(
+				limits.max_connections.min(Semaphore::MAX_PERMITS),
+			)),
+		}
+	}
+
+	/// Get the limits
+	pub fn limits(&self) -> &WebSocketLimits {
+		&self.limits
+	}
+
+	/// Get the number of open WebSocket connections
+	pub fn active(&self) -> usize {
+		self.limits.max_connections.min(Semaphore::MAX_PERMITS) - self.slots.available_permits()
+	}
+}
+
+type WebSocketLimitFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+impl<H: RequestHandler> RequestHandler for WebSocketLimit<H> {
+	type Error = H::Error;
+	type Output = WebSocketLimitFuture<H::Error>;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<Connector>,
+	) -> Self::Output {
+		if !is_websocket_upgrade(&request) {
+			return Box::pin(self.inner.handle(from_addr, request, client));
+		}
+
+		let slot = match self.slots.clone().try_acquire_owned() {
+			Ok(slot) => slot,
+			Err(_) => {
+				let response = self.overload.respond("websocket_connections");
+				return Box::pin(async { Ok(response) });
+			}
+		};
+		let fut = self.inner.handle(from_addr, request, client);
+		let limits = self.limits;
+
+		Box::pin(async move {
+			let mut response = fut.await?;
+			if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+				response.extensions_mut().insert(WebSocketPermit {
+					limits,
+					_slot: slot,
+				});
+			}
+			Ok(response)
+		})
+	}
+}
+
+// What ended a relayed WebSocket connection
+enum RelayEnd {
+	Closed,
+	TooBig,
+	Idle,
+}
+
+// Forward the frames from `reader` to `writer` until the connection is closed or a message is
+// too big, noting the time of every frame header and payload chunk in `activity`
+//
+// `mid_frame` is set while a frame is partly written, so that no close frame is put into the
+// middle of it once the relay is dropped.
+async fn relay_frames<R, W>(
+	reader: &mut R,
+	writer: &mut W,
+	max_message_size: u64,
+	activity: &Mutex<Instant>,
+	mid_frame: &AtomicBool,
+) -> io::Result<RelayEnd>
+where
+	R: AsyncRead + Unpin,
+	W: AsyncWrite + Unpin,
+{
+	let mut message_size = 0u64;
+	loop {
+		let mut header = [0; 14];
+		match reader.read_exact(&mut header[..2]).await {
+			Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(RelayEnd::Closed),
+			result => result?,
+		};
+		*activity.lock().unwrap() = Instant::now();
+
+		let opcode = header[0] & 0x0f;
+		let length_size = match header[1] & 0x7f {
+			126 => 2,
+			127 => 8,
+			_ => 0,
+		};
+		let mask_size = if header[1] & 0x80 != 0 { 4 } else { 0 };
+		let header_size = 2 + length_size + mask_size;
+		reader.read_exact(&mut header[2..header_size]).await?;
+		let payload_size = match length_size {
+			0 => u64::from(header[1] & 0x7f),
+			2 => u64::from(u16::from_be_bytes([header[2], header[3]])),
+			_ => {
+				let mut length = [0; 8];
+				length.copy_from_slice(&header[2..10]);
+				u64::from_be_bytes(length)
+			}
+		};
+
+		// Control frames can come between the fragments of a message and don't count
+		if opcode & 0x8 == 0 {
+			message_size = match opcode {
+				0 => message_size.saturating_add(payload_size),
+				_ => payload_size,
+			};
+			if message_size > max_message_size {
+				return Ok(RelayEnd::TooBig);
+			}
+		}
+
+		mid_frame.store(true, Ordering::Relaxed);
+		writer.write_all(&header[..header_size]).await?;
+		let mut remaining = payload_size;
+		let mut buf = [0; 8192];
+		while remaining > 0 {
+			let len = remaining.min(buf.len() as u64) as usize;
+			let read = reader.read(&mut buf[..len]).await?;
+			if read == 0 {
+				return Ok(RelayEnd::Closed);
+			}
+			*activity.lock().unwrap() = Instant::now();
+			writer.write_all(&buf[..read]).await?;
+			remaining -= read as u64;
+		}
+		mid_frame.store(false, Ordering::Relaxed);
+	}
+}
+
+async fn idle(activity: &Mutex<Instant>, timeout: Option<Duration>) -> RelayEnd {
+	let timeout = match timeout {
+		Some(timeout) => timeout,
+		None => return futures::future::pending().await,
+	};
+	loop {
+		let deadline = *activity.lock().unwrap() + timeout;
+		if Instant::now() >= deadline {
+			return RelayEnd::Idle;
+		}
+		tokio::time::sleep_until(deadline.into()).await;
+	}
+}
+
+// A close frame with the status code; frames to servers have to be masked, which is done
+// with an all-zero key
+fn close_frame(code: u16, masked: bool) -> Vec<u8> {
+	let mut frame = vec![0x88];
+	if masked {
+		frame.extend_from_slice(&[0x82, 0, 0, 0, 0]);
+	} else {
+		frame.push(0x02);
+	}
+	frame.extend_from_slice(&code.to_be_bytes());
+	frame
+}
+
+/// Forward the frames of an upgraded WebSocket connection between the client and the upstream,
+/// applying the limits of the permit
+pub(crate) async fn relay_websocket<C, U>(client: C, upstream: U, permit: WebSocketPermit)
+where
+	C: AsyncRead + AsyncWrite + Unpin,
+	U: AsyncRead + AsyncWrite + Unpin,
+{
+	let (mut client_read, mut client_write) = tokio::io::split(client);
+	let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+	let activity = Mutex::new(Instant::now());
+	let max_message_size = permit.limits.max_message_size as u64;
+	let upstream_mid_frame = AtomicBool::new(false);
+	let client_mid_frame = AtomicBool::new(false);
+
+	let end = {
+		let to_upstream = Box::pin(relay_frames(
+			&mut client_read,
+			&mut upstream_write,
+			max_message_size,
+			&activity,
+			&upstream_mid_frame,
+		));
+		let to_client = Box::pin(relay_frames(
+			&mut upstream_read,
+			&mut client_write,
+			max_message_size,
+			&activity,
+			&client_mid_frame,
+		));
+		let idle = Box::pin(idle(&activity, permit.limits.idle_timeout));
+		match select(select(to_upstream, to_client), idle).await {
+			Either::Left((Either::Left((end, _)) | Either::Right((end, _)), _)) => {
+				end.unwrap_or(RelayEnd::Closed)
+			}
+			Either::Right((end, _)) => end,
+		}
+	};
+
+	let code = match end {
+		RelayEnd::Closed => None,
+		RelayEnd::TooBig => Some(1009),
+		RelayEnd::Idle => Some(1001),
+	};
+	// The relays were dropped, maybe in the middle of a frame, after which a close frame would
+	// break the framing; those sides are just disconnected
+	if let Some(code) = code {
+		if !client_mid_frame.load(Ordering::Relaxed) {
+			let _ = client_write.write_all(&close_frame(code, false)).await;
+		}
+		if !upstream_mid_frame.load(Ordering::Relaxed) {
+			let _ = upstream_write.write_all(&close_frame(code, true)).await;
+		}
+	}
+	let _ = client_write.shutdown().await;
+	let _ = upstream_write.shutdown().await;
+	drop(permit);
+}
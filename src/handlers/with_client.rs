@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+
+use hyper::{Body, Client, Request};
+
+use crate::connect::{Connector, UpstreamConfig};
+use crate::RequestHandler;
+
+/// A request handler combinator that gives requests to another request handler together with
+/// its own client instead of the proxy's
+///
+/// This allows per-route control over how upstream connections are made and reused.
+pub struct WithClient<H: RequestHandler> {
+	/// The inner request handler to give requests to
+	pub inner: H,
+	/// The client the inner request handler gets
+	pub client: Client<Connector>,
+}
+
+impl<H: RequestHandler> WithClient<H> {
+	/// Create a [`WithClient`] with a client built from the given config
+	pub fn new(inner: H, config: &UpstreamConfig) -> Self {
+		Self {
+			inner,
+			client: config.build_client(),
+		}
+	}
+}
+
+impl<H: RequestHandler> RequestHandler for WithClient<H> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		_client: &Client<Connector>,
+	) -> Self::Output {
+		self.inner.handle(from_addr, request, &self.client)
+	}
+}
@@ -0,0 +1,300 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{stream, StreamExt};
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, Uri, Version};
+
+use crate::connect::{Connector, UpstreamConfig};
+use crate::handlers::redirect::HOP_BY_HOP_HEADERS;
+use crate::tls::TlsProxyConfig;
+use crate::{OnError, ProxyError, RequestHandler, ServeError};
+
+/// The config of an HTTP/3 listener, see [`run_http3_proxy`]
+///
+/// HTTP/3 clients usually find the listener through the `Alt-Svc` header of responses over
+/// HTTP/1.1 or HTTP/2, see [`AltSvc`](crate::handlers::alt_svc::AltSvc). To serve the same
+/// requests over both, the listeners can share one request handler with
+/// [`with_shared_handler`](Self::with_shared_handler).
+pub struct Http3Config<T: RequestHandler + 'static> {
+	/// The UDP address where the proxy listens for QUIC connections
+	pub listen_on: SocketAddr,
+	/// The handler that handles the incoming requests
+	pub request_handler: Arc<T>,
+	/// The TLS config, whose certificate is used for QUIC
+	///
+	/// QUIC needs TLS 1.3, and the ALPN protocols are replaced with `h3`.
+	pub tls: TlsProxyConfig,
+	/// Called whenever a non-fatal error occurs
+	pub on_error: Option<OnError>,
+	/// The client given to the request handler
+	pub client: Client<Connector>,
+}
+
+impl<T: RequestHandler + 'static> Http3Config<T> {
+	/// Create a config
+	pub fn new(listen_on: SocketAddr, request_handler: T, tls: TlsProxyConfig) -> Self {
+		Self::with_shared_handler(listen_on, Arc::new(request_handler), tls)
+	}
+
+	/// Create a config with a handler that is shared with other code (or other proxies)
+	pub fn with_shared_handler(
+		listen_on: SocketAddr,
+		request_handler: Arc<T>,
+		tls: TlsProxyConfig,
+	) -> Self {
+		Self {
+			listen_on,
+			request_handler,
+			tls,
+			on_error: None,
+			client: UpstreamConfig::default().build_client(),
+		}
+	}
+
+	/// Set the client given to the request handler
+	pub fn with_client(mut self, client: Client<Connector>) -> Self {
+		self.client = client;
+		self
+	}
+
+	/// Set the callback for non-fatal errors
+	pub fn with_on_error<F: Fn(&ServeError) + Send + Sync + 'static>(mut self, f: F) -> Self {
+		self.on_error = Some(Arc::new(f));
+		self
+	}
+}
+
+fn report(on_error: &Option<OnError>, error: ServeError) {
+	crate::report(on_error, &error)
+}
+
+fn http3_error(
+	peer_addr: SocketAddr,
+	error: impl std::error::Error + Send + Sync + 'static,
+) -> ServeError {
+	ServeError::Http3 {
+		peer_addr,
+		error: Box::new(error),
+	}
+}
+
+// h3 uses version 1 of the `http` crate and hyper version 0.2, so messages are converted
+// between them
+fn convert_headers(from: &http1::HeaderMap) -> HeaderMap {
+	let mut headers = HeaderMap::with_capacity(from.len());
+	for (name, value) in from {
+		if let (Ok(name), Ok(value)) = (
+			HeaderName::from_bytes(name.as_str().as_bytes()),
+			HeaderValue::from_bytes(value.as_bytes()),
+		) {
+			headers.append(name, value);
+		}
+	}
+	headers
+}
+
+// Connection-specific headers are not allowed in HTTP/3
+fn convert_headers_back(from: &HeaderMap) -> http1::HeaderMap {
+	let mut headers = http1::HeaderMap::with_capacity(from.len());
+	for (name, value) in from {
+		if HOP_BY_HOP_HEADERS.contains(name) {
+			continue;
+		}
+		if let (Ok(name), Ok(value)) = (
+			http1::HeaderName::from_bytes(name.as_str().as_bytes()),
+			http1::HeaderValue::from_bytes(value.as_bytes()),
+		) {
+			headers.append(name, value);
+		}
+	}
+	headers
+}
+
+type RequestStream<S> = h3::server::RequestStream<S, Bytes>;
+
+// The request body, which is empty if the stream ends right after the headers (as for `GET`
+// requests), so it isn't forwarded as a chunked one
+async fn request_body<S>(mut recv: RequestStream<S>) -> Result<Body, h3::error::StreamError>
+where
+	S: h3::quic::RecvStream + Send + 'static,
+{
+	let mut first = match recv.recv_data().await? {
+		Some(first) => first,
+		None => return Ok(Body::empty()),
+	};
+	let first = first.copy_to_bytes(first.remaining());
+	let rest = stream::unfold(Some(recv), |recv| async move {
+		let mut recv = recv?;
+		match recv.recv_data().await {
+			Ok(Some(mut chunk)) => Some((Ok(chunk.copy_to_bytes(chunk.remaining())), Some(recv))),
+			Ok(None) => None,
+			Err(e) => Some((Err(e), None)),
+		}
+	});
+	Ok(Body::wrap_stream(
+		stream::once(async { Ok(first) }).chain(rest),
+	))
+}
+
+async fn send_response<S>(
+	send: &mut RequestStream<S>,
+	response: Response<Body>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+	S: h3::quic::SendStream<Bytes>,
+{
+	let (parts, mut body) = response.into_parts();
+	let mut head = http1::Response::new(());
+	*head.status_mut() = http1::StatusCode::from_u16(parts.status.as_u16())?;
+	*head.headers_mut() = convert_headers_back(&parts.headers);
+	send.send_response(head).await?;
+
+	while let Some(chunk) = body.data().await {
+		send.send_data(chunk?).await?;
+	}
+	if let Some(trailers) = body.trailers().await? {
+		send.send_trailers(convert_headers_back(&trailers)).await?;
+	}
+	send.finish().await?;
+	Ok(())
+}
+
+// Serve one request of a connection
+async fn serve_request<T, C>(
+	resolver: h3::server::RequestResolver<C, Bytes>,
+	addr: SocketAddr,
+	handler: Arc<T>,
+	client: Client<Connector>,
+	on_error: Option<OnError>,
+) where
+	T: RequestHandler + Send + Sync + 'static,
+	C: h3::quic::Connection<Bytes>,
+	C::BidiStream: h3::quic::BidiStream<Bytes> + Send + 'static,
+	<C::BidiStream as h3::quic::BidiStream<Bytes>>::RecvStream: Send + 'static,
+{
+	let (head, stream) = match resolver.resolve_request().await {
+		Ok(request) => request,
+		Err(e) => return report(&on_error, http3_error(addr, e)),
+	};
+	let (mut send, recv) = stream.split();
+
+	let method = Method::from_bytes(head.method().as_str().as_bytes());
+	let uri = Uri::try_from(head.uri().to_string());
+	let (method, uri) = match (method, uri) {
+		(Ok(method), Ok(uri)) => (method, uri),
+		_ => {
+			let e = io::Error::new(io::ErrorKind::InvalidData, "invalid request line");
+			send.stop_stream(h3::error::Code::H3_MESSAGE_ERROR);
+			return report(&on_error, http3_error(addr, e));
+		}
+	};
+	let body = match request_body(recv).await {
+		Ok(body) => body,
+		Err(e) => return report(&on_error, http3_error(addr, e)),
+	};
+	let mut request = Request::new(body);
+	*request.method_mut() = method.clone();
+	*request.uri_mut() = uri.clone();
+	*request.version_mut() = Version::HTTP_3;
+	*request.headers_mut() = convert_headers(head.headers());
+
+	let response = match handler.handle(addr, request, &client).await {
+		Ok(response) => response,
+		Err(error) => {
+			send.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+			return report(
+				&on_error,
+				ServeError::Handler {
+					peer_addr: addr,
+					method,
+					uri,
+					error: Box::new(error),
+				},
+			);
+		}
+	};
+	if let Err(error) = send_response(&mut send, response).await {
+		report(
+			&on_error,
+			ServeError::Http3 {
+				peer_addr: addr,
+				error,
+			},
+		);
+	}
+}
+
+// Serve the requests of one QUIC connection
+async fn serve<T>(
+	incoming: quinn::Incoming,
+	handler: Arc<T>,
+	client: Client<Connector>,
+	on_error: Option<OnError>,
+) where
+	T: RequestHandler + Send + Sync + 'static,
+{
+	let addr = incoming.remote_address();
+	let connection = match incoming.await {
+		Ok(connection) => connection,
+		Err(e) => return report(&on_error, http3_error(addr, e)),
+	};
+	let mut connection = match h3::server::builder()
+		.build::<_, Bytes>(h3_quinn::Connection::new(connection))
+		.await
+	{
+		Ok(connection) => connection,
+		Err(e) => return report(&on_error, http3_error(addr, e)),
+	};
+
+	loop {
+		match connection.accept().await {
+			Ok(Some(resolver)) => {
+				tokio::spawn(serve_request(
+					resolver,
+					addr,
+					handler.clone(),
+					client.clone(),
+					on_error.clone(),
+				));
+			}
+			Ok(None) => return,
+			Err(e)
+				if e.is_h3_no_error()
+					|| matches!(e, h3::error::ConnectionError::Timeout { .. }) =>
+			{
+				return
+			}
+			Err(e) => return report(&on_error, http3_error(addr, e)),
+		}
+	}
+}
+
+/// Run an HTTP/3 listener with the given configuration (experimental)
+///
+/// The requests are given to the request handler like those of [`run_proxy`](crate::run_proxy),
+/// with the version [`HTTP_3`](Version::HTTP_3), so the same handlers can be used.
+pub async fn run_http3_proxy<T: RequestHandler + Send + Sync + 'static>(
+	config: Http3Config<T>,
+) -> Result<(), ProxyError> {
+	let mut tls = config.tls.server_config;
+	Arc::make_mut(&mut tls).alpn_protocols = vec![b"h3".to_vec()];
+	let crypto =
+		quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(ProxyError::QuicConfig)?;
+	let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+	let endpoint = quinn::Endpoint::server(server_config, config.listen_on)
+		.map_err(ProxyError::BindListener)?;
+
+	while let Some(incoming) = endpoint.accept().await {
+		tokio::spawn(serve(
+			incoming,
+			config.request_handler.clone(),
+			config.client.clone(),
+			config.on_error.clone(),
+		));
+	}
+	Ok(())
+}
@@ -1,18 +1,63 @@
 //! # Proxylib
 //! A library to make writing proxies easier
 
-use std::convert::Infallible;
 use std::future::Future;
-use std::net::{SocketAddr, TcpListener};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use hyper::client::HttpConnector;
-use hyper::server::conn::AddrStream;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Request, Response, Server};
+use futures::future::{join_all, poll_fn, FutureExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, Http};
+use hyper::service::service_fn;
+use hyper::{Body, Client, Method, Request, Response, Uri};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinHandle;
 
+/// Helpers for working with request and response bodies
+pub mod body;
+/// Sending upstream connections through parent proxies
+pub mod chain;
+#[cfg(feature = "serde")]
+/// Describing proxies in TOML or YAML config files
+pub mod config;
+/// Client connections and their lifecycle hooks
+pub mod conn;
+/// Establishing upstream connections
+pub mod connect;
+/// Name resolution for upstream connections
+pub mod dns;
 /// A collection of common [`RequestHandler`]s and combinators
 pub mod handlers;
+#[cfg(feature = "http3")]
+/// Serving HTTP/3 over QUIC (experimental)
+pub mod http3;
+/// Writing log streams to pluggable sinks
+pub mod log;
+/// Counters and other metrics collected by the handlers
+pub mod metrics;
+/// Warming caches ahead of traffic
+pub mod prime;
+#[cfg(feature = "prometheus")]
+/// Exporting metrics in the Prometheus text format
+pub mod prometheus;
+/// The PROXY protocol, which passes the addresses of clients through load balancers
+pub mod proxy_protocol;
+#[cfg(feature = "socks")]
+/// SOCKS5 proxies, both to connect to upstreams through and to serve clients as
+pub mod socks;
+#[cfg(feature = "tls")]
+/// TLS configuration for the listener
+pub mod tls;
+/// Transparent proxying of connections that were redirected to the proxy
+pub mod transparent;
+#[cfg(unix)]
+/// Upstreams listening on Unix sockets
+pub mod unix;
 
 /// Something that can handle a request and give back a response (or an error)
 pub trait RequestHandler {
@@ -26,20 +71,361 @@ pub trait RequestHandler {
 		&self,
 		from_addr: SocketAddr,
 		request: Request<Body>,
-		client: &Client<HttpConnector>,
+		client: &Client<connect::Connector>,
 	) -> Self::Output;
+
+	/// Erase the type of the request handler, e.g. to store different ones in one `Vec`
+	/// or to choose one at runtime
+	fn boxed(self) -> BoxRequestHandler
+	where
+		Self: Sized + Send + Sync + 'static,
+	{
+		BoxRequestHandler::new(self)
+	}
+
+	/// Run the given [`Middleware`](handlers::middleware::Middleware) around the request handler
+	fn layer<M: handlers::middleware::Middleware>(self, middleware: M) -> handlers::Layered<M, Self>
+	where
+		Self: Sized,
+	{
+		handlers::Layered::new(middleware, self)
+	}
+}
+
+/// The error type of a [`BoxRequestHandler`]
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct BoxHandlerError(pub Box<dyn std::error::Error + Send + Sync>);
+
+/// A shared request handler, e.g. one that is served by a proxy and also used by
+/// [`Admin::with_primer`](handlers::admin::Admin::with_primer)
+impl<H: RequestHandler> RequestHandler for Arc<H> {
+	type Error = H::Error;
+	type Output = H::Output;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<connect::Connector>,
+	) -> Self::Output {
+		(**self).handle(from_addr, request, client)
+	}
+}
+
+/// The future type of a [`BoxRequestHandler`]
+pub type BoxHandlerFuture =
+	Pin<Box<dyn Future<Output = Result<Response<Body>, BoxHandlerError>> + Send>>;
+
+// The object-safe part of `RequestHandler` behind a `BoxRequestHandler`
+trait DynRequestHandler: Send + Sync {
+	fn handle_boxed(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<connect::Connector>,
+	) -> BoxHandlerFuture;
+}
+
+impl<H: RequestHandler + Send + Sync> DynRequestHandler for H {
+	fn handle_boxed(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<connect::Connector>,
+	) -> BoxHandlerFuture {
+		let fut = self.handle(from_addr, request, client);
+		Box::pin(async move { fut.await.map_err(|e| BoxHandlerError(Box::new(e))) })
+	}
+}
+
+/// A request handler of any type, with boxed futures and errors
+///
+/// Create one with [`RequestHandler::boxed`] or [`new`](Self::new).
+///
+/// ```
+/// use hyper::http::uri::Authority;
+/// use proxylib::handlers::prelude::*;
+/// use proxylib::{BoxRequestHandler, RequestHandler};
+///
+/// let upstream = |to| Redirect::change_authority(Authority::from_static(to));
+/// let handlers: Vec<BoxRequestHandler> = vec![
+///     upstream("a.example.com").boxed(),
+///     Maintenance::new(upstream("b.example.com"), MaintenanceSwitch::new()).boxed(),
+/// ];
+/// ```
+pub struct BoxRequestHandler(Box<dyn DynRequestHandler>);
+
+impl BoxRequestHandler {
+	/// Box the given request handler
+	pub fn new<H: RequestHandler + Send + Sync + 'static>(inner: H) -> Self {
+		Self(Box::new(inner))
+	}
+}
+
+impl RequestHandler for BoxRequestHandler {
+	type Error = BoxHandlerError;
+	type Output = BoxHandlerFuture;
+
+	fn handle(
+		&self,
+		from_addr: SocketAddr,
+		request: Request<Body>,
+		client: &Client<connect::Connector>,
+	) -> Self::Output {
+		self.0.handle_boxed(from_addr, request, client)
+	}
+
+	fn boxed(self) -> BoxRequestHandler {
+		self
+	}
+}
+
+/// How a proxy serves HTTP/2, see [`ProxyConfig::with_http2`]
+///
+/// Requests that arrive over HTTP/2 are forwarded over whichever version the upstream
+/// connection uses.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Http2Config {
+	/// Whether HTTP/2 is served to clients that choose it with ALPN in the TLS handshake
+	pub enabled: bool,
+	/// Whether HTTP/2 with prior knowledge (h2c) is served to clients without TLS, besides
+	/// HTTP/1.1
+	pub h2c: bool,
+	/// The maximum number of concurrent requests on one connection
+	pub max_concurrent_streams: u32,
+	/// The interval of pings that check whether a connection is still alive
+	pub keep_alive_interval: Option<Duration>,
+	/// How long to wait for the answer to a ping before closing the connection
+	pub keep_alive_timeout: Duration,
+}
+
+impl Default for Http2Config {
+	/// Serve HTTP/2 over TLS, but not h2c, with 200 concurrent requests per connection and
+	/// without pings
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			h2c: false,
+			max_concurrent_streams: 200,
+			keep_alive_interval: None,
+			keep_alive_timeout: Duration::from_secs(20),
+		}
+	}
+}
+
+/// Options of the listening sockets of a proxy, see [`ProxyConfig::with_listener`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ListenerConfig {
+	/// The maximum number of connections that wait to be accepted, which the kernel may limit
+	/// further (like to `net.core.somaxconn` on Linux)
+	pub backlog: u32,
+	/// Whether the address can be bound again right after the proxy stopped, while connections
+	/// of the old listener are still in `TIME_WAIT` (`SO_REUSEADDR`)
+	///
+	/// On Windows, this lets other sockets bind the same address, so it's off there by default.
+	pub reuse_address: bool,
+}
+
+impl Default for ListenerConfig {
+	/// A backlog of 1024, reusing addresses except on Windows
+	fn default() -> Self {
+		Self {
+			backlog: 1024,
+			reuse_address: !cfg!(windows),
+		}
+	}
 }
 
 /// The config of a proxy
 pub struct ProxyConfig<T: RequestHandler + 'static> {
 	/// The address where the proxy listens for requests
 	pub listen_on: SocketAddr,
+	/// Further addresses where the proxy listens for requests, e.g. an IPv6 address besides an
+	/// IPv4 one or a second port
+	pub additional_listen_on: Vec<SocketAddr>,
 	/// The handler that handles the incoming requests
-	pub request_handler: &'static T,
+	///
+	/// It is dropped once the proxy has stopped and all connections are closed.
+	pub request_handler: Arc<T>,
+	/// Called whenever a client connection is accepted
+	pub on_connect: Option<conn::OnConnect>,
+	/// Called whenever a client connection is closed
+	pub on_disconnect: Option<conn::OnDisconnect>,
+	/// Called whenever a non-fatal error occurs
+	pub on_error: Option<OnError>,
+	/// Where the connections and requests are counted, see [`with_stats`](Self::with_stats)
+	pub stats: Option<Arc<metrics::ProxyStats>>,
+	/// The client given to the request handler
+	pub client: Client<connect::Connector>,
+	#[cfg(feature = "tls")]
+	/// The TLS config if the proxy serves HTTPS
+	pub tls: Option<tls::TlsProxyConfig>,
+	/// How HTTP/2 is served
+	pub http2: Http2Config,
+	/// The options of the listening sockets
+	pub listener: ListenerConfig,
+	/// Which client connections start with a PROXY protocol header
+	pub proxy_protocol: Option<Arc<proxy_protocol::ProxyProtocolConfig>>,
+	#[cfg(target_os = "linux")]
+	/// How redirected connections reach the proxy, if it is a transparent one
+	pub transparent: Option<transparent::TransparentMode>,
+	#[cfg(target_os = "linux")]
+	/// The number of acceptors of each address, see [`with_acceptors`](Self::with_acceptors)
+	/// (Linux only)
+	pub acceptors: usize,
+}
+
+impl<T: RequestHandler + 'static> ProxyConfig<T> {
+	/// Create a config without connection hooks
+	pub fn new(listen_on: SocketAddr, request_handler: T) -> Self {
+		Self::with_shared_handler(listen_on, Arc::new(request_handler))
+	}
+
+	/// Create a config with a handler that is shared with other code (or other proxies)
+	pub fn with_shared_handler(listen_on: SocketAddr, request_handler: Arc<T>) -> Self {
+		Self {
+			listen_on,
+			additional_listen_on: Vec::new(),
+			request_handler,
+			on_connect: None,
+			on_disconnect: None,
+			on_error: None,
+			stats: None,
+			client: connect::UpstreamConfig::default().build_client(),
+			#[cfg(feature = "tls")]
+			tls: None,
+			http2: Http2Config::default(),
+			listener: ListenerConfig::default(),
+			proxy_protocol: None,
+			#[cfg(target_os = "linux")]
+			transparent: None,
+			#[cfg(target_os = "linux")]
+			acceptors: 1,
+		}
+	}
+
+	/// Also listen on the given address, with the same request handler and options
+	///
+	/// ```
+	/// use proxylib::handlers::Redirect;
+	/// use proxylib::ProxyConfig;
+	///
+	/// let handler = Redirect::change_authority("app.internal:8080".parse().unwrap());
+	/// let config = ProxyConfig::new("0.0.0.0:8080".parse().unwrap(), handler)
+	///     .with_additional_listen_on("[::]:8080".parse().unwrap());
+	/// ```
+	pub fn with_additional_listen_on(mut self, listen_on: SocketAddr) -> Self {
+		self.additional_listen_on.push(listen_on);
+		self
+	}
+
+	/// Serve HTTPS, terminating TLS with the given config
+	#[cfg(feature = "tls")]
+	pub fn with_tls(mut self, tls: tls::TlsProxyConfig) -> Self {
+		self.tls = Some(tls);
+		self
+	}
+
+	/// Set how HTTP/2 is served, e.g. to also serve h2c to clients without TLS
+	pub fn with_http2(mut self, http2: Http2Config) -> Self {
+		self.http2 = http2;
+		self
+	}
+
+	/// Set the options of the listening sockets, e.g. a larger backlog for bursts of
+	/// connections
+	pub fn with_listener(mut self, listener: ListenerConfig) -> Self {
+		self.listener = listener;
+		self
+	}
+
+	/// Read the addresses of clients from the PROXY protocol headers of the trusted load
+	/// balancers
+	pub fn with_proxy_protocol(
+		mut self,
+		proxy_protocol: proxy_protocol::ProxyProtocolConfig,
+	) -> Self {
+		self.proxy_protocol = Some(Arc::new(proxy_protocol));
+		self
+	}
+
+	/// Serve connections that were redirected to the proxy with iptables, putting their
+	/// [`OriginalDestination`](transparent::OriginalDestination) into the request extensions
+	///
+	/// The requests can then be forwarded to where the clients meant to send them with a
+	/// [`Transparent`](handlers::transparent::Transparent), so clients don't need to be
+	/// configured to use the proxy.
+	#[cfg(target_os = "linux")]
+	pub fn with_transparent(mut self, mode: transparent::TransparentMode) -> Self {
+		self.transparent = Some(mode);
+		self
+	}
+
+	/// Accept the connections of each address with several listeners bound with `SO_REUSEPORT`
+	/// (like one per core), which the kernel spreads the connections between, instead of one
+	///
+	/// Every acceptor runs in its own task, so with a multi-threaded runtime, connections are
+	/// accepted in parallel, which helps with a high rate of new connections.
+	///
+	/// ```
+	/// use proxylib::handlers::Redirect;
+	/// use proxylib::ProxyConfig;
+	///
+	/// let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+	/// let handler = Redirect::change_authority("app.internal:8080".parse().unwrap());
+	/// let config = ProxyConfig::new("0.0.0.0:8080".parse().unwrap(), handler).with_acceptors(cores);
+	/// ```
+	#[cfg(target_os = "linux")]
+	pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+		self.acceptors = acceptors;
+		self
+	}
+
+	/// Set the client given to the request handler, e.g. one with a custom [`connect::Connector`]
+	pub fn with_client(mut self, client: Client<connect::Connector>) -> Self {
+		self.client = client;
+		self
+	}
+
+	/// Set the callback for accepted client connections
+	///
+	/// With TLS, it is called once the handshake has completed; connections whose handshake
+	/// fails are only reported to [`on_error`](Self::on_error), and not to
+	/// [`on_disconnect`](Self::on_disconnect) either.
+	pub fn with_on_connect<F: Fn(&conn::ConnectionInfo) + Send + Sync + 'static>(
+		mut self,
+		f: F,
+	) -> Self {
+		self.on_connect = Some(Arc::new(f));
+		self
+	}
+
+	/// Set the callback for closed client connections
+	pub fn with_on_disconnect<F: Fn(&conn::ConnectionSummary) + Send + Sync + 'static>(
+		mut self,
+		f: F,
+	) -> Self {
+		self.on_disconnect = Some(Arc::new(f));
+		self
+	}
+
+	/// Set the callback for non-fatal errors
+	pub fn with_on_error<F: Fn(&ServeError) + Send + Sync + 'static>(mut self, f: F) -> Self {
+		self.on_error = Some(Arc::new(f));
+		self
+	}
+
+	/// Count the open connections and the requests in flight (until their response body has
+	/// been sent) in the stats, e.g. for an [`Admin`](handlers::admin::Admin) API
+	pub fn with_stats(mut self, stats: Arc<metrics::ProxyStats>) -> Self {
+		self.stats = Some(stats);
+		self
+	}
 }
 
 #[derive(Debug, Error)]
-/// An error while running the proxy
+/// A fatal error while running the proxy
 pub enum ProxyError {
 	#[error("failed to bind TcpListener: {0}")]
 	/// Failed to bing the `TcpListener` to the specified address
@@ -47,32 +433,624 @@ pub enum ProxyError {
 	#[error("failed to start http server: {0}")]
 	/// Failed to start the internal http server
 	StartServer(hyper::Error),
-	#[error("http server stopped with error: {0}")]
-	/// The internal http server encountered an error while running
-	Serve(hyper::Error),
+	#[cfg(feature = "http3")]
+	#[error("failed to configure QUIC: {0}")]
+	/// The TLS config can't be used for QUIC, which needs TLS 1.3
+	QuicConfig(quinn::crypto::rustls::NoInitialCipherSuite),
+	#[error("{}", join_errors(.0))]
+	/// Listeners of a proxy with several addresses failed, see
+	/// [`ProxyConfig::additional_listen_on`]
+	Listeners(Vec<ListenerError>),
+}
+
+impl ProxyError {
+	// The errors of a proxy's listeners, where the error of a lone listener is returned as it is
+	fn from_listeners(mut errors: Vec<ListenerError>, listeners: usize) -> Self {
+		match errors.pop() {
+			Some(error) if listeners == 1 => error.error,
+			last => {
+				errors.extend(last);
+				Self::Listeners(errors)
+			}
+		}
+	}
+}
+
+fn join_errors(errors: &[ListenerError]) -> String {
+	errors
+		.iter()
+		.map(ToString::to_string)
+		.collect::<Vec<_>>()
+		.join("; ")
+}
+
+#[derive(Debug, Error)]
+#[error("listener on {listen_on} failed: {error}")]
+/// The fatal error of one listener of a proxy, see [`ProxyError::Listeners`]
+pub struct ListenerError {
+	/// The address of the listener
+	pub listen_on: SocketAddr,
+	/// The error
+	pub error: ProxyError,
+}
+
+#[derive(Debug, Error)]
+/// A non-fatal error while running the proxy, reported to [`ProxyConfig::on_error`]
+pub enum ServeError {
+	#[error("failed to accept connection: {0}")]
+	/// A client connection couldn't be accepted
+	Accept(io::Error),
+	#[error("connection from {peer_addr} failed: {error}")]
+	/// A client connection failed, e.g. because the client sent an invalid request
+	/// or went away in the middle of a response
+	Connection {
+		/// The address of the client
+		peer_addr: SocketAddr,
+		/// The error
+		error: hyper::Error,
+	},
+	#[error("handling {method} {uri} from {peer_addr} failed: {error}")]
+	/// The request handler returned an error, so the client connection is closed
+	Handler {
+		/// The address of the client
+		peer_addr: SocketAddr,
+		/// The method of the request
+		method: Method,
+		/// The URI of the request
+		uri: Uri,
+		/// The error returned by the request handler
+		error: Box<dyn std::error::Error + Send + Sync>,
+	},
+	#[error("PROXY protocol header from {peer_addr} failed: {error}")]
+	/// A trusted load balancer didn't start a connection with a valid PROXY protocol header
+	ProxyProtocol {
+		/// The address of the load balancer
+		peer_addr: SocketAddr,
+		/// The error
+		error: proxy_protocol::ProxyProtocolError,
+	},
+	#[cfg(feature = "http3")]
+	#[error("HTTP/3 connection from {peer_addr} failed: {error}")]
+	/// A connection to [`http3::run_http3_proxy`] or one of its requests failed
+	Http3 {
+		/// The address of the client
+		peer_addr: SocketAddr,
+		/// The error
+		error: Box<dyn std::error::Error + Send + Sync>,
+	},
+	#[cfg(feature = "socks")]
+	#[error("SOCKS5 connection from {peer_addr} failed: {error}")]
+	/// Serving a client of [`socks::run_socks5_proxy`] failed
+	Socks {
+		/// The address of the client
+		peer_addr: SocketAddr,
+		/// The error
+		error: socks::Socks5Error,
+	},
+	#[cfg(feature = "tls")]
+	#[error("TLS handshake with {peer_addr} failed: {error}")]
+	/// The TLS handshake with a client failed
+	Tls {
+		/// The address of the client
+		peer_addr: SocketAddr,
+		/// The error
+		error: io::Error,
+	},
+}
+
+/// A callback that is called when a non-fatal error occurs
+pub type OnError = Arc<dyn Fn(&ServeError) + Send + Sync>;
+
+// Report a non-fatal error to the callback, and as an event with the `tracing` feature
+pub(crate) fn report(on_error: &Option<OnError>, error: &ServeError) {
+	#[cfg(feature = "tracing")]
+	match error {
+		ServeError::Accept(_) => tracing::error!(%error, "accepting failed"),
+		// Clients going away is nothing unusual
+		ServeError::Connection { .. } => tracing::debug!(%error, "connection failed"),
+		_ => tracing::warn!(%error, "serving failed"),
+	}
+	if let Some(on_error) = on_error {
+		on_error(error);
+	}
+}
+
+// Accept errors that only affect one connection, after which accepting can continue right away
+pub(crate) fn is_connection_error(e: &io::Error) -> bool {
+	matches!(
+		e.kind(),
+		io::ErrorKind::ConnectionRefused
+			| io::ErrorKind::ConnectionAborted
+			| io::ErrorKind::ConnectionReset
+	)
+}
+
+/// A proxy whose listeners are bound, but not served yet
+///
+/// Binding first lets tests and orchestrators learn the addresses of the proxy (like the port
+/// it got for port 0) before serving it:
+/// ```
+/// use hyper::{Body, Response};
+/// use proxylib::handlers::from_fn::handler_fn;
+/// use proxylib::{Proxy, ProxyConfig};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let handler = handler_fn(|_, _, _| async {
+///     Ok::<_, std::convert::Infallible>(Response::new(Body::from("hello")))
+/// });
+/// let proxy = Proxy::bind(ProxyConfig::new("127.0.0.1:0".parse().unwrap(), handler)).unwrap();
+/// let addr = proxy.local_addr();
+/// tokio::spawn(proxy.run());
+///
+/// let uri = format!("http://{}/", addr).parse().unwrap();
+/// let response = hyper::Client::new().get(uri).await.unwrap();
+/// assert_eq!(hyper::body::to_bytes(response).await.unwrap(), "hello");
+/// # }
+/// ```
+pub struct Proxy<T> {
+	// The configured address and the listener of every acceptor
+	incoming: Vec<(SocketAddr, AddrIncoming)>,
+	local_addrs: Vec<SocketAddr>,
+	hooks: conn::Hooks,
+	serving: Arc<Serving<T>>,
+}
+
+impl<T: RequestHandler + Send + Sync + 'static> Proxy<T> {
+	/// Bind the listeners of a proxy with the given configuration, which has to be done within
+	/// a Tokio runtime
+	///
+	/// All addresses (see [`additional_listen_on`](ProxyConfig::additional_listen_on)) are
+	/// bound, or none. With several addresses, the errors of the ones that couldn't be bound
+	/// are returned together as [`ProxyError::Listeners`].
+	pub fn bind(config: ProxyConfig<T>) -> Result<Self, ProxyError> {
+		let mut listen_on = vec![config.listen_on];
+		listen_on.extend(&config.additional_listen_on);
+		let listeners = listen_on.len();
+		let mut incoming = Vec::new();
+		let mut local_addrs = Vec::with_capacity(listeners);
+		let mut errors = Vec::new();
+		for listen_on in listen_on {
+			match bind(listen_on, &config) {
+				Ok(bound) => {
+					local_addrs.push(bound[0].local_addr());
+					incoming.extend(bound.into_iter().map(|inner| (listen_on, inner)));
+				}
+				Err(error) => errors.push(ListenerError { listen_on, error }),
+			}
+		}
+		if !errors.is_empty() {
+			return Err(ProxyError::from_listeners(errors, listeners));
+		}
+
+		let http2 = config.http2;
+		let mut http = Http::new();
+		http.http2_max_concurrent_streams(http2.max_concurrent_streams)
+			.http2_keep_alive_interval(http2.keep_alive_interval)
+			.http2_keep_alive_timeout(http2.keep_alive_timeout);
+		#[cfg(feature = "tls")]
+		let tls = config.tls.map(|tls| {
+			let handshake_timeout = tls.handshake_timeout;
+			let mut server_config = tls.server_config;
+			if !http2.enabled {
+				Arc::make_mut(&mut server_config)
+					.alpn_protocols
+					.retain(|protocol| protocol != b"h2");
+			}
+			(
+				tokio_rustls::TlsAcceptor::from(server_config),
+				handshake_timeout,
+			)
+		});
+		Ok(Self {
+			incoming,
+			local_addrs,
+			hooks: conn::Hooks {
+				on_connect: config.on_connect,
+				on_disconnect: config.on_disconnect,
+				#[cfg(feature = "tls")]
+				tls: tls.is_some(),
+				#[cfg(not(feature = "tls"))]
+				tls: false,
+			},
+			serving: Arc::new(Serving {
+				handler: config.request_handler,
+				client: config.client,
+				on_error: config.on_error,
+				stats: config.stats,
+				http,
+				http2,
+				proxy_protocol: config.proxy_protocol,
+				#[cfg(target_os = "linux")]
+				transparent: config.transparent,
+				#[cfg(feature = "tls")]
+				tls,
+			}),
+		})
+	}
+
+	/// Get the address the proxy listens on for [`listen_on`](ProxyConfig::listen_on)
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addrs[0]
+	}
+
+	/// Get the addresses the proxy listens on, in the order of the configured ones
+	pub fn local_addrs(&self) -> &[SocketAddr] {
+		&self.local_addrs
+	}
+
+	/// Serve the proxy
+	///
+	/// The proxy stops accepting connections when the future is dropped.
+	///
+	/// With the `tracing` feature, serving is instrumented with [`tracing`](https://docs.rs/tracing) spans:
+	/// - `listener` for every listener, with the `addr` it listens on
+	/// - `connection` for every client connection, with the `peer` and `local` addresses of
+	///   the socket and the `client` (which differs from the peer behind a load balancer
+	///   sending the PROXY protocol)
+	/// - `request` for every request, with its `method`, `uri` and `version`, and the
+	///   `status` of the response and the `upstream` it was forwarded to (see
+	///   [`Upstream`](handlers::redirect::Upstream)) once it arrived
+	///
+	/// The errors reported to [`on_error`](ProxyConfig::on_error) are events too, and the
+	/// handlers emit debug events, e.g. when forwarding requests or filtering them out.
+	pub async fn run(self) -> Result<(), ProxyError> {
+		let Self {
+			incoming,
+			local_addrs,
+			hooks,
+			serving,
+		} = self;
+
+		// Every acceptor runs in its own task, so they can accept in parallel
+		let mut accepting = AcceptTasks(
+			incoming
+				.into_iter()
+				.map(|(listen_on, inner)| {
+					let local_addr = inner.local_addr();
+					let incoming = conn::TrackedIncoming {
+						inner,
+						hooks: hooks.clone(),
+					};
+					let accept = serving.clone().accept(local_addr, incoming);
+					#[cfg(feature = "tracing")]
+					let accept = tracing::Instrument::instrument(
+						accept,
+						tracing::info_span!("listener", addr = %local_addr),
+					);
+					tokio::spawn(accept.map(move |result| {
+						result.map_err(|error| ListenerError { listen_on, error })
+					}))
+				})
+				.collect(),
+		);
+		let mut errors = Vec::new();
+		for result in join_all(accepting.0.iter_mut()).await {
+			match result {
+				Ok(Ok(())) => {}
+				Ok(Err(error)) => errors.push(error),
+				Err(error) => std::panic::resume_unwind(error.into_panic()),
+			}
+		}
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(ProxyError::from_listeners(errors, local_addrs.len()))
+		}
+	}
 }
 
 /// Run a proxy with the given configuration
-pub async fn run_proxy<T: RequestHandler + Sync + 'static>(
+///
+/// This binds the proxy with [`Proxy::bind`] and serves it with [`Proxy::run`].
+pub async fn run_proxy<T: RequestHandler + Send + Sync + 'static>(
 	config: ProxyConfig<T>,
 ) -> Result<(), ProxyError> {
-	let listener = TcpListener::bind(config.listen_on).map_err(ProxyError::BindListener)?;
-	let server_builder = Server::from_tcp(listener).map_err(ProxyError::StartServer)?;
+	Proxy::bind(config)?.run().await
+}
 
-	let client: &'static Client<HttpConnector> = Box::leak(Box::new(Client::new()));
+// The tasks of the acceptors, which stop when the future of the proxy is dropped
+struct AcceptTasks(Vec<JoinHandle<Result<(), ListenerError>>>);
 
-	let make_service = make_service_fn(move |conn: &AddrStream| {
-		let addr = conn.remote_addr();
+impl Drop for AcceptTasks {
+	fn drop(&mut self) {
+		for task in &self.0 {
+			task.abort();
+		}
+	}
+}
 
-		let handler = config.request_handler;
+// Bind the listeners of one address of a proxy, one for each acceptor
+fn bind<T: RequestHandler>(
+	listen_on: SocketAddr,
+	config: &ProxyConfig<T>,
+) -> Result<Vec<AddrIncoming>, ProxyError> {
+	#[cfg(target_os = "linux")]
+	let acceptors = config.acceptors.max(1);
+	#[cfg(not(target_os = "linux"))]
+	let acceptors = 1;
 
-		let handle = move |req: Request<Body>| handler.handle(addr, req, client);
+	let mut bound = Vec::<AddrIncoming>::with_capacity(acceptors);
+	for _ in 0..acceptors {
+		// With port 0, the further listeners get the port of the first one
+		let addr = bound.first().map_or(listen_on, AddrIncoming::local_addr);
+		let listener = bind_listener(addr, config, acceptors).map_err(ProxyError::BindListener)?;
+		let mut addr_incoming =
+			AddrIncoming::from_listener(listener).map_err(ProxyError::StartServer)?;
+		// Accept errors are reported instead
+		addr_incoming.set_sleep_on_errors(false);
+		bound.push(addr_incoming);
+	}
+	Ok(bound)
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn bind_listener<T: RequestHandler>(
+	addr: SocketAddr,
+	config: &ProxyConfig<T>,
+	acceptors: usize,
+) -> io::Result<TcpListener> {
+	let socket = match addr {
+		SocketAddr::V4(_) => TcpSocket::new_v4()?,
+		SocketAddr::V6(_) => TcpSocket::new_v6()?,
+	};
+	socket.set_reuseaddr(config.listener.reuse_address)?;
+	// The acceptors share the address, with the kernel spreading the connections between them
+	#[cfg(target_os = "linux")]
+	if acceptors > 1 {
+		socket.set_reuseport(true)?;
+	}
+	#[cfg(target_os = "linux")]
+	if config.transparent == Some(transparent::TransparentMode::Tproxy) {
+		transparent::set_transparent(&socket)?;
+	}
+	socket.bind(addr)?;
+	socket.listen(config.listener.backlog)
+}
+
+// What the connections of all listeners of a proxy are served with
+struct Serving<T> {
+	handler: Arc<T>,
+	client: Client<connect::Connector>,
+	on_error: Option<OnError>,
+	stats: Option<Arc<metrics::ProxyStats>>,
+	http: Http,
+	http2: Http2Config,
+	proxy_protocol: Option<Arc<proxy_protocol::ProxyProtocolConfig>>,
+	#[cfg(target_os = "linux")]
+	transparent: Option<transparent::TransparentMode>,
+	#[cfg(feature = "tls")]
+	tls: Option<(tokio_rustls::TlsAcceptor, Duration)>,
+}
+
+impl<T: RequestHandler + Send + Sync + 'static> Serving<T> {
+	// Accept the connections of one listener
+	#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+	async fn accept(
+		self: Arc<Self>,
+		local_addr: SocketAddr,
+		mut incoming: conn::TrackedIncoming,
+	) -> Result<(), ProxyError> {
+		loop {
+			let stream = match poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await {
+				Some(Ok(stream)) => stream,
+				Some(Err(e)) => {
+					// Errors like running out of file descriptors need some time to resolve
+					let pause = !is_connection_error(&e);
+					report(&self.on_error, &ServeError::Accept(e));
+					if pause {
+						tokio::time::sleep(Duration::from_secs(1)).await;
+					}
+					continue;
+				}
+				None => return Ok(()),
+			};
+			let socket = proxy_protocol::ProxiedAddrs {
+				source: stream.remote_addr(),
+				destination: stream.local_addr(),
+			};
+			#[cfg(target_os = "linux")]
+			let original_destination = self.transparent.and_then(|mode| {
+				transparent::original_destination(mode, &stream, socket.destination, local_addr)
+			});
+			#[cfg(not(target_os = "linux"))]
+			let original_destination = None;
+			let connection = self
+				.clone()
+				.connection(stream, socket, original_destination);
+			#[cfg(feature = "tracing")]
+			let connection = tracing::Instrument::instrument(
+				connection,
+				tracing::info_span!(
+					"connection",
+					peer = %socket.source,
+					local = %socket.destination,
+					client = tracing::field::Empty,
+				),
+			);
+			tokio::spawn(connection);
+		}
+	}
+
+	// Serve one client connection
+	async fn connection(
+		self: Arc<Self>,
+		mut stream: conn::TrackedStream,
+		socket: proxy_protocol::ProxiedAddrs,
+		original_destination: Option<transparent::OriginalDestination>,
+	) {
+		let handler = self.handler.clone();
+		let client = self.client.clone();
+		let on_error = self.on_error.clone();
+		let stats = self.stats.clone();
+		let _open = stats.as_ref().map(metrics::ProxyStats::open_connection);
+		let mut http = self.http.clone();
+
+		// The header comes before anything else, including the TLS handshake
+		let addrs = match &self.proxy_protocol {
+			Some(proxy_protocol) => {
+				match proxy_protocol.connection_addrs(&mut stream, socket).await {
+					Ok(addrs) => addrs,
+					Err(error) => {
+						let error = ServeError::ProxyProtocol {
+							peer_addr: socket.source,
+							error,
+						};
+						return report(&on_error, &error);
+					}
+				}
+			}
+			None => socket,
+		};
+		// Behind a load balancer, the client is the one from the PROXY protocol header
+		#[cfg(feature = "tracing")]
+		tracing::Span::current().record("client", tracing::field::display(addrs.source));
+		let context = ConnectionContext {
+			addrs,
+			original_destination,
+			tls: None,
+		};
+		#[cfg(feature = "tls")]
+		if let Some((tls, handshake_timeout)) = &self.tls {
+			let handshake = tokio::time::timeout(*handshake_timeout, tls.accept(stream));
+			let handshake = handshake.await.unwrap_or_else(|_| {
+				Err(io::Error::new(
+					io::ErrorKind::TimedOut,
+					"the handshake timed out",
+				))
+			});
+			match handshake {
+				Ok(mut stream) => {
+					let (tracked, session) = stream.get_mut();
+					let info = tls::tls_info(session);
+					tracked.handshaken(info.clone());
+					let context = ConnectionContext {
+						tls: Some(info),
+						..context
+					};
+					if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+						http.http2_only(true);
+					} else {
+						http.http1_only(true);
+					}
+					serve(http, stream, context, handler, client, on_error, stats).await
+				}
+				Err(error) => {
+					let error = ServeError::Tls {
+						peer_addr: addrs.source,
+						error,
+					};
+					report(&on_error, &error);
+				}
+			}
+			return;
+		}
+		// Without h2c, hyper would still serve HTTP/2 to clients that start with its preface
+		if !self.http2.h2c {
+			http.http1_only(true);
+		}
+		serve(http, stream, context, handler, client, on_error, stats).await
+	}
+}
+
+// What the requests of a client connection get in their extensions
+#[derive(Clone)]
+struct ConnectionContext {
+	addrs: proxy_protocol::ProxiedAddrs,
+	original_destination: Option<transparent::OriginalDestination>,
+	tls: Option<conn::TlsInfo>,
+}
 
-		async move { Ok::<_, Infallible>(service_fn(handle)) }
-	});
+// Serve the requests of one client connection
+async fn serve<T, I>(
+	http: Http,
+	stream: I,
+	context: ConnectionContext,
+	handler: Arc<T>,
+	client: Client<connect::Connector>,
+	on_error: Option<OnError>,
+	stats: Option<Arc<metrics::ProxyStats>>,
+) where
+	T: RequestHandler + Send + Sync + 'static,
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let addr = context.addrs.source;
+	let on_handler_error = on_error.clone();
+	let handle = move |mut req: Request<Body>| {
+		req.extensions_mut().insert(context.addrs);
+		if let Some(original_destination) = context.original_destination {
+			req.extensions_mut().insert(original_destination);
+		}
+		if let Some(tls) = &context.tls {
+			req.extensions_mut().insert(tls.clone());
+		}
+		let request_line = (req.method().clone(), req.uri().clone());
+		let in_flight = stats.as_ref().map(metrics::ProxyStats::start_request);
+		#[cfg(feature = "tracing")]
+		let span = tracing::info_span!(
+			"request",
+			method = %req.method(),
+			uri = %req.uri(),
+			version = ?req.version(),
+			status = tracing::field::Empty,
+			upstream = tracing::field::Empty,
+		);
+		#[cfg(feature = "tracing")]
+		let fut = span.in_scope(|| handler.handle(addr, req, &client));
+		#[cfg(not(feature = "tracing"))]
+		let fut = handler.handle(addr, req, &client);
+		let on_error = on_handler_error.clone();
 
-	let server = server_builder.serve(make_service);
+		let fut = async move {
+			let result = fut.await;
+			#[cfg(feature = "tracing")]
+			if let Ok(response) = &result {
+				let span = tracing::Span::current();
+				span.record("status", response.status().as_u16());
+				if let Some(handlers::redirect::Upstream(upstream)) = response.extensions().get() {
+					span.record("upstream", tracing::field::display(upstream));
+				}
+				tracing::debug!("responded");
+			}
+			// The request is in flight until its response body has been sent
+			let result = match (result, in_flight) {
+				(Ok(response), Some(in_flight)) => {
+					let (parts, body) = response.into_parts();
+					let body = body::inspect_len(body, move |_| {
+						let _ = &in_flight;
+					});
+					Ok(Response::from_parts(parts, body))
+				}
+				(result, _) => result,
+			};
+			result.map_err(|error| {
+				let (method, uri) = request_line;
+				let error = ServeError::Handler {
+					peer_addr: addr,
+					method,
+					uri,
+					error: Box::new(error),
+				};
+				report(&on_error, &error);
+				error
+			})
+		};
+		#[cfg(feature = "tracing")]
+		let fut = tracing::Instrument::instrument(fut, span);
+		fut
+	};
 
-	// This future completes once the server quits
-	server.await.map_err(ProxyError::Serve)
+	let connection = http
+		.serve_connection(stream, service_fn(handle))
+		.with_upgrades();
+	if let Err(error) = connection.await {
+		// Handler errors have been reported already
+		if !error.is_user() {
+			let error = ServeError::Connection {
+				peer_addr: addr,
+				error,
+			};
+			report(&on_error, &error);
+		}
+	}
 }
@@ -0,0 +1,440 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One entry of a log stream, like an access log line
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogRecord {
+	/// When the record was created
+	pub time: SystemTime,
+	/// The fields of the record, in order
+	pub fields: Vec<(String, String)>,
+}
+
+impl LogRecord {
+	/// Create a record without fields
+	pub fn new() -> Self {
+		Self {
+			time: SystemTime::now(),
+			fields: Vec::new(),
+		}
+	}
+
+	/// Add a field
+	pub fn with(mut self, name: impl Into<String>, value: impl ToString) -> Self {
+		self.fields.push((name.into(), value.to_string()));
+		self
+	}
+
+	/// Format the record as a JSON object on one line, with the time in the `time` field
+	pub fn to_json(&self) -> String {
+		let mut json = format!("{{\"time\":\"{}\"", rfc3339(self.time));
+		for (name, value) in &self.fields {
+			json.push(',');
+			push_json_string(&mut json, name);
+			json.push(':');
+			push_json_string(&mut json, value);
+		}
+		json.push('}');
+		json
+	}
+
+	/// Format the record as `key=value` pairs on one line, quoting values where needed
+	pub fn to_text(&self) -> String {
+		let mut text = format!("time={}", rfc3339(self.time));
+		for (name, value) in &self.fields {
+			let _ = write!(text, " {}=", name);
+			if value.is_empty()
+				|| value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+			{
+				push_json_string(&mut text, value);
+			} else {
+				text.push_str(value);
+			}
+		}
+		text
+	}
+
+	/// Format the record in the Common Log Format of web servers, like
+	/// `192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] "GET /a.gif HTTP/1.1" 200 2326`
+	///
+	/// The line is made of the fields of access log records (see
+	/// [`AccessLog`](crate::handlers::access_log::AccessLog)): `client`, `user`, `method`,
+	/// `path`, `version`, `status` and `bytes_out`. Missing fields and other records are
+	/// written as `-`.
+	pub fn to_common(&self) -> String {
+		let field = |name: &str| {
+			self.field(name)
+				.filter(|value| !value.is_empty())
+				.unwrap_or("-")
+		};
+		let bytes = match field("bytes_out") {
+			"0" => "-",
+			bytes => bytes,
+		};
+		format!(
+			"{} - {} [{}] \"{} {} {}\" {} {}",
+			field("client"),
+			field("user"),
+			clf_time(self.time),
+			field("method"),
+			field("path").replace('"', "%22"),
+			field("version"),
+			field("status"),
+			bytes
+		)
+	}
+
+	/// Get the value of the first field with the name
+	pub fn field(&self, name: &str) -> Option<&str> {
+		self.fields
+			.iter()
+			.find(|(n, _)| n == name)
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+impl Default for LogRecord {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+pub(crate) fn push_json_string(out: &mut String, s: &str) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => {
+				let _ = write!(out, "\\u{:04x}", c as u32);
+			}
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+// The UTC date (year, month, day) and the seconds into the day of a time
+fn civil(time: SystemTime) -> (i64, i64, i64, u64) {
+	let secs = time
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+	// Howard Hinnant's civil_from_days
+	let z = days as i64 + 719_468;
+	let era = z.div_euclid(146_097);
+	let doe = z.rem_euclid(146_097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + i64::from(month <= 2);
+	(year, month, day, secs_of_day)
+}
+
+// Format a time as an RFC 3339 timestamp in UTC with millisecond precision
+fn rfc3339(time: SystemTime) -> String {
+	let (year, month, day, secs_of_day) = civil(time);
+	let millis = time
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.subsec_millis();
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		year,
+		month,
+		day,
+		secs_of_day / 3600,
+		secs_of_day / 60 % 60,
+		secs_of_day % 60,
+		millis
+	)
+}
+
+// Format a time like `10/Oct/2000:13:55:36 +0000`, as in the Common Log Format
+fn clf_time(time: SystemTime) -> String {
+	const MONTHS: [&str; 12] = [
+		"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+	];
+	let (year, month, day, secs_of_day) = civil(time);
+	format!(
+		"{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+		day,
+		MONTHS[month as usize - 1],
+		year,
+		secs_of_day / 3600,
+		secs_of_day / 60 % 60,
+		secs_of_day % 60
+	)
+}
+
+/// How a [`LogSink`] formats records
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum LogFormat {
+	#[default]
+	/// One JSON object per line, see [`LogRecord::to_json`]
+	Json,
+	/// `key=value` pairs, see [`LogRecord::to_text`]
+	Text,
+	/// The Common Log Format of web servers, see [`LogRecord::to_common`]
+	Common,
+}
+
+impl LogFormat {
+	/// Format the record
+	pub fn format(self, record: &LogRecord) -> String {
+		match self {
+			Self::Json => record.to_json(),
+			Self::Text => record.to_text(),
+			Self::Common => record.to_common(),
+		}
+	}
+}
+
+/// Where the records of a log stream (e.g. the access log or the audit log) are written to
+///
+/// Every log stream has its own sink, so for example the access log can go to stdout
+/// while the audit log goes to a rotating file.
+/// Sinks write synchronously, and errors are ignored so logging never fails a request.
+pub trait LogSink {
+	/// Write a record
+	fn log(&self, record: &LogRecord);
+}
+
+/// Write every record to all of the sinks
+impl LogSink for Vec<Box<dyn LogSink + Send + Sync>> {
+	fn log(&self, record: &LogRecord) {
+		for sink in self {
+			sink.log(record);
+		}
+	}
+}
+
+/// Send every record to a channel, e.g. to process them in a task
+///
+/// Records are dropped while the channel is full (or closed), so logging never waits.
+impl LogSink for tokio::sync::mpsc::Sender<LogRecord> {
+	fn log(&self, record: &LogRecord) {
+		let _ = self.try_send(record.clone());
+	}
+}
+
+/// Send every record to a channel, e.g. to process them in a task
+impl LogSink for tokio::sync::mpsc::UnboundedSender<LogRecord> {
+	fn log(&self, record: &LogRecord) {
+		let _ = self.send(record.clone());
+	}
+}
+
+/// A [`LogSink`] writing to stdout, one record per line
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stdout {
+	/// The format of the records
+	pub format: LogFormat,
+}
+
+impl LogSink for Stdout {
+	fn log(&self, record: &LogRecord) {
+		let line = self.format.format(record);
+		let _ = writeln!(io::stdout().lock(), "{}", line);
+	}
+}
+
+struct OpenFile {
+	file: LineWriter<File>,
+	size: u64,
+	opened: SystemTime,
+}
+
+/// A [`LogSink`] writing to a file that is rotated once it becomes too large or too old
+///
+/// On rotation, `access.log` is renamed to `access.log.1`, `access.log.1` to `access.log.2`
+/// and so on, and the oldest file beyond [`keep`](Self::keep) is deleted.
+pub struct RotatingFile {
+	/// The path of the current file
+	pub path: PathBuf,
+	/// The format of the records
+	pub format: LogFormat,
+	/// The size after which the file is rotated
+	pub max_size: Option<u64>,
+	/// The age after which the file is rotated
+	pub max_age: Option<Duration>,
+	/// The number of rotated files that are kept
+	pub keep: usize,
+	file: Mutex<Option<OpenFile>>,
+}
+
+impl RotatingFile {
+	/// Create a sink that rotates the file daily or at 100 MiB and keeps 7 rotated files
+	pub fn new(path: impl Into<PathBuf>) -> Self {
+		Self {
+			path: path.into(),
+			format: LogFormat::default(),
+			max_size: Some(100 << 20),
+			max_age: Some(Duration::from_secs(24 * 60 * 60)),
+			keep: 7,
+			file: Mutex::new(None),
+		}
+	}
+
+	fn rotated_path(&self, n: usize) -> PathBuf {
+		let mut path = self.path.clone().into_os_string();
+		path.push(format!(".{}", n));
+		path.into()
+	}
+
+	fn open(&self) -> io::Result<OpenFile> {
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)?;
+		let metadata = file.metadata()?;
+		Ok(OpenFile {
+			size: metadata.len(),
+			opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+			file: LineWriter::new(file),
+		})
+	}
+
+	/// Rotate the file now
+	pub fn rotate(&self) -> io::Result<()> {
+		let mut file = self.file.lock().unwrap();
+		self.rotate_locked(&mut file)
+	}
+
+	fn rotate_locked(&self, file: &mut Option<OpenFile>) -> io::Result<()> {
+		*file = None;
+		if self.keep == 0 {
+			return fs::remove_file(&self.path).or_else(ignore_not_found);
+		}
+		fs::remove_file(self.rotated_path(self.keep)).or_else(ignore_not_found)?;
+		for n in (1..self.keep).rev() {
+			fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).or_else(ignore_not_found)?;
+		}
+		fs::rename(&self.path, self.rotated_path(1)).or_else(ignore_not_found)
+	}
+
+	fn write(&self, line: &str) -> io::Result<()> {
+		let mut file = self.file.lock().unwrap();
+		let now = SystemTime::now();
+		let needs_rotation = file.as_ref().is_some_and(|f| {
+			self.max_size
+				.is_some_and(|max| f.size + line.len() as u64 + 1 > max && f.size > 0)
+				|| self
+					.max_age
+					.is_some_and(|max| now.duration_since(f.opened).unwrap_or_default() >= max)
+		});
+		if needs_rotation {
+			self.rotate_locked(&mut file)?;
+		}
+
+		let open = match &mut *file {
+			Some(open) => open,
+			None => file.insert(self.open()?),
+		};
+		writeln!(open.file, "{}", line)?;
+		open.size += line.len() as u64 + 1;
+		Ok(())
+	}
+}
+
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+	match e.kind() {
+		io::ErrorKind::NotFound => Ok(()),
+		_ => Err(e),
+	}
+}
+
+impl LogSink for RotatingFile {
+	fn log(&self, record: &LogRecord) {
+		let _ = self.write(&self.format.format(record));
+	}
+}
+
+enum SyslogTarget {
+	Udp(UdpSocket, SocketAddr),
+	#[cfg(unix)]
+	Unix(std::os::unix::net::UnixDatagram),
+}
+
+/// A [`LogSink`] sending records to syslog in the format of RFC 5424
+pub struct Syslog {
+	/// The name of the application in the messages
+	pub app_name: String,
+	/// The facility of the messages, e.g. 16 for `local0`
+	pub facility: u8,
+	/// The format of the records in the messages
+	pub format: LogFormat,
+	hostname: String,
+	target: SyslogTarget,
+}
+
+impl Syslog {
+	/// Send the records to a syslog server over UDP
+	pub fn udp(server: SocketAddr, app_name: impl Into<String>) -> io::Result<Self> {
+		let bind: SocketAddr = if server.is_ipv4() {
+			([0, 0, 0, 0], 0).into()
+		} else {
+			([0u16; 8], 0).into()
+		};
+		let socket = UdpSocket::bind(bind)?;
+		Ok(Self::with_target(
+			SyslogTarget::Udp(socket, server),
+			app_name,
+		))
+	}
+
+	/// Send the records to the local syslog daemon via `/dev/log`
+	#[cfg(unix)]
+	pub fn local(app_name: impl Into<String>) -> io::Result<Self> {
+		let socket = std::os::unix::net::UnixDatagram::unbound()?;
+		socket.connect("/dev/log")?;
+		Ok(Self::with_target(SyslogTarget::Unix(socket), app_name))
+	}
+
+	fn with_target(target: SyslogTarget, app_name: impl Into<String>) -> Self {
+		let hostname = fs::read_to_string("/etc/hostname")
+			.ok()
+			.map(|h| h.trim().to_string())
+			.filter(|h| !h.is_empty())
+			.unwrap_or_else(|| "-".to_string());
+		Self {
+			app_name: app_name.into(),
+			facility: 16,
+			format: LogFormat::default(),
+			hostname,
+			target,
+		}
+	}
+}
+
+impl LogSink for Syslog {
+	fn log(&self, record: &LogRecord) {
+		// Severity 6 is "informational"
+		let message = format!(
+			"<{}>1 {} {} {} {} - - {}",
+			u32::from(self.facility) * 8 + 6,
+			rfc3339(record.time),
+			self.hostname,
+			self.app_name,
+			std::process::id(),
+			self.format.format(record)
+		);
+		let _ = match &self.target {
+			SyslogTarget::Udp(socket, server) => socket.send_to(message.as_bytes(), server),
+			#[cfg(unix)]
+			SyslogTarget::Unix(socket) => socket.send(message.as_bytes()),
+		};
+	}
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A family of counters that share a name and are distinguished by label values,
+/// e.g. `proxylib_filter_requests_total{filter="admin",outcome="blocked"}`
+#[derive(Debug)]
+pub struct CounterFamily {
+	/// The name of the counters
+	pub name: String,
+	/// A description of what is counted
+	pub help: String,
+	/// The names of the labels
+	pub label_names: Vec<String>,
+	values: Mutex<HashMap<Vec<String>, u64>>,
+}
+
+impl CounterFamily {
+	/// Create a family without any counters
+	pub fn new(name: impl Into<String>, help: impl Into<String>, label_names: &[&str]) -> Self {
+		Self {
+			name: name.into(),
+			help: help.into(),
+			label_names: owned(label_names),
+			values: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Add to the counter with the given label values (in the order of the label names)
+	pub fn add(&self, labels: &[&str], n: u64) {
+		debug_assert_eq!(labels.len(), self.label_names.len());
+		let mut values = self.values.lock().unwrap();
+		*values.entry(owned(labels)).or_insert(0) += n;
+	}
+
+	/// Increment the counter with the given label values
+	pub fn inc(&self, labels: &[&str]) {
+		self.add(labels, 1);
+	}
+
+	/// Get the value of the counter with the given label values
+	pub fn get(&self, labels: &[&str]) -> u64 {
+		let values = self.values.lock().unwrap();
+		values.get(&owned(labels)).copied().unwrap_or(0)
+	}
+
+	/// Get the label values and values of all counters
+	pub fn snapshot(&self) -> Vec<(Vec<String>, u64)> {
+		let mut snapshot = self
+			.values
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(labels, &value)| (labels.clone(), value))
+			.collect::<Vec<_>>();
+		snapshot.sort();
+		snapshot
+	}
+}
+
+/// Live counts of the connections and requests of a proxy, see
+/// [`ProxyConfig::with_stats`](crate::ProxyConfig::with_stats)
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+	connections_open: AtomicU64,
+	connections_total: AtomicU64,
+	requests_in_flight: AtomicU64,
+	requests_total: AtomicU64,
+}
+
+impl ProxyStats {
+	/// Get the number of client connections that are open
+	pub fn connections_open(&self) -> u64 {
+		self.connections_open.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of client connections that have been accepted
+	pub fn connections_total(&self) -> u64 {
+		self.connections_total.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of requests whose response hasn't been sent completely yet
+	pub fn requests_in_flight(&self) -> u64 {
+		self.requests_in_flight.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of requests that have been received
+	pub fn requests_total(&self) -> u64 {
+		self.requests_total.load(Ordering::Relaxed)
+	}
+
+	// Count a connection, which is open until the guard is dropped
+	pub(crate) fn open_connection(self: &Arc<Self>) -> OpenGuard {
+		self.connections_total.fetch_add(1, Ordering::Relaxed);
+		OpenGuard::new(self, |stats| &stats.connections_open)
+	}
+
+	// Count a request, which is in flight until the guard is dropped
+	pub(crate) fn start_request(self: &Arc<Self>) -> OpenGuard {
+		self.requests_total.fetch_add(1, Ordering::Relaxed);
+		OpenGuard::new(self, |stats| &stats.requests_in_flight)
+	}
+}
+
+// Decrements a gauge of the stats when dropped
+pub(crate) struct OpenGuard {
+	stats: Arc<ProxyStats>,
+	gauge: fn(&ProxyStats) -> &AtomicU64,
+}
+
+impl OpenGuard {
+	fn new(stats: &Arc<ProxyStats>, gauge: fn(&ProxyStats) -> &AtomicU64) -> Self {
+		gauge(stats).fetch_add(1, Ordering::Relaxed);
+		Self {
+			stats: stats.clone(),
+			gauge,
+		}
+	}
+}
+
+impl Drop for OpenGuard {
+	fn drop(&mut self) {
+		(self.gauge)(&self.stats).fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+fn owned(labels: &[&str]) -> Vec<String> {
+	labels.iter().map(|l| l.to_string()).collect()
+}
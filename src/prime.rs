@@ -0,0 +1,133 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::stream::{self, StreamExt};
+use hyper::{Body, Client, Method, Request, Uri};
+
+use crate::connect::Connector;
+use crate::RequestHandler;
+
+/// How far a [`Primer`] has come, as reported by [`Primer::progress`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct PrimeProgress {
+	/// The number of requests of the current run
+	pub total: usize,
+	/// The number of requests that completed successfully
+	pub succeeded: usize,
+	/// The number of requests that failed
+	pub failed: usize,
+}
+
+impl PrimeProgress {
+	/// Return whether all requests have completed
+	pub fn is_done(&self) -> bool {
+		self.succeeded + self.failed >= self.total
+	}
+}
+
+/// The result of a [`Primer`] run
+#[derive(Debug, Clone, Default)]
+pub struct PrimeReport {
+	/// The number of requests that completed successfully
+	pub succeeded: usize,
+	/// The requests that failed, with the reason
+	pub failed: Vec<(Method, Uri, String)>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+	total: AtomicUsize,
+	succeeded: AtomicUsize,
+	failed: AtomicUsize,
+}
+
+/// Warms caches by sending a list of requests through a request handler ahead of traffic,
+/// e.g. before a product launch or after a failover
+///
+/// The requests go through the whole handler chain like any other request, and their response
+/// bodies are read completely and discarded. Requests fail if the handler returns an error,
+/// the response status isn't a success or redirection, or the body can't be read.
+///
+/// Operators can start runs and follow their progress through the admin API, see
+/// [`Admin::with_primer`](crate::handlers::admin::Admin::with_primer).
+#[derive(Debug)]
+pub struct Primer {
+	/// The maximum number of requests in flight at once
+	pub concurrency: usize,
+	/// The client address the requests appear to come from
+	pub from_addr: SocketAddr,
+	counts: Arc<Counts>,
+}
+
+impl Primer {
+	/// Create a [`Primer`] sending up to `concurrency` requests at once from `127.0.0.1`
+	pub fn new(concurrency: usize) -> Self {
+		Self {
+			concurrency: concurrency.max(1),
+			from_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+			counts: Arc::default(),
+		}
+	}
+
+	/// Get the progress of the current (or last) run
+	pub fn progress(&self) -> PrimeProgress {
+		PrimeProgress {
+			total: self.counts.total.load(Ordering::SeqCst),
+			succeeded: self.counts.succeeded.load(Ordering::SeqCst),
+			failed: self.counts.failed.load(Ordering::SeqCst),
+		}
+	}
+
+	/// Send the requests through the request handler
+	pub async fn run<H: RequestHandler>(
+		&self,
+		handler: &H,
+		requests: Vec<Request<Body>>,
+		client: &Client<Connector>,
+	) -> PrimeReport {
+		self.counts.total.store(requests.len(), Ordering::SeqCst);
+		self.counts.succeeded.store(0, Ordering::SeqCst);
+		self.counts.failed.store(0, Ordering::SeqCst);
+
+		let results = stream::iter(requests)
+			.map(|request| {
+				let method = request.method().clone();
+				let uri = request.uri().clone();
+				let fut = handler.handle(self.from_addr, request, client);
+				let counts = &self.counts;
+				async move {
+					let result = match fut.await {
+						Ok(response)
+							if !response.status().is_success()
+								&& !response.status().is_redirection() =>
+						{
+							Err(format!("status {}", response.status()))
+						}
+						Ok(response) => hyper::body::to_bytes(response.into_body())
+							.await
+							.map(|_| ())
+							.map_err(|e| e.to_string()),
+						Err(e) => Err(e.to_string()),
+					};
+					let count = match result {
+						Ok(()) => &counts.succeeded,
+						Err(_) => &counts.failed,
+					};
+					count.fetch_add(1, Ordering::SeqCst);
+					(method, uri, result)
+				}
+			})
+			.buffer_unordered(self.concurrency)
+			.collect::<Vec<_>>();
+
+		let mut report = PrimeReport::default();
+		for (method, uri, result) in results.await {
+			match result {
+				Ok(()) => report.succeeded += 1,
+				Err(reason) => report.failed.push((method, uri, reason)),
+			}
+		}
+		report
+	}
+}
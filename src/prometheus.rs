@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::connect::{ConnectionStats, UpstreamConnections};
+use crate::dns::{CachingResolver, Resolver};
+use crate::metrics::CounterFamily;
+
+/// The content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Something that can be exported in the Prometheus text format
+pub trait Metric {
+	/// Append the metric (with its `HELP` and `TYPE` lines) to `out`
+	fn encode(&self, out: &mut String);
+}
+
+/// A set of metrics that are exported together, e.g. by a
+/// [`ServeMetrics`](crate::handlers::metrics::ServeMetrics)
+///
+/// Clones of a registry share the metrics.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::filter::filter_counters;
+/// use proxylib::prometheus::Registry;
+///
+/// let registry = Registry::default();
+/// let counters = filter_counters();
+/// registry.register(counters.clone());
+/// counters.inc(&["admin", "blocked"]);
+/// assert!(registry
+///     .encode()
+///     .contains("proxylib_filter_requests_total{filter=\"admin\",outcome=\"blocked\"} 1\n"));
+/// ```
+#[derive(Clone, Default)]
+pub struct Registry {
+	metrics: Arc<Mutex<Vec<Arc<dyn Metric + Send + Sync>>>>,
+}
+
+impl Registry {
+	/// Add a metric, which is exported after the ones added before
+	pub fn register(&self, metric: Arc<dyn Metric + Send + Sync>) {
+		self.metrics.lock().unwrap().push(metric);
+	}
+
+	/// Export all metrics in the Prometheus text format
+	pub fn encode(&self) -> String {
+		let metrics = self.metrics.lock().unwrap().clone();
+		let mut out = String::new();
+		for metric in metrics {
+			metric.encode(&mut out);
+		}
+		out
+	}
+}
+
+/// A value that can go up and down, like the number of requests in flight
+#[derive(Debug)]
+pub struct Gauge {
+	/// The name of the gauge
+	pub name: String,
+	/// A description of what is measured
+	pub help: String,
+	value: AtomicI64,
+}
+
+impl Gauge {
+	/// Create a gauge with the value 0
+	pub fn new(name: impl Into<String>, help: impl Into<String>) -> Self {
+		Self {
+			name: name.into(),
+			help: help.into(),
+			value: AtomicI64::new(0),
+		}
+	}
+
+	/// Increment the value
+	pub fn inc(&self) {
+		self.value.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Decrement the value
+	pub fn dec(&self) {
+		self.value.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/// Set the value
+	pub fn set(&self, value: i64) {
+		self.value.store(value, Ordering::Relaxed);
+	}
+
+	/// Get the value
+	pub fn get(&self) -> i64 {
+		self.value.load(Ordering::Relaxed)
+	}
+}
+
+/// The default buckets of a [`HistogramFamily`], which suit latencies in seconds
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A family of histograms that share a name and buckets and are distinguished by label values,
+/// e.g. `proxylib_request_duration_seconds`
+#[derive(Debug)]
+pub struct HistogramFamily {
+	/// The name of the histograms
+	pub name: String,
+	/// A description of what is observed
+	pub help: String,
+	/// The names of the labels
+	pub label_names: Vec<String>,
+	/// The upper bounds of the buckets, in ascending order
+	pub buckets: Vec<f64>,
+	values: Mutex<HashMap<Vec<String>, Histogram>>,
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+	// The number of observations in each bucket (and above the last one), not cumulative
+	counts: Vec<u64>,
+	sum: f64,
+}
+
+impl HistogramFamily {
+	/// Create a family without any histograms
+	pub fn new(
+		name: impl Into<String>,
+		help: impl Into<String>,
+		label_names: &[&str],
+		buckets: &[f64],
+	) -> Self {
+		Self {
+			name: name.into(),
+			help: help.into(),
+			label_names: owned(label_names),
+			buckets: buckets.to_vec(),
+			values: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Add an observation to the histogram with the given label values (in the order of the
+	/// label names)
+	pub fn observe(&self, labels: &[&str], value: f64) {
+		debug_assert_eq!(labels.len(), self.label_names.len());
+		let bucket = self
+			.buckets
+			.iter()
+			.position(|&bound| value <= bound)
+			.unwrap_or(self.buckets.len());
+		let mut values = self.values.lock().unwrap();
+		let histogram = values.entry(owned(labels)).or_insert_with(|| Histogram {
+			counts: vec![0; self.buckets.len() + 1],
+			sum: 0.0,
+		});
+		histogram.counts[bucket] += 1;
+		histogram.sum += value;
+	}
+
+	/// Get the number of observations of the histogram with the given label values
+	pub fn count(&self, labels: &[&str]) -> u64 {
+		let values = self.values.lock().unwrap();
+		values
+			.get(&owned(labels))
+			.map_or(0, |histogram| histogram.counts.iter().sum())
+	}
+
+	/// Get the sum of the observations of the histogram with the given label values
+	pub fn sum(&self, labels: &[&str]) -> f64 {
+		let values = self.values.lock().unwrap();
+		values
+			.get(&owned(labels))
+			.map_or(0.0, |histogram| histogram.sum)
+	}
+}
+
+impl Metric for CounterFamily {
+	fn encode(&self, out: &mut String) {
+		header(out, &self.name, &self.help, "counter");
+		for (values, value) in self.snapshot() {
+			let labels = labels(&self.label_names, &values, None);
+			let _ = writeln!(out, "{}{} {}", self.name, labels, value);
+		}
+	}
+}
+
+impl Metric for Gauge {
+	fn encode(&self, out: &mut String) {
+		header(out, &self.name, &self.help, "gauge");
+		let _ = writeln!(out, "{} {}", self.name, self.get());
+	}
+}
+
+/// The gauges `proxylib_upstream_connections_open` and `proxylib_upstream_connections_idle`,
+/// labelled with the upstream, followed by the metrics of the DNS cache if the client has one
+impl Metric for ConnectionStats {
+	fn encode(&self, out: &mut String) {
+		let snapshot = self.snapshot();
+		let label_names = owned(&["upstream"]);
+		let mut gauge = |name: &str, help: &str, value: fn(&UpstreamConnections) -> usize| {
+			header(out, name, help, "gauge");
+			for upstream in &snapshot {
+				let labels = labels(
+					&label_names,
+					std::slice::from_ref(&upstream.authority),
+					None,
+				);
+				let _ = writeln!(out, "{}{} {}", name, labels, value(upstream));
+			}
+		};
+		gauge(
+			"proxylib_upstream_connections_open",
+			"The number of open connections to the upstream",
+			|upstream| upstream.open,
+		);
+		gauge(
+			"proxylib_upstream_connections_idle",
+			"The number of open connections to the upstream without recent traffic",
+			|upstream| upstream.idle,
+		);
+		if let Some(resolver) = &self.resolver {
+			resolver.encode(out);
+		}
+	}
+}
+
+/// The counters `proxylib_dns_cache_hits_total`, `proxylib_dns_lookups_total`,
+/// `proxylib_dns_lookup_failures_total`, `proxylib_dns_stale_hits_total` and
+/// `proxylib_dns_lookup_seconds_total`
+impl<R: Resolver> Metric for CachingResolver<R> {
+	fn encode(&self, out: &mut String) {
+		let stats = self.stats();
+		let mut counter = |name: &str, help: &str, value: &dyn fmt::Display| {
+			header(out, name, help, "counter");
+			let _ = writeln!(out, "{} {}", name, value);
+		};
+		counter(
+			"proxylib_dns_cache_hits_total",
+			"The number of DNS lookups answered from the cache",
+			&stats.cache_hits,
+		);
+		counter(
+			"proxylib_dns_lookups_total",
+			"The number of DNS lookups passed to the resolver",
+			&stats.lookups,
+		);
+		counter(
+			"proxylib_dns_lookup_failures_total",
+			"The number of DNS lookups where the resolver failed",
+			&stats.failures,
+		);
+		counter(
+			"proxylib_dns_stale_hits_total",
+			"The number of failed DNS lookups answered with an expired entry",
+			&stats.stale_hits,
+		);
+		counter(
+			"proxylib_dns_lookup_seconds_total",
+			"The total time spent waiting for the resolver",
+			&stats.total_lookup_time.as_secs_f64(),
+		);
+	}
+}
+
+impl Metric for HistogramFamily {
+	fn encode(&self, out: &mut String) {
+		header(out, &self.name, &self.help, "histogram");
+		let mut histograms = self
+			.values
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(labels, histogram)| (labels.clone(), histogram.clone()))
+			.collect::<Vec<_>>();
+		histograms.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+		for (values, histogram) in histograms {
+			let mut count = 0;
+			for (bound, bucket_count) in self.buckets.iter().zip(&histogram.counts) {
+				count += bucket_count;
+				let le = bound.to_string();
+				let labels = labels(&self.label_names, &values, Some(&le));
+				let _ = writeln!(out, "{}_bucket{} {}", self.name, labels, count);
+			}
+			count += histogram.counts[self.buckets.len()];
+			let labels_inf = labels(&self.label_names, &values, Some("+Inf"));
+			let _ = writeln!(out, "{}_bucket{} {}", self.name, labels_inf, count);
+			let labels = labels(&self.label_names, &values, None);
+			let _ = writeln!(out, "{}_sum{} {}", self.name, labels, histogram.sum);
+			let _ = writeln!(out, "{}_count{} {}", self.name, labels, count);
+		}
+	}
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+	let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Format label names and values like `{a="1",b="2"}`, with an `le` label for histogram buckets
+fn labels(names: &[String], values: &[String], le: Option<&str>) -> String {
+	let mut pairs = names
+		.iter()
+		.zip(values)
+		.map(|(name, value)| {
+			let value = value
+				.replace('\\', "\\\\")
+				.replace('"', "\\\"")
+				.replace('\n', "\\n");
+			format!("{}=\"{}\"", name, value)
+		})
+		.collect::<Vec<_>>();
+	if let Some(le) = le {
+		pairs.push(format!("le=\"{}\"", le));
+	}
+	if pairs.is_empty() {
+		String::new()
+	} else {
+		format!("{{{}}}", pairs.join(","))
+	}
+}
+
+fn owned(labels: &[&str]) -> Vec<String> {
+	labels.iter().map(|l| l.to_string()).collect()
+}
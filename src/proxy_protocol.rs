@@ -0,0 +1,310 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::service::Service;
+use hyper::Uri;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::connect::ConnectError;
+use crate::handlers::filter::IpNet;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// The longest version 1 header, including the line break
+const V1_MAX_LEN: usize = 107;
+
+/// The addresses of a client connection, as sent in PROXY protocol headers
+///
+/// [`run_proxy`](crate::run_proxy) puts them into the extensions of every request, with the
+/// addresses from the header of the load balancer if there was one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ProxiedAddrs {
+	/// The address of the client
+	pub source: SocketAddr,
+	/// The address the client connected to
+	pub destination: SocketAddr,
+}
+
+#[derive(Debug, Error)]
+/// An error while reading a PROXY protocol header
+pub enum ProxyProtocolError {
+	#[error("invalid PROXY protocol header")]
+	/// The connection didn't start with a valid header
+	Invalid,
+	#[error("timed out reading the PROXY protocol header")]
+	/// The header didn't arrive in time
+	TimedOut,
+	#[error("{0}")]
+	/// Reading from the connection failed
+	Io(#[from] io::Error),
+}
+
+/// How client connections pass on the addresses of their clients with the PROXY protocol, see
+/// [`ProxyConfig::with_proxy_protocol`](crate::ProxyConfig::with_proxy_protocol)
+///
+/// Load balancers that work on the TCP level (like HAProxy or AWS NLBs) can send the address of
+/// the client in a header at the start of the connection, which is then used as the address of
+/// the client (the `from_addr` of request handlers).
+///
+/// Only connections from the [`trusted`](Self::trusted) networks are expected to start with a
+/// header, and they must (except for the `LOCAL` command of version 2 or `UNKNOWN` of version
+/// 1, which load balancers use for health checks). Other clients are served with their own
+/// address, so they can't pretend to be someone else.
+///
+/// # Example
+/// ```
+/// use proxylib::handlers::Redirect;
+/// use proxylib::proxy_protocol::ProxyProtocolConfig;
+/// use proxylib::ProxyConfig;
+///
+/// let load_balancers = vec!["10.0.0.0/24".parse().unwrap()];
+/// let handler = Redirect::change_authority("app.internal:8080".parse().unwrap());
+/// let config = ProxyConfig::new("0.0.0.0:8080".parse().unwrap(), handler)
+///     .with_proxy_protocol(ProxyProtocolConfig::new(load_balancers));
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProxyProtocolConfig {
+	/// The networks of the load balancers that send the header
+	pub trusted: Vec<IpNet>,
+	/// How long reading the header may take
+	pub timeout: Duration,
+}
+
+impl ProxyProtocolConfig {
+	/// Create a config that expects a header from the given networks, within 5 seconds
+	pub fn new(trusted: Vec<IpNet>) -> Self {
+		Self {
+			trusted,
+			timeout: Duration::from_secs(5),
+		}
+	}
+
+	/// Return whether connections from the address are expected to start with a header
+	pub fn is_trusted(&self, ip: IpAddr) -> bool {
+		self.trusted.iter().any(|net| net.contains(ip))
+	}
+
+	/// Get the addresses of a connection with the addresses of its socket, reading its header if
+	/// the peer is trusted
+	pub async fn connection_addrs<S: AsyncRead + Unpin>(
+		&self,
+		stream: &mut S,
+		socket: ProxiedAddrs,
+	) -> Result<ProxiedAddrs, ProxyProtocolError> {
+		if !self.is_trusted(socket.source.ip()) {
+			return Ok(socket);
+		}
+		match tokio::time::timeout(self.timeout, read_header(stream)).await {
+			Ok(Ok(addrs)) => Ok(addrs.unwrap_or(socket)),
+			Ok(Err(e)) => Err(e),
+			Err(_) => Err(ProxyProtocolError::TimedOut),
+		}
+	}
+}
+
+/// Read a PROXY protocol header (version 1 or 2) from the start of a connection
+///
+/// Nothing after the header is read. Returns `None` for headers without addresses, like the
+/// ones of health checks or of connections that aren't over IP.
+pub async fn read_header<S: AsyncRead + Unpin>(
+	stream: &mut S,
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+	// Both versions are longer than this, so nothing after the header is read yet
+	let mut start = [0; 12];
+	stream.read_exact(&mut start).await?;
+	if start == V2_SIGNATURE {
+		read_v2(stream).await
+	} else if start.starts_with(b"PROXY ") {
+		read_v1(stream, &start).await
+	} else {
+		Err(ProxyProtocolError::Invalid)
+	}
+}
+
+// Read the rest of a version 1 header, which is a line like
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`
+async fn read_v1<S: AsyncRead + Unpin>(
+	stream: &mut S,
+	start: &[u8],
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+	let mut line = start.to_vec();
+	while !line.ends_with(b"\r\n") {
+		if line.len() >= V1_MAX_LEN {
+			return Err(ProxyProtocolError::Invalid);
+		}
+		line.push(stream.read_u8().await?);
+	}
+
+	let line =
+		std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| ProxyProtocolError::Invalid)?;
+	let mut fields = line.split(' ').skip(1);
+	let protocol = fields.next().ok_or(ProxyProtocolError::Invalid)?;
+	if protocol == "UNKNOWN" {
+		return Ok(None);
+	}
+	let mut next = || fields.next().ok_or(ProxyProtocolError::Invalid);
+	let (source, destination, source_port, destination_port) = (next()?, next()?, next()?, next()?);
+	let parse_ip = |ip: &str| -> Result<IpAddr, ProxyProtocolError> {
+		match protocol {
+			"TCP4" => ip.parse::<Ipv4Addr>().map(IpAddr::V4),
+			"TCP6" => ip.parse::<Ipv6Addr>().map(IpAddr::V6),
+			_ => return Err(ProxyProtocolError::Invalid),
+		}
+		.map_err(|_| ProxyProtocolError::Invalid)
+	};
+	let parse_port = |port: &str| port.parse::<u16>().map_err(|_| ProxyProtocolError::Invalid);
+	Ok(Some(ProxiedAddrs {
+		source: SocketAddr::new(parse_ip(source)?, parse_port(source_port)?),
+		destination: SocketAddr::new(parse_ip(destination)?, parse_port(destination_port)?),
+	}))
+}
+
+// Read the rest of a version 2 header, which is binary
+async fn read_v2<S: AsyncRead + Unpin>(
+	stream: &mut S,
+) -> Result<Option<ProxiedAddrs>, ProxyProtocolError> {
+	let mut head = [0; 4];
+	stream.read_exact(&mut head).await?;
+	let [version_command, family, len @ ..] = head;
+	let mut payload = vec![0; usize::from(u16::from_be_bytes(len))];
+	stream.read_exact(&mut payload).await?;
+
+	if version_command >> 4 != 2 {
+		return Err(ProxyProtocolError::Invalid);
+	}
+	match version_command & 0x0f {
+		// LOCAL, e.g. a health check of the load balancer itself
+		0 => return Ok(None),
+		// PROXY
+		1 => {}
+		_ => return Err(ProxyProtocolError::Invalid),
+	}
+
+	// The addresses come first and are followed by optional TLVs, which are ignored
+	let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+	match family >> 4 {
+		// IPv4
+		1 => {
+			if payload.len() < 12 {
+				return Err(ProxyProtocolError::Invalid);
+			}
+			let ip = |at: usize| {
+				IpAddr::V4(Ipv4Addr::new(
+					payload[at],
+					payload[at + 1],
+					payload[at + 2],
+					payload[at + 3],
+				))
+			};
+			Ok(Some(ProxiedAddrs {
+				source: SocketAddr::new(ip(0), port(8)),
+				destination: SocketAddr::new(ip(4), port(10)),
+			}))
+		}
+		// IPv6
+		2 => {
+			if payload.len() < 36 {
+				return Err(ProxyProtocolError::Invalid);
+			}
+			let ip = |at: usize| {
+				let mut octets = [0; 16];
+				octets.copy_from_slice(&payload[at..at + 16]);
+				IpAddr::V6(Ipv6Addr::from(octets))
+			};
+			Ok(Some(ProxiedAddrs {
+				source: SocketAddr::new(ip(0), port(32)),
+				destination: SocketAddr::new(ip(16), port(34)),
+			}))
+		}
+		// Unix sockets or unspecified
+		_ => Ok(None),
+	}
+}
+
+/// Encode a PROXY protocol header (version 2) with the addresses
+///
+/// If only one of the addresses is an IPv6 address, the other one is sent as an IPv4-mapped
+/// IPv6 address.
+pub fn encode_header(addrs: &ProxiedAddrs) -> Vec<u8> {
+	let mut header = V2_SIGNATURE.to_vec();
+	// Version 2, PROXY
+	header.push(0x21);
+	match (addrs.source.ip(), addrs.destination.ip()) {
+		(IpAddr::V4(source), IpAddr::V4(destination)) => {
+			// IPv4 over TCP
+			header.push(0x11);
+			header.extend_from_slice(&12u16.to_be_bytes());
+			header.extend_from_slice(&source.octets());
+			header.extend_from_slice(&destination.octets());
+		}
+		(source, destination) => {
+			let v6 = |ip: IpAddr| match ip {
+				IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+				IpAddr::V6(ip) => ip,
+			};
+			// IPv6 over TCP
+			header.push(0x21);
+			header.extend_from_slice(&36u16.to_be_bytes());
+			header.extend_from_slice(&v6(source).octets());
+			header.extend_from_slice(&v6(destination).octets());
+		}
+	}
+	header.extend_from_slice(&addrs.source.port().to_be_bytes());
+	header.extend_from_slice(&addrs.destination.port().to_be_bytes());
+	header
+}
+
+/// A connector adapter that starts every connection of another connector with a PROXY protocol
+/// header (version 2) with the given addresses
+///
+/// The header is about one client, so the connections must only be used for its requests, see
+/// [`SendProxyProtocol`](crate::handlers::send_proxy::SendProxyProtocol). With a
+/// [`ProxyChainConnector`](crate::chain::ProxyChainConnector) inside, the header is sent to the
+/// upstream through the tunnel, or to the parent proxy itself for requests that aren't tunneled.
+#[derive(Clone)]
+pub struct ProxyHeaderConnector<C> {
+	inner: C,
+	header: Arc<[u8]>,
+}
+
+impl<C> ProxyHeaderConnector<C> {
+	/// Send the addresses on the connections of the inner connector
+	pub fn new(inner: C, addrs: &ProxiedAddrs) -> Self {
+		Self {
+			inner,
+			header: encode_header(addrs).into(),
+		}
+	}
+}
+
+impl<C> Service<Uri> for ProxyHeaderConnector<C>
+where
+	C: Service<Uri> + Send,
+	C::Response: AsyncWrite + Unpin + Send + 'static,
+	C::Error: Into<ConnectError>,
+	C::Future: Send + 'static,
+{
+	type Response = C::Response;
+	type Error = ConnectError;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ConnectError>> + Send>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectError>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, uri: Uri) -> Self::Future {
+		let connecting = self.inner.call(uri);
+		let header = self.header.clone();
+
+		Box::pin(async move {
+			let mut stream = connecting.await.map_err(Into::into)?;
+			stream.write_all(&header).await?;
+			Ok(stream)
+		})
+	}
+}
@@ -0,0 +1,461 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{
+	NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache, WebPkiClientVerifier,
+};
+use rustls::{RootCertStore, ServerConfig};
+
+use crate::conn::TlsInfo;
+
+/// A key for encrypting session tickets
+///
+/// The name identifies the key in the tickets it encrypted, so tickets from other
+/// (e.g. previous) keys can be told apart.
+#[derive(Clone, Eq, PartialEq)]
+pub struct TicketKey {
+	/// The name of the key
+	pub name: [u8; 16],
+	/// The AES-256 key
+	pub secret: [u8; 32],
+}
+
+impl fmt::Debug for TicketKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TicketKey")
+			.field("name", &self.name)
+			.finish_non_exhaustive()
+	}
+}
+
+impl TicketKey {
+	/// Generate a random key
+	pub fn generate() -> io::Result<Self> {
+		let rng = SystemRandom::new();
+		let mut key = Self {
+			name: [0; 16],
+			secret: [0; 32],
+		};
+		rng.fill(&mut key.name)
+			.and_then(|()| rng.fill(&mut key.secret))
+			.map_err(|_| io::Error::other("failed to generate a ticket key"))?;
+		Ok(key)
+	}
+
+	/// Read a key from its 48 bytes: 16 bytes of name followed by 32 bytes of secret
+	pub fn from_bytes(bytes: &[u8; 48]) -> Self {
+		let mut key = Self {
+			name: [0; 16],
+			secret: [0; 32],
+		};
+		key.name.copy_from_slice(&bytes[..16]);
+		key.secret.copy_from_slice(&bytes[16..]);
+		key
+	}
+
+	fn aead(&self) -> LessSafeKey {
+		LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.secret).unwrap())
+	}
+}
+
+/// The keys for session tickets at one point in time
+#[derive(Debug, Clone)]
+pub struct TicketKeys {
+	/// The key new tickets are encrypted with
+	pub current: TicketKey,
+	/// Older keys whose tickets are still accepted
+	pub previous: Vec<TicketKey>,
+}
+
+/// The exchangable part of a [`Ticketer`] that provides the keys for session tickets
+///
+/// For resumption to work across multiple instances of the proxy, they all need to use the
+/// same keys, for example by reading them from shared storage with [`FileTicketKeys`].
+pub trait TicketKeyProvider {
+	/// Get the current keys
+	fn keys(&self) -> Arc<TicketKeys>;
+}
+
+/// A [`TicketKeyProvider`] with random keys that are replaced after an interval
+///
+/// Tickets are accepted for one more interval after their key has been replaced.
+/// As the keys are local to the process, resumption only works with the same instance.
+#[derive(Debug)]
+pub struct RotatingTicketKeys {
+	/// The time after which the current key is replaced
+	pub interval: Duration,
+	state: Mutex<(Arc<TicketKeys>, Instant)>,
+}
+
+impl RotatingTicketKeys {
+	/// Create a provider with a random key
+	pub fn new(interval: Duration) -> io::Result<Self> {
+		let keys = TicketKeys {
+			current: TicketKey::generate()?,
+			previous: Vec::new(),
+		};
+		Ok(Self {
+			interval,
+			state: Mutex::new((Arc::new(keys), Instant::now())),
+		})
+	}
+
+	/// Replace the current key now
+	pub fn rotate(&self) -> io::Result<()> {
+		let mut state = self.state.lock().unwrap();
+		Self::rotate_locked(&mut state)
+	}
+
+	fn rotate_locked(state: &mut (Arc<TicketKeys>, Instant)) -> io::Result<()> {
+		let keys = TicketKeys {
+			current: TicketKey::generate()?,
+			previous: vec![state.0.current.clone()],
+		};
+		*state = (Arc::new(keys), Instant::now());
+		Ok(())
+	}
+}
+
+impl TicketKeyProvider for RotatingTicketKeys {
+	fn keys(&self) -> Arc<TicketKeys> {
+		let mut state = self.state.lock().unwrap();
+		if state.1.elapsed() >= self.interval {
+			// Keep using the old key if no new one can be generated
+			let _ = Self::rotate_locked(&mut state);
+		}
+		state.0.clone()
+	}
+}
+
+/// A [`TicketKeyProvider`] reading the keys from a file, for sharing them between instances
+///
+/// The file consists of keys of 48 bytes each (see [`TicketKey::from_bytes`]), the first of
+/// which is the current one. To rotate the keys, a deployment tool writes a new file with a
+/// new key in front of the old ones, distributes it to all instances, and calls
+/// [`reload`](Self::reload) on each of them.
+pub struct FileTicketKeys {
+	/// The path of the file
+	pub path: PathBuf,
+	keys: ArcSwap<TicketKeys>,
+}
+
+impl FileTicketKeys {
+	/// Read the keys from a file
+	pub fn from_file(path: impl Into<PathBuf>) -> io::Result<Self> {
+		let path = path.into();
+		let keys = Self::read(&path)?;
+		Ok(Self {
+			path,
+			keys: ArcSwap::from_pointee(keys),
+		})
+	}
+
+	/// Read the file again, keeping the old keys if it can't be read
+	pub fn reload(&self) -> io::Result<()> {
+		self.keys.store(Arc::new(Self::read(&self.path)?));
+		Ok(())
+	}
+
+	fn read(path: &PathBuf) -> io::Result<TicketKeys> {
+		let bytes = fs::read(path)?;
+		if bytes.is_empty() || bytes.len() % 48 != 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"ticket key file must consist of one or more 48-byte keys",
+			));
+		}
+		let mut keys = bytes
+			.chunks_exact(48)
+			.map(|chunk| TicketKey::from_bytes(chunk.try_into().unwrap()));
+		Ok(TicketKeys {
+			current: keys.next().unwrap(),
+			previous: keys.collect(),
+		})
+	}
+}
+
+impl TicketKeyProvider for FileTicketKeys {
+	fn keys(&self) -> Arc<TicketKeys> {
+		self.keys.load_full()
+	}
+}
+
+/// Encrypts and decrypts session tickets with the keys of a [`TicketKeyProvider`]
+///
+/// Tickets are encrypted with AES-256-GCM and consist of the key name, the nonce
+/// and the ciphertext.
+pub struct Ticketer {
+	/// The provider of the keys
+	pub provider: Arc<dyn TicketKeyProvider + Send + Sync>,
+	/// The lifetime of tickets, as told to clients
+	pub lifetime: Duration,
+	rng: SystemRandom,
+}
+
+impl fmt::Debug for Ticketer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Ticketer")
+			.field("lifetime", &self.lifetime)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Ticketer {
+	/// Create a [`Ticketer`]
+	pub fn new(provider: Arc<dyn TicketKeyProvider + Send + Sync>, lifetime: Duration) -> Self {
+		Self {
+			provider,
+			lifetime,
+			rng: SystemRandom::new(),
+		}
+	}
+}
+
+impl ProducesTickets for Ticketer {
+	fn enabled(&self) -> bool {
+		true
+	}
+
+	fn lifetime(&self) -> u32 {
+		self.lifetime.as_secs().min(u32::MAX as u64) as u32
+	}
+
+	fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+		let keys = self.provider.keys();
+		let key = &keys.current;
+
+		let mut nonce = [0; NONCE_LEN];
+		self.rng.fill(&mut nonce).ok()?;
+
+		let mut ticket = Vec::with_capacity(16 + NONCE_LEN + plain.len() + 16);
+		ticket.extend_from_slice(&key.name);
+		ticket.extend_from_slice(&nonce);
+		let mut in_out = plain.to_vec();
+		key.aead()
+			.seal_in_place_append_tag(
+				Nonce::assume_unique_for_key(nonce),
+				Aad::from(&key.name),
+				&mut in_out,
+			)
+			.ok()?;
+		ticket.extend_from_slice(&in_out);
+		Some(ticket)
+	}
+
+	fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+		if cipher.len() < 16 + NONCE_LEN {
+			return None;
+		}
+		let (name, rest) = cipher.split_at(16);
+		let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+		let keys = self.provider.keys();
+		let key = std::iter::once(&keys.current)
+			.chain(&keys.previous)
+			.find(|key| key.name == name)?;
+		let mut in_out = ciphertext.to_vec();
+		let plain_len = key
+			.aead()
+			.open_in_place(
+				Nonce::try_assume_unique_for_key(nonce).ok()?,
+				Aad::from(name),
+				&mut in_out,
+			)
+			.ok()?
+			.len();
+		in_out.truncate(plain_len);
+		Some(in_out)
+	}
+}
+
+/// Options for resuming TLS sessions, applied to a [`ServerConfig`]
+///
+/// Sessions can be resumed either with the server-side session cache (which only works with
+/// the same instance) or with session tickets, which are encrypted by the server and kept by
+/// the client.
+#[derive(Clone)]
+pub struct SessionResumption {
+	/// The number of sessions kept in the session cache, or 0 to disable it
+	pub cache_size: usize,
+	/// The lifetime of session tickets
+	pub ticket_lifetime: Duration,
+	/// The provider of the keys for session tickets, or `None` to disable tickets
+	pub ticket_keys: Option<Arc<dyn TicketKeyProvider + Send + Sync>>,
+	/// The number of tickets sent to TLS 1.3 clients after the handshake
+	pub tls13_tickets: usize,
+}
+
+impl SessionResumption {
+	/// Create options with a cache of 256 sessions and tickets with local keys
+	/// that are rotated every 12 hours
+	pub fn new() -> io::Result<Self> {
+		let ticket_lifetime = Duration::from_secs(12 * 60 * 60);
+		Ok(Self {
+			cache_size: 256,
+			ticket_lifetime,
+			ticket_keys: Some(Arc::new(RotatingTicketKeys::new(ticket_lifetime)?)),
+			tls13_tickets: 2,
+		})
+	}
+
+	/// Use the given provider for the keys of session tickets, e.g. to share them across a fleet
+	pub fn with_ticket_keys(
+		mut self,
+		keys: impl TicketKeyProvider + Send + Sync + 'static,
+	) -> Self {
+		self.ticket_keys = Some(Arc::new(keys));
+		self
+	}
+
+	/// Apply the options to the config
+	pub fn apply(&self, config: &mut ServerConfig) {
+		config.session_storage = if self.cache_size == 0 {
+			Arc::new(NoServerSessionStorage {})
+		} else {
+			ServerSessionMemoryCache::new(self.cache_size)
+		};
+		if let Some(keys) = &self.ticket_keys {
+			config.ticketer = Arc::new(Ticketer::new(keys.clone(), self.ticket_lifetime));
+		}
+		config.send_tls13_tickets = self.tls13_tickets;
+	}
+}
+
+/// The TLS config of a proxy that serves HTTPS, see
+/// [`ProxyConfig::with_tls`](crate::ProxyConfig::with_tls)
+///
+/// TLS is terminated by the proxy, so request handlers get the decrypted requests.
+#[derive(Clone)]
+pub struct TlsProxyConfig {
+	/// The rustls config used for accepting connections
+	pub server_config: Arc<ServerConfig>,
+	/// How long the handshake with a client may take before the connection is closed
+	pub handshake_timeout: Duration,
+}
+
+impl TlsProxyConfig {
+	/// Create a config from a PEM certificate chain and a PEM private key
+	///
+	/// HTTP/2 (unless turned off in [`Http2Config`](crate::Http2Config)) and HTTP/1.1 are
+	/// offered via ALPN, sessions can be resumed with the defaults of [`SessionResumption`],
+	/// and handshakes have to complete within 10 seconds.
+	pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> io::Result<Self> {
+		let certs = CertificateDer::pem_slice_iter(cert_chain)
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(pem_error)?;
+		let key = PrivateKeyDer::from_pem_slice(private_key).map_err(pem_error)?;
+		Self::from_der(certs, key)
+	}
+
+	/// Create a config from a PEM certificate chain file and a PEM private key file
+	pub fn from_pem_files(
+		cert_chain: impl AsRef<Path>,
+		private_key: impl AsRef<Path>,
+	) -> io::Result<Self> {
+		Self::from_pem(&fs::read(cert_chain)?, &fs::read(private_key)?)
+	}
+
+	/// Create a config like [`from_pem`](Self::from_pem) that requires clients to authenticate
+	/// with a certificate issued by one of the PEM root certificates
+	///
+	/// The certificate chain of a client is part of the [`TlsInfo`] of its connection, e.g. for
+	/// resolving tenants with a [`CertificateKey`](crate::handlers::tenancy::CertificateKey).
+	pub fn from_pem_with_client_auth(
+		cert_chain: &[u8],
+		private_key: &[u8],
+		client_roots: &[u8],
+	) -> io::Result<Self> {
+		let certs = CertificateDer::pem_slice_iter(cert_chain)
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(pem_error)?;
+		let key = PrivateKeyDer::from_pem_slice(private_key).map_err(pem_error)?;
+		let mut roots = RootCertStore::empty();
+		for root in CertificateDer::pem_slice_iter(client_roots) {
+			roots
+				.add(root.map_err(pem_error)?)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		}
+		let verifier = WebPkiClientVerifier::builder_with_provider(
+			Arc::new(roots),
+			Arc::new(rustls::crypto::ring::default_provider()),
+		)
+		.build()
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		Self::with_verifier(certs, key, verifier)
+	}
+
+	/// Create a config from a DER certificate chain and a DER private key
+	pub fn from_der(
+		cert_chain: Vec<CertificateDer<'static>>,
+		private_key: PrivateKeyDer<'static>,
+	) -> io::Result<Self> {
+		Self::with_verifier(
+			cert_chain,
+			private_key,
+			WebPkiClientVerifier::no_client_auth(),
+		)
+	}
+
+	fn with_verifier(
+		cert_chain: Vec<CertificateDer<'static>>,
+		private_key: PrivateKeyDer<'static>,
+		verifier: Arc<dyn ClientCertVerifier>,
+	) -> io::Result<Self> {
+		let mut config =
+			ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+				.with_safe_default_protocol_versions()
+				.and_then(|builder| {
+					builder
+						.with_client_cert_verifier(verifier)
+						.with_single_cert(cert_chain, private_key)
+				})
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+		SessionResumption::new()?.apply(&mut config);
+		Ok(Self {
+			server_config: Arc::new(config),
+			handshake_timeout: Duration::from_secs(10),
+		})
+	}
+
+	/// Apply other session resumption options
+	pub fn with_resumption(mut self, resumption: &SessionResumption) -> Self {
+		resumption.apply(Arc::make_mut(&mut self.server_config));
+		self
+	}
+}
+
+fn pem_error(e: rustls::pki_types::pem::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Describe the TLS session of a client connection for the connection hooks
+pub(crate) fn tls_info(session: &rustls::ServerConnection) -> TlsInfo {
+	TlsInfo {
+		server_name: session.server_name().map(str::to_string),
+		alpn_protocol: session.alpn_protocol().map(<[u8]>::to_vec),
+		protocol_version: match session.protocol_version() {
+			Some(rustls::ProtocolVersion::TLSv1_2) => "TLSv1.2",
+			Some(rustls::ProtocolVersion::TLSv1_3) => "TLSv1.3",
+			Some(version) => version.as_str().unwrap_or("unknown"),
+			None => "unknown",
+		},
+		client_certificates: session
+			.peer_certificates()
+			.unwrap_or_default()
+			.iter()
+			.map(|cert| cert.to_vec())
+			.collect(),
+	}
+}
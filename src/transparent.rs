@@ -0,0 +1,73 @@
+#[cfg(target_os = "linux")]
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+
+/// How connections reach a transparent proxy, see
+/// [`ProxyConfig::with_transparent`](crate::ProxyConfig::with_transparent) (Linux only)
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TransparentMode {
+	/// The connections are redirected to the proxy with the `REDIRECT` (or `DNAT`) target of
+	/// iptables, and their original destination is looked up with `SO_ORIGINAL_DST`
+	Redirect,
+	/// The connections are intercepted with the `TPROXY` target of iptables, so their local
+	/// address is the original destination
+	///
+	/// The listener is made transparent with `IP_TRANSPARENT`, which needs the
+	/// `CAP_NET_ADMIN` capability and only works for IPv4.
+	Tproxy,
+}
+
+/// The address a client connected to before the connection was redirected to the proxy
+///
+/// Transparent proxies put it into the extensions of every request of redirected connections,
+/// see [`Transparent`](crate::handlers::transparent::Transparent). Connections made to the
+/// proxy itself don't have one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct OriginalDestination(pub SocketAddr);
+
+/// Get the original destination of a connection that was redirected with iptables, using
+/// `SO_ORIGINAL_DST`
+#[cfg(target_os = "linux")]
+pub fn original_dst<S: AsRawFd>(stream: &S) -> io::Result<SocketAddr> {
+	// SAFETY: the file descriptor stays open while the stream is borrowed
+	let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+	let socket = SockRef::from(&fd);
+	let addr = if socket.local_addr()?.is_ipv6() {
+		socket.original_dst_ipv6()?
+	} else {
+		socket.original_dst()?
+	};
+	addr.as_socket()
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP address"))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_transparent<S: AsFd>(socket: &S) -> io::Result<()> {
+	SockRef::from(socket).set_ip_transparent(true)
+}
+
+// Get the original destination of a connection, unless it was made to the listener directly
+#[cfg(target_os = "linux")]
+pub(crate) fn original_destination<S: AsRawFd>(
+	mode: TransparentMode,
+	stream: &S,
+	local_addr: SocketAddr,
+	listen_on: SocketAddr,
+) -> Option<OriginalDestination> {
+	let destination = match mode {
+		TransparentMode::Redirect => original_dst(stream).ok()?,
+		TransparentMode::Tproxy => local_addr,
+	};
+	let to_listener = destination.port() == listen_on.port()
+		&& (listen_on.ip().is_unspecified() || listen_on.ip() == destination.ip());
+	if to_listener {
+		return None;
+	}
+	Some(OriginalDestination(destination))
+}
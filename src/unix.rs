@@ -0,0 +1,230 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::http::uri::{Authority, PathAndQuery, Scheme};
+use hyper::service::Service;
+use hyper::Uri;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+
+use crate::chain::percent_decode;
+use crate::connect::ConnectError;
+use crate::handlers::redirect::{Redirect, RedirectLogic};
+
+/// The scheme of URIs whose upstream is a Unix socket
+pub const SCHEME: &str = "unix";
+
+/// Get the authority that stands for the Unix socket at the path in `unix://` URIs
+///
+/// Paths can't be authorities, so the path is hex-encoded (like `/run/app.sock` as
+/// `2f72756e2f6170702e736f636b`), which is also how other HTTP clients for Unix sockets
+/// encode it.
+///
+/// # Panics
+/// Panics if the path is empty.
+pub fn socket_authority(path: &Path) -> Authority {
+	let hex = path
+		.as_os_str()
+		.as_bytes()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect::<String>();
+	hex.parse().expect("empty Unix socket path")
+}
+
+/// Get the path of the Unix socket of a URI, or `None` if its scheme isn't `unix`
+pub fn socket_path(uri: &Uri) -> Option<PathBuf> {
+	if uri.scheme_str() != Some(SCHEME) {
+		return None;
+	}
+	let hex = uri.host()?.as_bytes();
+	if hex.is_empty() || hex.len() % 2 != 0 {
+		return None;
+	}
+	let bytes = hex
+		.chunks(2)
+		.map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+		.collect::<Option<Vec<u8>>>()?;
+	Some(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes)))
+}
+
+/// A [`RedirectLogic`] that sends requests to an upstream listening on a Unix socket, like an
+/// application server or a container runtime
+///
+/// It is parsed from a URI like `unix:///var/run/app.sock`, where the path is percent-decoded.
+/// The path and query of requests are kept; their `Host` header is too, so the upstream sees
+/// the host the client asked for.
+///
+/// The client has to connect to Unix sockets, see
+/// [`UpstreamConfig::unix_sockets`](crate::connect::UpstreamConfig::unix_sockets).
+///
+/// # Example
+/// ```
+/// use proxylib::connect::UpstreamConfig;
+/// use proxylib::handlers::with_client::WithClient;
+/// use proxylib::handlers::Redirect;
+///
+/// let config = UpstreamConfig {
+///     unix_sockets: true,
+///     ..UpstreamConfig::default()
+/// };
+/// let handler = WithClient::new(
+///     Redirect::unix_socket("unix:///var/run/app.sock".parse().unwrap()),
+///     &config,
+/// );
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnixSocket {
+	/// The path of the socket
+	pub path: PathBuf,
+}
+
+/// The error returned when parsing a [`UnixSocket`] fails
+#[derive(Debug, Error)]
+#[error("invalid Unix socket URI: {0:?}")]
+pub struct ParseUnixSocketError(String);
+
+impl std::str::FromStr for UnixSocket {
+	type Err = ParseUnixSocketError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || ParseUnixSocketError(s.to_string());
+		let path = match s.trim().split_once("://") {
+			Some((scheme, path)) if scheme.eq_ignore_ascii_case(SCHEME) => path,
+			_ => return Err(invalid()),
+		};
+		if !path.starts_with('/') {
+			return Err(invalid());
+		}
+		let path = percent_decode(path).ok_or_else(invalid)?;
+		Ok(Self { path: path.into() })
+	}
+}
+
+impl RedirectLogic for UnixSocket {
+	fn change_uri(&self, uri: &mut Uri) {
+		let mut uri_parts = uri.clone().into_parts();
+		uri_parts.scheme = Some(SCHEME.parse::<Scheme>().unwrap());
+		uri_parts.authority = Some(socket_authority(&self.path));
+		if uri_parts.path_and_query.is_none() {
+			uri_parts.path_and_query = Some(PathAndQuery::from_static("/"));
+		}
+		*uri = Uri::from_parts(uri_parts).unwrap();
+	}
+}
+
+impl Redirect<UnixSocket> {
+	/// A convenience method to get a [`Redirect`]`<`[`UnixSocket`]`>`
+	pub fn unix_socket(to: UnixSocket) -> Self {
+		Self::new(to)
+	}
+}
+
+/// A connector adapter that connects to Unix sockets for `unix://` URIs (see
+/// [`socket_path`]) and uses another connector for other URIs
+///
+/// `unix://` URIs never go through the parent proxies of a
+/// [`ProxyChainConnector`](crate::chain::ProxyChainConnector) inside.
+#[derive(Debug, Clone)]
+pub struct UnixConnector<C> {
+	inner: C,
+}
+
+impl<C> UnixConnector<C> {
+	/// Use the inner connector for URIs that aren't `unix://` ones
+	pub fn new(inner: C) -> Self {
+		Self { inner }
+	}
+}
+
+/// A connection made by a [`UnixConnector`]
+pub enum MaybeUnix<T> {
+	/// A connection of the inner connector
+	Other(T),
+	/// A connection to a Unix socket
+	Unix(UnixStream),
+}
+
+impl<T: Connection> Connection for MaybeUnix<T> {
+	fn connected(&self) -> Connected {
+		match self {
+			Self::Other(stream) => stream.connected(),
+			Self::Unix(_) => Connected::new(),
+		}
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MaybeUnix<T> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Other(stream) => Pin::new(stream).poll_read(cx, buf),
+			Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+		}
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MaybeUnix<T> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Self::Other(stream) => Pin::new(stream).poll_write(cx, buf),
+			Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Other(stream) => Pin::new(stream).poll_flush(cx),
+			Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Other(stream) => Pin::new(stream).poll_shutdown(cx),
+			Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+		}
+	}
+}
+
+impl<C> Service<Uri> for UnixConnector<C>
+where
+	C: Service<Uri> + Send,
+	C::Response: Send + 'static,
+	C::Error: Into<ConnectError>,
+	C::Future: Send + 'static,
+{
+	type Response = MaybeUnix<C::Response>;
+	type Error = ConnectError;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ConnectError>> + Send>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectError>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, uri: Uri) -> Self::Future {
+		if let Some(path) = socket_path(&uri) {
+			return Box::pin(async move { Ok(MaybeUnix::Unix(UnixStream::connect(path).await?)) });
+		}
+		if uri.scheme_str() == Some(SCHEME) {
+			return Box::pin(async move {
+				Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid Unix socket URI").into())
+			});
+		}
+		let connecting = self.inner.call(uri);
+		Box::pin(async move { Ok(MaybeUnix::Other(connecting.await.map_err(Into::into)?)) })
+	}
+}